@@ -0,0 +1,439 @@
+//! Storage backends for the block devices (SD card and virtio-blk).
+//!
+//! Devices address a [`BlockBackend`] by byte offset and never hold the
+//! whole image themselves. [`MemoryBackend`] keeps the image in a `Vec`
+//! (WASM, downloaded images, tests); [`FileBackend`] reads and writes a host
+//! file in place, so guest writes persist and large images need no RAM copy.
+//! [`Qcow2Backend`] layers a copy-on-write qcow2 overlay over a read-only
+//! base image.
+//! [`HttpBackend`] fetches an image from a web server as it is read.
+
+use std::io;
+use std::sync::RwLock;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod http;
+#[cfg(not(target_arch = "wasm32"))]
+mod qcow2;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use http::HttpBackend;
+#[cfg(not(target_arch = "wasm32"))]
+pub use qcow2::Qcow2Backend;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::{File, OpenOptions, TryLockError};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
+
+/// Sector size used by every block device.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Largest buffer of zeros written at once by the default `write_zeroes`.
+const ZERO_CHUNK: u64 = 1 << 20;
+
+/// Byte-addressed disk image shared by a device and the host.
+pub trait BlockBackend: Send + Sync {
+    /// Image size in bytes.
+    fn size(&self) -> u64;
+
+    /// Fill `buf` from `offset`. Fails if the range is beyond the image.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Write `data` at `offset`. Fails if the range is beyond the image.
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Make completed writes durable.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// True if writes will fail, so devices can report the disk read-only.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Zero `len` bytes at `offset`. With `unmap`, the backend may free the
+    /// host storage behind them instead of writing zeros.
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let _ = unmap;
+        check_range(offset, len, self.size())?;
+        let zeros = vec![0; len.min(ZERO_CHUNK) as usize];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(ZERO_CHUNK) as usize;
+            self.write_at(offset + done, &zeros[..n])?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    /// The guest no longer needs `len` bytes at `offset`; their contents are
+    /// undefined afterwards. Backends that cannot free storage ignore it.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        check_range(offset, len, self.size())
+    }
+
+    /// Where the image lives, for log messages.
+    fn describe(&self) -> String;
+}
+
+fn out_of_range(offset: u64, len: u64, size: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("{} bytes at {:#x} beyond {}-byte image", len, offset, size),
+    )
+}
+
+fn check_range(offset: u64, len: u64, size: u64) -> io::Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(out_of_range(offset, len, size)),
+    }
+}
+
+/// Disk image held in memory. Writes last as long as the backend.
+pub struct MemoryBackend {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryBackend {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+        }
+    }
+
+    /// The image, including guest writes.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+}
+
+impl BlockBackend for MemoryBackend {
+    fn size(&self) -> u64 {
+        self.data.read().unwrap().len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.read().unwrap();
+        check_range(offset, buf.len() as u64, data.len() as u64)?;
+        let start = offset as usize;
+        buf.copy_from_slice(&data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        check_range(offset, src.len() as u64, data.len() as u64)?;
+        let start = offset as usize;
+        data[start..start + src.len()].copy_from_slice(src);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("memory ({} bytes)", self.size())
+    }
+}
+
+/// Whether the file at `path` starts with the qcow2 magic number.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn is_qcow2(path: &Path) -> Result<bool, String> {
+    qcow2::probe(path)
+}
+
+/// Open the image at `path`, as qcow2 if it has the qcow2 magic number and
+/// as a raw image otherwise.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn open_image(path: &Path, read_only: bool) -> Result<Arc<dyn BlockBackend>, String> {
+    if is_qcow2(path)? {
+        Ok(Arc::new(Qcow2Backend::open(path, read_only)?))
+    } else {
+        Ok(Arc::new(FileBackend::open(path, read_only)?))
+    }
+}
+
+/// A `--drive` option: `file=PATH[,ro=on|off][,serial=ID]`.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriveSpec {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// Reported to the guest by virtio-blk GET_ID (up to 20 bytes).
+    pub serial: Option<String>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DriveSpec {
+    /// Parse `file=PATH,key=value,...`. A leading element without `=` is
+    /// taken as the file.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut path = None;
+        let mut read_only = false;
+        let mut serial = None;
+        for (i, part) in spec.split(',').enumerate() {
+            match part.split_once('=') {
+                Some(("file", value)) => path = Some(PathBuf::from(value)),
+                Some(("ro" | "readonly", value)) => {
+                    read_only = match value {
+                        "on" | "true" | "1" => true,
+                        "off" | "false" | "0" => false,
+                        _ => {
+                            return Err(format!("invalid value '{}' for ro (use on or off)", value));
+                        }
+                    }
+                }
+                Some(("serial", value)) => serial = Some(value.to_string()),
+                Some((key, _)) => return Err(format!("unknown drive option '{}'", key)),
+                None if i == 0 && !part.is_empty() => path = Some(PathBuf::from(part)),
+                None => return Err(format!("expected key=value, got '{}'", part)),
+            }
+        }
+        let path = path.ok_or_else(|| format!("drive '{}' has no file=", spec))?;
+        Ok(Self {
+            path,
+            read_only,
+            serial,
+        })
+    }
+
+    /// Open the image (raw or qcow2).
+    pub fn open(&self) -> Result<Arc<dyn BlockBackend>, String> {
+        open_image(&self.path, self.read_only)
+    }
+}
+
+/// Open `path` and lock it: exclusively when writable, shared when not.
+#[cfg(not(target_arch = "wasm32"))]
+fn open_locked(path: &Path, read_only: bool) -> Result<File, String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)
+        .map_err(|e| format!("Failed to open disk image '{}': {}", path.display(), e))?;
+    let locked = if read_only {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(format!(
+            "Disk image '{}' is in use by another process",
+            path.display()
+        )),
+        Err(TryLockError::Error(e)) => Err(format!("Failed to lock '{}': {}", path.display(), e)),
+    }
+}
+
+/// Disk image file on the host, accessed with positioned reads and writes.
+///
+/// The file is locked for as long as the backend lives: exclusively when
+/// writable, shared when read-only, so two VMs cannot corrupt one image.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FileBackend {
+    file: File,
+    size: u64,
+    read_only: bool,
+    path: String,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileBackend {
+    /// Open and lock the image at `path`.
+    pub fn open(path: &Path, read_only: bool) -> Result<Self, String> {
+        let file = open_locked(path, read_only)?;
+        let size = file
+            .metadata()
+            .map_err(|e| format!("Failed to stat '{}': {}", path.display(), e))?
+            .len();
+        Ok(Self {
+            file,
+            size,
+            read_only,
+            path: path.display().to_string(),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BlockBackend for FileBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(offset, buf.len() as u64, self.size)?;
+        read_exact_at(&self.file, buf, offset)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("'{}' is read-only", self.path),
+            ));
+        }
+        check_range(offset, data.len() as u64, self.size)?;
+        write_all_at(&self.file, data, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.sync_data()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        check_range(offset, len, self.size)?;
+        if unmap && !self.read_only && punch_hole(&self.file, offset, len)? {
+            return Ok(());
+        }
+        let zeros = vec![0; len.min(ZERO_CHUNK) as usize];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(ZERO_CHUNK) as usize;
+            self.write_at(offset + done, &zeros[..n])?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        check_range(offset, len, self.size)?;
+        if !self.read_only {
+            punch_hole(&self.file, offset, len)?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.path.clone()
+    }
+}
+
+/// Free the host blocks behind `len` bytes at `offset`, which then read as
+/// zeros. Returns false where the host can't punch holes.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+    if len == 0 {
+        return Ok(true);
+    }
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: fallocate only reads its integer arguments.
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as i64, len as i64) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(all(not(target_os = "linux"), not(target_arch = "wasm32")))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_backend_bounds() {
+        let disk = MemoryBackend::new(vec![0; 1024]);
+        disk.write_at(512, &[1, 2, 3]).unwrap();
+        let mut buf = [0; 4];
+        disk.read_at(511, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+        assert!(disk.read_at(1022, &mut buf).is_err());
+        assert!(disk.write_at(u64::MAX, &[0]).is_err());
+        assert_eq!(disk.size(), 1024);
+    }
+
+    #[test]
+    fn drive_spec_parsing() {
+        let drive = DriveSpec::parse("file=data.img,ro=on,serial=dataset-1").unwrap();
+        assert_eq!(drive.path, PathBuf::from("data.img"));
+        assert!(drive.read_only);
+        assert_eq!(drive.serial.as_deref(), Some("dataset-1"));
+        let drive = DriveSpec::parse("scratch.qcow2").unwrap();
+        assert_eq!((drive.read_only, drive.serial), (false, None));
+        assert!(DriveSpec::parse("ro=on").is_err());
+        assert!(DriveSpec::parse("file=a,ro=maybe").is_err());
+        assert!(DriveSpec::parse("file=a,cache=none").is_err());
+    }
+
+    #[test]
+    fn file_backend_persists_and_locks() {
+        let path = std::env::temp_dir().join(format!("blockdev-{}.img", std::process::id()));
+        std::fs::write(&path, vec![0u8; 4096]).unwrap();
+        {
+            let disk = FileBackend::open(&path, false).unwrap();
+            assert_eq!(disk.size(), 4096);
+            disk.write_at(1000, b"persist").unwrap();
+            disk.flush().unwrap();
+            let err = FileBackend::open(&path, true).err().unwrap();
+            assert!(err.contains("in use"), "{}", err);
+            assert!(disk.write_at(4095, b"xx").is_err());
+        }
+        let disk = FileBackend::open(&path, true).unwrap();
+        let mut buf = [0; 7];
+        disk.read_at(1000, &mut buf).unwrap();
+        assert_eq!(&buf, b"persist");
+        assert!(disk.write_at(0, b"x").is_err());
+        drop(disk);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,743 @@
+//! Disk images served over HTTP(S), fetched as the guest reads them.
+//!
+//! The image is split into [`CHUNK_SIZE`] chunks. A chunk is downloaded
+//! with a range request the first time any byte of it is read and kept in
+//! a sparse local cache file, so a VM boots after fetching only what it
+//! touches and the next run reuses the cache. A chunk map next to the cache
+//! records which chunks are present; it is thrown away when the image on
+//! the server changes (ETag or Last-Modified) or the server reports
+//! neither. An optional manifest of per-chunk SHA-256 digests is checked
+//! as chunks arrive.
+//!
+//! Several VMs can share one cache. Each holds a shared lock on the cache
+//! file; the chunk map is locked exclusively while a chunk is fetched, and
+//! re-read first, so a chunk another VM already fetched is not downloaded
+//! again. The cache is only thrown away when no other VM has it open.
+//!
+//! The backend is read-only; layer a qcow2 overlay over it for writes.
+
+use super::{BlockBackend, check_range, read_exact_at, write_all_at};
+use crate::sdboot::{self, PartitionSelector};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Bytes fetched per request; also the unit of caching and verification.
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+/// Downloads of a chunk before a read fails.
+const ATTEMPTS: u32 = 3;
+
+/// First word of a chunk map; the image size and validator follow.
+const MAP_MAGIC: &str = "riscv-vm-http-cache-1";
+
+/// SHA-256 digest of one chunk.
+pub type ChunkDigest = [u8; 32];
+
+/// Read-only image at an HTTP(S) URL with a local chunk cache.
+pub struct HttpBackend {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    url: String,
+    agent: ureq::Agent,
+    size: u64,
+    /// Header and value sent with every fetch so a changed image fails
+    /// instead of mixing old and new chunks.
+    validator: Option<(&'static str, String)>,
+    cache: File,
+    /// Chunk map, also the lock that serializes fetches between VMs.
+    map: File,
+    present: Vec<AtomicBool>,
+    /// Held while downloading, so each chunk is fetched once.
+    fetching: Mutex<()>,
+    manifest: Option<Vec<ChunkDigest>>,
+    /// Set when the backend is dropped, to stop prefetching.
+    stop: AtomicBool,
+}
+
+impl HttpBackend {
+    /// Open the image at `url`, caching it in `cache` (created if missing,
+    /// with its chunk map at `cache` + `.map`). If the server ignores range
+    /// requests, the whole image is downloaded into the cache up front.
+    /// With `manifest`, every chunk is verified as it is downloaded.
+    pub fn open(
+        url: &str,
+        cache: &Path,
+        manifest: Option<Vec<ChunkDigest>>,
+    ) -> Result<Self, String> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_secs(60))
+            .build();
+        let response = agent
+            .get(url)
+            .set("Range", "bytes=0-0")
+            .call()
+            .map_err(|e| format!("Failed to open '{}': {}", url, e))?;
+        // A 200 reply carries the whole image instead of the first byte.
+        let whole = match response.status() {
+            206 => false,
+            200 => true,
+            status => return Err(format!("Failed to open '{}': HTTP {}", url, status)),
+        };
+        let size = if whole {
+            response.header("Content-Length")
+        } else {
+            response
+                .header("Content-Range")
+                .and_then(|range| range.rsplit_once('/'))
+                .map(|(_, total)| total)
+        }
+        .and_then(|size| size.parse::<u64>().ok())
+        .ok_or_else(|| format!("'{}' did not report the image size", url))?;
+        // Weak ETags can't be used with If-Match.
+        let validator = match (response.header("ETag"), response.header("Last-Modified")) {
+            (Some(etag), _) if !etag.starts_with("W/") => Some(("If-Match", etag.to_string())),
+            (_, Some(date)) => Some(("If-Unmodified-Since", date.to_string())),
+            _ => None,
+        };
+
+        let chunks = size.div_ceil(CHUNK_SIZE) as usize;
+        if let Some(manifest) = &manifest
+            && manifest.len() != chunks
+        {
+            return Err(format!(
+                "manifest has {} chunks, but '{}' has {}",
+                manifest.len(),
+                url,
+                chunks
+            ));
+        }
+
+        let err = |e: io::Error| format!("Failed to set up cache '{}': {}", cache.display(), e);
+        let open = |path: &Path| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(err)
+        };
+        let file = open(cache)?;
+        let mut map_path = cache.as_os_str().to_owned();
+        map_path.push(".map");
+        let map = open(Path::new(&map_path))?;
+
+        let map_lock = MapLock::new(&map).map_err(err)?;
+        let header = map_header(size, validator.as_ref());
+        let saved = match read_map(&map) {
+            Ok(map) if validator.is_some() => map
+                .strip_prefix(header.as_bytes())
+                .filter(|bits| bits.len() == chunks.div_ceil(8))
+                .map(<[u8]>::to_vec),
+            _ => None,
+        };
+        let mut bits = match saved {
+            Some(bits) => bits,
+            None => {
+                // Other VMs may still be reading the chunks we would drop.
+                match file.try_lock() {
+                    Ok(()) => {}
+                    Err(TryLockError::WouldBlock) => {
+                        return Err(format!(
+                            "Cache '{}' holds another version of '{}' and is in use by another process",
+                            cache.display(),
+                            url
+                        ));
+                    }
+                    Err(TryLockError::Error(e)) => return Err(err(e)),
+                }
+                // Start over, keeping the file sparse.
+                file.set_len(0).map_err(err)?;
+                file.set_len(size).map_err(err)?;
+                let bits = vec![0; chunks.div_ceil(8)];
+                write_map(&map, &header, &bits).map_err(err)?;
+                file.unlock().map_err(err)?;
+                bits
+            }
+        };
+        let complete = (0..chunks).all(|i| bits[i / 8] & (1 << (i % 8)) != 0);
+        if whole && !complete {
+            log::info!(
+                "[HTTP] {} ignores range requests; downloading it whole",
+                url
+            );
+            let download = |e: io::Error| format!("Failed to download '{}': {}", url, e);
+            let mut reader = response.into_reader().take(size);
+            let mut buf = vec![0; CHUNK_SIZE as usize];
+            for chunk in 0..chunks {
+                let start = chunk as u64 * CHUNK_SIZE;
+                let data = &mut buf[..(size - start).min(CHUNK_SIZE) as usize];
+                reader.read_exact(data).map_err(download)?;
+                if let Some(manifest) = &manifest
+                    && Sha256::digest(&*data)[..] != manifest[chunk]
+                {
+                    return Err(format!(
+                        "chunk {} of '{}' does not match the manifest",
+                        chunk, url
+                    ));
+                }
+                write_all_at(&file, data, start).map_err(err)?;
+                bits[chunk / 8] |= 1 << (chunk % 8);
+            }
+            file.sync_data().map_err(err)?;
+            write_map(&map, &header, &bits).map_err(err)?;
+        }
+        // Nobody else can hold the exclusive lock while we hold the map.
+        file.try_lock_shared()
+            .map_err(|e| format!("Failed to lock '{}': {}", cache.display(), e))?;
+        drop(map_lock);
+        let present = (0..chunks)
+            .map(|i| AtomicBool::new(bits[i / 8] & (1 << (i % 8)) != 0))
+            .collect();
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                url: url.to_string(),
+                agent,
+                size,
+                validator,
+                cache: file,
+                map,
+                present,
+                fetching: Mutex::new(()),
+                manifest,
+                stop: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    /// Number of chunks already in the cache.
+    pub fn cached_chunks(&self) -> usize {
+        let present = &self.inner.present;
+        present.iter().filter(|p| p.load(Ordering::Acquire)).count()
+    }
+
+    /// Download the chunks covering `len` bytes at `offset` on a background
+    /// thread. Reads of chunks it has not reached yet fetch them as usual.
+    pub fn prefetch(&self, offset: u64, len: u64) -> JoinHandle<()> {
+        let inner = Arc::clone(&self.inner);
+        thread::Builder::new()
+            .name("http-prefetch".to_string())
+            .spawn(move || {
+                let end = offset.saturating_add(len).min(inner.size);
+                let mut chunk = offset / CHUNK_SIZE;
+                while chunk * CHUNK_SIZE < end && !inner.stop.load(Ordering::Relaxed) {
+                    if let Err(e) = inner.ensure(chunk as usize) {
+                        log::warn!("[HTTP] Prefetch of {} stopped: {}", inner.url, e);
+                        break;
+                    }
+                    chunk += 1;
+                }
+            })
+            .expect("Failed to spawn prefetch thread")
+    }
+
+    /// Start prefetching the boot partition (kernel, device tree), which
+    /// the SD card boot reads in full: the one `boot` selects, or the first
+    /// FAT partition. Returns `None` if there is no such partition.
+    pub fn prefetch_boot_partition(
+        &self,
+        boot: Option<&PartitionSelector>,
+    ) -> io::Result<Option<JoinHandle<()>>> {
+        // Chunk 0 holds the partition table; fetch errors surface here.
+        self.read_at(0, &mut [0; 512])?;
+        let Ok(partitions) = sdboot::read_partitions(self) else {
+            return Ok(None);
+        };
+        let Ok(part) = sdboot::select_boot_partition(&partitions, boot) else {
+            return Ok(None);
+        };
+        Ok(Some(self.prefetch(
+            part.start_lba * super::SECTOR_SIZE,
+            part.sector_count * super::SECTOR_SIZE,
+        )))
+    }
+}
+
+impl Drop for HttpBackend {
+    fn drop(&mut self) {
+        self.inner.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Inner {
+    /// Make sure `chunk` is in the cache.
+    fn ensure(&self, chunk: usize) -> io::Result<()> {
+        if self.present[chunk].load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let _fetching = self.fetching.lock().unwrap();
+        if self.present[chunk].load(Ordering::Acquire) {
+            return Ok(());
+        }
+        // Another VM sharing the cache may have fetched it meanwhile.
+        let _map_lock = MapLock::new(&self.map)?;
+        self.load_map()?;
+        if self.present[chunk].load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let start = chunk as u64 * CHUNK_SIZE;
+        let len = (self.size - start).min(CHUNK_SIZE);
+        let mut attempt = 1;
+        let data = loop {
+            match self.download(chunk, start, len) {
+                Ok(data) => break data,
+                Err(e) if attempt < ATTEMPTS => {
+                    log::warn!("[HTTP] Chunk {} of {}: {}; retrying", chunk, self.url, e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        write_all_at(&self.cache, &data, start)?;
+        // The map must never list a chunk the cache doesn't hold.
+        self.cache.sync_data()?;
+        self.present[chunk].store(true, Ordering::Release);
+        self.save_map()
+    }
+
+    fn download(&self, chunk: usize, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut request = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, start + len - 1));
+        if let Some((header, value)) = &self.validator {
+            request = request.set(header, value);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(412, _)) => {
+                return Err(io::Error::other(format!(
+                    "'{}' changed on the server",
+                    self.url
+                )));
+            }
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "HTTP {} for a range request",
+                response.status()
+            )));
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        response.into_reader().take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(manifest) = &self.manifest
+            && Sha256::digest(&data)[..] != manifest[chunk]
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk {} does not match the manifest", chunk),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Mark the chunks other VMs have added to the map as present. The
+    /// caller holds the map lock.
+    fn load_map(&self) -> io::Result<()> {
+        let map = read_map(&self.map)?;
+        let header = map_header(self.size, self.validator.as_ref());
+        if let Some(bits) = map.strip_prefix(header.as_bytes())
+            && bits.len() == self.present.len().div_ceil(8)
+        {
+            for (i, present) in self.present.iter().enumerate() {
+                if bits[i / 8] & (1 << (i % 8)) != 0 {
+                    present.store(true, Ordering::Release);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the map out. The caller holds the map lock.
+    fn save_map(&self) -> io::Result<()> {
+        let mut bits = vec![0u8; self.present.len().div_ceil(8)];
+        for (i, present) in self.present.iter().enumerate() {
+            if present.load(Ordering::Acquire) {
+                bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+        write_map(
+            &self.map,
+            &map_header(self.size, self.validator.as_ref()),
+            &bits,
+        )
+    }
+}
+
+/// Exclusive lock on a chunk map, released on drop.
+struct MapLock<'a>(&'a File);
+
+impl<'a> MapLock<'a> {
+    fn new(map: &'a File) -> io::Result<Self> {
+        map.lock()?;
+        Ok(Self(map))
+    }
+}
+
+impl Drop for MapLock<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+fn read_map(map: &File) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; map.metadata()?.len() as usize];
+    read_exact_at(map, &mut data, 0)?;
+    Ok(data)
+}
+
+fn write_map(map: &File, header: &str, bits: &[u8]) -> io::Result<()> {
+    let data = [header.as_bytes(), bits].concat();
+    write_all_at(map, &data, 0)?;
+    map.set_len(data.len() as u64)
+}
+
+impl BlockBackend for HttpBackend {
+    fn size(&self) -> u64 {
+        self.inner.size
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(offset, buf.len() as u64, self.inner.size)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let first = offset / CHUNK_SIZE;
+        let last = (offset + buf.len() as u64 - 1) / CHUNK_SIZE;
+        for chunk in first..=last {
+            self.inner.ensure(chunk as usize)?;
+        }
+        read_exact_at(&self.inner.cache, buf, offset)
+    }
+
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("'{}' is read-only", self.inner.url),
+        ))
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} ({} of {} MiB cached)",
+            self.inner.url,
+            self.cached_chunks(),
+            self.inner.size.div_ceil(CHUNK_SIZE)
+        )
+    }
+}
+
+fn map_header(size: u64, validator: Option<&(&'static str, String)>) -> String {
+    let validator = validator.map_or("", |(_, value)| value);
+    format!("{} {} {}\n", MAP_MAGIC, size, validator)
+}
+
+/// Where the image at `url` is cached by default: a file named after the
+/// URL's hash in `$XDG_CACHE_HOME/riscv-vm` (or `~/.cache/riscv-vm`, or the
+/// temporary directory), which is created.
+pub fn default_cache_path(url: &str) -> Result<PathBuf, String> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    let dir = base.join("riscv-vm");
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create '{}': {}", dir.display(), e))?;
+    let hash = hex::encode(&Sha256::digest(url.as_bytes())[..8]);
+    Ok(dir.join(format!("{}.img", hash)))
+}
+
+/// Digest every chunk of `disk`, one lowercase hex SHA-256 per line: the
+/// manifest format read by [`parse_manifest`].
+pub fn manifest(disk: &dyn BlockBackend) -> io::Result<String> {
+    let mut out = String::new();
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut offset = 0;
+    while offset < disk.size() {
+        let len = (disk.size() - offset).min(CHUNK_SIZE) as usize;
+        disk.read_at(offset, &mut buf[..len])?;
+        out.push_str(&hex::encode(Sha256::digest(&buf[..len])));
+        out.push('\n');
+        offset += len as u64;
+    }
+    Ok(out)
+}
+
+/// Parse a manifest: one hex SHA-256 digest per chunk, in order. Blank
+/// lines and lines starting with `#` are ignored.
+pub fn parse_manifest(text: &str) -> Result<Vec<ChunkDigest>, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .enumerate()
+        .map(|(i, line)| {
+            let mut digest = [0; 32];
+            hex::decode_to_slice(line, &mut digest)
+                .map_err(|e| format!("manifest entry {}: {}", i + 1, e))?;
+            Ok(digest)
+        })
+        .collect()
+}
+
+/// Read a manifest from a local file or an HTTP(S) URL.
+pub fn read_manifest(source: &str) -> Result<Vec<ChunkDigest>, String> {
+    let text = if source.starts_with("http://") || source.starts_with("https://") {
+        ureq::get(source)
+            .call()
+            .map_err(|e| format!("Failed to download manifest '{}': {}", source, e))?
+            .into_string()
+            .map_err(|e| format!("Failed to read manifest '{}': {}", source, e))?
+    } else {
+        std::fs::read_to_string(source)
+            .map_err(|e| format!("Failed to read manifest '{}': {}", source, e))?
+    };
+    parse_manifest(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::MemoryBackend;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Serve `image` on loopback with range support. Returns the URL and
+    /// the count of requests answered.
+    fn serve(image: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        serve_with(image, true)
+    }
+
+    /// Like [`serve`], but without `ranges` every request gets the whole
+    /// image with a 200, as from a server that ignores `Range`.
+    fn serve_with(image: Vec<u8>, ranges: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/sdcard.img", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Range: bytes=")
+                        && let Some((start, end)) = value.split_once('-')
+                    {
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                }
+                let (start, end) = range.expect("only range requests are served");
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (body, head) = if ranges {
+                    let body = &image[start..=end];
+                    let head = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n\
+                         Content-Length: {}\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                        start,
+                        end,
+                        image.len(),
+                        body.len()
+                    );
+                    (body, head)
+                } else {
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\
+                         Connection: close\r\n\r\n",
+                        image.len()
+                    );
+                    (&image[..], head)
+                };
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(body);
+            }
+        });
+        (url, requests)
+    }
+
+    /// `chunks` chunks and a half; every 512-byte sector holds its index.
+    fn image(chunks: u64) -> Vec<u8> {
+        let len = (chunks * CHUNK_SIZE + CHUNK_SIZE / 2) as usize;
+        (0..len).map(|i| (i / 512) as u8).collect()
+    }
+
+    #[test]
+    fn chunks_are_fetched_once_and_cached() {
+        let data = image(2);
+        let (url, requests) = serve(data.clone());
+        let dir = std::env::temp_dir().join(format!("http-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("image.cache");
+
+        let disk = HttpBackend::open(&url, &cache, None).unwrap();
+        assert_eq!(disk.size(), data.len() as u64);
+        assert!(disk.is_read_only());
+        assert!(disk.write_at(0, &[1]).is_err());
+
+        // Straddles the first two chunks.
+        let at = CHUNK_SIZE as usize - 100;
+        let mut buf = vec![0; 200];
+        disk.read_at(at as u64, &mut buf).unwrap();
+        assert_eq!(buf, data[at..at + 200]);
+        disk.read_at(0, &mut buf).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        // The short last chunk.
+        let tail = data.len() - 10;
+        disk.read_at(tail as u64, &mut buf[..10]).unwrap();
+        assert_eq!(buf[..10], data[tail..]);
+        assert!(disk.read_at(tail as u64, &mut buf[..11]).is_err());
+        assert_eq!(disk.cached_chunks(), 3);
+        drop(disk);
+
+        // Another run only asks for the size.
+        let disk = HttpBackend::open(&url, &cache, None).unwrap();
+        assert_eq!(disk.cached_chunks(), 3);
+        disk.read_at(at as u64, &mut buf).unwrap();
+        assert_eq!(buf, data[at..at + 200]);
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+        drop(disk);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn whole_image_without_range_support() {
+        let data = image(1);
+        let (url, requests) = serve_with(data.clone(), false);
+        let dir = std::env::temp_dir().join(format!("http-whole-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("image.cache");
+
+        let disk = HttpBackend::open(&url, &cache, None).unwrap();
+        assert_eq!(disk.size(), data.len() as u64);
+        assert_eq!(disk.cached_chunks(), 2);
+        let mut buf = vec![0; data.len()];
+        disk.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        drop(disk);
+
+        // A complete cache is reused without reading the body again.
+        let disk = HttpBackend::open(&url, &cache, None).unwrap();
+        assert_eq!(disk.cached_chunks(), 2);
+        drop(disk);
+
+        let mut digests =
+            parse_manifest(&manifest(&MemoryBackend::new(data.clone())).unwrap()).unwrap();
+        digests[1][0] ^= 1;
+        std::fs::remove_file(dir.join("image.cache.map")).unwrap();
+        assert!(HttpBackend::open(&url, &cache, Some(digests)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn two_vms_share_a_cache() {
+        let data = image(1);
+        let (url, requests) = serve(data.clone());
+        let dir = std::env::temp_dir().join(format!("http-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("image.cache");
+
+        let first = HttpBackend::open(&url, &cache, None).unwrap();
+        let second = HttpBackend::open(&url, &cache, None).unwrap();
+        let mut buf = [0; 512];
+        first.read_at(512, &mut buf).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        // The second VM finds the chunk in the shared map.
+        second.read_at(1024, &mut buf).unwrap();
+        assert_eq!(buf, [2; 512]);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        second.read_at(CHUNK_SIZE, &mut buf).unwrap();
+        first.read_at(CHUNK_SIZE + 512, &mut buf).unwrap();
+        assert_eq!(buf, data[CHUNK_SIZE as usize + 512..][..512]);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        assert_eq!(first.cached_chunks(), 2);
+
+        // A changed image can't reset the cache under them.
+        let (other, _) = serve(image(2));
+        assert!(HttpBackend::open(&other, &cache, None).is_err());
+        drop((first, second));
+        assert!(HttpBackend::open(&other, &cache, None).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_catches_bad_chunks() {
+        let data = image(1);
+        let mut digests =
+            parse_manifest(&manifest(&MemoryBackend::new(data.clone())).unwrap()).unwrap();
+        assert_eq!(digests.len(), 2);
+        digests[1][0] ^= 1;
+        let (url, requests) = serve(data);
+        let dir = std::env::temp_dir().join(format!("http-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("image.cache");
+
+        assert!(HttpBackend::open(&url, &cache, Some(digests[..1].to_vec())).is_err());
+        let disk = HttpBackend::open(&url, &cache, Some(digests)).unwrap();
+        let mut buf = [0; 512];
+        disk.read_at(512, &mut buf).unwrap();
+        assert_eq!(buf, [1; 512]);
+        let err = disk.read_at(CHUNK_SIZE, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(disk.cached_chunks(), 1);
+        // Two opens, one good chunk and every attempt at the bad one.
+        assert_eq!(requests.load(Ordering::SeqCst), 3 + ATTEMPTS as usize);
+
+        assert!(parse_manifest("# comment\n\nzz").is_err());
+        drop(disk);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn boot_partition_is_prefetched() {
+        let mut data = image(3);
+        // A FAT32 partition covering the second and third chunks.
+        let sectors = (CHUNK_SIZE / 512) as u32;
+        data[446 + 4] = 0x0c;
+        data[446 + 8..446 + 12].copy_from_slice(&sectors.to_le_bytes());
+        data[446 + 12..446 + 16].copy_from_slice(&(2 * sectors).to_le_bytes());
+        data[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let (url, requests) = serve(data.clone());
+        let dir = std::env::temp_dir().join(format!("http-prefetch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let disk = HttpBackend::open(&url, &dir.join("image.cache"), None).unwrap();
+        disk.prefetch_boot_partition(None)
+            .unwrap()
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(disk.cached_chunks(), 3);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        let mut buf = vec![0; 2 * CHUNK_SIZE as usize];
+        disk.read_at(CHUNK_SIZE, &mut buf).unwrap();
+        assert_eq!(buf, data[CHUNK_SIZE as usize..3 * CHUNK_SIZE as usize]);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        drop(disk);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,915 @@
+//! qcow2 images (versions 2 and 3), used as copy-on-write overlays.
+//!
+//! Clusters the image has not written come from its backing image, or read
+//! as zeros without one. The first write to such a cluster copies it from
+//! the backing image into a new cluster at the end of the file, so the base
+//! is opened read-only and can be shared by any number of overlays.
+//! Compressed clusters, encryption, external data files and writing to
+//! images with internal snapshots are not supported.
+
+use super::{
+    BlockBackend, FileBackend, check_range, open_image, open_locked, punch_hole, read_exact_at,
+    write_all_at,
+};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const MAGIC: u32 = 0x5146_49fb; // "QFI\xfb"
+const V2_HEADER_LEN: u64 = 72;
+const V3_HEADER_LEN: u32 = 104;
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Host offset bits of L1 and L2 entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster's refcount is exactly one, so it may be written in place.
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeros (version 3).
+const ZERO: u64 = 1;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+/// Refcounts are 16 bits wide (refcount_order 4), as in every v2 image.
+const REFCOUNT_ORDER: u32 = 4;
+/// Cluster size of new images (64 KiB), as qemu-img.
+const CLUSTER_BITS: u32 = 16;
+/// Longest backing file name qemu accepts.
+const MAX_BACKING_NAME: usize = 1023;
+
+fn be32(b: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn be64(b: &[u8], off: usize) -> u64 {
+    u64::from_be_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+fn read_u64(file: &File, offset: u64) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    read_exact_at(file, &mut buf, offset)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_u64(file: &File, offset: u64, value: u64) -> io::Result<()> {
+    write_all_at(file, &value.to_be_bytes(), offset)
+}
+
+/// Read `count` big-endian u64s at `offset`.
+fn read_table(file: &File, offset: u64, count: usize) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0; count * 8];
+    read_exact_at(file, &mut buf, offset)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|e| u64::from_be_bytes(e.try_into().unwrap()))
+        .collect())
+}
+
+/// True if the file at `path` starts with the qcow2 magic number.
+pub(super) fn probe(path: &Path) -> Result<bool, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to open disk image '{}': {}", path.display(), e))?;
+    let mut magic = [0; 4];
+    Ok(read_exact_at(&file, &mut magic, 0).is_ok() && u32::from_be_bytes(magic) == MAGIC)
+}
+
+/// The parts of the image header this implementation uses.
+struct Header {
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    refcount_order: u32,
+    nb_snapshots: u32,
+    autoclear_features: u64,
+    backing_file: Option<String>,
+    backing_format: Option<String>,
+}
+
+impl Header {
+    fn read(file: &File, path: &Path) -> Result<Self, String> {
+        let err = |what: &str| format!("'{}': {}", path.display(), what);
+        let io_err = |e: io::Error| err(&format!("truncated qcow2 header ({})", e));
+
+        let mut h = [0; V3_HEADER_LEN as usize];
+        read_exact_at(file, &mut h[..V2_HEADER_LEN as usize], 0).map_err(io_err)?;
+        if be32(&h, 0) != MAGIC {
+            return Err(err("not a qcow2 image"));
+        }
+        let version = be32(&h, 4);
+        let (header_len, refcount_order, autoclear_features) = match version {
+            2 => (V2_HEADER_LEN, REFCOUNT_ORDER, 0),
+            3 => {
+                read_exact_at(file, &mut h, 0).map_err(io_err)?;
+                let incompatible = be64(&h, 72);
+                if incompatible & INCOMPAT_DIRTY != 0 {
+                    return Err(err(
+                        "image was not closed cleanly; repair it with `qemu-img check -r all`",
+                    ));
+                }
+                if incompatible != 0 {
+                    return Err(err(&format!(
+                        "uses unsupported qcow2 features ({:#x})",
+                        incompatible
+                    )));
+                }
+                (be32(&h, 100) as u64, be32(&h, 96), be64(&h, 88))
+            }
+            v => return Err(err(&format!("unsupported qcow2 version {}", v))),
+        };
+        if be32(&h, 32) != 0 {
+            return Err(err("encrypted qcow2 images are not supported"));
+        }
+        let cluster_bits = be32(&h, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(err(&format!("invalid cluster size 2^{}", cluster_bits)));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+
+        // Header extensions follow the header, up to the end of cluster 0.
+        let mut backing_format = None;
+        let mut offset = header_len;
+        while offset + 8 <= cluster_size {
+            let mut ext = [0; 8];
+            read_exact_at(file, &mut ext, offset).map_err(io_err)?;
+            let (kind, len) = (be32(&ext, 0), be32(&ext, 4) as u64);
+            if kind == EXT_END {
+                break;
+            }
+            if offset + 8 + len > cluster_size {
+                return Err(err("header extension runs past the first cluster"));
+            }
+            if kind == EXT_BACKING_FORMAT {
+                let mut name = vec![0; len as usize];
+                read_exact_at(file, &mut name, offset + 8).map_err(io_err)?;
+                backing_format = Some(String::from_utf8_lossy(&name).into_owned());
+            }
+            offset += 8 + len.next_multiple_of(8);
+        }
+
+        let backing_offset = be64(&h, 8);
+        let backing_len = be32(&h, 16) as usize;
+        let backing_file = if backing_offset != 0 && backing_len > 0 {
+            if backing_len > MAX_BACKING_NAME {
+                return Err(err("backing file name too long"));
+            }
+            let mut name = vec![0; backing_len];
+            read_exact_at(file, &mut name, backing_offset).map_err(io_err)?;
+            Some(String::from_utf8(name).map_err(|_| err("backing file name is not UTF-8"))?)
+        } else {
+            None
+        };
+
+        // The tables are allocated before they are read, so a corrupt
+        // header must not get to ask for more than the file can hold.
+        let size = be64(&h, 24);
+        let l1_size = be32(&h, 36);
+        let l1_offset = be64(&h, 40);
+        let refcount_table_offset = be64(&h, 48);
+        let refcount_table_clusters = be32(&h, 56);
+        let file_len = file.metadata().map_err(|e| err(&e.to_string()))?.len();
+        let fits =
+            |offset: u64, len: u64| offset.checked_add(len).is_some_and(|end| end <= file_len);
+        let max_l1_size = size.div_ceil(cluster_size).max(1);
+        if l1_size as u64 > max_l1_size || !fits(l1_offset, l1_size as u64 * 8) {
+            return Err(err(&format!("invalid L1 table size {}", l1_size)));
+        }
+        if !fits(
+            refcount_table_offset,
+            (refcount_table_clusters as u64) << cluster_bits,
+        ) {
+            return Err(err(&format!(
+                "invalid refcount table size {} clusters",
+                refcount_table_clusters
+            )));
+        }
+
+        Ok(Self {
+            version,
+            cluster_bits,
+            size,
+            l1_size,
+            l1_offset,
+            refcount_table_offset,
+            refcount_table_clusters,
+            refcount_order,
+            nb_snapshots: be32(&h, 60),
+            autoclear_features,
+            backing_file,
+            backing_format,
+        })
+    }
+}
+
+/// Tables that change as clusters are allocated.
+struct Meta {
+    l1: Vec<u64>,
+    refcount_table: Vec<u64>,
+    /// Where the next cluster goes: the end of the file.
+    next_free: u64,
+}
+
+/// Where a guest cluster's data is.
+enum Cluster {
+    /// In the backing image (or zeros without one).
+    Unallocated,
+    /// Zeros; a host cluster may already be reserved for it.
+    Zero(u64),
+    /// At this host offset.
+    Data(u64),
+}
+
+/// A qcow2 image, optionally layered over a backing image.
+pub struct Qcow2Backend {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    cluster_bits: u32,
+    l1_offset: u64,
+    refcount_table_offset: u64,
+    read_only: bool,
+    /// Version 3 images can mark clusters as reading zeros.
+    zero_clusters: bool,
+    backing: Option<Arc<dyn BlockBackend>>,
+    backing_file: Option<PathBuf>,
+    meta: Mutex<Meta>,
+}
+
+impl Qcow2Backend {
+    /// Open and lock the image at `path`. Its backing image, if it names
+    /// one, is opened read-only (relative names are relative to `path`).
+    pub fn open(path: &Path, read_only: bool) -> Result<Self, String> {
+        let file = open_locked(path, read_only)?;
+        let header = Header::read(&file, path)?;
+        let backing = match backing_path(path, &header) {
+            Some(base) => Some(open_backing(&base, header.backing_format.as_deref(), true)?),
+            None => None,
+        };
+        Self::from_parts(file, path, header, read_only, backing)
+    }
+
+    /// Open the image at `path` over `backing` instead of the image its
+    /// header names, e.g. a writable base for [`commit`](Self::commit).
+    pub fn open_with_backing(
+        path: &Path,
+        read_only: bool,
+        backing: Option<Arc<dyn BlockBackend>>,
+    ) -> Result<Self, String> {
+        let file = open_locked(path, read_only)?;
+        let header = Header::read(&file, path)?;
+        Self::from_parts(file, path, header, read_only, backing)
+    }
+
+    /// Open the image at `path` read-only over a writable backing image,
+    /// ready for [`commit`](Self::commit).
+    pub fn open_for_commit(path: &Path) -> Result<Self, String> {
+        let file = open_locked(path, true)?;
+        let header = Header::read(&file, path)?;
+        let base = backing_path(path, &header)
+            .ok_or_else(|| format!("'{}' has no backing image", path.display()))?;
+        let backing = open_backing(&base, header.backing_format.as_deref(), false)?;
+        Self::from_parts(file, path, header, true, Some(backing))
+    }
+
+    fn from_parts(
+        file: File,
+        path: &Path,
+        header: Header,
+        read_only: bool,
+        backing: Option<Arc<dyn BlockBackend>>,
+    ) -> Result<Self, String> {
+        let err = |what: String| format!("'{}': {}", path.display(), what);
+        if !read_only {
+            if header.nb_snapshots > 0 {
+                return Err(err(
+                    "cannot write to an image with internal snapshots".into()
+                ));
+            }
+            if header.refcount_order != REFCOUNT_ORDER {
+                return Err(err(format!(
+                    "cannot write with {}-bit refcounts",
+                    1 << header.refcount_order
+                )));
+            }
+            // Autoclear features we don't maintain must be cleared on write.
+            if header.autoclear_features != 0 {
+                write_u64(&file, 88, 0).map_err(|e| err(e.to_string()))?;
+            }
+        }
+
+        let cluster_size = 1u64 << header.cluster_bits;
+        let l1 = read_table(&file, header.l1_offset, header.l1_size as usize)
+            .map_err(|e| err(format!("failed to read L1 table: {}", e)))?;
+        let refcount_table = read_table(
+            &file,
+            header.refcount_table_offset,
+            (header.refcount_table_clusters as usize) << (header.cluster_bits - 3),
+        )
+        .map_err(|e| err(format!("failed to read refcount table: {}", e)))?;
+        let file_len = file.metadata().map_err(|e| err(e.to_string()))?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: header.size,
+            cluster_bits: header.cluster_bits,
+            l1_offset: header.l1_offset,
+            refcount_table_offset: header.refcount_table_offset,
+            read_only,
+            zero_clusters: header.version >= 3,
+            backing_file: backing_path(path, &header),
+            backing,
+            meta: Mutex::new(Meta {
+                l1,
+                refcount_table,
+                next_free: file_len.next_multiple_of(cluster_size),
+            }),
+            file,
+        })
+    }
+
+    /// Create an empty image at `path` (which must not exist) of `size`
+    /// bytes. `backing` is the backing image's name as stored in the header
+    /// and its format (`raw` or `qcow2`).
+    pub fn create(path: &Path, size: u64, backing: Option<(&str, &str)>) -> Result<(), String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| format!("Failed to create '{}': {}", path.display(), e))?;
+        format_image(&file, size, backing)
+            .map_err(|e| format!("Failed to write '{}': {}", path.display(), e))
+    }
+
+    /// Create an overlay at `path` over the image at `base`, the same size
+    /// as it. The base is named by its absolute path.
+    pub fn create_overlay(path: &Path, base: &Path) -> Result<(), String> {
+        let format = if probe(base)? { "qcow2" } else { "raw" };
+        let size = open_backing(base, Some(format), true)?.size();
+        let base = std::path::absolute(base)
+            .map_err(|e| format!("Failed to resolve '{}': {}", base.display(), e))?;
+        let name = base
+            .to_str()
+            .ok_or_else(|| format!("'{}' is not valid UTF-8", base.display()))?;
+        Self::create(path, size, Some((name, format)))
+    }
+
+    /// Throw away everything written to the image at `path`, keeping its
+    /// size and backing image.
+    pub fn discard(path: &Path) -> Result<(), String> {
+        let file = open_locked(path, false)?;
+        let header = Header::read(&file, path)?;
+        let backing = header
+            .backing_file
+            .as_deref()
+            .map(|name| (name, header.backing_format.as_deref().unwrap_or("raw")));
+        file.set_len(0)
+            .and_then(|()| format_image(&file, header.size, backing))
+            .map_err(|e| format!("Failed to rewrite '{}': {}", path.display(), e))
+    }
+
+    /// Path of the backing image named in the header, if any.
+    pub fn backing_file(&self) -> Option<&Path> {
+        self.backing_file.as_deref()
+    }
+
+    /// Copy every cluster written to this image into its backing image and
+    /// flush it. Returns the number of bytes copied.
+    pub fn commit(&self) -> Result<u64, String> {
+        let backing = self
+            .backing
+            .as_ref()
+            .ok_or_else(|| format!("'{}' has no backing image", self.path.display()))?;
+        let err = |e: io::Error| format!("Commit of '{}' failed: {}", self.path.display(), e);
+        let meta = self.meta.lock().unwrap();
+        let cluster_size = self.cluster_size();
+        let l2_bits = self.cluster_bits - 3;
+        let mut copied = 0;
+        for (l1_index, l1_entry) in meta.l1.iter().enumerate() {
+            let table = l1_entry & OFFSET_MASK;
+            if table == 0 {
+                continue;
+            }
+            let l2 = read_table(&self.file, table, 1 << l2_bits).map_err(err)?;
+            for (l2_index, &entry) in l2.iter().enumerate() {
+                let pos = (((l1_index as u64) << l2_bits) + l2_index as u64) << self.cluster_bits;
+                let len = cluster_size
+                    .min(self.size.saturating_sub(pos))
+                    .min(backing.size().saturating_sub(pos)) as usize;
+                let mut buf = vec![0; len];
+                match classify(entry).map_err(err)? {
+                    Cluster::Unallocated => continue,
+                    Cluster::Zero(_) => {}
+                    Cluster::Data(host) => {
+                        read_exact_at(&self.file, &mut buf, host).map_err(err)?
+                    }
+                }
+                backing.write_at(pos, &buf).map_err(err)?;
+                copied += len as u64;
+            }
+        }
+        backing.flush().map_err(err)?;
+        Ok(copied)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// File offset of the L2 entry for guest offset `pos`, or `None` if its
+    /// L2 table does not exist and `allocate` is false.
+    fn l2_slot(&self, meta: &mut Meta, pos: u64, allocate: bool) -> io::Result<Option<u64>> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (pos >> (self.cluster_bits + l2_bits)) as usize;
+        let l2_index = (pos >> self.cluster_bits) & ((1 << l2_bits) - 1);
+        let Some(&l1_entry) = meta.l1.get(l1_index) else {
+            return Err(io::Error::other("offset beyond the L1 table"));
+        };
+        let mut table = l1_entry & OFFSET_MASK;
+        if table == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            table = self.alloc_cluster(meta)?;
+            write_all_at(&self.file, &vec![0; self.cluster_size() as usize], table)?;
+            meta.l1[l1_index] = table | COPIED;
+            write_u64(
+                &self.file,
+                self.l1_offset + l1_index as u64 * 8,
+                table | COPIED,
+            )?;
+        }
+        Ok(Some(table + l2_index * 8))
+    }
+
+    /// Reserve a cluster at the end of the file. The caller writes it.
+    fn alloc_cluster(&self, meta: &mut Meta) -> io::Result<u64> {
+        let offset = meta.next_free;
+        meta.next_free += self.cluster_size();
+        self.set_refcount(meta, offset, 1)?;
+        Ok(offset)
+    }
+
+    fn set_refcount(&self, meta: &mut Meta, offset: u64, refcount: u16) -> io::Result<()> {
+        let per_block = self.cluster_size() / 2;
+        let cluster = offset >> self.cluster_bits;
+        let table_index = (cluster / per_block) as usize;
+        let Some(&entry) = meta.refcount_table.get(table_index) else {
+            return Err(io::Error::other("qcow2 refcount table is full"));
+        };
+        let mut block = entry & OFFSET_MASK;
+        if block == 0 {
+            block = meta.next_free;
+            meta.next_free += self.cluster_size();
+            write_all_at(&self.file, &vec![0; self.cluster_size() as usize], block)?;
+            meta.refcount_table[table_index] = block;
+            write_u64(
+                &self.file,
+                self.refcount_table_offset + table_index as u64 * 8,
+                block,
+            )?;
+            self.set_refcount(meta, block, 1)?;
+        }
+        write_all_at(
+            &self.file,
+            &refcount.to_be_bytes(),
+            block + (cluster % per_block) * 2,
+        )
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("'{}' is read-only", self.path.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Make the whole clusters in `len` bytes at `offset` read as zeros,
+    /// freeing their host storage if `unmap`. Partial clusters are zeroed
+    /// with writes, or left alone if `partial` is false.
+    fn zero_range(&self, offset: u64, len: u64, unmap: bool, partial: bool) -> io::Result<()> {
+        self.check_writable()?;
+        check_range(offset, len, self.size)?;
+        let cluster_size = self.cluster_size();
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos & (cluster_size - 1);
+            let n = (cluster_size - in_cluster).min(end - pos);
+            if n == cluster_size && self.zero_clusters {
+                self.zero_cluster(pos, unmap)?;
+            } else if partial {
+                self.write_at(pos, &vec![0; n as usize])?;
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// Mark the cluster at guest offset `pos` as zeros. Its host cluster,
+    /// if any, stays reserved for later writes unless `unmap` frees it.
+    fn zero_cluster(&self, pos: u64, unmap: bool) -> io::Result<()> {
+        let mut meta = self.meta.lock().unwrap();
+        // Without a backing image, unallocated clusters already read zeros.
+        let allocate = self.backing.is_some();
+        let Some(slot) = self.l2_slot(&mut meta, pos, allocate)? else {
+            return Ok(());
+        };
+        let entry = match classify(read_u64(&self.file, slot)?)? {
+            Cluster::Unallocated if !allocate => return Ok(()),
+            Cluster::Unallocated => ZERO,
+            Cluster::Zero(0) => return Ok(()),
+            Cluster::Zero(host) | Cluster::Data(host) => {
+                if unmap {
+                    punch_hole(&self.file, host, self.cluster_size())?;
+                }
+                host | COPIED | ZERO
+            }
+        };
+        write_u64(&self.file, slot, entry)
+    }
+
+    /// Fill `buf` from guest offset `pos` of the backing image.
+    fn read_backing(&self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        let available = match &self.backing {
+            Some(backing) => {
+                let n = (backing.size().saturating_sub(pos) as usize).min(buf.len());
+                backing.read_at(pos, &mut buf[..n])?;
+                n
+            }
+            None => 0,
+        };
+        buf[available..].fill(0);
+        Ok(())
+    }
+
+    /// Split `len` bytes at `offset` into per-cluster pieces of
+    /// (guest offset, offset in cluster, range in the caller's buffer).
+    fn chunks(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (u64, u64, std::ops::Range<usize>)> {
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            if done >= len {
+                return None;
+            }
+            let pos = offset + done as u64;
+            let in_cluster = pos & (cluster_size - 1);
+            let n = ((cluster_size - in_cluster) as usize).min(len - done);
+            let range = done..done + n;
+            done += n;
+            Some((pos, in_cluster, range))
+        })
+    }
+}
+
+/// Decode an L2 entry.
+fn classify(entry: u64) -> io::Result<Cluster> {
+    if entry & COMPRESSED != 0 {
+        return Err(io::Error::other(
+            "compressed qcow2 clusters are not supported",
+        ));
+    }
+    let host = entry & OFFSET_MASK;
+    Ok(if entry & ZERO != 0 {
+        Cluster::Zero(host)
+    } else if host == 0 {
+        Cluster::Unallocated
+    } else {
+        Cluster::Data(host)
+    })
+}
+
+/// The backing file named in `header`, relative to the image at `path`.
+fn backing_path(path: &Path, header: &Header) -> Option<PathBuf> {
+    let backing_file = header.backing_file.as_ref()?;
+    let name = Path::new(backing_file);
+    // URLs (HTTP backing images) are kept as they are.
+    Some(match path.parent() {
+        Some(dir) if name.is_relative() && !backing_file.contains("://") => dir.join(name),
+        _ => name.to_path_buf(),
+    })
+}
+
+/// Open a backing image, probing its format if the header doesn't say.
+fn open_backing(
+    path: &Path,
+    format: Option<&str>,
+    read_only: bool,
+) -> Result<Arc<dyn BlockBackend>, String> {
+    match format {
+        Some("raw") => Ok(Arc::new(FileBackend::open(path, read_only)?)),
+        Some("qcow2") => Ok(Arc::new(Qcow2Backend::open(path, read_only)?)),
+        Some(other) => Err(format!(
+            "Backing image '{}' has unsupported format '{}'",
+            path.display(),
+            other
+        )),
+        None => open_image(path, read_only),
+    }
+}
+
+/// Write a new, empty version 3 image to `file`: the header in cluster 0,
+/// then the refcount table, the first refcount block and the L1 table.
+fn format_image(file: &File, size: u64, backing: Option<(&str, &str)>) -> io::Result<()> {
+    let cluster_size = 1u64 << CLUSTER_BITS;
+    let l2_coverage = cluster_size * (cluster_size / 8);
+    let l1_size = size.div_ceil(l2_coverage).max(1);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+    let refcount_table = cluster_size;
+    let refcount_block = 2 * cluster_size;
+    let l1_offset = 3 * cluster_size;
+    let clusters = 3 + l1_clusters;
+    if clusters > cluster_size / 2 {
+        return Err(io::Error::other("image too large"));
+    }
+
+    let mut header = vec![0u8; cluster_size as usize];
+    let put32 =
+        |h: &mut [u8], off: usize, v: u32| h[off..off + 4].copy_from_slice(&v.to_be_bytes());
+    let put64 =
+        |h: &mut [u8], off: usize, v: u64| h[off..off + 8].copy_from_slice(&v.to_be_bytes());
+    put32(&mut header, 0, MAGIC);
+    put32(&mut header, 4, 3);
+    put32(&mut header, 20, CLUSTER_BITS);
+    put64(&mut header, 24, size);
+    put32(&mut header, 36, l1_size as u32);
+    put64(&mut header, 40, l1_offset);
+    put64(&mut header, 48, refcount_table);
+    put32(&mut header, 56, 1);
+    put32(&mut header, 96, REFCOUNT_ORDER);
+    put32(&mut header, 100, V3_HEADER_LEN);
+
+    let mut ext = V3_HEADER_LEN as usize;
+    if let Some((name, format)) = backing {
+        if name.len() > MAX_BACKING_NAME {
+            return Err(io::Error::other("backing file name too long"));
+        }
+        put32(&mut header, ext, EXT_BACKING_FORMAT);
+        put32(&mut header, ext + 4, format.len() as u32);
+        header[ext + 8..ext + 8 + format.len()].copy_from_slice(format.as_bytes());
+        ext += 8 + format.len().next_multiple_of(8);
+        // The end-of-extensions marker is the zeroed 8 bytes at `ext`.
+        let name_offset = ext + 8;
+        header[name_offset..name_offset + name.len()].copy_from_slice(name.as_bytes());
+        put64(&mut header, 8, name_offset as u64);
+        put32(&mut header, 16, name.len() as u32);
+    }
+
+    let mut table = vec![0u8; cluster_size as usize];
+    put64(&mut table, 0, refcount_block);
+    let mut block = vec![0u8; cluster_size as usize];
+    for cluster in 0..clusters as usize {
+        block[cluster * 2..cluster * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+    }
+
+    write_all_at(file, &header, 0)?;
+    write_all_at(file, &table, refcount_table)?;
+    write_all_at(file, &block, refcount_block)?;
+    write_all_at(
+        file,
+        &vec![0; (l1_clusters * cluster_size) as usize],
+        l1_offset,
+    )?;
+    file.sync_all()
+}
+
+impl BlockBackend for Qcow2Backend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(offset, buf.len() as u64, self.size)?;
+        let mut meta = self.meta.lock().unwrap();
+        for (pos, in_cluster, range) in self.chunks(offset, buf.len()) {
+            let chunk = &mut buf[range];
+            let entry = match self.l2_slot(&mut meta, pos, false)? {
+                Some(slot) => read_u64(&self.file, slot)?,
+                None => 0,
+            };
+            match classify(entry)? {
+                Cluster::Unallocated => self.read_backing(pos, chunk)?,
+                Cluster::Zero(_) => chunk.fill(0),
+                Cluster::Data(host) => read_exact_at(&self.file, chunk, host + in_cluster)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        check_range(offset, data.len() as u64, self.size)?;
+        let mut meta = self.meta.lock().unwrap();
+        for (pos, in_cluster, range) in self.chunks(offset, data.len()) {
+            let chunk = &data[range];
+            let slot = self
+                .l2_slot(&mut meta, pos, true)?
+                .expect("L2 table allocated");
+            let (mut cluster, target) = match classify(read_u64(&self.file, slot)?)? {
+                Cluster::Data(host) => {
+                    write_all_at(&self.file, chunk, host + in_cluster)?;
+                    continue;
+                }
+                // Copy the rest of the cluster from the backing image.
+                Cluster::Unallocated => {
+                    let mut cluster = vec![0; self.cluster_size() as usize];
+                    self.read_backing(pos - in_cluster, &mut cluster)?;
+                    (cluster, self.alloc_cluster(&mut meta)?)
+                }
+                Cluster::Zero(host) => {
+                    let target = match host {
+                        0 => self.alloc_cluster(&mut meta)?,
+                        host => host,
+                    };
+                    (vec![0; self.cluster_size() as usize], target)
+                }
+            };
+            cluster[in_cluster as usize..in_cluster as usize + chunk.len()].copy_from_slice(chunk);
+            write_all_at(&self.file, &cluster, target)?;
+            write_u64(&self.file, slot, target | COPIED)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.sync_data()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.zero_range(offset, len, unmap, true)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        if self.read_only {
+            return check_range(offset, len, self.size);
+        }
+        self.zero_range(offset, len, true, false)
+    }
+
+    fn describe(&self) -> String {
+        match &self.backing_file {
+            Some(base) => format!("{} (qcow2 over {})", self.path.display(), base.display()),
+            None => format!("{} (qcow2)", self.path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::MemoryBackend;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qcow2-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn overlay_reads_through_and_copies_on_write() {
+        let path = temp("overlay.qcow2");
+        let base: Vec<u8> = (0..3 << 16).map(|i| (i / 512) as u8).collect();
+        let base = Arc::new(MemoryBackend::new(base));
+        Qcow2Backend::create(&path, 3 << 16, Some(("base.img", "raw"))).unwrap();
+
+        let overlay = Qcow2Backend::open_with_backing(&path, false, Some(base.clone())).unwrap();
+        assert_eq!(
+            overlay.backing_file(),
+            Some(path.with_file_name("base.img").as_path())
+        );
+        let mut buf = [0; 4];
+        overlay.read_at(0x10000 + 511, &mut buf).unwrap();
+        assert_eq!(buf, [128, 129, 129, 129]);
+
+        // A write spanning two clusters copies both from the base.
+        overlay.write_at(0xfffe, b"abcd").unwrap();
+        overlay.read_at(0xfffc, &mut buf).unwrap();
+        assert_eq!(&buf, &[127, 127, b'a', b'b']);
+        let mut cluster = vec![0; 0x10000];
+        overlay.read_at(0x10000, &mut cluster).unwrap();
+        assert_eq!(&cluster[..2], b"cd");
+        assert_eq!(cluster[0x200], 129);
+        assert!(
+            base.contents()[0xfffe..0x10002]
+                .iter()
+                .all(|&b| b == 127 || b == 128)
+        );
+        drop(overlay);
+
+        // Reopened, the writes are still there; committing moves them down.
+        let overlay = Qcow2Backend::open_with_backing(&path, false, Some(base.clone())).unwrap();
+        overlay.read_at(0xfffe, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(overlay.commit().unwrap(), 2 << 16);
+        assert_eq!(&base.contents()[0xfffe..0x10002], b"abcd");
+        drop(overlay);
+
+        Qcow2Backend::discard(&path).unwrap();
+        base.write_at(0xfffe, b"wxyz").unwrap();
+        let overlay = Qcow2Backend::open_with_backing(&path, true, Some(base)).unwrap();
+        overlay.read_at(0xfffe, &mut buf).unwrap();
+        assert_eq!(&buf, b"wxyz");
+        assert!(overlay.write_at(0, b"x").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_table_sizes_are_rejected() {
+        let path = temp("corrupt.qcow2");
+        Qcow2Backend::create(&path, 3 << 16, Some(("base.img", "raw"))).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let ext_len = V3_HEADER_LEN as u64 + 4;
+        for (offset, value) in [(36, u32::MAX), (56, u32::MAX), (ext_len, 0xffff_fff0)] {
+            let mut saved = [0; 4];
+            read_exact_at(&file, &mut saved, offset).unwrap();
+            write_all_at(&file, &value.to_be_bytes(), offset).unwrap();
+            assert!(Qcow2Backend::open_with_backing(&path, true, None).is_err());
+            write_all_at(&file, &saved, offset).unwrap();
+        }
+        assert!(Qcow2Backend::open_with_backing(&path, true, None).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refcounts_cover_every_cluster() {
+        let path = temp("standalone.qcow2");
+        let size = 2u64 << 30;
+        Qcow2Backend::create(&path, size, None).unwrap();
+        let image = Qcow2Backend::open(&path, false).unwrap();
+        image.write_at(size - 4, b"tail").unwrap();
+        image.write_at(0, b"head").unwrap();
+        let mut buf = [0xff; 8];
+        image.read_at(1 << 20, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+
+        // Every cluster in the file has refcount 1: header, refcount table
+        // and block, L1 table, two L2 tables and two data clusters.
+        let clusters = image.file.metadata().unwrap().len() >> CLUSTER_BITS;
+        assert_eq!(clusters, 8);
+        let meta = image.meta.lock().unwrap();
+        let block = meta.refcount_table[0];
+        let mut refcounts = vec![0; clusters as usize * 2];
+        read_exact_at(&image.file, &mut refcounts, block).unwrap();
+        assert!(refcounts.chunks(2).all(|r| r == [0, 1]));
+        drop(meta);
+        drop(image);
+
+        let image = Qcow2Backend::open(&path, true).unwrap();
+        image.read_at(size - 4, &mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], b"tail");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn zeroed_clusters_hide_the_base() {
+        let path = temp("zeroes.qcow2");
+        let base = Arc::new(MemoryBackend::new(vec![0x55; 3 << 16]));
+        Qcow2Backend::create(&path, 3 << 16, Some(("base.img", "raw"))).unwrap();
+        let image = Qcow2Backend::open_with_backing(&path, false, Some(base)).unwrap();
+        image.write_at(0x10000, &[0xaa; 0x10000]).unwrap();
+
+        // Cluster 0 is unallocated and cluster 1 has data; both read zeros
+        // afterwards. The partial cluster 2 is written with zeros.
+        image.write_zeroes(0, 0x20100, true).unwrap();
+        let mut buf = vec![0xff; 3 << 16];
+        image.read_at(0, &mut buf).unwrap();
+        assert!(buf[..0x20100].iter().all(|&b| b == 0));
+        assert!(buf[0x20100..].iter().all(|&b| b == 0x55));
+
+        // Discard leaves partial clusters alone.
+        image.discard(0x20000, 0x8000).unwrap();
+        image.read_at(0x20200, &mut buf[..4]).unwrap();
+        assert_eq!(buf[..4], [0x55; 4]);
+
+        // The reserved cluster is reused by the next write.
+        let len = image.file.metadata().unwrap().len();
+        image.write_at(0x10000, b"again").unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), len);
+        image.read_at(0x10000, &mut buf[..6]).unwrap();
+        assert_eq!(&buf[..6], b"again\0");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,9 @@
 use crate::Trap;
 use crate::devices::clint::{CLINT_BASE, CLINT_SIZE, Clint};
-use crate::devices::plic::{PLIC_BASE, PLIC_SIZE, Plic, UART_IRQ, VIRTIO0_IRQ};
+use crate::devices::htif::Htif;
+use crate::devices::plic::{PLIC_BASE, PLIC_SIZE, Plic, UART_IRQ, VIRTIO0_IRQ, uart_irq};
 use crate::devices::sysinfo::{SYSINFO_BASE, SYSINFO_SIZE, SysInfo};
-use crate::devices::uart::{UART_BASE, UART_SIZE, Uart};
+use crate::devices::uart::{MAX_UARTS, UART_BASE, UART_SIZE, Uart};
 use crate::devices::virtio::VirtioDevice;
 use crate::dram::Dram;
 
@@ -36,11 +37,30 @@ pub const DRAM_BASE: u64 = 0x8000_0000;
 /// Base address of the RISC-V test finisher MMIO region.
 pub const TEST_FINISHER_BASE: u64 = 0x0010_0000;
 pub const TEST_FINISHER_SIZE: u64 = 0x1000;
+/// Test finisher value reporting success.
+pub const FINISHER_PASS: u64 = 0x5555;
+/// Test finisher value reporting failure; the exit code goes in bits 16 and up.
+pub const FINISHER_FAIL: u64 = 0x3333;
+
+/// Encode a program exit code as a test finisher value.
+pub fn finisher_code(exit_code: u64) -> u64 {
+    if exit_code == 0 {
+        FINISHER_PASS
+    } else {
+        (exit_code << 16) | FINISHER_FAIL
+    }
+}
+
+/// Boot ROM holding the reset vector when firmware is loaded.
+pub const BOOT_ROM_BASE: u64 = 0x1000;
+pub const BOOT_ROM_SIZE: u64 = 0x1000;
 
 /// VirtIO MMIO base address (for the first device).
 pub const VIRTIO_BASE: u64 = 0x1000_1000;
 /// Size of each VirtIO MMIO region.
 pub const VIRTIO_STRIDE: u64 = 0x1000;
+/// Number of VirtIO MMIO slots (`DeviceId::VirtioSlot0..5`).
+pub const VIRTIO_SLOTS: usize = 6;
 
 /// RTC (Real-Time Clock) MMIO device base address.
 /// Provides host Unix timestamp to guest kernel.
@@ -209,6 +229,13 @@ pub trait Bus: Send + Sync {
         0
     }
 
+    /// Emit bytes on the console shown for the primary UART.
+    ///
+    /// Host interfaces that print without an emulated device (semihosting)
+    /// use this so their output is interleaved with the serial console.
+    /// Buses without a console discard the bytes.
+    fn console_write(&self, _data: &[u8]) {}
+
     /// Poll hardware interrupt sources for a specific hart.
     /// Returns MIP bits for that hart.
     /// Default implementation returns 0 (no interrupts).
@@ -406,6 +433,8 @@ pub struct SystemBus {
     pub clint: Clint,
     pub plic: Plic,
     pub uart: Uart,
+    /// UARTs 1 and up, each `UART_SIZE` above the previous one
+    pub extra_uarts: Vec<Uart>,
     pub sysinfo: SysInfo,
     pub virtio_devices: Vec<Box<dyn VirtioDevice>>,
     
@@ -432,6 +461,14 @@ pub struct SystemBus {
     /// RTC timestamp from host (Unix seconds since epoch)
     /// Updated by the host each tick to provide wall-clock time to the guest
     rtc_timestamp: std::sync::atomic::AtomicU64,
+
+    /// HTIF host interface for Spike-style bare-metal binaries
+    htif: Option<Htif>,
+    /// 8-byte aligned `tohost` address checked on DRAM stores (u64::MAX when HTIF is off)
+    htif_tohost: u64,
+
+    /// Contents of the boot ROM at `BOOT_ROM_BASE` (empty when unmapped)
+    boot_rom: Vec<u8>,
 }
 
 impl SystemBus {
@@ -441,6 +478,7 @@ impl SystemBus {
             clint: Clint::new(),
             plic: Plic::new(),
             uart: Uart::new(),
+            extra_uarts: Vec::new(),
             sysinfo: SysInfo::new(),
             virtio_devices: Vec::new(),
             d1_mmc: RwLock::new(None),
@@ -457,6 +495,9 @@ impl SystemBus {
             #[cfg(target_arch = "wasm32")]
             shared_control: None,
             rtc_timestamp: std::sync::atomic::AtomicU64::new(0),
+            htif: None,
+            htif_tohost: u64::MAX,
+            boot_rom: Vec::new(),
         }
     }
 
@@ -507,6 +548,7 @@ impl SystemBus {
             clint,
             plic: Plic::new(),
             uart: Uart::new(),
+            extra_uarts: Vec::new(),
             sysinfo: SysInfo::new(),
             virtio_devices: Vec::new(),
             d1_mmc: RwLock::new(None),
@@ -519,6 +561,9 @@ impl SystemBus {
             shared_uart_input,
             shared_control: Some(shared_control),
             rtc_timestamp: std::sync::atomic::AtomicU64::new(0),
+            htif: None,
+            htif_tohost: u64::MAX,
+            boot_rom: Vec::new(),
         }
     }
 
@@ -536,6 +581,116 @@ impl SystemBus {
         self.clint.set_num_harts(num_harts);
     }
 
+    /// Enable the HTIF host interface at the given `tohost`/`fromhost` addresses.
+    ///
+    /// Stores to `tohost` are then decoded as HTIF commands; an exit request
+    /// surfaces as `Trap::RequestedTrap` with the test finisher encoding.
+    pub fn enable_htif(&mut self, tohost: u64, fromhost: Option<u64>) {
+        self.htif_tohost = tohost & !7;
+        self.htif = Some(Htif::new(tohost, fromhost));
+    }
+
+    /// Let HTIF `openat` calls reach files below `root` (see [`Htif::set_root`]).
+    pub fn set_htif_root(&mut self, root: std::path::PathBuf) {
+        if let Some(htif) = &mut self.htif {
+            htif.set_root(root);
+        }
+    }
+
+    /// Map `rom` read-only at [`BOOT_ROM_BASE`].
+    pub fn set_boot_rom(&mut self, rom: Vec<u8>) -> Result<(), String> {
+        if rom.len() as u64 > BOOT_ROM_SIZE {
+            return Err(format!("Boot ROM is {} bytes, over the {} byte limit", rom.len(), BOOT_ROM_SIZE));
+        }
+        self.boot_rom = rom;
+        Ok(())
+    }
+
+    #[inline]
+    fn boot_rom_load(&self, addr: u64, size: usize) -> Option<u64> {
+        let offset = addr.checked_sub(BOOT_ROM_BASE)? as usize;
+        let bytes = self.boot_rom.get(offset..offset.checked_add(size)?)?;
+        Some(bytes.iter().rev().fold(0, |val, &b| (val << 8) | b as u64))
+    }
+
+    /// Handle a store that landed on the HTIF `tohost` word.
+    #[cold]
+    fn htif_write(&self) -> Result<(), Trap> {
+        let Some(htif) = &self.htif else {
+            return Ok(());
+        };
+        let value = self
+            .dram
+            .offset(htif.tohost)
+            .and_then(|off| self.dram.load_64(off as u64).ok())
+            .unwrap_or(0);
+        if value == 0 {
+            return Ok(());
+        }
+        match htif.handle(&self.dram, &self.uart, value) {
+            Some(code) => Err(Trap::RequestedTrap(finisher_code(code))),
+            None => Ok(()),
+        }
+    }
+
+    /// Finish a DRAM store to `addr`: one that touched the `tohost` word is
+    /// handed to the HTIF, whatever its width.
+    #[inline(always)]
+    fn dram_stored(&self, addr: u64) -> Result<(), Trap> {
+        if addr & !7 == self.htif_tohost {
+            return self.htif_write();
+        }
+        Ok(())
+    }
+
+    /// DRAM offset for an AMO, or `None` on the `tohost` word so the locked
+    /// read-modify-write fallback stores through `write32`/`write64`.
+    #[inline(always)]
+    fn amo_dram_offset(&self, addr: u64) -> Option<usize> {
+        if addr & !7 == self.htif_tohost {
+            return None;
+        }
+        self.dram.offset(addr)
+    }
+
+    /// Add another 16550 UART after the existing ones and return its index.
+    /// UART `n` sits at `uart_base(n)` and raises PLIC source `uart_irq(n)`.
+    pub fn add_uart(&mut self) -> Result<usize, String> {
+        if self.num_uarts() >= MAX_UARTS {
+            return Err(format!("at most {} UARTs are supported", MAX_UARTS));
+        }
+        self.extra_uarts.push(Uart::new());
+        Ok(self.extra_uarts.len())
+    }
+
+    /// Number of UARTs, including the console at `UART_BASE`.
+    pub fn num_uarts(&self) -> usize {
+        1 + self.extra_uarts.len()
+    }
+
+    /// UART `index`, where 0 is the console.
+    pub fn uart(&self, index: usize) -> Option<&Uart> {
+        match index {
+            0 => Some(&self.uart),
+            _ => self.extra_uarts.get(index - 1),
+        }
+    }
+
+    /// The additional UART decoding `addr`, with the offset into it.
+    fn extra_uart(&self, addr: u64) -> Option<(&Uart, u64)> {
+        let offset = addr.checked_sub(UART_BASE + UART_SIZE)?;
+        let uart = self.extra_uarts.get((offset / UART_SIZE) as usize)?;
+        Some((uart, offset % UART_SIZE))
+    }
+
+    /// Mirror every UART's interrupt line into the PLIC.
+    fn update_uart_irqs(&self) {
+        self.plic.set_source_level(UART_IRQ, self.uart.is_interrupting());
+        for (i, uart) in self.extra_uarts.iter().enumerate() {
+            self.plic.set_source_level(uart_irq(i + 1), uart.is_interrupting());
+        }
+    }
+
     /// Set the RTC timestamp from host.
     /// Call this each tick with the current Unix timestamp (seconds since epoch).
     /// The guest kernel can read this to display wall-clock time.
@@ -565,14 +720,16 @@ impl SystemBus {
         }
 
         // Update PLIC with UART interrupt status
-        let uart_irq = self.uart.is_interrupting();
-        self.plic.set_source_level(UART_IRQ, uart_irq);
+        self.update_uart_irqs();
 
         // Update PLIC with VirtIO interrupts
         // Device 0 -> IRQ 1 (VIRTIO0_IRQ)
         // Device 1 -> IRQ 2
         // etc.
         for (i, dev) in self.virtio_devices.iter().enumerate() {
+            if let Err(e) = dev.complete(&self.dram) {
+                log::warn!("[Bus] VirtIO completion error: {:?}", e);
+            }
             let irq = VIRTIO0_IRQ + i as u32;
             if irq < 32 {
                 self.plic.set_source_level(irq, dev.is_interrupting());
@@ -652,8 +809,7 @@ impl SystemBus {
             self.clint.tick();
 
             // Update PLIC with UART interrupt status
-            let uart_irq = self.uart.is_interrupting();
-            self.plic.set_source_level(UART_IRQ, uart_irq);
+            self.update_uart_irqs();
 
             // Update PLIC with VirtIO interrupts
             for (i, dev) in self.virtio_devices.iter().enumerate() {
@@ -766,6 +922,10 @@ impl SystemBus {
 
     #[cold]
     fn read8_slow(&self, addr: u64) -> Result<u8, Trap> {
+        if let Some(val) = self.boot_rom_load(addr, 1) {
+            return Ok(val as u8);
+        }
+
         // Test finisher region: reads are harmless and return zero.
         if addr >= TEST_FINISHER_BASE && addr < TEST_FINISHER_BASE + TEST_FINISHER_SIZE {
             return Ok(0);
@@ -825,6 +985,13 @@ impl SystemBus {
             return Ok(val as u8);
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            let val = uart
+                .load(offset, 1)
+                .map_err(|_| Trap::LoadAccessFault(addr))?;
+            return Ok(val as u8);
+        }
+
         if let Some((idx, offset)) = self.get_virtio_device(addr) {
             // Emulate narrow MMIO reads by extracting from the 32-bit register value
             let aligned = offset & !3;
@@ -847,6 +1014,10 @@ impl SystemBus {
 
     #[cold]
     fn read16_slow(&self, addr: u64) -> Result<u16, Trap> {
+        if let Some(val) = self.boot_rom_load(addr, 2) {
+            return Ok(val as u16);
+        }
+
         if addr >= TEST_FINISHER_BASE && addr < TEST_FINISHER_BASE + TEST_FINISHER_SIZE {
             return Ok(0);
         }
@@ -881,6 +1052,13 @@ impl SystemBus {
             return Ok(val as u16);
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            let val = uart
+                .load(offset, 2)
+                .map_err(|_| Trap::LoadAccessFault(addr))?;
+            return Ok(val as u16);
+        }
+
         if let Some((idx, offset)) = self.get_virtio_device(addr) {
             let aligned = offset & !3;
             let word = self.virtio_devices[idx]
@@ -902,6 +1080,10 @@ impl SystemBus {
 
     #[cold]
     fn read32_slow(&self, addr: u64) -> Result<u32, Trap> {
+        if let Some(val) = self.boot_rom_load(addr, 4) {
+            return Ok(val as u32);
+        }
+
         if addr >= TEST_FINISHER_BASE && addr < TEST_FINISHER_BASE + TEST_FINISHER_SIZE {
             return Ok(0);
         }
@@ -947,6 +1129,13 @@ impl SystemBus {
             return Ok(val as u32);
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            let val = uart
+                .load(offset, 4)
+                .map_err(|_| Trap::LoadAccessFault(addr))?;
+            return Ok(val as u32);
+        }
+
         // D1 MMC Controller (0x0402_0000 - 0x0402_0FFF)
         if addr >= D1_MMC0_BASE && addr < D1_MMC0_BASE + D1_MMC0_SIZE {
             // DEBUG: Log first MMC access to trace device visibility
@@ -1083,6 +1272,10 @@ impl SystemBus {
 
     #[cold]
     fn read64_slow(&self, addr: u64) -> Result<u64, Trap> {
+        if let Some(val) = self.boot_rom_load(addr, 8) {
+            return Ok(val);
+        }
+
         if addr >= TEST_FINISHER_BASE && addr < TEST_FINISHER_BASE + TEST_FINISHER_SIZE {
             return Ok(0);
         }
@@ -1117,6 +1310,13 @@ impl SystemBus {
             return Ok(val);
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            let val = uart
+                .load(offset, 8)
+                .map_err(|_| Trap::LoadAccessFault(addr))?;
+            return Ok(val);
+        }
+
         if let Some((idx, offset)) = self.get_virtio_device(addr) {
             let low = self.virtio_devices[idx]
                 .read(offset)
@@ -1183,6 +1383,12 @@ impl SystemBus {
             return Ok(());
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            uart.store(offset, 1, val as u64)
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return Ok(());
+        }
+
         if let Some((_idx, _offset)) = self.get_virtio_device(addr) {
             // VirtIO registers are 32-bit. Byte writes are not strictly supported by the spec for all registers.
             // We ignore them for now to be safe.
@@ -1226,6 +1432,12 @@ impl SystemBus {
             return Ok(());
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            uart.store(offset, 2, val as u64)
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return Ok(());
+        }
+
         if let Some((_idx, _offset)) = self.get_virtio_device(addr) {
             return Ok(());
         }
@@ -1267,6 +1479,12 @@ impl SystemBus {
             return Ok(());
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            uart.store(offset, 4, val as u64)
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return Ok(());
+        }
+
         // D1 MMC Controller (0x0402_0000 - 0x0402_0FFF)
         if addr >= D1_MMC0_BASE && addr < D1_MMC0_BASE + D1_MMC0_SIZE {
             if let Ok(mut mmc) = self.d1_mmc.write() {
@@ -1406,6 +1624,12 @@ impl SystemBus {
             return Ok(());
         }
 
+        if let Some((uart, offset)) = self.extra_uart(addr) {
+            uart.store(offset, 8, val)
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return Ok(());
+        }
+
         if let Some((_idx, _offset)) = self.get_virtio_device(addr) {
             // VirtIO registers are 32-bit. 64-bit writes are not typically supported directly via MMIO
             // except for legacy queue PFN which is 32-bit anyway.
@@ -1427,6 +1651,12 @@ impl Bus for SystemBus {
         self.check_interrupts()
     }
 
+    fn console_write(&self, data: &[u8]) {
+        for &b in data {
+            self.uart.push_output(b);
+        }
+    }
+
     #[inline]
     fn poll_interrupts_for_hart(&self, hart_id: usize) -> u64 {
         self.check_interrupts_for_hart(hart_id)
@@ -1439,7 +1669,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_swap(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self
                     .dram
@@ -1469,7 +1699,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_add(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self
                     .dram
@@ -1498,7 +1728,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_and(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self
                     .dram
@@ -1527,7 +1757,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_or(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self
                     .dram
@@ -1556,7 +1786,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_xor(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self
                     .dram
@@ -1586,7 +1816,7 @@ impl Bus for SystemBus {
     #[cfg(target_arch = "wasm32")]
     fn atomic_min(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
         // AMOMIN doesn't have direct Atomics support, use CAS loop
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             loop {
                 let old = if is_word {
                     self.dram
@@ -1648,7 +1878,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_max(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             loop {
                 let old = if is_word {
                     self.dram
@@ -1709,7 +1939,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_minu(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             loop {
                 let old = if is_word {
                     self.dram
@@ -1763,7 +1993,7 @@ impl Bus for SystemBus {
 
     #[cfg(target_arch = "wasm32")]
     fn atomic_maxu(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             loop {
                 let old = if is_word {
                     self.dram
@@ -1823,7 +2053,7 @@ impl Bus for SystemBus {
         new_value: u64,
         is_word: bool,
     ) -> Result<(bool, u64), Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let (success, old) = self
                     .dram
@@ -1866,7 +2096,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_swap(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self
                     .dram
@@ -1897,7 +2127,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_add(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_add_32(off as u64, value as u32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -1923,7 +2153,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_and(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_and_32(off as u64, value as u32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -1949,7 +2179,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_or(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_or_32(off as u64, value as u32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -1975,7 +2205,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_xor(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_xor_32(off as u64, value as u32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -2001,7 +2231,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_min(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_min_32(off as u64, value as i32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -2029,7 +2259,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_max(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_max_32(off as u64, value as i32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -2057,7 +2287,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_minu(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_minu_32(off as u64, value as u32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -2085,7 +2315,7 @@ impl Bus for SystemBus {
 
     #[cfg(not(target_arch = "wasm32"))]
     fn atomic_maxu(&self, addr: u64, value: u64, is_word: bool) -> Result<u64, Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let old = self.dram.atomic_maxu_32(off as u64, value as u32)
                     .map_err(|_| Trap::StoreAccessFault(addr))?;
@@ -2119,7 +2349,7 @@ impl Bus for SystemBus {
         new_value: u64,
         is_word: bool,
     ) -> Result<(bool, u64), Trap> {
-        if let Some(off) = self.dram.offset(addr) {
+        if let Some(off) = self.amo_dram_offset(addr) {
             if is_word {
                 let (success, old) = self.dram
                     .atomic_compare_exchange_32(off as u64, expected as u32, new_value as u32)
@@ -2218,10 +2448,10 @@ impl Bus for SystemBus {
     fn write8(&self, addr: u64, val: u8) -> Result<(), Trap> {
         // Fast path: DRAM access (most common case)
         if let Some(off) = self.dram.offset(addr) {
-            return self
-                .dram
+            self.dram
                 .store_8(off as u64, val as u64)
-                .map_err(|_| Trap::StoreAccessFault(addr));
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return self.dram_stored(addr);
         }
         // Slow path: MMIO devices
         self.write8_slow(addr, val)
@@ -2234,10 +2464,10 @@ impl Bus for SystemBus {
         }
         // Fast path: DRAM access (most common case)
         if let Some(off) = self.dram.offset(addr) {
-            return self
-                .dram
+            self.dram
                 .store_16(off as u64, val as u64)
-                .map_err(|_| Trap::StoreAccessFault(addr));
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return self.dram_stored(addr);
         }
         // Slow path: MMIO devices
         self.write16_slow(addr, val)
@@ -2250,10 +2480,10 @@ impl Bus for SystemBus {
         }
         // Fast path: DRAM access (most common case)
         if let Some(off) = self.dram.offset(addr) {
-            return self
-                .dram
+            self.dram
                 .store_32(off as u64, val as u64)
-                .map_err(|_| Trap::StoreAccessFault(addr));
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return self.dram_stored(addr);
         }
         // Slow path: MMIO devices
         self.write32_slow(addr, val)
@@ -2266,10 +2496,10 @@ impl Bus for SystemBus {
         }
         // Fast path: DRAM access (most common case)
         if let Some(off) = self.dram.offset(addr) {
-            return self
-                .dram
+            self.dram
                 .store_64(off as u64, val)
-                .map_err(|_| Trap::StoreAccessFault(addr));
+                .map_err(|_| Trap::StoreAccessFault(addr))?;
+            return self.dram_stored(addr);
         }
         // Slow path: MMIO devices
         self.write64_slow(addr, val)
@@ -0,0 +1,998 @@
+//! Character-device backends for the serial console (`--serial SPEC`).
+//!
+//! The 16550 UART is pumped on hart 0's thread: guest output is handed to
+//! [`Chardev::write`] and host input is collected with
+//! [`Chardev::read_available`]. Backends never block the VM; anything that
+//! can stall (accepting clients, socket reads and writes) runs on its own
+//! thread and talks to the VM through channels.
+//!
+//! | Spec | Backend |
+//! |------|---------|
+//! | `stdio` | the terminal the VM was started from (default) |
+//! | `pty` | a new host pseudo-terminal |
+//! | `tcp:[HOST]:PORT[,telnet]` | TCP server, optionally speaking telnet |
+//! | `unix:PATH` | UNIX stream socket server |
+//! | `ws:[HOST]:PORT` | WebSocket server for xterm.js style clients |
+//! | `file:PATH` | output only, appended to a file |
+//! | `pipe:PATH` | `PATH.in`/`PATH.out` FIFOs, or `PATH` for both |
+//! | `null` | discard output, no input |
+//!
+//! Socket servers take one client at a time; a new connection replaces the
+//! previous one. Output produced while nobody is connected is dropped.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+const ACCEPT_POLL: Duration = Duration::from_millis(20);
+
+/// Host side of the serial console.
+pub trait Chardev: Send {
+    /// Send guest output to the host.
+    fn write(&mut self, data: &[u8]);
+
+    /// Input received from the host since the last call.
+    fn read_available(&mut self) -> Vec<u8>;
+
+    /// True for the VM's own terminal. Its input is read by the VM itself
+    /// so that Ctrl-A escapes keep working, and its output is held back
+    /// while the monitor owns the terminal.
+    fn is_stdio(&self) -> bool {
+        false
+    }
+
+    /// Where the console is, for the startup banner.
+    fn describe(&self) -> String;
+}
+
+/// Open the backend named by a `--serial` spec.
+pub fn open(spec: &str) -> Result<Box<dyn Chardev>, String> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    let need_arg = || {
+        if arg.is_empty() {
+            Err(format!("serial backend '{}' needs an argument", kind))
+        } else {
+            Ok(arg)
+        }
+    };
+    match kind {
+        "stdio" => Ok(Box::new(Stdio)),
+        "null" => Ok(Box::new(Null)),
+        #[cfg(unix)]
+        "pty" => Ok(Box::new(Pty::open()?)),
+        "tcp" => {
+            let (addr, telnet) = match need_arg()?.split_once(',') {
+                Some((addr, "telnet")) => (addr, true),
+                Some((_, opt)) => return Err(format!("unknown tcp option '{}'", opt)),
+                None => (arg, false),
+            };
+            Ok(Box::new(Server::tcp(&listen_addr(addr), telnet)?))
+        }
+        #[cfg(unix)]
+        "unix" => Ok(Box::new(Server::unix(Path::new(need_arg()?))?)),
+        "ws" => Ok(Box::new(Server::websocket(&listen_addr(need_arg()?))?)),
+        "file" => Ok(Box::new(FileChardev::create(Path::new(need_arg()?))?)),
+        #[cfg(unix)]
+        "pipe" => Ok(Box::new(FileChardev::pipe(Path::new(need_arg()?))?)),
+        _ => Err(format!("unknown serial backend '{}'", spec)),
+    }
+}
+
+/// `PORT` and `:PORT` listen on localhost only.
+fn listen_addr(addr: &str) -> String {
+    match addr.rsplit_once(':') {
+        Some(("", port)) | Some(("localhost", port)) => format!("127.0.0.1:{}", port),
+        Some(_) => addr.to_string(),
+        None => format!("127.0.0.1:{}", addr),
+    }
+}
+
+/// The terminal the VM runs in.
+pub struct Stdio;
+
+impl Chardev for Stdio {
+    fn write(&mut self, data: &[u8]) {
+        // The terminal is in raw mode, so do the \n -> \r\n translation the
+        // tty would normally do.
+        let mut out = io::stdout().lock();
+        for &byte in data {
+            if byte == b'\n' {
+                out.write_all(b"\r\n").ok();
+            } else {
+                out.write_all(&[byte]).ok();
+            }
+        }
+        out.flush().ok();
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn is_stdio(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        "stdio".to_string()
+    }
+}
+
+/// Discards output, never has input.
+pub struct Null;
+
+impl Chardev for Null {
+    fn write(&mut self, _data: &[u8]) {}
+
+    fn read_available(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn describe(&self) -> String {
+        "null".to_string()
+    }
+}
+
+/// Drain everything a reader thread has queued.
+fn drain(rx: &Receiver<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    while let Ok(chunk) = rx.try_recv() {
+        bytes.extend(chunk);
+    }
+    bytes
+}
+
+/// Copy `reader` into `tx` until EOF, an error, or `stop`.
+fn spawn_reader<R: Read + Send + 'static>(
+    name: &str,
+    mut reader: R,
+    tx: Sender<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let mut buf = [0u8; 1024];
+            while !stop.load(Ordering::Relaxed) {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    // Nonblocking PTY masters, or a PTY with no slave open.
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.raw_os_error() == Some(libc::EIO) =>
+                    {
+                        thread::sleep(ACCEPT_POLL);
+                    }
+                    Err(_) => break,
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// A host pseudo-terminal. Connect with `screen`, `minicom` or `picocom`.
+#[cfg(unix)]
+pub struct Pty {
+    master: File,
+    slave: PathBuf,
+    input: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+}
+
+#[cfg(unix)]
+impl Pty {
+    pub fn open() -> Result<Self, String> {
+        use std::ffi::CStr;
+        use std::os::unix::io::FromRawFd;
+
+        let err = |what: &str| {
+            format!(
+                "Failed to create PTY ({}): {}",
+                what,
+                io::Error::last_os_error()
+            )
+        };
+        // SAFETY: plain libc calls on a descriptor we own; ptsname_r writes
+        // a NUL-terminated path into `name`.
+        let (master, slave) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(err("posix_openpt"));
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(err("unlockpt"));
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(err("ptsname"));
+            }
+
+            // Raw mode, so the guest sees every byte and its output is not
+            // post-processed twice.
+            let mut tio = std::mem::MaybeUninit::<libc::termios>::uninit();
+            if libc::tcgetattr(fd, tio.as_mut_ptr()) == 0 {
+                let mut tio = tio.assume_init();
+                libc::cfmakeraw(&mut tio);
+                libc::tcsetattr(fd, libc::TCSANOW, &tio);
+            }
+            // Never block the VM when nobody drains the slave side.
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+
+            let slave = PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned());
+            (master, slave)
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, input) = mpsc::channel();
+        let reader = master.try_clone().map_err(|e| e.to_string())?;
+        spawn_reader("serial-pty", reader, tx, Arc::clone(&stop)).map_err(|e| e.to_string())?;
+        Ok(Self {
+            master,
+            slave,
+            input,
+            stop,
+        })
+    }
+
+    /// Path of the slave side, e.g. `/dev/pts/3`.
+    pub fn path(&self) -> &Path {
+        &self.slave
+    }
+}
+
+#[cfg(unix)]
+impl Chardev for Pty {
+    fn write(&mut self, data: &[u8]) {
+        // A full PTY buffer drops output rather than stalling the guest.
+        self.master.write_all(data).ok();
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        drain(&self.input)
+    }
+
+    fn describe(&self) -> String {
+        format!("pty {}", self.slave.display())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Pty {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Output to a file, input (for pipes) from another.
+pub struct FileChardev {
+    output: File,
+    input: Option<Receiver<Vec<u8>>>,
+    stop: Arc<AtomicBool>,
+    describe: String,
+}
+
+impl FileChardev {
+    /// Append guest output to `path`. There is no input.
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let output = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?;
+        Ok(Self {
+            output,
+            input: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            describe: format!("file {}", path.display()),
+        })
+    }
+
+    /// Use the FIFOs `PATH.in` and `PATH.out` if both exist, otherwise
+    /// `PATH` in both directions.
+    #[cfg(unix)]
+    pub fn pipe(path: &Path) -> Result<Self, String> {
+        let with_suffix = |suffix: &str| {
+            let mut p = path.as_os_str().to_owned();
+            p.push(suffix);
+            PathBuf::from(p)
+        };
+        let (input_path, output_path) = match (with_suffix(".in"), with_suffix(".out")) {
+            (i, o) if i.exists() && o.exists() => (i, o),
+            _ => (path.to_path_buf(), path.to_path_buf()),
+        };
+        // Opening a FIFO read-write never waits for the other end.
+        let open = |p: &Path| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(p)
+                .map_err(|e| format!("Failed to open '{}': {}", p.display(), e))
+        };
+        let output = open(&output_path)?;
+        let reader = open(&input_path)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, input) = mpsc::channel();
+        spawn_reader("serial-pipe", reader, tx, Arc::clone(&stop)).map_err(|e| e.to_string())?;
+        Ok(Self {
+            output,
+            input: Some(input),
+            stop,
+            describe: format!("pipe {}", path.display()),
+        })
+    }
+}
+
+impl Chardev for FileChardev {
+    fn write(&mut self, data: &[u8]) {
+        self.output.write_all(data).ok();
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        self.input.as_ref().map(drain).unwrap_or_default()
+    }
+
+    fn describe(&self) -> String {
+        self.describe.clone()
+    }
+}
+
+impl Drop for FileChardev {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Telnet protocol bytes.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+const OPT_LINEMODE: u8 = 34;
+
+/// Put the client in character-at-a-time mode with the guest echoing.
+const TELNET_GREETING: &[u8] = &[
+    IAC,
+    WILL,
+    OPT_ECHO,
+    IAC,
+    WILL,
+    OPT_SGA,
+    IAC,
+    DONT,
+    OPT_LINEMODE,
+];
+
+/// Strips telnet commands from client input.
+#[derive(Debug, Default)]
+struct TelnetFilter {
+    state: TelnetState,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    /// After CR: the client sends CR NUL or CR LF for Enter.
+    Cr,
+    Iac,
+    /// Waiting for the option byte of WILL/WONT/DO/DONT.
+    Option,
+    Sub,
+    SubIac,
+}
+
+impl TelnetFilter {
+    fn filter(&mut self, input: &[u8]) -> Vec<u8> {
+        use TelnetState::*;
+        let mut out = Vec::with_capacity(input.len());
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (Data | Cr, IAC) => Iac,
+                (Cr, 0 | b'\n') => Data,
+                (Data | Cr, b'\r') => {
+                    out.push(b'\r');
+                    Cr
+                }
+                (Data | Cr, _) => {
+                    out.push(byte);
+                    Data
+                }
+                (Iac, IAC) => {
+                    out.push(IAC);
+                    Data
+                }
+                (Iac, WILL | WONT | DO | DONT) => Option,
+                (Iac, SB) => Sub,
+                (Iac, _) | (Option, _) => Data,
+                (Sub, IAC) => SubIac,
+                (Sub, _) => Sub,
+                (SubIac, SE) => Data,
+                (SubIac, _) => Sub,
+            };
+        }
+        out
+    }
+}
+
+/// Double IAC bytes in output so the client does not read them as commands.
+fn telnet_escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &byte in data {
+        if byte == IAC {
+            out.push(IAC);
+        }
+        out.push(byte);
+    }
+    out
+}
+
+/// Connection number and output channel of the connected client, if any.
+type Client = Arc<Mutex<Option<(u64, Sender<Vec<u8>>)>>>;
+
+/// Accepts one pending connection; false if there was none.
+type Accept<L> = fn(&L, &Client, &Sender<Vec<u8>>) -> io::Result<bool>;
+
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(1);
+
+/// Make a new connection the current client, returning its number.
+fn set_client(client: &Client, output: Sender<Vec<u8>>) -> u64 {
+    let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+    // Replacing the sender ends the previous client's writer thread.
+    *client.lock().unwrap() = Some((id, output));
+    id
+}
+
+/// TCP, UNIX socket or WebSocket server with at most one client.
+pub struct Server {
+    client: Client,
+    input: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    describe: String,
+    /// Socket file to remove on drop.
+    path: Option<PathBuf>,
+}
+
+impl Server {
+    fn start<L: Send + 'static>(
+        listener: L,
+        describe: String,
+        path: Option<PathBuf>,
+        accept: Accept<L>,
+    ) -> Result<Self, String> {
+        let client = Client::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, input) = mpsc::channel();
+        {
+            let client = Arc::clone(&client);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("serial-accept".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        match accept(&listener, &client, &tx) {
+                            Ok(true) => {}
+                            Ok(false) => thread::sleep(ACCEPT_POLL),
+                            Err(e) => {
+                                eprintln!("[Serial] accept failed: {}", e);
+                                thread::sleep(ACCEPT_POLL);
+                            }
+                        }
+                    }
+                    // Dropping the sender ends the client's writer thread.
+                    client.lock().unwrap().take();
+                })
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(Self {
+            client,
+            input,
+            stop,
+            describe,
+            path,
+        })
+    }
+
+    /// Listen for TCP clients on `addr`.
+    pub fn tcp(addr: &str, telnet: bool) -> Result<Self, String> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format!("Failed to listen on '{}': {}", addr, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let local = listener.local_addr().map_err(|e| e.to_string())?;
+        let (describe, accept): (_, Accept<TcpListener>) = if telnet {
+            (format!("telnet {}", local), |l, c, tx| {
+                accept_tcp(l, c, tx, true)
+            })
+        } else {
+            (format!("tcp {}", local), |l, c, tx| {
+                accept_tcp(l, c, tx, false)
+            })
+        };
+        Self::start(listener, describe, None, accept)
+    }
+
+    /// Listen for clients on the UNIX socket `path`.
+    #[cfg(unix)]
+    pub fn unix(path: &Path) -> Result<Self, String> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // Replace a socket left behind by a previous run, but nothing else.
+        if let Ok(meta) = std::fs::symlink_metadata(path)
+            && meta.file_type().is_socket()
+        {
+            std::fs::remove_file(path).ok();
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("Failed to bind '{}': {}", path.display(), e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Self::start(
+            listener,
+            format!("unix {}", path.display()),
+            Some(path.to_path_buf()),
+            |listener, client, tx| match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    let writer = stream.try_clone()?;
+                    connect(client, tx, stream, writer, false)?;
+                    Ok(true)
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                Err(e) => Err(e),
+            },
+        )
+    }
+
+    /// Serve the console as binary WebSocket messages on `addr`.
+    pub fn websocket(addr: &str) -> Result<Self, String> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format!("Failed to listen on '{}': {}", addr, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let local = listener.local_addr().map_err(|e| e.to_string())?;
+        Self::start(
+            listener,
+            format!("ws://{}", local),
+            None,
+            |listener, client, tx| {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        stream.set_nonblocking(false)?;
+                        // The handshake runs on the client thread so a slow
+                        // client cannot hold up the accept loop.
+                        let (out_tx, out_rx) = mpsc::channel();
+                        set_client(client, out_tx);
+                        let tx = tx.clone();
+                        thread::Builder::new()
+                            .name(format!("serial-ws-{}", peer))
+                            .spawn(move || serve_websocket(stream, tx, out_rx))?;
+                        Ok(true)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                    Err(e) => Err(e),
+                }
+            },
+        )
+    }
+
+    /// True while a client is connected.
+    pub fn is_connected(&self) -> bool {
+        self.client.lock().unwrap().is_some()
+    }
+}
+
+fn accept_tcp(
+    listener: &TcpListener,
+    client: &Client,
+    tx: &Sender<Vec<u8>>,
+    telnet: bool,
+) -> io::Result<bool> {
+    match listener.accept() {
+        Ok((stream, _)) => {
+            stream.set_nonblocking(false)?;
+            stream.set_nodelay(true).ok();
+            let writer = stream.try_clone()?;
+            connect(client, tx, stream, writer, telnet)?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Make a byte-stream connection the current client: one thread copies its
+/// input to the VM, another writes queued output to it.
+fn connect<S: Read + Write + Send + 'static>(
+    client: &Client,
+    tx: &Sender<Vec<u8>>,
+    mut reader: S,
+    mut writer: S,
+    telnet: bool,
+) -> io::Result<()> {
+    if telnet {
+        writer.write_all(TELNET_GREETING)?;
+    }
+    let (out_tx, out_rx) = mpsc::channel::<Vec<u8>>();
+    let id = set_client(client, out_tx);
+
+    thread::Builder::new()
+        .name("serial-out".to_string())
+        .spawn(move || {
+            for data in out_rx {
+                let data = if telnet { telnet_escape(&data) } else { data };
+                if writer.write_all(&data).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    let tx = tx.clone();
+    let client = Arc::clone(client);
+    thread::Builder::new()
+        .name("serial-in".to_string())
+        .spawn(move || {
+            let mut filter = TelnetFilter::default();
+            let mut buf = [0u8; 1024];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        let data = if telnet {
+                            filter.filter(&buf[..n])
+                        } else {
+                            buf[..n].to_vec()
+                        };
+                        if !data.is_empty() && tx.send(data).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+            // Forget this client unless it has already been replaced.
+            let mut current = client.lock().unwrap();
+            if current.as_ref().is_some_and(|(current, _)| *current == id) {
+                current.take();
+            }
+        })?;
+    Ok(())
+}
+
+fn serve_websocket(stream: TcpStream, tx: Sender<Vec<u8>>, output: Receiver<Vec<u8>>) {
+    use tungstenite::{Error, Message};
+
+    let mut ws = match tungstenite::accept(stream) {
+        Ok(ws) => ws,
+        Err(e) => {
+            eprintln!("[Serial] WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+    // Alternate between short blocking reads and flushing queued output;
+    // tungstenite streams cannot be split across threads.
+    ws.get_ref().set_read_timeout(Some(ACCEPT_POLL)).ok();
+    loop {
+        loop {
+            match output.try_recv() {
+                Ok(data) => {
+                    if ws.send(Message::Binary(data)).is_err() {
+                        return;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                // Replaced by a newer client or the VM has gone.
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    ws.close(None).ok();
+                    ws.flush().ok();
+                    return;
+                }
+            }
+        }
+        match ws.read() {
+            Ok(Message::Binary(data)) => {
+                if tx.send(data).is_err() {
+                    return;
+                }
+            }
+            Ok(Message::Text(text)) => {
+                if tx.send(text.into_bytes()).is_err() {
+                    return;
+                }
+            }
+            Ok(Message::Close(_)) => {
+                ws.flush().ok();
+                return;
+            }
+            Ok(_) => {}
+            Err(Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+impl Chardev for Server {
+    fn write(&mut self, data: &[u8]) {
+        if let Some((_, client)) = self.client.lock().unwrap().as_ref() {
+            client.send(data.to_vec()).ok();
+        }
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        drain(&self.input)
+    }
+
+    fn describe(&self) -> String {
+        self.describe.clone()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.client.lock().unwrap().take();
+        if let Some(path) = &self.path {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Tees guest output into a log file, each line prefixed with the time
+/// since the log was opened (`[   12.345678] `).
+pub struct Logged {
+    inner: Box<dyn Chardev>,
+    log: BufWriter<File>,
+    start: Instant,
+    line_start: bool,
+}
+
+impl Logged {
+    pub fn new(inner: Box<dyn Chardev>, path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open serial log '{}': {}", path.display(), e))?;
+        Ok(Self {
+            inner,
+            log: BufWriter::new(file),
+            start: Instant::now(),
+            line_start: true,
+        })
+    }
+
+    fn log(&mut self, data: &[u8]) {
+        let elapsed = self.start.elapsed();
+        for &byte in data {
+            if byte == b'\r' {
+                continue;
+            }
+            if self.line_start {
+                write!(
+                    self.log,
+                    "[{:5}.{:06}] ",
+                    elapsed.as_secs(),
+                    elapsed.subsec_micros()
+                )
+                .ok();
+            }
+            self.log.write_all(&[byte]).ok();
+            self.line_start = byte == b'\n';
+        }
+        self.log.flush().ok();
+    }
+}
+
+impl Chardev for Logged {
+    fn write(&mut self, data: &[u8]) {
+        self.log(data);
+        self.inner.write(data);
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        self.inner.read_available()
+    }
+
+    fn is_stdio(&self) -> bool {
+        self.inner.is_stdio()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+
+    /// Poll a backend until it has produced `len` input bytes.
+    fn read_input(dev: &mut dyn Chardev, len: usize) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut input = Vec::new();
+        while input.len() < len && Instant::now() < deadline {
+            input.extend(dev.read_available());
+            thread::sleep(Duration::from_millis(5));
+        }
+        input
+    }
+
+    fn wait_connected(server: &Server) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.is_connected() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(server.is_connected());
+    }
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(listen_addr("4444"), "127.0.0.1:4444");
+        assert_eq!(listen_addr(":4444"), "127.0.0.1:4444");
+        assert_eq!(listen_addr("0.0.0.0:4444"), "0.0.0.0:4444");
+        assert!(open("stdio").unwrap().is_stdio());
+        assert!(open("bogus").is_err());
+        assert!(open("file").is_err());
+        assert!(open("tcp:127.0.0.1:0,raw").is_err());
+    }
+
+    #[test]
+    fn telnet_filter_strips_commands() {
+        let mut filter = TelnetFilter::default();
+        let input = [
+            IAC, DO, OPT_ECHO, b'l', b's', b'\r', 0, IAC, IAC, IAC, SB, 24, 0, b'x', IAC, SE, b'a',
+            b'\r', b'\n',
+        ];
+        assert_eq!(filter.filter(&input), b"ls\r\xffa\r");
+        // Commands split across reads.
+        assert_eq!(filter.filter(&[IAC]), b"");
+        assert_eq!(filter.filter(&[WILL]), b"");
+        assert_eq!(filter.filter(&[OPT_SGA, b'z']), b"z");
+        assert_eq!(telnet_escape(&[1, IAC, 2]), [1, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn tcp_roundtrip() {
+        let mut server = Server::tcp("127.0.0.1:0", false).unwrap();
+        let addr = server.describe().trim_start_matches("tcp ").to_string();
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        wait_connected(&server);
+
+        stream.write_all(b"root\n").unwrap();
+        assert_eq!(read_input(&mut server, 5), b"root\n");
+
+        server.write(b"login: ");
+        let mut buf = [0u8; 7];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"login: ");
+
+        drop(stream);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.is_connected() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!server.is_connected());
+    }
+
+    #[test]
+    fn telnet_greets_and_escapes() {
+        let mut server = Server::tcp("127.0.0.1:0", true).unwrap();
+        let addr = server.describe().trim_start_matches("telnet ").to_string();
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        let mut greeting = [0u8; TELNET_GREETING.len()];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, TELNET_GREETING);
+
+        stream
+            .write_all(&[IAC, DO, OPT_SGA, b'y', b'\r', 0])
+            .unwrap();
+        assert_eq!(read_input(&mut server, 2), b"y\r");
+
+        server.write(&[b'a', IAC]);
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [b'a', IAC, IAC]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_replaces_client() {
+        let dir = std::env::temp_dir().join(format!("riscv-vm-serial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("console.sock");
+        let mut server = Server::unix(&path).unwrap();
+
+        let _first = UnixStream::connect(&path).unwrap();
+        wait_connected(&server);
+        let mut second = UnixStream::connect(&path).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        // Output goes to the second client once it has been accepted.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0u8; 1];
+        loop {
+            server.write(b"!");
+            if second.read(&mut buf).is_ok_and(|n| n == 1) {
+                break;
+            }
+            assert!(Instant::now() < deadline);
+        }
+        second.write_all(b"hi").unwrap();
+        assert_eq!(read_input(&mut server, 2), b"hi");
+
+        drop(server);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn websocket_roundtrip() {
+        use tungstenite::Message;
+
+        let mut server = Server::websocket("127.0.0.1:0").unwrap();
+        let url = server.describe();
+        let (mut ws, _) = tungstenite::connect(url.as_str()).unwrap();
+        wait_connected(&server);
+
+        ws.send(Message::Text("ls\r".to_string())).unwrap();
+        ws.send(Message::Binary(vec![3])).unwrap();
+        assert_eq!(read_input(&mut server, 4), b"ls\r\x03");
+
+        server.write(b"# ");
+        match ws.read().unwrap() {
+            Message::Binary(data) => assert_eq!(data, b"# "),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn log_tee_timestamps_lines() {
+        let dir = std::env::temp_dir().join(format!("riscv-vm-serial-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.txt");
+        let log = dir.join("log.txt");
+
+        let file = FileChardev::create(&out).unwrap();
+        let mut dev = Logged::new(Box::new(file), &log).unwrap();
+        dev.write(b"Linux version\r\nlog");
+        dev.write(b"in: ");
+        drop(dev);
+
+        assert_eq!(std::fs::read(&out).unwrap(), b"Linux version\r\nlogin: ");
+        let logged = std::fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("[    0.") && lines[0].ends_with("] Linux version"));
+        assert!(lines[1].ends_with("] login: "));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
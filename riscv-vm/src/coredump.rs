@@ -0,0 +1,581 @@
+//! ELF core files of the guest.
+//!
+//! A dump is an `ET_CORE` file for `EM_RISCV` laid out the way Linux lays
+//! out process cores, so `gdb-multiarch vmlinux core` can load it:
+//!
+//! - one `NT_PRSTATUS` note per hart, with `pr_pid = hart + 1` and the
+//!   register set `[pc, x1..x31]`;
+//! - one `NT_BAVY_CSRS` note per hart (owner `"BAVY"`) holding the hart id,
+//!   privilege mode and `(csr, value)` pairs for the supervisor and machine
+//!   trap state;
+//! - a `PT_LOAD` segment covering DRAM at its physical address;
+//! - further `PT_LOAD` segments for every Sv39 mapping reachable from the
+//!   first hart's `satp`, pointing at the same file bytes, so kernel virtual
+//!   addresses resolve in the debugger without duplicating memory.
+//!
+//! Zero pages of DRAM are skipped with a seek, so the file is sparse on
+//! filesystems that support it.
+
+use crate::Mode;
+use crate::cpu::Cpu;
+use crate::csr::{
+    CSR_MCAUSE, CSR_MEPC, CSR_MIE, CSR_MIP, CSR_MSTATUS, CSR_MTVAL, CSR_MTVEC, CSR_SATP,
+    CSR_SCAUSE, CSR_SEPC, CSR_STVAL, CSR_STVEC,
+};
+use crate::dram::Dram;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Note type of the per-hart CSR note (owner `"BAVY"`).
+pub const NT_BAVY_CSRS: u32 = 0x4353;
+
+/// CSRs recorded in the CSR note, in order.
+pub const DUMPED_CSRS: [u16; 12] = [
+    CSR_SATP,
+    CSR_MSTATUS,
+    CSR_SCAUSE,
+    CSR_STVAL,
+    CSR_SEPC,
+    CSR_STVEC,
+    CSR_MCAUSE,
+    CSR_MTVAL,
+    CSR_MEPC,
+    CSR_MTVEC,
+    CSR_MIE,
+    CSR_MIP,
+];
+
+const PAGE_SIZE: u64 = 4096;
+const NT_PRSTATUS: u32 = 1;
+const PRSTATUS_SIZE: usize = 376;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+/// Keeps `e_phnum` clear of `PN_XNUM` for pathological page tables.
+const MAX_MAPPINGS: usize = 0xff00;
+const SIGSEGV: u16 = 11;
+
+/// Register state of one hart at the time of the dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HartState {
+    pub hart_id: usize,
+    pub pc: u64,
+    pub regs: [u64; 32],
+    pub mode: Mode,
+    /// Values of [`DUMPED_CSRS`], in the same order.
+    pub csrs: [u64; DUMPED_CSRS.len()],
+    /// Set on the hart that hit the fatal trap; reported as SIGSEGV.
+    pub faulted: bool,
+}
+
+impl HartState {
+    pub fn capture(cpu: &Cpu) -> Self {
+        Self {
+            hart_id: cpu.csrs[crate::csr::CSR_MHARTID as usize] as usize,
+            pc: cpu.pc,
+            regs: cpu.regs,
+            mode: cpu.mode,
+            csrs: DUMPED_CSRS.map(|csr| cpu.csrs[csr as usize]),
+            faulted: false,
+        }
+    }
+
+    pub fn csr(&self, csr: u16) -> Option<u64> {
+        let index = DUMPED_CSRS.iter().position(|&c| c == csr)?;
+        Some(self.csrs[index])
+    }
+
+    fn prstatus(&self) -> Vec<u8> {
+        let mut desc = vec![0u8; PRSTATUS_SIZE];
+        if self.faulted {
+            // si_signo and pr_cursig
+            desc[0..4].copy_from_slice(&u32::from(SIGSEGV).to_le_bytes());
+            desc[12..14].copy_from_slice(&SIGSEGV.to_le_bytes());
+        }
+        let pid = self.hart_id as u32 + 1;
+        desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&pid.to_le_bytes());
+        let regs = desc[PRSTATUS_REGS..].chunks_exact_mut(8);
+        for (i, slot) in regs.take(32).enumerate() {
+            let value = if i == 0 { self.pc } else { self.regs[i] };
+            slot.copy_from_slice(&value.to_le_bytes());
+        }
+        desc
+    }
+
+    fn csr_note(&self) -> Vec<u8> {
+        let mut desc = Vec::with_capacity(24 + 16 * DUMPED_CSRS.len());
+        desc.extend_from_slice(&(self.hart_id as u64).to_le_bytes());
+        desc.extend_from_slice(&self.mode.to_mpp().to_le_bytes());
+        desc.extend_from_slice(&(DUMPED_CSRS.len() as u64).to_le_bytes());
+        for (csr, value) in DUMPED_CSRS.iter().zip(self.csrs) {
+            desc.extend_from_slice(&u64::from(*csr).to_le_bytes());
+            desc.extend_from_slice(&value.to_le_bytes());
+        }
+        desc
+    }
+}
+
+/// Collects hart states from the hart threads and queues on-demand dumps.
+///
+/// Harts call [`CoreDumper::maybe_publish`] between batches; requesting a
+/// snapshot bumps an epoch so each hart publishes once, like the profiler's
+/// sampling tick. Harts keep running while the snapshot is taken, so the
+/// registers of different harts may be a batch apart.
+#[derive(Default)]
+pub struct CoreDumper {
+    epoch: AtomicU64,
+    states: Mutex<BTreeMap<usize, (u64, HartState)>>,
+    pending: Mutex<Option<PathBuf>>,
+}
+
+impl CoreDumper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish `cpu` if a snapshot was requested since `last_epoch`.
+    #[inline]
+    pub fn maybe_publish(&self, last_epoch: &mut u64, cpu: &Cpu) {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        if epoch != *last_epoch {
+            *last_epoch = epoch;
+            self.publish(HartState::capture(cpu));
+        }
+    }
+
+    /// Record the latest state of a hart.
+    pub fn publish(&self, state: HartState) {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        self.states
+            .lock()
+            .unwrap()
+            .insert(state.hart_id, (epoch, state));
+    }
+
+    /// Ask every hart to publish its state and wait (briefly) for `harts`
+    /// of them to do so. Harts that do not answer in time are reported with
+    /// their last published state, if any.
+    pub fn snapshot(&self, harts: &[usize]) -> Vec<HartState> {
+        let epoch = self.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        for _ in 0..500 {
+            let states = self.states.lock().unwrap();
+            let fresh = harts
+                .iter()
+                .all(|h| states.get(h).is_some_and(|(e, _)| *e >= epoch));
+            if fresh {
+                break;
+            }
+            drop(states);
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.states()
+    }
+
+    /// Last published state of every hart, ordered by hart id.
+    pub fn states(&self) -> Vec<HartState> {
+        let states = self.states.lock().unwrap();
+        states.values().map(|(_, state)| state.clone()).collect()
+    }
+
+    /// Queue a dump to `path`; hart 0 writes it at its next console poll.
+    pub fn request(&self, path: impl Into<PathBuf>) {
+        *self.pending.lock().unwrap() = Some(path.into());
+    }
+
+    pub fn take_request(&self) -> Option<PathBuf> {
+        self.pending.lock().unwrap().take()
+    }
+}
+
+/// A virtual range backed by DRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mapping {
+    va: u64,
+    pa: u64,
+    len: u64,
+    flags: u32,
+}
+
+/// Write a core file for `harts` to `path`.
+pub fn write_core_file(path: &Path, dram: &Dram, harts: &[HartState]) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|e| format!("Failed to create core file '{}': {}", path.display(), e))?;
+    let mut out = BufWriter::new(file);
+    write_core(&mut out, dram, harts)
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("Failed to write core file '{}': {}", path.display(), e))
+}
+
+/// Write a core file for `harts` to `out`.
+///
+/// The hart that faulted (if any) is emitted first so that the debugger
+/// selects it as the current thread.
+pub fn write_core<W: Write + Seek>(
+    out: &mut W,
+    dram: &Dram,
+    harts: &[HartState],
+) -> io::Result<()> {
+    let mut harts: Vec<&HartState> = harts.iter().collect();
+    harts.sort_by_key(|h| (!h.faulted, h.hart_id));
+
+    let mut notes = Vec::new();
+    for hart in &harts {
+        push_note(&mut notes, b"CORE\0", NT_PRSTATUS, &hart.prstatus());
+        push_note(&mut notes, b"BAVY\0", NT_BAVY_CSRS, &hart.csr_note());
+    }
+
+    let satp = harts.first().and_then(|h| h.csr(CSR_SATP)).unwrap_or(0);
+    let mappings = sv39_mappings(dram, satp);
+
+    let phnum = 2 + mappings.len() as u64;
+    let notes_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let dram_offset = (notes_offset + notes.len() as u64).next_multiple_of(PAGE_SIZE);
+    let dram_size = dram.size() as u64;
+
+    let mut header = Vec::with_capacity(notes_offset as usize);
+    push_ehdr(&mut header, phnum as u16);
+    push_phdr(
+        &mut header,
+        PT_NOTE,
+        0,
+        notes_offset,
+        0,
+        0,
+        notes.len() as u64,
+    );
+    push_phdr(
+        &mut header,
+        PT_LOAD,
+        PF_R | PF_W | PF_X,
+        dram_offset,
+        dram.base,
+        dram.base,
+        dram_size,
+    );
+    for m in &mappings {
+        let offset = dram_offset + (m.pa - dram.base);
+        push_phdr(&mut header, PT_LOAD, m.flags, offset, m.va, m.pa, m.len);
+    }
+
+    out.write_all(&header)?;
+    out.write_all(&notes)?;
+
+    let chunk = 64 * 1024;
+    let mut written = (notes_offset + notes.len() as u64) as usize;
+    let mut offset = 0;
+    while offset < dram.size() {
+        let len = chunk.min(dram.size() - offset);
+        let bytes = dram
+            .read_range(offset, len)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        for (i, page) in bytes.chunks(PAGE_SIZE as usize).enumerate() {
+            if page.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let at = dram_offset as usize + offset + i * PAGE_SIZE as usize;
+            if at != written {
+                out.seek(SeekFrom::Start(at as u64))?;
+            }
+            out.write_all(page)?;
+            written = at + page.len();
+        }
+        offset += len;
+    }
+
+    // Extend the file over any trailing zero pages.
+    let end = (dram_offset + dram_size) as usize;
+    if written < end {
+        out.seek(SeekFrom::Start(end as u64 - 1))?;
+        out.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+fn push_ehdr(out: &mut Vec<u8>, phnum: u16) {
+    out.extend_from_slice(b"\x7fELF");
+    out.extend_from_slice(&[2, 1, 1, 0]); // ELFCLASS64, little endian, EV_CURRENT, SYSV
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&4u16.to_le_bytes()); // ET_CORE
+    out.extend_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    out.extend_from_slice(&EHDR_SIZE.to_le_bytes()); // e_phoff
+    out.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    out.extend_from_slice(&1u32.to_le_bytes()); // EF_RISCV_RVC
+    out.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&phnum.to_le_bytes());
+    out.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+    out.extend_from_slice(&0u16.to_le_bytes()); // e_shnum
+    out.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+}
+
+fn push_phdr(
+    out: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    size: u64,
+) {
+    let align = if kind == PT_LOAD { PAGE_SIZE } else { 1 };
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&vaddr.to_le_bytes());
+    out.extend_from_slice(&paddr.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes()); // p_filesz
+    out.extend_from_slice(&size.to_le_bytes()); // p_memsz
+    out.extend_from_slice(&align.to_le_bytes());
+}
+
+fn push_note(out: &mut Vec<u8>, name: &[u8], kind: u32, desc: &[u8]) {
+    out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(name);
+    out.resize(out.len().next_multiple_of(4), 0);
+    out.extend_from_slice(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+/// Leaf mappings of an Sv39 page table that land in DRAM, with adjacent
+/// ranges merged. Empty when translation is off.
+fn sv39_mappings(dram: &Dram, satp: u64) -> Vec<Mapping> {
+    let mut out = Vec::new();
+    if satp >> 60 == 8 {
+        let root = (satp & ((1 << 44) - 1)) << 12;
+        walk(dram, root, 2, 0, &mut out);
+    }
+    if out.len() > MAX_MAPPINGS {
+        log::warn!(
+            "[Core] Page table has {} mappings, keeping the first {}",
+            out.len(),
+            MAX_MAPPINGS
+        );
+        out.truncate(MAX_MAPPINGS);
+    }
+    out
+}
+
+fn walk(dram: &Dram, table: u64, level: u32, prefix: u64, out: &mut Vec<Mapping>) {
+    let dram_end = dram.base + dram.size() as u64;
+    if table < dram.base || table + PAGE_SIZE > dram_end {
+        return;
+    }
+    for i in 0..512u64 {
+        let Ok(pte) = dram.load_64(table - dram.base + i * 8) else {
+            return;
+        };
+        if pte & 1 == 0 {
+            continue;
+        }
+        let shift = 12 + 9 * level;
+        let va = prefix | (i << shift);
+        let pa = ((pte >> 10) & ((1 << 44) - 1)) << 12;
+        let rwx = (pte >> 1) & 0b111;
+        if rwx == 0 {
+            if level > 0 {
+                walk(dram, pa, level - 1, va, out);
+            }
+            continue;
+        }
+
+        // Clip the leaf to DRAM; large pages may extend past its end.
+        let start = pa.max(dram.base);
+        let end = (pa + (1 << shift)).min(dram_end);
+        if start >= end {
+            continue;
+        }
+        let va = (((va + (start - pa)) << 25) as i64 >> 25) as u64;
+        let mut flags = 0;
+        if rwx & 0b001 != 0 {
+            flags |= PF_R;
+        }
+        if rwx & 0b010 != 0 {
+            flags |= PF_W;
+        }
+        if rwx & 0b100 != 0 {
+            flags |= PF_X;
+        }
+
+        let mapping = Mapping {
+            va,
+            pa: start,
+            len: end - start,
+            flags,
+        };
+        match out.last_mut() {
+            Some(last)
+                if last.va.wrapping_add(last.len) == va
+                    && last.pa + last.len == start
+                    && last.flags == flags =>
+            {
+                last.len += mapping.len;
+            }
+            _ => out.push(mapping),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::elf::Elf;
+    use goblin::elf::program_header::{PT_LOAD, PT_NOTE};
+    use std::io::Cursor;
+
+    const BASE: u64 = 0x8000_0000;
+
+    fn hart(hart_id: usize) -> HartState {
+        let mut cpu = Cpu::new(BASE + 0x100, hart_id as u64);
+        cpu.regs[2] = 0x1234;
+        cpu.csrs[CSR_SCAUSE as usize] = 13;
+        HartState::capture(&cpu)
+    }
+
+    #[test]
+    fn core_file_has_notes_and_dram() {
+        let dram = Dram::new(BASE, 1 << 20);
+        dram.write_bytes(0x2000, b"guest").unwrap();
+        let mut faulted = hart(1);
+        faulted.faulted = true;
+
+        let mut out = Cursor::new(Vec::new());
+        write_core(&mut out, &dram, &[hart(0), faulted]).unwrap();
+        let bytes = out.into_inner();
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.header.e_type, goblin::elf::header::ET_CORE);
+        assert_eq!(elf.header.e_machine, goblin::elf::header::EM_RISCV);
+        assert_eq!(elf.program_headers.len(), 2);
+        assert_eq!(elf.program_headers[0].p_type, PT_NOTE);
+
+        let load = &elf.program_headers[1];
+        assert_eq!(load.p_type, PT_LOAD);
+        assert_eq!((load.p_vaddr, load.p_memsz), (BASE, 1 << 20));
+        assert_eq!(bytes.len() as u64, load.p_offset + load.p_filesz);
+        let guest = (load.p_offset + 0x2000) as usize;
+        assert_eq!(&bytes[guest..guest + 5], b"guest");
+
+        let notes: Vec<_> = elf
+            .iter_note_headers(&bytes)
+            .unwrap()
+            .map(|n| n.unwrap())
+            .collect();
+        assert_eq!(notes.len(), 4);
+        // The faulting hart comes first.
+        assert_eq!(notes[0].n_type, NT_PRSTATUS);
+        let prstatus = notes[0].desc;
+        assert_eq!(prstatus.len(), PRSTATUS_SIZE);
+        assert_eq!(prstatus[12], SIGSEGV as u8);
+        assert_eq!(prstatus[PRSTATUS_PID], 2);
+        let reg = |i: usize| {
+            let at = PRSTATUS_REGS + i * 8;
+            u64::from_le_bytes(prstatus[at..at + 8].try_into().unwrap())
+        };
+        assert_eq!((reg(0), reg(2)), (BASE + 0x100, 0x1234));
+
+        assert_eq!((notes[1].name, notes[1].n_type), ("BAVY", NT_BAVY_CSRS));
+        let csrs = notes[1].desc;
+        assert_eq!(csrs[0], 1);
+        let scause = 24 + 16 * 2;
+        assert_eq!(
+            u64::from_le_bytes(csrs[scause..scause + 8].try_into().unwrap()),
+            u64::from(CSR_SCAUSE)
+        );
+        assert_eq!(
+            u64::from_le_bytes(csrs[scause + 8..scause + 16].try_into().unwrap()),
+            13
+        );
+    }
+
+    #[test]
+    fn sv39_walk_emits_virtual_segments() {
+        let dram = Dram::new(BASE, 1 << 20);
+        let root = BASE + 0x1000;
+        let l1 = BASE + 0x2000;
+        let l0 = BASE + 0x3000;
+        let pte = |pa: u64, bits: u64| ((pa >> 12) << 10) | bits;
+        // Gigapage at 0xffff_ffc0_0000_0000 -> DRAM base (clipped to DRAM).
+        dram.store_64(0x1000 + 256 * 8, pte(BASE, 0b1111)).unwrap();
+        // Two adjacent 4K user pages at 0x10000 -> BASE + 0x5000 (R|X).
+        dram.store_64(0x1000, pte(l1, 1)).unwrap();
+        dram.store_64(0x2000, pte(l0, 1)).unwrap();
+        dram.store_64(0x3000 + 16 * 8, pte(BASE + 0x5000, 0b1011))
+            .unwrap();
+        dram.store_64(0x3000 + 17 * 8, pte(BASE + 0x6000, 0b1011))
+            .unwrap();
+
+        let satp = (8 << 60) | (root >> 12);
+        let mappings = sv39_mappings(&dram, satp);
+        assert_eq!(
+            mappings,
+            vec![
+                Mapping {
+                    va: 0x10000,
+                    pa: BASE + 0x5000,
+                    len: 0x2000,
+                    flags: PF_R | PF_X
+                },
+                Mapping {
+                    va: 0xffff_ffc0_0000_0000,
+                    pa: BASE,
+                    len: 1 << 20,
+                    flags: PF_R | PF_W | PF_X
+                },
+            ]
+        );
+        assert!(sv39_mappings(&dram, 0).is_empty());
+
+        let mut state = hart(0);
+        state.csrs[0] = satp;
+        let mut out = Cursor::new(Vec::new());
+        write_core(&mut out, &dram, &[state]).unwrap();
+        let bytes = out.into_inner();
+        let elf = Elf::parse(&bytes).unwrap();
+        let user = &elf.program_headers[2];
+        assert_eq!((user.p_vaddr, user.p_paddr), (0x10000, BASE + 0x5000));
+        assert_eq!(user.p_offset, elf.program_headers[1].p_offset + 0x5000);
+    }
+
+    #[test]
+    fn snapshot_collects_published_harts() {
+        let dumper = CoreDumper::new();
+        let cpu = Cpu::new(BASE, 1);
+        let mut seen = 0;
+        dumper.maybe_publish(&mut seen, &cpu);
+        assert!(dumper.states().is_empty());
+
+        dumper.publish(hart(0));
+        let handle = std::thread::scope(|s| {
+            let waiter = s.spawn(|| dumper.snapshot(&[1]));
+            while dumper.epoch.load(Ordering::Acquire) == 0 {
+                thread::yield_now();
+            }
+            dumper.maybe_publish(&mut seen, &cpu);
+            waiter.join().unwrap()
+        });
+        assert_eq!(
+            handle.iter().map(|h| h.hart_id).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        dumper.request("core");
+        assert_eq!(dumper.take_request(), Some(PathBuf::from("core")));
+        assert_eq!(dumper.take_request(), None);
+    }
+}
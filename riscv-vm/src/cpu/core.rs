@@ -4,7 +4,9 @@ use crate::engine::cache::BlockCache;
 use crate::engine::decoder::{self, Op, Register};
 use crate::engine::microop::MicroOp;
 use crate::mmu::{self, AccessType as MmuAccessType, Tlb};
+use crate::semihosting::Semihosting;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use super::csr::{
     CSR_MCAUSE, CSR_MEDELEG, CSR_MEPC, CSR_MHARTID, CSR_MIDELEG, CSR_MIE, CSR_MIP, CSR_MISA,
@@ -59,6 +61,15 @@ pub struct Cpu {
     pub block_cache: BlockCache,
     /// Enable/disable superblock optimization.
     pub use_blocks: bool,
+    /// Compile one-instruction blocks and disable chaining so that each
+    /// `step()` retires at most one instruction (used by the commit tracer).
+    pub(crate) single_insn_blocks: bool,
+    /// Semihosting host interface; when set, `ebreak`s wrapped in the
+    /// semihosting sequence are serviced instead of trapping.
+    pub(crate) semihosting: Option<Arc<Semihosting>>,
+    /// Service S-mode ECALLs with the built-in SBI (`crate::sbi`). Off when
+    /// M-mode firmware is loaded, so ECALLs trap to it.
+    pub(crate) builtin_sbi: bool,
 }
 
 impl Cpu {
@@ -69,9 +80,9 @@ impl Cpu {
     /// * `hart_id` - Hardware thread ID (0 for primary, 1+ for secondary)
     pub fn new(pc: u64, hart_id: u64) -> Self {
         let mut csrs = CsrFile::new();
-        // misa: rv64imac_zicsr_zifencei (value from phase-0.md)
-        const MISA_RV64IMAC_ZICSR_ZIFENCEI: u64 = 0x4000_0000_0018_1125;
-        csrs[CSR_MISA as usize] = MISA_RV64IMAC_ZICSR_ZIFENCEI;
+        // misa: MXL=2 (RV64), extensions A, C, I, M, S and U
+        const MISA_RV64IMAC_SU: u64 = 0x8000_0000_0014_1105;
+        csrs[CSR_MISA as usize] = MISA_RV64IMAC_SU;
         csrs[CSR_MHARTID as usize] = hart_id; // Initialize hart ID
 
         // mstatus initial value: all zeros except UXL/SXL can be left as 0 (WARL).
@@ -88,9 +99,33 @@ impl Cpu {
             decode_cache: [None; DECODE_CACHE_SIZE],
             block_cache: BlockCache::new(),
             use_blocks: true, // Disabled by default; enable for production workloads
+            single_insn_blocks: false,
+            semihosting: None,
+            builtin_sbi: true,
         }
     }
 
+    /// Restrict the block engine to one instruction per `step()`.
+    ///
+    /// Flushes the block cache so no multi-instruction blocks compiled
+    /// earlier are reused.
+    pub fn set_single_insn_blocks(&mut self, enabled: bool) {
+        if self.single_insn_blocks != enabled {
+            self.single_insn_blocks = enabled;
+            self.block_cache.clear();
+        }
+    }
+
+    /// Enable (or disable) servicing of semihosting calls.
+    pub fn set_semihosting(&mut self, semihosting: Option<Arc<Semihosting>>) {
+        self.semihosting = semihosting;
+    }
+
+    /// Enable (or disable) the built-in SBI implementation.
+    pub fn set_builtin_sbi(&mut self, enabled: bool) {
+        self.builtin_sbi = enabled;
+    }
+
     /// Configure the CPU for S-mode kernel boot.
     ///
     /// This sets up the necessary CSRs so that the kernel will run in S-mode
@@ -161,9 +196,9 @@ impl Cpu {
         // pmpaddr0 = 0x1FFFFFFF_FFFFFFFF (all 1s except top bits, NAPOT for max range)
         // pmpcfg0 = L=0, A=NAPOT(3), X=1, W=1, R=1 => 0x1F for entry 0
         // This gives S-mode full RWX access to all memory
-        self.csrs[CSR_PMPADDR0 as usize] = 0x003F_FFFF_FFFF_FFFF; // NAPOT covering 0-max
+        let _ = self.csrs.write(CSR_PMPADDR0, 0x003F_FFFF_FFFF_FFFF, Mode::Machine); // NAPOT covering 0-max
         // pmpcfg0 byte 0: A=NAPOT(3), X=1, W=1, R=1 = 0b00011111 = 0x1F
-        self.csrs[CSR_PMPCFG0 as usize] = 0x1F;
+        let _ = self.csrs.write(CSR_PMPCFG0, 0x1F, Mode::Machine);
 
         // Set a0 (x10) to hart ID - SBI convention for S-mode kernel entry
         // The kernel will use this instead of reading mhartid CSR
@@ -268,6 +303,11 @@ impl Cpu {
         }
     }
 
+    /// Drop any LR/SC reservation (e.g. when switching guest threads).
+    pub(crate) fn clear_reservation(&mut self) {
+        self.reservation = None;
+    }
+
     pub fn read_csr(&self, addr: u16) -> Result<u64, Trap> {
         self.csrs.read(addr, self.mode)
     }
@@ -412,6 +452,28 @@ impl Cpu {
         Err(trap)
     }
 
+    /// Take a non-maskable interrupt.
+    ///
+    /// There is no Smrnmi, so this is the non-resumable NMI of the
+    /// privileged spec: the hart enters M-mode at the `mtvec` base, which
+    /// serves as the NMI vector, whatever `mstatus.MIE`, `mie` and
+    /// delegation say. `mcause` is the interrupt bit with cause 0 (unknown
+    /// source) and `mepc` the instruction that was about to execute.
+    pub fn take_nmi(&mut self) {
+        self.csrs[CSR_MEPC as usize] = self.pc;
+        self.csrs[CSR_MTVAL as usize] = 0;
+        self.csrs[CSR_MCAUSE as usize] = 1 << 63;
+
+        let mut mstatus = self.csrs[CSR_MSTATUS as usize];
+        let mie = (mstatus >> 3) & 1;
+        mstatus = (mstatus & !(1 << 7)) | (mie << 7);
+        mstatus &= !(1 << 3);
+        mstatus = (mstatus & !(0b11 << 11)) | (self.mode.to_mpp() << 11);
+        self.csrs[CSR_MSTATUS as usize] = mstatus;
+        self.mode = Mode::Machine;
+        self.pc = self.csrs[CSR_MTVEC as usize] & !0b11;
+    }
+
     /// Translate a virtual address to a physical address using the MMU.
     ///
     /// On translation failure, this enters the trap handler and returns the
@@ -424,9 +486,7 @@ impl Cpu {
         pc: u64,
         insn_raw: Option<u32>,
     ) -> Result<u64, Trap> {
-        let satp = self.csrs[CSR_SATP as usize];
-        let mstatus = self.csrs[CSR_MSTATUS as usize];
-        match mmu::translate(bus, &mut self.tlb, self.mode, satp, mstatus, vaddr, access) {
+        match self.translate_addr_for_block(bus, vaddr, access) {
             Ok(pa) => Ok(pa),
             Err(trap) => self.handle_trap(trap, pc, insn_raw),
         }
@@ -1274,7 +1334,12 @@ impl Cpu {
     ) -> Result<u64, Trap> {
         let satp = self.csrs[CSR_SATP as usize];
         let mstatus = self.csrs[CSR_MSTATUS as usize];
-        mmu::translate(bus, &mut self.tlb, self.mode, satp, mstatus, vaddr, access)
+        let pa = mmu::translate(bus, &mut self.tlb, self.mode, satp, mstatus, vaddr, access)?;
+        let mode = mmu::effective_mode(self.mode, mstatus, access);
+        if !self.csrs.pmp().allows(pa, mode, access) {
+            return Err(mmu::access_fault(access, vaddr));
+        }
+        Ok(pa)
     }
 
     /// Handle block execution result and return to normal step() flow
@@ -1811,6 +1876,42 @@ mod tests {
         assert_eq!(cpu.read_reg(Register::X3), 0xF);
     }
 
+    #[test]
+    fn test_pmp_denied_block_recompiled_after_mret() {
+        use super::super::csr::{CSR_PMPADDR0, CSR_PMPCFG0};
+
+        let bus = make_bus();
+        let mut cpu = Cpu::new(0x8000_0000, 0);
+
+        // Firmware page at 0x80000000: addi x5,x5,1 ; jalr x0,0(x6)
+        bus.write32(0x8000_0000, encode_i(1, 5, 0, 5, 0x13)).unwrap();
+        bus.write32(0x8000_0004, encode_i(0, 6, 0, 0, 0x67)).unwrap();
+        // mret
+        bus.write32(0x8000_2000, 0x3020_0073).unwrap();
+
+        // Entry 0 hides the firmware page from S/U, entry 1 opens the rest
+        cpu.write_csr(CSR_PMPADDR0, (0x8000_0000 >> 2) | ((0x1000 >> 3) - 1)).unwrap();
+        cpu.write_csr(CSR_PMPADDR0 + 1, (1 << 54) - 1).unwrap();
+        cpu.write_csr(CSR_PMPCFG0, 0x18 | (0x1F << 8)).unwrap();
+        cpu.write_csr(CSR_MTVEC, 0x8000_3000).unwrap();
+        cpu.write_csr(CSR_MEPC, 0x8000_0000).unwrap();
+        cpu.write_csr(CSR_MSTATUS, 1 << 11).unwrap(); // MPP = S
+        cpu.write_reg(Register::X6, 0x8000_2000);
+
+        // M-mode runs (and caches) the firmware block
+        cpu.step(&bus).unwrap();
+        assert_eq!(cpu.pc, 0x8000_2000);
+        assert_eq!(cpu.read_reg(Register::X5), 1);
+
+        // mret to S-mode at the same PC: the cached block must not run
+        cpu.step(&bus).unwrap();
+        assert_eq!((cpu.mode, cpu.pc), (Mode::Supervisor, 0x8000_0000));
+        assert_eq!(cpu.step(&bus), Err(Trap::InstructionAccessFault(0x8000_0000)));
+        assert_eq!(cpu.read_reg(Register::X5), 1);
+        assert_eq!(cpu.csrs[CSR_MCAUSE as usize], 1);
+        assert_eq!((cpu.mode, cpu.pc), (Mode::Machine, 0x8000_3000));
+    }
+
     #[test]
     fn test_a_extension_lr_sc_basic() {
         let bus = make_bus();
@@ -2146,4 +2247,229 @@ mod tests {
             _ => panic!("Expected MachineExternalInterrupt, got {:?}", res),
         }
     }
+
+    // --- Disassembler round trip -------------------------------------------
+
+    /// Re-assemble `disassemble_no_aliases` output with the encoders above.
+    fn assemble(pc: u64, text: &str) -> u32 {
+        use crate::csr::csr_name;
+        use crate::engine::disasm::ABI_NAMES;
+
+        let (name, operands) = text.split_once(' ').unwrap_or((text, ""));
+        let ops: Vec<&str> = operands.split(',').collect();
+        let num = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).unwrap(),
+            None => s.parse::<i64>().unwrap(),
+        };
+        let reg = |s: &str| ABI_NAMES.iter().position(|n| *n == s).unwrap() as u32;
+        let mem = |s: &str| {
+            let (off, base) = s.trim_end_matches(')').split_once('(').unwrap();
+            (if off.is_empty() { 0 } else { num(off) as i32 }, reg(base))
+        };
+        let rel = |s: &str| num(s).wrapping_sub(pc as i64) as i32;
+        let csr = |s: &str| match (0..4096u16).find(|&a| csr_name(a) == Some(s)) {
+            Some(addr) => addr as i32,
+            None => num(s) as i32,
+        };
+        let fence_set = |s: &str| {
+            "iorw"
+                .chars()
+                .enumerate()
+                .filter(|(_, c)| s.contains(*c))
+                .fold(0, |acc, (i, _)| acc | (8 >> i))
+        };
+
+        const ALU: [&str; 8] = ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"];
+        const MULDIV: [&str; 8] = [
+            "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+        ];
+        const ALU_IMM: [&str; 8] = ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"];
+        const LOADS: [&str; 7] = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"];
+        const STORES: [&str; 4] = ["sb", "sh", "sw", "sd"];
+        const BRANCHES: [&str; 8] = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"];
+        const CSRS: [&str; 8] = [
+            "", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci",
+        ];
+        const AMOS: [(&str, u32); 11] = [
+            ("lr", 0x02),
+            ("sc", 0x03),
+            ("amoswap", 0x01),
+            ("amoadd", 0x00),
+            ("amoxor", 0x04),
+            ("amoand", 0x0c),
+            ("amoor", 0x08),
+            ("amomin", 0x10),
+            ("amomax", 0x14),
+            ("amominu", 0x18),
+            ("amomaxu", 0x1c),
+        ];
+        let find = |table: &[&str], n: &str| table.iter().position(|t| *t == n).map(|i| i as u32);
+
+        if name == ".4byte" {
+            return num(operands) as u32;
+        }
+        if let Some(f3) = find(&ALU, name) {
+            return encode_r(0, reg(ops[2]), reg(ops[1]), f3, reg(ops[0]), 0x33);
+        }
+        if let Some(f3) = find(&MULDIV, name) {
+            return encode_r(1, reg(ops[2]), reg(ops[1]), f3, reg(ops[0]), 0x33);
+        }
+        if let Some(f3) = find(&ALU_IMM, name) {
+            return encode_i(num(ops[2]) as i32, reg(ops[1]), f3, reg(ops[0]), 0x13);
+        }
+        if let Some(f3) = find(&LOADS, name) {
+            let (off, base) = mem(ops[1]);
+            return encode_i(off, base, f3, reg(ops[0]), 0x03);
+        }
+        if let Some(f3) = find(&STORES, name) {
+            let (off, base) = mem(ops[1]);
+            return encode_s(off, reg(ops[0]), base, f3, 0x23);
+        }
+        if let Some(f3) = find(&BRANCHES, name) {
+            return encode_b(rel(ops[2]), reg(ops[1]), reg(ops[0]), f3, 0x63);
+        }
+        if let Some(f3) = find(&CSRS, name) {
+            let src = if f3 >= 5 {
+                num(ops[2]) as u32
+            } else {
+                reg(ops[2])
+            };
+            return encode_i(csr(ops[1]), src, f3, reg(ops[0]), 0x73);
+        }
+        if let Some((base, width)) = name.split_once('.')
+            && let Some(&(_, funct5)) = AMOS.iter().find(|(n, _)| *n == base)
+        {
+            let f3 = if width.starts_with('w') { 2 } else { 3 };
+            let (aq, rl) = (width.contains("aq"), width.contains("rl"));
+            let (rs2, addr) = if base == "lr" {
+                (0, ops[1])
+            } else {
+                (reg(ops[1]), ops[2])
+            };
+            return encode_amo(funct5, aq, rl, rs2, mem(addr).1, f3, reg(ops[0]));
+        }
+
+        let rd = || reg(ops[0]);
+        let shift = |f3: u32, hi: i32, op: u32| {
+            encode_i(hi | num(ops[2]) as i32, reg(ops[1]), f3, rd(), op)
+        };
+        match name {
+            "sub" => encode_r(0x20, reg(ops[2]), reg(ops[1]), 0, rd(), 0x33),
+            "sra" => encode_r(0x20, reg(ops[2]), reg(ops[1]), 5, rd(), 0x33),
+            "addw" => encode_r(0, reg(ops[2]), reg(ops[1]), 0, rd(), 0x3B),
+            "subw" => encode_r(0x20, reg(ops[2]), reg(ops[1]), 0, rd(), 0x3B),
+            "sllw" => encode_r(0, reg(ops[2]), reg(ops[1]), 1, rd(), 0x3B),
+            "srlw" => encode_r(0, reg(ops[2]), reg(ops[1]), 5, rd(), 0x3B),
+            "sraw" => encode_r(0x20, reg(ops[2]), reg(ops[1]), 5, rd(), 0x3B),
+            "mulw" => encode_r(1, reg(ops[2]), reg(ops[1]), 0, rd(), 0x3B),
+            "divw" => encode_r(1, reg(ops[2]), reg(ops[1]), 4, rd(), 0x3B),
+            "divuw" => encode_r(1, reg(ops[2]), reg(ops[1]), 5, rd(), 0x3B),
+            "remw" => encode_r(1, reg(ops[2]), reg(ops[1]), 6, rd(), 0x3B),
+            "remuw" => encode_r(1, reg(ops[2]), reg(ops[1]), 7, rd(), 0x3B),
+            "slli" => shift(1, 0, 0x13),
+            "srli" => shift(5, 0, 0x13),
+            "srai" => shift(5, 0x400, 0x13),
+            "addiw" => encode_i(num(ops[2]) as i32, reg(ops[1]), 0, rd(), 0x1B),
+            "slliw" => shift(1, 0, 0x1B),
+            "srliw" => shift(5, 0, 0x1B),
+            "sraiw" => shift(5, 0x400, 0x1B),
+            "lui" => ((num(ops[1]) as u32) << 12) | (rd() << 7) | 0x37,
+            "auipc" => ((num(ops[1]) as u32) << 12) | (rd() << 7) | 0x17,
+            "jal" => {
+                let imm = rel(ops[1]) as u32;
+                let bits = ((imm >> 20) & 1) << 31
+                    | ((imm >> 1) & 0x3FF) << 21
+                    | ((imm >> 11) & 1) << 20
+                    | ((imm >> 12) & 0xFF) << 12;
+                bits | (rd() << 7) | 0x6F
+            }
+            "jalr" => {
+                let (off, base) = mem(ops[1]);
+                encode_i(off, base, 0, rd(), 0x67)
+            }
+            "ecall" => 0x0000_0073,
+            "ebreak" => 0x0010_0073,
+            "sret" => 0x1020_0073,
+            "mret" => 0x3020_0073,
+            "wfi" => 0x1050_0073,
+            "sfence.vma" => encode_r(0x09, reg(ops[1]), reg(ops[0]), 0, 0, 0x73),
+            "fence.i" => encode_i(0, 0, 1, 0, 0x0F),
+            "fence.tso" => 0x8330_000F,
+            "fence" if operands.is_empty() => 0x0FF0_000F,
+            "fence" => (fence_set(ops[0]) << 24) | (fence_set(ops[1]) << 20) | 0x0F,
+            _ => panic!("cannot assemble {:?}", text),
+        }
+    }
+
+    #[test]
+    fn disassembly_round_trips_through_encoders() {
+        use crate::engine::disasm::disassemble_no_aliases;
+
+        let pc = 0x8000_1000;
+        let regs = [0, 1, 2, 8, 10, 15, 31];
+        let imms = [-2048, -7, -1, 0, 1, 42, 2047];
+        let mut words = Vec::new();
+        for &rd in &regs {
+            for &rs in &regs {
+                for f3 in 0..8 {
+                    words.push(encode_r(0, rs, rd, f3, rs, 0x33));
+                    words.push(encode_r(1, rs, rd, f3, rs, 0x3B));
+                    words.push(encode_amo(
+                        0x1c,
+                        f3 & 1 != 0,
+                        f3 & 2 != 0,
+                        rs,
+                        rd,
+                        2 + (f3 & 1),
+                        rd,
+                    ));
+                }
+                for &imm in &imms {
+                    for f3 in 0..8 {
+                        words.push(encode_i(imm, rs, f3, rd, 0x13));
+                        words.push(encode_i(imm, rs, f3, rd, 0x03));
+                        words.push(encode_s(imm, rs, rd, f3, 0x23));
+                        words.push(encode_b(imm * 2, rs, rd, f3, 0x63));
+                        words.push(encode_i(imm & 0xFFF, rs, f3, rd, 0x73));
+                    }
+                    words.push(encode_i(imm, rs, 0, rd, 0x1B));
+                    words.push(encode_i(imm, rs, 0, rd, 0x67));
+                }
+            }
+        }
+        // Plus a sweep of arbitrary 32-bit words (simple LCG).
+        let mut x: u32 = 0x1234_5678;
+        for _ in 0..200_000 {
+            x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            words.push(x | 0x3);
+        }
+
+        for raw in words {
+            let text = disassemble_no_aliases(pc, raw);
+            assert_eq!(assemble(pc, &text), raw, "0x{:08x} -> {:?}", raw, text);
+        }
+    }
+
+    #[test]
+    fn compressed_disassembly_matches_expansion() {
+        use crate::engine::decoder::expand_compressed;
+        use crate::engine::disasm::disassemble;
+
+        let pc = 0x8000_1000;
+        for half in 0..=u16::MAX {
+            if half & 0x3 == 0x3 {
+                continue;
+            }
+            let text = disassemble(pc, half as u32);
+            match expand_compressed(half) {
+                Ok(insn) if half != 0 => assert_eq!(text, disassemble(pc, insn), "0x{:04x}", half),
+                _ => assert!(
+                    text == "unimp" || text.starts_with(".2byte"),
+                    "0x{:04x}: {}",
+                    half,
+                    text
+                ),
+            }
+        }
+    }
 }
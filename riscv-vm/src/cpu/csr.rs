@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::ops::{Index, IndexMut};
 
+use super::pmp::{self, PMP_ENTRIES, Pmp};
 use super::types::Trap;
 
 pub use super::types::Mode;
@@ -8,11 +9,20 @@ pub use super::types::Mode;
 /// Compact CSR storage with privilege-aware access helpers.
 pub struct CsrFile {
     storage: [u64; 4096],
+    /// Decoded PMP entries, rebuilt on writes to the PMP CSRs.
+    pmp: Pmp,
 }
 
 impl CsrFile {
     pub const fn new() -> Self {
-        Self { storage: [0; 4096] }
+        Self {
+            storage: [0; 4096],
+            pmp: Pmp::new(),
+        }
+    }
+
+    pub fn pmp(&self) -> &Pmp {
+        &self.pmp
     }
 
     pub fn export(&self) -> HashMap<u16, u64> {
@@ -33,12 +43,13 @@ impl CsrFile {
                 self.storage[idx] = val;
             }
         }
+        self.pmp = Pmp::from_csrs(self);
     }
 
     pub fn read(&self, addr: u16, mode: Mode) -> Result<u64, Trap> {
         let required_priv = (addr >> 8) & 0x3;
         let current_priv = mode.privilege_level() as u16;
-        if current_priv < required_priv {
+        if current_priv < required_priv || !implemented(addr) {
             return Err(Trap::IllegalInstruction(addr as u64));
         }
 
@@ -70,11 +81,28 @@ impl CsrFile {
 
         let required_priv = (addr >> 8) & 0x3;
         let current_priv = mode.privilege_level() as u16;
-        if current_priv < required_priv {
+        if current_priv < required_priv || !implemented(addr) {
             return Err(Trap::IllegalInstruction(addr as u64));
         }
 
         match addr {
+            CSR_PMPCFG0..=0x3AF => {
+                let entry = (addr - CSR_PMPCFG0) as usize * 4;
+                if entry < PMP_ENTRIES {
+                    let old = self.storage[addr as usize];
+                    self.storage[addr as usize] = pmp::legalize_cfg(old, val);
+                    self.pmp = Pmp::from_csrs(self);
+                }
+            }
+            CSR_PMPADDR0..=0x3EF => {
+                let entry = (addr - CSR_PMPADDR0) as usize;
+                if entry < PMP_ENTRIES
+                    && let Some(val) = pmp::legalize_addr(self, entry, val)
+                {
+                    self.storage[addr as usize] = val;
+                    self.pmp = Pmp::from_csrs(self);
+                }
+            }
             CSR_SSTATUS => {
                 let mut mstatus = self.storage[CSR_MSTATUS as usize];
                 let mask = (1 << 1) | (1 << 5) | (1 << 8) | (3 << 13) | (1 << 18) | (1 << 19);
@@ -102,6 +130,46 @@ impl CsrFile {
     }
 }
 
+/// Whether the hart has CSR `addr`; accessing any other one raises an
+/// illegal instruction exception, which is how firmware probes for
+/// extensions. Custom (vendor) CSRs read as zero.
+fn implemented(addr: u16) -> bool {
+    // RV64 has only the even pmpcfg registers
+    if (CSR_PMPCFG0..=0x3AF).contains(&addr) {
+        return addr.is_multiple_of(2);
+    }
+    matches!(
+        addr,
+        0x001..=0x003
+            | 0xC00..=0xC02
+            | CSR_SSTATUS
+            | CSR_SIE
+            | CSR_STVEC
+            | 0x106
+            | 0x10A
+            | CSR_SSCRATCH..=CSR_SIP
+            | CSR_STIMECMP
+            | CSR_SATP
+            | CSR_MSTATUS..=CSR_MCOUNTEREN
+            | CSR_MENVCFG
+            | 0x320
+            | 0x340..=CSR_MIP
+            | CSR_PMPADDR0..=0x3EF
+            | 0x7A0..=0x7A1
+            | 0xB00
+            | 0xB02
+            | CSR_MVENDORID..=0xF15
+            | 0x5C0..=0x5FF
+            | 0x7C0..=0x7FF
+            | 0x800..=0x8FF
+            | 0x9C0..=0x9FF
+            | 0xBC0..=0xBFF
+            | 0xCC0..=0xCFF
+            | 0xDC0..=0xDFF
+            | 0xFC0..=0xFFF
+    )
+}
+
 impl Default for CsrFile {
     fn default() -> Self {
         Self::new()
@@ -174,3 +242,74 @@ pub const CSR_PMPADDR6: u16 = 0x3B6;
 pub const CSR_PMPADDR7: u16 = 0x3B7;
 // Additional pmpaddr8-15 available at 0x3B8-0x3BF
 
+/// Assembler name of a CSR, as used by disassemblers and Spike commit logs.
+pub fn csr_name(addr: u16) -> Option<&'static str> {
+    Some(match addr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0xC00 => "cycle",
+        0xC01 => "time",
+        0xC02 => "instret",
+        CSR_SSTATUS => "sstatus",
+        CSR_SIE => "sie",
+        CSR_STVEC => "stvec",
+        0x106 => "scounteren",
+        0x10A => "senvcfg",
+        CSR_SSCRATCH => "sscratch",
+        CSR_SEPC => "sepc",
+        CSR_SCAUSE => "scause",
+        CSR_STVAL => "stval",
+        CSR_SIP => "sip",
+        CSR_STIMECMP => "stimecmp",
+        CSR_SATP => "satp",
+        CSR_MSTATUS => "mstatus",
+        CSR_MISA => "misa",
+        CSR_MEDELEG => "medeleg",
+        CSR_MIDELEG => "mideleg",
+        CSR_MIE => "mie",
+        CSR_MTVEC => "mtvec",
+        CSR_MCOUNTEREN => "mcounteren",
+        CSR_MENVCFG => "menvcfg",
+        0x320 => "mcountinhibit",
+        0x340 => "mscratch",
+        CSR_MEPC => "mepc",
+        CSR_MCAUSE => "mcause",
+        CSR_MTVAL => "mtval",
+        CSR_MIP => "mip",
+        CSR_PMPCFG0 => "pmpcfg0",
+        CSR_PMPCFG1 => "pmpcfg1",
+        CSR_PMPCFG2 => "pmpcfg2",
+        CSR_PMPCFG3 => "pmpcfg3",
+        0x3B0..=0x3BF => PMPADDR_NAMES[(addr - CSR_PMPADDR0) as usize],
+        0x7A0 => "tselect",
+        0x7A1 => "tdata1",
+        0x7A2 => "tdata2",
+        0xB00 => "mcycle",
+        0xB02 => "minstret",
+        CSR_MVENDORID => "mvendorid",
+        CSR_MARCHID => "marchid",
+        CSR_MIMPID => "mimpid",
+        CSR_MHARTID => "mhartid",
+        _ => return None,
+    })
+}
+
+const PMPADDR_NAMES: [&str; 16] = [
+    "pmpaddr0",
+    "pmpaddr1",
+    "pmpaddr2",
+    "pmpaddr3",
+    "pmpaddr4",
+    "pmpaddr5",
+    "pmpaddr6",
+    "pmpaddr7",
+    "pmpaddr8",
+    "pmpaddr9",
+    "pmpaddr10",
+    "pmpaddr11",
+    "pmpaddr12",
+    "pmpaddr13",
+    "pmpaddr14",
+    "pmpaddr15",
+];
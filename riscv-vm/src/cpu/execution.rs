@@ -1,7 +1,7 @@
 use super::core::Cpu;
 use super::csr::{
-    CSR_MENVCFG, CSR_MEPC, CSR_MHARTID, CSR_MIP, CSR_MSTATUS, CSR_SATP, CSR_SEPC, CSR_STIMECMP,
-    CSR_TIME,
+    CSR_MENVCFG, CSR_MEPC, CSR_MHARTID, CSR_MIP, CSR_MSTATUS, CSR_PMPADDR0, CSR_PMPCFG0, CSR_SATP,
+    CSR_SEPC, CSR_STIMECMP, CSR_TIME,
 };
 use crate::Mode;
 use crate::Trap;
@@ -11,6 +11,7 @@ use crate::engine::block::{Block, BlockCompiler, CompileResult, MAX_BLOCK_SIZE};
 use crate::engine::decoder::{self, Op, Register};
 use crate::engine::microop::MicroOp;
 use crate::mmu::AccessType as MmuAccessType;
+use crate::semihosting::Semihosting;
 
 impl Cpu {
     pub fn step(&mut self, bus: &dyn Bus) -> Result<(), Trap> {
@@ -18,33 +19,7 @@ impl Cpu {
         self.poll_counter = self.poll_counter.wrapping_add(1);
 
         if self.poll_counter == 0 {
-            // Poll device-driven interrupts into MIP mask.
-            let hart_id = self.csrs[CSR_MHARTID as usize] as usize;
-            let mut hw_mip = bus.poll_interrupts_for_hart(hart_id);
-
-            // Sstc support: raise STIP (bit 5) when time >= stimecmp and Sstc enabled.
-            let menvcfg = self.csrs[CSR_MENVCFG as usize];
-            let sstc_enabled = ((menvcfg >> 63) & 1) == 1;
-            let stimecmp = self.csrs[CSR_STIMECMP as usize];
-            if sstc_enabled && stimecmp != 0 {
-                if let Ok(now) = bus.read64(CLINT_BASE + MTIME_OFFSET) {
-                    if now >= stimecmp {
-                        hw_mip |= 1 << 5; // STIP
-                    }
-                }
-            }
-
-            // Update MIP
-            let hw_bits: u64 = (1 << 3) | (1 << 7) | (1 << 9) | (1 << 11);
-            let hw_bits_with_stip: u64 = hw_bits | (1 << 5);
-            let mask = if sstc_enabled {
-                hw_bits_with_stip
-            } else {
-                hw_bits
-            };
-            let old_mip = self.csrs[CSR_MIP as usize];
-            self.csrs[CSR_MIP as usize] = (old_mip & !mask) | (hw_mip & mask);
-
+            self.poll_interrupts(bus);
             if let Some(trap) = self.check_pending_interrupt() {
                 return self.handle_trap(trap, self.pc, None);
             }
@@ -70,6 +45,8 @@ impl Cpu {
     fn try_execute_block(&mut self, bus: &dyn Bus) -> Option<Result<(), Trap>> {
         let mut current_pc = self.pc;
         const MAX_CHAIN_DEPTH: u32 = 16; // Limit chaining to avoid starvation
+        // In single-instruction mode each step() must retire exactly one instruction.
+        let max_chain = if self.single_insn_blocks { 1 } else { MAX_CHAIN_DEPTH };
         let mut chain_count = 0u32;
 
         loop {
@@ -78,7 +55,7 @@ impl Cpu {
             // Safety: The block is not removed from cache during execution (we hold &mut self),
             // and the block is not mutated during execution. This eliminates ~1KB copy per block.
             let block_ptr_and_meta: Option<(*const Block, Option<u64>)> = {
-                if let Some(block) = self.block_cache.get_and_touch(current_pc) {
+                if let Some(block) = self.block_cache.get_and_touch(current_pc, self.mode) {
                     Some((block as *const Block, block.next_block_pc))
                 } else {
                     None
@@ -101,7 +78,7 @@ impl Cpu {
                         // Block Chaining: if we have a known next block and haven't chained too many,
                         // try to jump directly to it
                         chain_count += 1;
-                        if chain_count < MAX_CHAIN_DEPTH {
+                        if chain_count < max_chain {
                             if let Some(chain_pc) = next_block_pc {
                                 if chain_pc == next_pc {
                                     // Target matches - try to chain
@@ -141,6 +118,8 @@ impl Cpu {
                     mstatus,
                     mode: self.mode,
                     tlb: &mut self.tlb,
+                    pmp: self.csrs.pmp(),
+                    max_ops: if self.single_insn_blocks { 1 } else { MAX_BLOCK_SIZE },
                 };
                 compiler.compile(current_pc, generation)
             };
@@ -155,6 +134,7 @@ impl Cpu {
                         ops: block.ops,
                         exec_count: 0,
                         generation: block.generation,
+                        mode: block.mode,
                         next_block_pc: block.next_block_pc,
                     };
                     let next_block_pc = block.next_block_pc;
@@ -168,7 +148,7 @@ impl Cpu {
                             
                             // Try chaining for newly compiled block too
                             chain_count += 1;
-                            if chain_count < MAX_CHAIN_DEPTH {
+                            if chain_count < max_chain {
                                 if let Some(chain_pc) = next_block_pc {
                                     if chain_pc == next_pc {
                                         current_pc = next_pc;
@@ -194,36 +174,43 @@ impl Cpu {
         }
     }
 
+    /// Refresh the device-driven bits of MIP (MSIP, MTIP, SEIP, MEIP, and
+    /// STIP when Sstc is enabled) from the bus.
+    pub fn poll_interrupts(&mut self, bus: &dyn Bus) {
+        let hart_id = self.csrs[CSR_MHARTID as usize] as usize;
+        let mut hw_mip = bus.poll_interrupts_for_hart(hart_id);
+
+        // Sstc support: raise STIP (bit 5) when time >= stimecmp and Sstc enabled.
+        let menvcfg = self.csrs[CSR_MENVCFG as usize];
+        let sstc_enabled = ((menvcfg >> 63) & 1) == 1;
+        let stimecmp = self.csrs[CSR_STIMECMP as usize];
+        if sstc_enabled
+            && stimecmp != 0
+            && let Ok(now) = bus.read64(CLINT_BASE + MTIME_OFFSET)
+            && now >= stimecmp
+        {
+            hw_mip |= 1 << 5; // STIP
+        }
+
+        // Update MIP
+        let hw_bits: u64 = (1 << 3) | (1 << 7) | (1 << 9) | (1 << 11);
+        let hw_bits_with_stip: u64 = hw_bits | (1 << 5);
+        let mask = if sstc_enabled {
+            hw_bits_with_stip
+        } else {
+            hw_bits
+        };
+        let old_mip = self.csrs[CSR_MIP as usize];
+        self.csrs[CSR_MIP as usize] = (old_mip & !mask) | (hw_mip & mask);
+    }
+
     /// Execute a single instruction (interpreter mode).
     /// This is the original step() implementation without the interrupt check.
     pub(super) fn step_single(&mut self, bus: &dyn Bus) -> Result<(), Trap> {
         // Check interrupts (needed when called from block exit)
         self.poll_counter = self.poll_counter.wrapping_add(1);
         if self.poll_counter == 0 {
-            let hart_id = self.csrs[CSR_MHARTID as usize] as usize;
-            let mut hw_mip = bus.poll_interrupts_for_hart(hart_id);
-
-            let menvcfg = self.csrs[CSR_MENVCFG as usize];
-            let sstc_enabled = ((menvcfg >> 63) & 1) == 1;
-            let stimecmp = self.csrs[CSR_STIMECMP as usize];
-            if sstc_enabled && stimecmp != 0 {
-                if let Ok(now) = bus.read64(CLINT_BASE + MTIME_OFFSET) {
-                    if now >= stimecmp {
-                        hw_mip |= 1 << 5;
-                    }
-                }
-            }
-
-            let hw_bits: u64 = (1 << 3) | (1 << 7) | (1 << 9) | (1 << 11);
-            let hw_bits_with_stip: u64 = hw_bits | (1 << 5);
-            let mask = if sstc_enabled {
-                hw_bits_with_stip
-            } else {
-                hw_bits
-            };
-            let old_mip = self.csrs[CSR_MIP as usize];
-            self.csrs[CSR_MIP as usize] = (old_mip & !mask) | (hw_mip & mask);
-
+            self.poll_interrupts(bus);
             if let Some(trap) = self.check_pending_interrupt() {
                 return self.handle_trap(trap, self.pc, None);
             }
@@ -922,8 +909,24 @@ impl Cpu {
                         } else {
                             match insn_raw {
                                 0x0010_0073 => {
-                                    // EBREAK
-                                    return self.handle_trap(Trap::Breakpoint, pc, Some(insn_raw));
+                                    // EBREAK - or a semihosting call when enabled
+                                    let semihosting = self
+                                        .semihosting
+                                        .clone()
+                                        .filter(|_| Semihosting::is_call(self, bus, pc));
+                                    match semihosting.map(|sh| sh.call(self, bus)) {
+                                        Some(Ok(ret)) => self.regs[10] = ret,
+                                        Some(Err(trap)) => {
+                                            return self.handle_trap(trap, pc, Some(insn_raw));
+                                        }
+                                        None => {
+                                            return self.handle_trap(
+                                                Trap::Breakpoint,
+                                                pc,
+                                                Some(insn_raw),
+                                            );
+                                        }
+                                    }
                                 }
                                 0x1050_0073 => {
                                     // WFI - Wait For Interrupt
@@ -933,7 +936,11 @@ impl Cpu {
                                 0x0000_0073 => {
                                     // ECALL - route based on current privilege mode
                                     // For S-mode, try SBI call first before trapping
-                                    if self.mode == Mode::Supervisor {
+                                    // (unless M-mode firmware provides the SBI)
+                                    if self.mode == Mode::Supervisor && self.builtin_sbi {
+                                        if let Some(code) = crate::sbi::shutdown_request(self) {
+                                            return Err(Trap::RequestedTrap(code));
+                                        }
                                         if crate::sbi::handle_sbi_call(self, bus) {
                                             // SBI handled the call, advance PC and continue
                                             next_pc = pc.wrapping_add(insn_len as u64);
@@ -973,6 +980,9 @@ impl Cpu {
                                     mstatus = (mstatus & !(1 << 3)) | (mpie << 3);
                                     mstatus |= 1 << 7; // MPIE = 1
                                     mstatus &= !(0b11 << 11); // MPP = U (00)
+                                    if self.mode != Mode::Machine {
+                                        mstatus &= !(1 << 17); // MPRV = 0
+                                    }
 
                                     self.csrs[CSR_MSTATUS as usize] = mstatus;
                                     next_pc = mepc;
@@ -1001,10 +1011,11 @@ impl Cpu {
                                         Mode::Supervisor
                                     };
 
-                                    // SIE <= SPIE, SPIE <= 1, SPP <= U (0)
+                                    // SIE <= SPIE, SPIE <= 1, SPP <= U (0), MPRV <= 0
                                     mstatus = (mstatus & !(1 << 1)) | (spie << 1);
                                     mstatus |= 1 << 5; // SPIE = 1
                                     mstatus &= !(1 << 8); // SPP = U
+                                    mstatus &= !(1 << 17);
 
                                     self.csrs[CSR_MSTATUS as usize] = mstatus;
                                     next_pc = sepc;
@@ -1028,7 +1039,13 @@ impl Cpu {
                         } else {
                             match self.read_csr(csr_addr) {
                                 Ok(v) => v,
-                                Err(e) => return self.handle_trap(e, pc, Some(insn_raw)),
+                                Err(_) => {
+                                    return self.handle_trap(
+                                        Trap::IllegalInstruction(insn_raw as u64),
+                                        pc,
+                                        Some(insn_raw),
+                                    );
+                                }
                             }
                         };
 
@@ -1076,14 +1093,22 @@ impl Cpu {
                         }
 
                         if let Some(new_val) = write_new {
-                            if let Err(e) = self.write_csr(csr_addr, new_val) {
-                                return self.handle_trap(e, pc, Some(insn_raw));
+                            if self.write_csr(csr_addr, new_val).is_err() {
+                                return self.handle_trap(
+                                    Trap::IllegalInstruction(insn_raw as u64),
+                                    pc,
+                                    Some(insn_raw),
+                                );
                             }
                             // Invalidate decode cache if SATP changed (address space switch)
                             if csr_addr == CSR_SATP {
                                 self.tlb.flush();
                                 self.invalidate_decode_cache();
                             }
+                            // Compiled blocks were fetch-checked against the old PMP
+                            if (CSR_PMPCFG0..=CSR_PMPADDR0 + 63).contains(&csr_addr) {
+                                self.invalidate_blocks();
+                            }
                         }
 
                         if rd != Register::X0 {
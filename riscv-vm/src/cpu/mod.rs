@@ -1,6 +1,7 @@
 pub mod core;
 pub mod csr;
 pub mod execution;
+pub mod pmp;
 pub mod types;
 
 pub use core::Cpu;
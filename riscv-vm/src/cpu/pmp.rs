@@ -0,0 +1,188 @@
+//! Physical memory protection.
+//!
+//! 16 entries with 4-byte granularity; `pmpcfg4..15` and `pmpaddr16..63`
+//! read as zero. As in QEMU, a hart with no active entry is not restricted.
+
+use super::csr::{CSR_PMPADDR0, CSR_PMPCFG0, CsrFile};
+use super::types::Mode;
+use crate::mmu::AccessType;
+
+/// Number of implemented PMP entries.
+pub const PMP_ENTRIES: usize = 16;
+
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 3 << 3;
+const PMP_L: u8 = 1 << 7;
+
+const A_TOR: u8 = 1 << 3;
+const A_NA4: u8 = 2 << 3;
+const A_NAPOT: u8 = 3 << 3;
+
+/// `pmpaddr` holds bits 55:2 of an address.
+const ADDR_MASK: u64 = (1 << 54) - 1;
+
+fn cfg(csrs: &CsrFile, entry: usize) -> u8 {
+    let reg = CSR_PMPCFG0 as usize + entry / 8 * 2;
+    (csrs[reg] >> (entry % 8 * 8)) as u8
+}
+
+/// Legal value of the `pmpcfg` register holding `old` after writing `val`:
+/// locked entries keep their configuration and W without R is dropped.
+pub(super) fn legalize_cfg(old: u64, val: u64) -> u64 {
+    (0..8).fold(0, |acc, i| {
+        let old = (old >> (i * 8)) as u8;
+        let mut new = (val >> (i * 8)) as u8 & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+        if old & PMP_L != 0 {
+            new = old;
+        } else if new & (PMP_R | PMP_W) == PMP_W {
+            new &= !PMP_W;
+        }
+        acc | (new as u64) << (i * 8)
+    })
+}
+
+/// Legal value of `pmpaddr<entry>` after writing `val`, or `None` if the
+/// write is ignored because the entry (or a TOR entry above it) is locked.
+pub(super) fn legalize_addr(csrs: &CsrFile, entry: usize, val: u64) -> Option<u64> {
+    let locked = cfg(csrs, entry) & PMP_L != 0;
+    let locked_tor =
+        entry + 1 < PMP_ENTRIES && cfg(csrs, entry + 1) & (PMP_L | PMP_A) == PMP_L | A_TOR;
+    (!locked && !locked_tor).then_some(val & ADDR_MASK)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+    start: u64,
+    /// Exclusive; `u64::MAX` for a NAPOT region covering everything.
+    end: u64,
+    cfg: u8,
+}
+
+/// Active PMP entries, decoded from the CSRs.
+#[derive(Debug, Clone, Default)]
+pub struct Pmp {
+    rules: Vec<Rule>,
+    locked: bool,
+}
+
+impl Pmp {
+    pub const fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            locked: false,
+        }
+    }
+
+    pub fn from_csrs(csrs: &CsrFile) -> Self {
+        let mut rules = Vec::new();
+        let mut prev = 0u64;
+        for entry in 0..PMP_ENTRIES {
+            let cfg = cfg(csrs, entry);
+            let addr = csrs[CSR_PMPADDR0 as usize + entry];
+            let range = match cfg & PMP_A {
+                A_TOR => Some((prev << 2, addr << 2)),
+                A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+                A_NAPOT => {
+                    let ones = addr.trailing_ones();
+                    match 1u64.checked_shl(ones + 3).filter(|&size| size <= 1 << 56) {
+                        Some(size) => {
+                            let start = (addr << 2) & !(size - 1);
+                            Some((start, start.saturating_add(size)))
+                        }
+                        None => Some((0, u64::MAX)),
+                    }
+                }
+                _ => None,
+            };
+            if let Some((start, end)) = range {
+                rules.push(Rule { start, end, cfg });
+            }
+            prev = addr;
+        }
+        let locked = rules.iter().any(|rule| rule.cfg & PMP_L != 0);
+        Self { rules, locked }
+    }
+
+    /// Whether an access at physical address `addr` in `mode` is allowed.
+    #[inline]
+    pub fn allows(&self, addr: u64, mode: Mode, access: AccessType) -> bool {
+        if self.rules.is_empty() || (mode == Mode::Machine && !self.locked) {
+            return true;
+        }
+        // The lowest-numbered matching entry decides
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.start <= addr && addr < rule.end)
+        else {
+            return mode == Mode::Machine;
+        };
+        if mode == Mode::Machine && rule.cfg & PMP_L == 0 {
+            return true;
+        }
+        let needed = match access {
+            AccessType::Instruction => PMP_X,
+            AccessType::Load => PMP_R,
+            AccessType::Store => PMP_W,
+        };
+        rule.cfg & needed != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csrs(entries: &[(u8, u64)]) -> CsrFile {
+        let mut csrs = CsrFile::new();
+        for (i, &(cfg, addr)) in entries.iter().enumerate() {
+            csrs[CSR_PMPCFG0 as usize + i / 8 * 2] |= (cfg as u64) << (i % 8 * 8);
+            csrs[CSR_PMPADDR0 as usize + i] = addr;
+        }
+        csrs
+    }
+
+    #[test]
+    fn opensbi_layout() {
+        // Firmware at 0x80000000..0x80080000 hidden from S-mode, everything
+        // else open, as OpenSBI sets it up
+        let csrs = csrs(&[
+            (A_NAPOT, (0x8000_0000 >> 2) | ((0x8_0000 >> 3) - 1)),
+            (A_NAPOT | PMP_X | PMP_W | PMP_R, ADDR_MASK),
+        ]);
+        let pmp = Pmp::from_csrs(&csrs);
+        let s = Mode::Supervisor;
+        assert!(!pmp.allows(0x8000_0000, s, AccessType::Load));
+        assert!(!pmp.allows(0x8007_fff8, s, AccessType::Instruction));
+        assert!(pmp.allows(0x8008_0000, s, AccessType::Store));
+        assert!(pmp.allows(0x1000_0000, Mode::User, AccessType::Load));
+        assert!(pmp.allows(0x8000_0000, Mode::Machine, AccessType::Store));
+
+        // No active entry: no restriction
+        assert!(Pmp::from_csrs(&CsrFile::new()).allows(0x8000_0000, s, AccessType::Store));
+    }
+
+    #[test]
+    fn tor_locking_and_warl() {
+        let csrs = csrs(&[
+            (0, 0x8000_0000 >> 2),
+            (A_TOR | PMP_L | PMP_R, 0x8000_1000 >> 2),
+        ]);
+        let pmp = Pmp::from_csrs(&csrs);
+        assert!(pmp.allows(0x8000_0ffc, Mode::Machine, AccessType::Load));
+        assert!(!pmp.allows(0x8000_0000, Mode::Machine, AccessType::Store));
+        assert!(pmp.allows(0x9000_0000, Mode::Machine, AccessType::Store));
+        assert!(!pmp.allows(0x9000_0000, Mode::Supervisor, AccessType::Load));
+
+        // Locked entry 1 freezes pmpaddr0 (its TOR base) and pmpaddr1
+        assert_eq!(legalize_addr(&csrs, 0, 0), None);
+        assert_eq!(legalize_addr(&csrs, 1, 0), None);
+        assert_eq!(legalize_addr(&csrs, 2, u64::MAX), Some(ADDR_MASK));
+        let old = csrs[CSR_PMPCFG0 as usize];
+        assert_eq!(legalize_cfg(old, 0) & 0xff00, old & 0xff00);
+        // W without R is reserved
+        assert_eq!(legalize_cfg(0, (A_NAPOT | PMP_W) as u64), A_NAPOT as u64);
+    }
+}
@@ -71,7 +71,15 @@ pub enum Trap {
 
 impl std::fmt::Display for Trap {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Trap::IllegalInstruction(bits) => write!(
+                f,
+                "IllegalInstruction(0x{:x}: {})",
+                bits,
+                crate::engine::disasm::disassemble(0, *bits as u32)
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
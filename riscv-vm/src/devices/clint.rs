@@ -1,5 +1,5 @@
 use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 
 /// Get current time in milliseconds (platform-specific).
 #[cfg(not(target_arch = "wasm32"))]
@@ -29,6 +29,9 @@ pub const HART_COUNT_OFFSET: u64 = 0x0F00;
 /// Set high enough to support modern multi-core systems.
 pub const MAX_HARTS: usize = 128;
 
+/// `frozen_mtime` value meaning mtime follows the wall clock.
+const NOT_FROZEN: u64 = u64::MAX;
+
 /// Time increment per tick (in timer ticks).
 /// This is called every 256 CPU steps (when CPU poll_counter wraps), so we
 /// increment by 256 to maintain the same effective timer rate.
@@ -107,6 +110,9 @@ pub struct Clint {
     /// mtime = (now_millis - start_time_ms) * 10_000 to get 10MHz tick rate.
     start_time_ms: AtomicU64,
 
+    /// mtime value held while the VM is paused; `NOT_FROZEN` otherwise.
+    frozen_mtime: AtomicU64,
+
     /// Per-hart Machine Software Interrupt Pending bits.
     /// Only bit 0 is meaningful for each entry.
     msip: [AtomicU32; MAX_HARTS],
@@ -118,8 +124,25 @@ pub struct Clint {
     /// Number of harts in the system (set at initialization).
     num_harts: AtomicUsize,
 
-    /// Per-hart wakeup condvars for WFI.
-    wakeups: [HartWakeup; MAX_HARTS],
+    /// Per-hart wakeup condvars for WFI, shared with [`HartWaker`]s.
+    wakeups: Arc<[HartWakeup; MAX_HARTS]>,
+}
+
+/// Wakes the harts of a [`Clint`] from WFI, for devices that finish work
+/// on their own threads.
+#[derive(Clone)]
+pub struct HartWaker {
+    wakeups: Arc<[HartWakeup; MAX_HARTS]>,
+    num_harts: usize,
+}
+
+impl HartWaker {
+    /// Wake every hart so whichever takes the interrupt sees it promptly.
+    pub fn wake_all(&self) {
+        for wakeup in &self.wakeups[..self.num_harts] {
+            wakeup.wake();
+        }
+    }
 }
 
 impl Clint {
@@ -139,10 +162,11 @@ impl Clint {
 
         Self {
             start_time_ms: AtomicU64::new(now_millis()),
+            frozen_mtime: AtomicU64::new(NOT_FROZEN),
             msip: [ZERO_U32; MAX_HARTS],
             mtimecmp: [MAX_U64; MAX_HARTS],
             num_harts: AtomicUsize::new(num_harts.min(MAX_HARTS)),
-            wakeups: [WAKEUP; MAX_HARTS],
+            wakeups: Arc::new([WAKEUP; MAX_HARTS]),
         }
     }
 
@@ -163,6 +187,10 @@ impl Clint {
     /// Lock-free for performance.
     #[inline]
     pub fn mtime(&self) -> u64 {
+        let frozen = self.frozen_mtime.load(Ordering::Acquire);
+        if frozen != NOT_FROZEN {
+            return frozen;
+        }
         // Get elapsed milliseconds since start
         let start = self.start_time_ms.load(Ordering::Relaxed);
         let elapsed_ms = now_millis().saturating_sub(start);
@@ -173,6 +201,10 @@ impl Clint {
     /// Sets mtime to a specific value (used for snapshot restore).
     /// Adjusts start_time_ms so that mtime() returns the specified value.
     pub fn set_mtime(&self, val: u64) {
+        if self.is_frozen() {
+            self.frozen_mtime.store(val, Ordering::Release);
+            return;
+        }
         // val = elapsed_ms * 10_000, so elapsed_ms = val / 10_000
         let target_elapsed_ms = val / 10_000;
         // start_time_ms = now_millis - target_elapsed_ms
@@ -180,6 +212,25 @@ impl Clint {
         self.start_time_ms.store(new_start, Ordering::Relaxed);
     }
 
+    /// Stop mtime at its current value until [`Self::thaw`].
+    pub fn freeze(&self) {
+        if !self.is_frozen() {
+            self.frozen_mtime.store(self.mtime(), Ordering::Release);
+        }
+    }
+
+    /// Let mtime run again from the value it was frozen at.
+    pub fn thaw(&self) {
+        let frozen = self.frozen_mtime.swap(NOT_FROZEN, Ordering::AcqRel);
+        if frozen != NOT_FROZEN {
+            self.set_mtime(frozen);
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen_mtime.load(Ordering::Acquire) != NOT_FROZEN
+    }
+
     /// Advance mtime by one tick. 
     /// Now a no-op since mtime is wall-clock based.
     #[inline]
@@ -233,6 +284,14 @@ impl Clint {
         self.wakeups[hart].wait_for_interrupt(timeout_ms);
     }
 
+    /// A handle that wakes the harts from other threads.
+    pub fn waker(&self) -> HartWaker {
+        HartWaker {
+            wakeups: Arc::clone(&self.wakeups),
+            num_harts: self.num_harts.load(Ordering::Acquire),
+        }
+    }
+
     /// Wake a hart that may be sleeping in WFI.
     /// Called when an interrupt becomes pending.
     pub fn wake_hart(&self, hart: usize) {
@@ -595,6 +654,26 @@ mod tests {
         assert_eq!(clint.get_mtimecmp(0), 0x2222_2222_1111_1111);
     }
 
+    #[test]
+    fn test_freeze_mtime() {
+        let clint = Clint::new();
+        clint.set_mtime(50_000);
+        clint.freeze();
+        let frozen = clint.mtime();
+        assert!(clint.is_frozen());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(clint.mtime(), frozen);
+
+        // Restoring a snapshot while frozen moves the frozen value.
+        clint.set_mtime(90_000);
+        assert_eq!(clint.mtime(), 90_000);
+
+        clint.thaw();
+        assert!(!clint.is_frozen());
+        let thawed = clint.mtime();
+        assert!((90_000..90_000 + 10 * 10_000).contains(&thawed));
+    }
+
     #[test]
     fn test_num_harts_atomic() {
         let clint = Clint::with_harts(4);
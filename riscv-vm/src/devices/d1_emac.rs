@@ -34,6 +34,8 @@ const PHY_PHYSID1: u32 = 0x02;
 const PHY_PHYSID2: u32 = 0x03;
 const PHY_ADVERTISE: u32 = 0x04;
 const PHY_LPA: u32 = 0x05;
+/// BMSR link status bit
+const BMSR_LINK_STATUS: u32 = 1 << 2;
 
 /// Emulated D1 EMAC controller
 pub struct D1EmacEmulated {
@@ -140,6 +142,24 @@ impl D1EmacEmulated {
         self.assigned_ip
     }
 
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac_addr
+    }
+
+    /// Set the PHY link status reported in BMSR (bit 2).
+    pub fn set_link(&mut self, up: bool) {
+        if up {
+            self.phy_bmsr |= BMSR_LINK_STATUS;
+        } else {
+            self.phy_bmsr &= !BMSR_LINK_STATUS;
+        }
+    }
+
+    /// Whether the PHY currently reports link up.
+    pub fn link_up(&self) -> bool {
+        self.phy_bmsr & BMSR_LINK_STATUS != 0
+    }
+
     fn handle_mii_cmd(&mut self) {
         let phy_addr = (self.mii_cmd >> 12) & 0x1F;
         let reg_addr = (self.mii_cmd >> 4) & 0x1F;
@@ -4,7 +4,8 @@
 //! Allows the kernel to use the same D1 MMC driver on both real hardware
 //! and the emulator.
 
-use std::sync::{Arc, RwLock};
+use crate::blockdev::{BlockBackend, MemoryBackend, SECTOR_SIZE};
+use std::sync::Arc;
 
 /// MMC0 base address (matching D1)
 pub const D1_MMC0_BASE: u64 = 0x0402_0000;
@@ -41,7 +42,7 @@ const INT_DATA_OVER: u32 = 1 << 3;
 /// Emulated D1 MMC controller
 pub struct D1MmcEmulated {
     /// Backing storage (disk image)
-    disk: Arc<RwLock<Vec<u8>>>,
+    disk: Arc<dyn BlockBackend>,
     
     // Registers
     ctrl: u32,
@@ -64,26 +65,12 @@ pub struct D1MmcEmulated {
 }
 
 impl D1MmcEmulated {
+    /// Serve an in-memory copy of `disk_image`.
     pub fn new(disk_image: Vec<u8>) -> Self {
-        Self {
-            disk: Arc::new(RwLock::new(disk_image)),
-            ctrl: 0,
-            clkdiv: 0,
-            blksiz: 512,
-            bytcnt: 0,
-            cmd: 0,
-            cmdarg: 0,
-            resp: [0; 4],
-            rintsts: 0,
-            status: 0x0004, // FIFO empty
-            fifo: Vec::with_capacity(128),
-            fifo_pos: 0,
-            current_sector: 0,
-            transfer_active: false,
-        }
+        Self::with_backend(Arc::new(MemoryBackend::new(disk_image)))
     }
 
-    pub fn with_disk(disk: Arc<RwLock<Vec<u8>>>) -> Self {
+    pub fn with_backend(disk: Arc<dyn BlockBackend>) -> Self {
         Self {
             disk,
             ctrl: 0,
@@ -148,9 +135,7 @@ impl D1MmcEmulated {
             }
             9 => {
                 // CMD9: SEND_CSD (Card Specific Data)
-                let disk = self.disk.read().unwrap();
-                let disk_len = disk.len();
-                let sectors = disk_len / 512;
+                let sectors = self.disk.size() / SECTOR_SIZE;
                 let c_size = (sectors / 1024).saturating_sub(1);
                 self.resp[0] = ((c_size as u32) << 16) | 0x400E00; // CSD v2
                 self.resp[1] = (c_size as u32) >> 6;
@@ -166,17 +151,10 @@ impl D1MmcEmulated {
                 self.fifo_pos = 0;
                 
                 // Read sector into FIFO
-                let disk = self.disk.read().unwrap();
-                let offset = (sector * 512) as usize;
-                if offset + 512 <= disk.len() {
-                    for i in (0..512).step_by(4) {
-                        let word = u32::from_le_bytes([
-                            disk[offset + i],
-                            disk[offset + i + 1],
-                            disk[offset + i + 2],
-                            disk[offset + i + 3],
-                        ]);
-                        self.fifo.push(word);
+                let mut block = [0u8; 512];
+                if self.disk.read_at(sector * SECTOR_SIZE, &mut block).is_ok() {
+                    for word in block.chunks_exact(4) {
+                        self.fifo.push(u32::from_le_bytes(word.try_into().unwrap()));
                     }
                 }
                 
@@ -229,16 +207,9 @@ impl D1MmcEmulated {
         // Check if we've received a full sector
         if self.fifo.len() >= 128 { // 512 bytes / 4 = 128 words
             // Write to disk
-            let mut disk = self.disk.write().unwrap();
-            let offset = (self.current_sector * 512) as usize;
-            if offset + 512 <= disk.len() {
-                for (i, &word) in self.fifo.iter().take(128).enumerate() {
-                    let bytes = word.to_le_bytes();
-                    disk[offset + i * 4] = bytes[0];
-                    disk[offset + i * 4 + 1] = bytes[1];
-                    disk[offset + i * 4 + 2] = bytes[2];
-                    disk[offset + i * 4 + 3] = bytes[3];
-                }
+            let block: Vec<u8> = self.fifo.iter().take(128).flat_map(|w| w.to_le_bytes()).collect();
+            if let Err(e) = self.disk.write_at(self.current_sector * SECTOR_SIZE, &block) {
+                log::warn!("[MMC] Write to sector {} failed: {}", self.current_sector, e);
             }
             
             self.rintsts |= INT_DATA_OVER;
@@ -0,0 +1,349 @@
+//! Berkeley Host-Target Interface (HTIF).
+//!
+//! Spike-style bare-metal binaries (riscv-tests, riscv-pk benchmarks) talk to
+//! the host through two 64-bit words in guest memory, `tohost` and
+//! `fromhost`, located via ELF symbols. A value written to `tohost` encodes a
+//! command:
+//!
+//! ```text
+//!  63      56 55      48 47                                   0
+//! +----------+----------+--------------------------------------+
+//! |  device  | command  |               payload                |
+//! +----------+----------+--------------------------------------+
+//! ```
+//!
+//! * device 0 (syscall proxy), command 0: if bit 0 of the payload is set the
+//!   program exits with code `payload >> 1`; otherwise the payload points at
+//!   a "magic memory" block `[n, a0, a1, ...]` describing a syscall, whose
+//!   return value is written back to word 0.
+//! * device 1 (console), command 1: write the low byte of the payload.
+//!
+//! The host acknowledges a command by clearing `tohost` and writing a
+//! response to `fromhost`.
+//!
+//! Proxied `openat` calls are resolved inside a sandbox directory with the
+//! same rules as semihosting (absolute paths and `..` are rejected). Without
+//! a sandbox root every `openat` fails with `EACCES`.
+
+use crate::devices::uart::Uart;
+use crate::dram::Dram;
+use crate::semihosting::sandbox_path;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CONSOLE_CMD_PUTCHAR: u64 = 1;
+
+// Linux riscv64 syscall numbers used by the fesvr syscall proxy.
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+const EBADF: i64 = 9;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+/// Linux open(2) flags understood by the proxy.
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+/// Largest single read/write the proxy will service (guards against bogus lengths).
+const MAX_IO_LEN: u64 = 16 * 1024 * 1024;
+
+/// HTIF device state.
+pub struct Htif {
+    /// Physical address of the `tohost` word.
+    pub tohost: u64,
+    /// Physical address of the `fromhost` word, if the binary has one.
+    pub fromhost: Option<u64>,
+    /// Host directory that `openat` paths are resolved against.
+    root: Option<PathBuf>,
+    /// Host files opened by the guest, keyed by guest fd (0-2 are the console).
+    files: Mutex<HashMap<u64, File>>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            root: None,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allow the guest to open files below `root`.
+    pub fn set_root(&mut self, root: PathBuf) {
+        self.root = Some(root);
+    }
+
+    /// Process a command just written to `tohost`.
+    ///
+    /// Console output goes to the UART output queue so it shows up wherever
+    /// the serial console is displayed. Returns `Some(code)` when the guest
+    /// requested exit.
+    pub fn handle(&self, dram: &Dram, uart: &Uart, value: u64) -> Option<u64> {
+        let device = value >> 56;
+        let command = (value >> 48) & 0xFF;
+        let payload = value & 0xFFFF_FFFF_FFFF;
+
+        let response = match (device, command) {
+            (DEV_SYSCALL, 0) if payload & 1 == 1 => return Some(payload >> 1),
+            (DEV_SYSCALL, 0) => {
+                if let Some(code) = self.syscall(dram, uart, payload) {
+                    return Some(code);
+                }
+                1
+            }
+            (DEV_CONSOLE, CONSOLE_CMD_PUTCHAR) => {
+                uart.push_output(payload as u8);
+                0x100 | (payload & 0xFF)
+            }
+            _ => {
+                log::debug!("[HTIF] Unsupported command 0x{:016x}", value);
+                0
+            }
+        };
+
+        self.store(dram, self.tohost, 0);
+        if let Some(fromhost) = self.fromhost {
+            self.store(dram, fromhost, (device << 56) | (command << 48) | response);
+        }
+        None
+    }
+
+    /// Execute a proxied syscall described by the magic memory block at `magic`.
+    fn syscall(&self, dram: &Dram, uart: &Uart, magic: u64) -> Option<u64> {
+        let arg = |i: u64| self.load(dram, magic + 8 * i);
+        let n = arg(0);
+
+        let ret: i64 = match n {
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(arg(1)),
+            SYS_WRITE => self.sys_write(dram, uart, arg(1), arg(2), arg(3)),
+            SYS_READ => self.sys_read(dram, arg(1), arg(2), arg(3)),
+            SYS_OPENAT => self.sys_openat(dram, arg(2), arg(3), arg(4)),
+            SYS_CLOSE => match self.files.lock().unwrap().remove(&arg(1)) {
+                Some(_) => 0,
+                None if arg(1) <= 2 => 0,
+                None => -EBADF,
+            },
+            SYS_LSEEK => self.sys_lseek(arg(1), arg(2) as i64, arg(3)),
+            _ => {
+                log::debug!("[HTIF] Unsupported syscall {}", n);
+                -ENOSYS
+            }
+        };
+
+        self.store(dram, magic, ret as u64);
+        None
+    }
+
+    fn sys_write(&self, dram: &Dram, uart: &Uart, fd: u64, buf: u64, len: u64) -> i64 {
+        let Some(data) = self.read_guest(dram, buf, len) else {
+            return -EFAULT;
+        };
+        if fd == 1 || fd == 2 {
+            for &b in &data {
+                uart.push_output(b);
+            }
+            return data.len() as i64;
+        }
+        match self.files.lock().unwrap().get_mut(&fd) {
+            Some(file) => file.write(&data).map_or(-EINVAL, |n| n as i64),
+            None => -EBADF,
+        }
+    }
+
+    fn sys_read(&self, dram: &Dram, fd: u64, buf: u64, len: u64) -> i64 {
+        if fd == 0 {
+            // Console input is not proxied; report end-of-file.
+            return 0;
+        }
+        if len > MAX_IO_LEN {
+            return -EINVAL;
+        }
+        let mut data = vec![0u8; len as usize];
+        let n = match self.files.lock().unwrap().get_mut(&fd) {
+            Some(file) => match file.read(&mut data) {
+                Ok(n) => n,
+                Err(_) => return -EINVAL,
+            },
+            None => return -EBADF,
+        };
+        match dram.offset(buf) {
+            Some(off) if dram.write_bytes(off as u64, &data[..n]).is_ok() => n as i64,
+            _ => -EFAULT,
+        }
+    }
+
+    fn sys_openat(&self, dram: &Dram, path: u64, len: u64, flags: u64) -> i64 {
+        let Some(raw) = self.read_guest(dram, path, len) else {
+            return -EFAULT;
+        };
+        let name = raw.split(|&b| b == 0).next().unwrap_or(&[]);
+        let Ok(name) = std::str::from_utf8(name) else {
+            return -EINVAL;
+        };
+        let Some(path) = self
+            .root
+            .as_deref()
+            .and_then(|root| sandbox_path(root, name))
+        else {
+            return -EACCES;
+        };
+
+        let mut options = OpenOptions::new();
+        match flags & 0o3 {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+
+        match options.open(path) {
+            Ok(file) => {
+                let mut files = self.files.lock().unwrap();
+                let fd = (3..).find(|fd| !files.contains_key(fd)).unwrap();
+                files.insert(fd, file);
+                fd as i64
+            }
+            Err(e) => -(e.raw_os_error().unwrap_or(EINVAL as i32) as i64),
+        }
+    }
+
+    fn sys_lseek(&self, fd: u64, offset: i64, whence: u64) -> i64 {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+        match self.files.lock().unwrap().get_mut(&fd) {
+            Some(file) => file.seek(pos).map_or(-EINVAL, |p| p as i64),
+            None => -EBADF,
+        }
+    }
+
+    fn read_guest(&self, dram: &Dram, addr: u64, len: u64) -> Option<Vec<u8>> {
+        if len > MAX_IO_LEN {
+            return None;
+        }
+        dram.read_range(dram.offset(addr)?, len as usize).ok()
+    }
+
+    fn load(&self, dram: &Dram, addr: u64) -> u64 {
+        dram.offset(addr)
+            .and_then(|off| dram.load_64(off as u64).ok())
+            .unwrap_or(0)
+    }
+
+    fn store(&self, dram: &Dram, addr: u64, value: u64) {
+        if let Some(off) = dram.offset(addr) {
+            dram.store_64(off as u64, value).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+
+    const TOHOST: u64 = DRAM_BASE + 0x1000;
+    const FROMHOST: u64 = DRAM_BASE + 0x1008;
+    const MAGIC: u64 = DRAM_BASE + 0x2000;
+
+    #[test]
+    fn test_htif_exit() {
+        let dram = Dram::new(DRAM_BASE, 0x10000);
+        let uart = Uart::new();
+        let htif = Htif::new(TOHOST, Some(FROMHOST));
+        assert_eq!(htif.handle(&dram, &uart, 1), Some(0));
+        assert_eq!(htif.handle(&dram, &uart, (3 << 1) | 1), Some(3));
+    }
+
+    #[test]
+    fn test_htif_console_and_syscall_write() {
+        let dram = Dram::new(DRAM_BASE, 0x10000);
+        let uart = Uart::new();
+        let htif = Htif::new(TOHOST, Some(FROMHOST));
+
+        assert_eq!(
+            htif.handle(&dram, &uart, (1 << 56) | (1 << 48) | b'A' as u64),
+            None
+        );
+        assert_eq!(htif.load(&dram, FROMHOST), (1 << 56) | (1 << 48) | 0x141);
+
+        let msg = b"hi\n";
+        dram.write_bytes(0x3000, msg).unwrap();
+        for (i, v) in [SYS_WRITE, 1, DRAM_BASE + 0x3000, msg.len() as u64]
+            .iter()
+            .enumerate()
+        {
+            dram.store_64(0x2000 + 8 * i as u64, *v).unwrap();
+        }
+        assert_eq!(htif.handle(&dram, &uart, MAGIC), None);
+        assert_eq!(htif.load(&dram, MAGIC), 3);
+        assert_eq!(htif.load(&dram, TOHOST), 0);
+        assert_eq!(htif.load(&dram, FROMHOST), 1);
+        assert_eq!(uart.drain_output(), b"Ahi\n");
+    }
+
+    fn openat(htif: &Htif, dram: &Dram, name: &[u8], flags: u64) -> i64 {
+        dram.write_bytes(0x3000, name).unwrap();
+        for (i, v) in [SYS_OPENAT, 0, DRAM_BASE + 0x3000, name.len() as u64, flags]
+            .iter()
+            .enumerate()
+        {
+            dram.store_64(0x2000 + 8 * i as u64, *v).unwrap();
+        }
+        assert_eq!(htif.handle(dram, &Uart::new(), MAGIC), None);
+        htif.load(dram, MAGIC) as i64
+    }
+
+    #[test]
+    fn test_htif_openat_sandbox() {
+        let dram = Dram::new(DRAM_BASE, 0x10000);
+        let dir = std::env::temp_dir().join(format!("htif-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("input.txt"), b"data").unwrap();
+
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST));
+        assert_eq!(openat(&htif, &dram, b"input.txt\0", 0), -EACCES);
+
+        htif.set_root(dir.clone());
+        assert_eq!(openat(&htif, &dram, b"input.txt\0", 0), 3);
+        assert_eq!(openat(&htif, &dram, b"/etc/passwd\0", 0), -EACCES);
+        assert_eq!(openat(&htif, &dram, b"../input.txt\0", 0), -EACCES);
+        assert_eq!(
+            openat(
+                &htif,
+                &dram,
+                b"../escape.txt\0",
+                O_WRONLY | O_CREAT | O_TRUNC
+            ),
+            -EACCES
+        );
+        assert!(!dir.parent().unwrap().join("escape.txt").exists());
+        assert_eq!(openat(&htif, &dram, b"out.txt\0", O_WRONLY | O_CREAT), 4);
+        assert!(dir.join("out.txt").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 pub mod clint;
+pub mod htif;
 pub mod plic;
 pub mod sysinfo;
 pub mod uart;
@@ -9,6 +9,11 @@ pub const PLIC_SIZE: u64 = 0x400_0000;
 pub const UART_IRQ: u32 = 10;
 pub const VIRTIO0_IRQ: u32 = 1;
 
+/// PLIC source of UART `index`: 10 for the console, then 11, 12, ...
+pub const fn uart_irq(index: usize) -> u32 {
+    UART_IRQ + index as u32
+}
+
 const NUM_SOURCES: usize = 32;
 /// Number of interrupt contexts.
 /// Each hart has 2 contexts: M-mode (2*N) and S-mode (2*N+1).
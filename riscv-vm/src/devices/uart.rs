@@ -4,6 +4,13 @@ use std::sync::Mutex;
 
 pub const UART_BASE: u64 = 0x1000_0000;
 pub const UART_SIZE: u64 = 0x100;
+/// UARTs are packed below the first virtio slot at 0x1000_1000.
+pub const MAX_UARTS: usize = 8;
+
+/// MMIO base of UART `index` (0 is the console).
+pub const fn uart_base(index: usize) -> u64 {
+    UART_BASE + index as u64 * UART_SIZE
+}
 
 // Registers (offset)
 const RBR: u64 = 0x00; // Receiver Buffer (Read)
@@ -1,9 +1,40 @@
+use crate::blockdev::{BlockBackend, MemoryBackend, SECTOR_SIZE};
 use crate::bus::DRAM_BASE;
 use crate::dram::{Dram, MemoryError};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering, fence};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::JoinHandle;
 
 use super::device::{self, VirtioDevice};
 
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+/// Length of the GET_ID serial number.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+/// Size of a discard/write-zeroes segment: sector, sector count and flags.
+const SEGMENT_LEN: u32 = 16;
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// Data descriptors per request (the header and status take two).
+const SEG_MAX: u32 = device::QUEUE_SIZE - 2;
+/// Sectors one discard/write-zeroes segment may cover (2 GiB).
+const MAX_ZERO_SECTORS: u32 = 1 << 22;
+/// Discard granularity in sectors (4 KiB).
+const DISCARD_ALIGNMENT: u32 = 8;
+/// Largest run of adjacent reads or writes merged into one backend call.
+const MAX_MERGE_BYTES: u64 = 1 << 20;
+
+// Request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
 /// Internal mutable state for VirtioBlock, protected by Mutex
 struct VirtioBlockState {
     driver_features: u32,
@@ -18,17 +49,95 @@ struct VirtioBlockState {
     queue_ready: bool,
     interrupt_status: u32,
     status: u32,
-    disk: Vec<u8>,
     last_avail_idx: u16,
+    /// Bumped on reset, so requests still in flight are not completed
+    /// into the new queues.
+    generation: u64,
     debug: bool,
 }
 
+/// One descriptor of a request chain.
+#[derive(Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u64,
+}
+
+/// A request taken off the avail ring. The buffers the device reads are
+/// copied out of guest memory, so it can be served without touching DRAM.
+struct Request {
+    head: u16,
+    generation: u64,
+    blk_type: u32,
+    offset: u64,
+    /// Guest buffers the reply data goes to.
+    targets: Vec<Desc>,
+    /// Write data, discard/write-zeroes segments, or the GET_ID reply.
+    payload: Vec<u8>,
+    /// `None` for malformed chains, which are returned without a reply.
+    status: Option<Desc>,
+}
+
+impl Request {
+    /// Bytes the request moves to or from the disk.
+    fn len(&self) -> u64 {
+        match self.blk_type {
+            VIRTIO_BLK_T_IN => self.targets.iter().map(|d| d.len as u64).sum(),
+            _ => self.payload.len() as u64,
+        }
+    }
+}
+
+/// A served request, waiting to be written back to the guest.
+struct Completion {
+    request: Request,
+    code: u8,
+    /// Reply data, scattered over `request.targets`.
+    data: Vec<u8>,
+}
+
+/// Completions handed back by the I/O thread.
+#[derive(Default)]
+struct Finished {
+    list: Mutex<Vec<Completion>>,
+    pending: AtomicBool,
+}
+
+/// The I/O thread of an asynchronous device. Dropping it lets the thread
+/// finish what is queued and joins it.
+struct IoThread {
+    requests: Option<mpsc::Sender<Vec<Request>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for IoThread {
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
 pub struct VirtioBlock {
     state: Mutex<VirtioBlockState>,
+    disk: Arc<dyn BlockBackend>,
+    /// Returned by GET_ID, truncated to 20 bytes.
+    serial: Vec<u8>,
+    /// Set by [`VirtioBlock::with_io_thread`]; requests are served inside
+    /// the queue notify otherwise.
+    io: Option<IoThread>,
+    finished: Arc<Finished>,
 }
 
 impl VirtioBlock {
+    /// Serve an in-memory copy of `disk_image`.
     pub fn new(disk_image: Vec<u8>) -> Self {
+        Self::with_backend(Arc::new(MemoryBackend::new(disk_image)))
+    }
+
+    pub fn with_backend(disk: Arc<dyn BlockBackend>) -> Self {
         Self {
             state: Mutex::new(VirtioBlockState {
                 driver_features: 0,
@@ -43,11 +152,84 @@ impl VirtioBlock {
                 queue_ready: false,
                 interrupt_status: 0,
                 status: 0,
-                disk: disk_image,
                 last_avail_idx: 0,
+                generation: 0,
                 debug: false,
             }),
+            disk,
+            serial: Vec::new(),
+            io: None,
+            finished: Arc::default(),
+        }
+    }
+
+    /// Report `serial` to the guest (`/sys/block/vdX/serial`); longer
+    /// strings are cut to 20 bytes.
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        self.serial = serial.bytes().take(VIRTIO_BLK_ID_BYTES).collect();
+        self
+    }
+
+    /// Serve requests on a host thread instead of inside the guest's queue
+    /// notify, so a slow disk does not stall the hart. The thread calls
+    /// `notify` after each batch; the results reach the guest, with the
+    /// interrupt, from the next [`VirtioDevice::complete`].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_io_thread(mut self, notify: impl Fn() + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<Request>>();
+        let disk = Arc::clone(&self.disk);
+        let finished = Arc::clone(&self.finished);
+        let handle = std::thread::Builder::new()
+            .name("virtio-blk-io".to_string())
+            .spawn(move || {
+                while let Ok(mut batch) = rx.recv() {
+                    // Notifies that came in meanwhile join the batch.
+                    while let Ok(more) = rx.try_recv() {
+                        batch.extend(more);
+                    }
+                    let done = execute(&*disk, batch);
+                    finished.list.lock().unwrap().extend(done);
+                    finished.pending.store(true, Ordering::Release);
+                    notify();
+                }
+            })
+            .expect("Failed to spawn block I/O thread");
+        self.io = Some(IoThread {
+            requests: Some(tx),
+            handle: Some(handle),
+        });
+        self
+    }
+
+    fn device_features(&self) -> u64 {
+        let mut features = (1 << device::VIRTIO_BLK_F_SEG_MAX)
+            | (1 << device::VIRTIO_BLK_F_BLK_SIZE)
+            | (1 << device::VIRTIO_BLK_F_FLUSH);
+        if self.disk.is_read_only() {
+            features |= 1 << device::VIRTIO_BLK_F_RO;
+        } else {
+            features |=
+                (1 << device::VIRTIO_BLK_F_DISCARD) | (1 << device::VIRTIO_BLK_F_WRITE_ZEROES);
         }
+        features
+    }
+
+    /// `struct virtio_blk_config`, up to the write-zeroes fields.
+    fn config_space(&self) -> [u8; 60] {
+        let mut config = [0; 60];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            config[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &(self.disk.size() / SECTOR_SIZE).to_le_bytes());
+        put(12, &SEG_MAX.to_le_bytes());
+        put(20, &(SECTOR_SIZE as u32).to_le_bytes());
+        put(36, &MAX_ZERO_SECTORS.to_le_bytes());
+        put(40, &SEG_MAX.to_le_bytes());
+        put(44, &DISCARD_ALIGNMENT.to_le_bytes());
+        put(48, &MAX_ZERO_SECTORS.to_le_bytes());
+        put(52, &SEG_MAX.to_le_bytes());
+        put(56, &[1]); // write_zeroes_may_unmap
+        config
     }
 
     fn phys_to_offset(addr: u64) -> Result<u64, MemoryError> {
@@ -57,102 +239,189 @@ impl VirtioBlock {
         Ok(addr - DRAM_BASE)
     }
 
-    fn process_queue(state: &mut VirtioBlockState, dram: &Dram) -> Result<(), MemoryError> {
+    fn queue_size(state: &VirtioBlockState) -> u32 {
+        if state.queue_num > 0 {
+            state.queue_num
+        } else {
+            device::QUEUE_SIZE
+        }
+    }
+
+    fn load_desc(
+        state: &VirtioBlockState,
+        dram: &Dram,
+        idx: u16,
+    ) -> Result<(Desc, u16), MemoryError> {
+        let off = Self::phys_to_offset(state.queue_desc.wrapping_add((idx as u64) * 16))?;
+        let desc = Desc {
+            addr: dram.load_64(off)?,
+            len: dram.load_32(off + 8)?,
+            flags: dram.load_16(off + 12)? as u64,
+        };
+        Ok((desc, dram.load_16(off + 14)?))
+    }
+
+    /// Read the request whose chain starts at `head`.
+    fn parse(
+        &self,
+        state: &VirtioBlockState,
+        dram: &Dram,
+        head: u16,
+    ) -> Result<Request, MemoryError> {
+        let qsz = Self::queue_size(state);
+        // Header, data buffers, then the status byte.
+        let mut chain = Vec::new();
+        let mut desc_idx = head;
+        loop {
+            let (desc, next) = Self::load_desc(state, dram, desc_idx)?;
+            chain.push(desc);
+            if (desc.flags & device::VRING_DESC_F_NEXT) == 0 || chain.len() > qsz as usize {
+                break;
+            }
+            desc_idx = next;
+        }
+
+        let mut request = Request {
+            head,
+            generation: state.generation,
+            blk_type: 0,
+            offset: 0,
+            targets: Vec::new(),
+            payload: Vec::new(),
+            status: None,
+        };
+        // Malformed chains are consumed without a reply to avoid looping.
+        let Some((header, rest)) = chain.split_first() else {
+            return Ok(request);
+        };
+        let Some((status, data)) = rest.split_last() else {
+            return Ok(request);
+        };
+        if header.len < 16 {
+            return Ok(request);
+        }
+        let off_header_addr = Self::phys_to_offset(header.addr)?;
+        request.blk_type = dram.load_32(off_header_addr)?;
+        request.offset = dram
+            .load_64(off_header_addr + 8)?
+            .saturating_mul(SECTOR_SIZE);
+        request.status = Some(*status);
+        match request.blk_type {
+            VIRTIO_BLK_T_IN => request.targets = data.to_vec(),
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                for desc in data {
+                    // Bulk read from DRAM for performance
+                    let dram_off = Self::phys_to_offset(desc.addr)?;
+                    request
+                        .payload
+                        .extend(dram.read_range(dram_off as usize, desc.len as usize)?);
+                }
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                // NUL-padded; no terminator when all 20 bytes are used.
+                request.payload = self.serial.clone();
+                request.payload.resize(VIRTIO_BLK_ID_BYTES, 0);
+                request.targets = data.iter().take(1).copied().collect();
+            }
+            _ => {}
+        }
+        Ok(request)
+    }
+
+    /// Take the new requests off the avail ring and serve them, or hand
+    /// them to the I/O thread.
+    fn process_queue(&self, state: &mut VirtioBlockState, dram: &Dram) -> Result<(), MemoryError> {
+        let mut requests = Vec::new();
+        let taken = self.take_requests(state, dram, &mut requests);
+        // Requests already taken must be answered even if a later one
+        // could not be read.
+        if !requests.is_empty() {
+            match self.io.as_ref().and_then(|io| io.requests.as_ref()) {
+                Some(tx) => {
+                    let _ = tx.send(requests);
+                }
+                None => Self::publish(state, dram, execute(&*self.disk, requests))?,
+            }
+        }
+        taken
+    }
+
+    fn take_requests(
+        &self,
+        state: &mut VirtioBlockState,
+        dram: &Dram,
+        requests: &mut Vec<Request>,
+    ) -> Result<(), MemoryError> {
         let avail_idx_addr = state.queue_avail.wrapping_add(2);
-        let avail_idx = dram.load_16(Self::phys_to_offset(avail_idx_addr)?)? as u16;
+        let avail_idx = dram.load_16(Self::phys_to_offset(avail_idx_addr)?)?;
 
-        let mut processed_any = false;
         while state.last_avail_idx != avail_idx {
-            let qsz = if state.queue_num > 0 {
-                state.queue_num
-            } else {
-                device::QUEUE_SIZE
-            };
+            let qsz = Self::queue_size(state);
             let ring_slot = (state.last_avail_idx as u32 % qsz) as u64;
             let head_idx_addr = state
                 .queue_avail
                 .wrapping_add(4)
                 .wrapping_add(ring_slot * 2);
-            let head_desc_idx = dram.load_16(Self::phys_to_offset(head_idx_addr)?)? as u16;
-
-            let desc_idx = head_desc_idx;
+            let head_desc_idx = dram.load_16(Self::phys_to_offset(head_idx_addr)?)?;
+            requests.push(self.parse(state, dram, head_desc_idx)?);
+            state.last_avail_idx = state.last_avail_idx.wrapping_add(1);
+        }
+        Ok(())
+    }
 
-            let desc_addr0 = state.queue_desc.wrapping_add((desc_idx as u64) * 16);
-            let off_desc_addr0 = Self::phys_to_offset(desc_addr0)?;
-            let header_addr = dram.load_64(off_desc_addr0)?;
-            let header_len = dram.load_32(off_desc_addr0 + 8)?;
-            let header_flags = dram.load_16(off_desc_addr0 + 12)? as u64;
-            let mut next_desc_idx = dram.load_16(off_desc_addr0 + 14)?;
+    /// Write `done` back to the guest: reply data and status bytes, then the
+    /// used elements, and the used index once at the end so the driver never
+    /// sees a request before its data.
+    fn publish(
+        state: &mut VirtioBlockState,
+        dram: &Dram,
+        done: Vec<Completion>,
+    ) -> Result<(), MemoryError> {
+        let qsz = Self::queue_size(state) as u64;
+        let used_idx_addr = Self::phys_to_offset(state.queue_used.wrapping_add(2))?;
+        let mut used_idx = dram.load_16(used_idx_addr)?;
 
-            if header_len < 16 {
-                // Consume malformed descriptor to avoid loop
-                state.last_avail_idx = state.last_avail_idx.wrapping_add(1);
-                processed_any = true;
+        let mut published = false;
+        for Completion {
+            request,
+            code,
+            data,
+        } in done
+        {
+            // Issued before a reset.
+            if request.generation != state.generation {
                 continue;
             }
-
-            let off_header_addr = Self::phys_to_offset(header_addr)?;
-            let blk_type = dram.load_32(off_header_addr)?;
-            let _blk_reserved = dram.load_32(off_header_addr + 4)?;
-            let blk_sector = dram.load_64(off_header_addr + 8)?;
-
-            let mut data_len_done: u32 = 0;
-
-            if (header_flags & device::VRING_DESC_F_NEXT) != 0 {
-                let desc2_addr = state.queue_desc.wrapping_add((next_desc_idx as u64) * 16);
-                let off_desc2_addr = Self::phys_to_offset(desc2_addr)?;
-                let data_addr = dram.load_64(off_desc2_addr)?;
-                let data_len = dram.load_32(off_desc2_addr + 8)?;
-                let flags2 = dram.load_16(off_desc2_addr + 12)? as u64;
-                next_desc_idx = dram.load_16(off_desc2_addr + 14)?;
-
-                if blk_type == 0 {
-                    // IN (Read)
-                    let offset = blk_sector * 512;
-                    if offset + (data_len as u64) <= state.disk.len() as u64 {
-                        let slice =
-                            &state.disk[offset as usize..(offset as usize + data_len as usize)];
-                        let dram_off = Self::phys_to_offset(data_addr)?;
-                        dram.write_bytes(dram_off, slice)?;
-                        data_len_done = data_len as u32;
-                    }
-                } else if blk_type == 1 {
-                    // OUT (Write) - use bulk read from DRAM for performance
-                    let offset = blk_sector * 512;
-                    if offset + (data_len as u64) <= state.disk.len() as u64 {
-                        let dram_off = Self::phys_to_offset(data_addr)?;
-                        let src = dram.read_range(dram_off as usize, data_len as usize)?;
-                        state.disk[offset as usize..offset as usize + data_len as usize]
-                            .copy_from_slice(&src);
-                        data_len_done = data_len as u32;
+            let mut used_len: u32 = 0;
+            if let Some(status) = request.status {
+                let mut rest = &data[..];
+                for desc in &request.targets {
+                    let len = rest.len().min(desc.len as usize);
+                    if len == 0 {
+                        break;
                     }
+                    dram.write_bytes(Self::phys_to_offset(desc.addr)?, &rest[..len])?;
+                    rest = &rest[len..];
                 }
-
-                if (flags2 & device::VRING_DESC_F_NEXT) != 0 {
-                    let desc3_addr = state.queue_desc.wrapping_add((next_desc_idx as u64) * 16);
-                    let off_desc3_addr = Self::phys_to_offset(desc3_addr)?;
-                    let status_addr = dram.load_64(off_desc3_addr)?;
-                    dram.store_8(Self::phys_to_offset(status_addr)?, 0)?; // Status: OK
-                }
+                dram.store_8(Self::phys_to_offset(status.addr)?, code as u64)?;
+                // Data written to guest memory, plus the status byte.
+                used_len = (data.len() - rest.len()) as u32 + 1;
             }
 
-            let used_idx_addr = state.queue_used.wrapping_add(2);
-            let mut used_idx = dram.load_16(Self::phys_to_offset(used_idx_addr)?)? as u16;
             let elem_addr = state
                 .queue_used
                 .wrapping_add(4)
-                .wrapping_add((used_idx as u64 % qsz as u64) * 8);
+                .wrapping_add((used_idx as u64 % qsz) * 8);
             let off_elem_addr = Self::phys_to_offset(elem_addr)?;
-            dram.store_32(off_elem_addr, head_desc_idx as u64)?;
-            dram.store_32(off_elem_addr + 4, data_len_done as u64)?;
+            dram.store_32(off_elem_addr, request.head as u64)?;
+            dram.store_32(off_elem_addr + 4, used_len as u64)?;
             used_idx = used_idx.wrapping_add(1);
-            dram.store_16(Self::phys_to_offset(used_idx_addr)?, used_idx as u64)?;
-
-            state.last_avail_idx = state.last_avail_idx.wrapping_add(1);
-            processed_any = true;
+            published = true;
         }
 
-        if processed_any {
+        if published {
+            fence(Ordering::Release);
+            dram.store_16(used_idx_addr, used_idx as u64)?;
             state.interrupt_status |= 1;
         }
 
@@ -160,16 +429,212 @@ impl VirtioBlock {
     }
 }
 
+/// Serve `requests` in order. Runs of adjacent reads or writes are merged
+/// into one backend call and back-to-back flushes into one flush.
+fn execute(disk: &dyn BlockBackend, requests: Vec<Request>) -> Vec<Completion> {
+    let mut done = Vec::with_capacity(requests.len());
+    let mut requests = requests.into_iter().peekable();
+    while let Some(first) = requests.next() {
+        let mut run = vec![first];
+        let mut run_len = run[0].len();
+        while let Some(next) = requests.next_if(|next| joins(disk, &run, run_len, next)) {
+            run_len += next.len();
+            run.push(next);
+        }
+        if run.len() == 1 {
+            done.extend(run.into_iter().map(|request| serve(disk, request)));
+            continue;
+        }
+
+        let offset = run[0].offset;
+        match run[0].blk_type {
+            VIRTIO_BLK_T_FLUSH => {
+                let code = match disk.flush() {
+                    Ok(()) => VIRTIO_BLK_S_OK,
+                    Err(e) => {
+                        log::warn!("[VirtIO] Block flush failed: {}", e);
+                        VIRTIO_BLK_S_IOERR
+                    }
+                };
+                done.extend(run.into_iter().map(|request| Completion {
+                    request,
+                    code,
+                    data: Vec::new(),
+                }));
+            }
+            VIRTIO_BLK_T_IN => {
+                let mut buf = vec![0; run_len as usize];
+                if disk.read_at(offset, &mut buf).is_err() {
+                    // Retry one by one so only the failing requests fail.
+                    done.extend(run.into_iter().map(|request| serve(disk, request)));
+                    continue;
+                }
+                let mut pos = 0;
+                for request in run {
+                    let len = request.len() as usize;
+                    let data = buf[pos..pos + len].to_vec();
+                    pos += len;
+                    done.push(Completion {
+                        request,
+                        code: VIRTIO_BLK_S_OK,
+                        data,
+                    });
+                }
+            }
+            _ => {
+                let data: Vec<u8> = run.iter().flat_map(|r| &r.payload).copied().collect();
+                if disk.write_at(offset, &data).is_err() {
+                    done.extend(run.into_iter().map(|request| serve(disk, request)));
+                    continue;
+                }
+                done.extend(run.into_iter().map(|request| Completion {
+                    request,
+                    code: VIRTIO_BLK_S_OK,
+                    data: Vec::new(),
+                }));
+            }
+        }
+    }
+    done
+}
+
+/// Whether `next` can be served by the same backend call as `run`, which
+/// moves `run_len` bytes.
+fn joins(disk: &dyn BlockBackend, run: &[Request], run_len: u64, next: &Request) -> bool {
+    let first = &run[0];
+    if first.status.is_none() || next.status.is_none() || first.blk_type != next.blk_type {
+        return false;
+    }
+    match next.blk_type {
+        VIRTIO_BLK_T_FLUSH => true,
+        VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+            (next.blk_type == VIRTIO_BLK_T_IN || !disk.is_read_only())
+                && next.offset == first.offset.saturating_add(run_len)
+                && run_len + next.len() <= MAX_MERGE_BYTES
+        }
+        _ => false,
+    }
+}
+
+/// Serve one request on its own.
+fn serve(disk: &dyn BlockBackend, request: Request) -> Completion {
+    let (code, data) = if request.status.is_some() {
+        serve_request(disk, &request)
+    } else {
+        (VIRTIO_BLK_S_OK, Vec::new())
+    };
+    Completion {
+        request,
+        code,
+        data,
+    }
+}
+
+/// Returns the status byte and the reply data.
+fn serve_request(disk: &dyn BlockBackend, request: &Request) -> (u8, Vec<u8>) {
+    let offset = request.offset;
+    let writes = matches!(
+        request.blk_type,
+        VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+    );
+    if writes && disk.is_read_only() {
+        return (VIRTIO_BLK_S_IOERR, Vec::new());
+    }
+    match request.blk_type {
+        VIRTIO_BLK_T_IN => {
+            let mut buf = vec![0; request.len() as usize];
+            if let Err(e) = disk.read_at(offset, &mut buf) {
+                log::warn!("[VirtIO] Block read at {:#x} failed: {}", offset, e);
+                return (VIRTIO_BLK_S_IOERR, Vec::new());
+            }
+            (VIRTIO_BLK_S_OK, buf)
+        }
+        VIRTIO_BLK_T_OUT => {
+            if let Err(e) = disk.write_at(offset, &request.payload) {
+                log::warn!("[VirtIO] Block write at {:#x} failed: {}", offset, e);
+                return (VIRTIO_BLK_S_IOERR, Vec::new());
+            }
+            (VIRTIO_BLK_S_OK, Vec::new())
+        }
+        VIRTIO_BLK_T_FLUSH => {
+            if let Err(e) = disk.flush() {
+                log::warn!("[VirtIO] Block flush failed: {}", e);
+                return (VIRTIO_BLK_S_IOERR, Vec::new());
+            }
+            (VIRTIO_BLK_S_OK, Vec::new())
+        }
+        VIRTIO_BLK_T_GET_ID => {
+            if request.targets.is_empty() {
+                return (VIRTIO_BLK_S_IOERR, Vec::new());
+            }
+            (VIRTIO_BLK_S_OK, request.payload.clone())
+        }
+        VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+            if !request.payload.len().is_multiple_of(SEGMENT_LEN as usize) {
+                return (VIRTIO_BLK_S_UNSUPP, Vec::new());
+            }
+            for seg in request.payload.chunks_exact(SEGMENT_LEN as usize) {
+                let sector = u64::from_le_bytes(seg[0..8].try_into().unwrap());
+                let sectors = u32::from_le_bytes(seg[8..12].try_into().unwrap());
+                let flags = u32::from_le_bytes(seg[12..16].try_into().unwrap());
+                let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                // Discard takes no flags; write-zeroes only unmap.
+                if sectors > MAX_ZERO_SECTORS
+                    || (request.blk_type == VIRTIO_BLK_T_DISCARD && flags != 0)
+                    || flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+                {
+                    return (VIRTIO_BLK_S_UNSUPP, Vec::new());
+                }
+                let offset = sector.saturating_mul(SECTOR_SIZE);
+                let len = sectors as u64 * SECTOR_SIZE;
+                let result = if request.blk_type == VIRTIO_BLK_T_DISCARD {
+                    disk.discard(offset, len)
+                } else {
+                    disk.write_zeroes(offset, len, unmap)
+                };
+                if let Err(e) = result {
+                    log::warn!("[VirtIO] Block discard/zero at {:#x} failed: {}", offset, e);
+                    return (VIRTIO_BLK_S_IOERR, Vec::new());
+                }
+            }
+            (VIRTIO_BLK_S_OK, Vec::new())
+        }
+        _ => (VIRTIO_BLK_S_UNSUPP, Vec::new()),
+    }
+}
+
 impl VirtioDevice for VirtioBlock {
     fn device_id(&self) -> u32 {
         device::VIRTIO_BLK_DEVICE_ID
     }
 
+    fn queues(&self) -> Vec<device::QueueState> {
+        let state = self.state.lock().unwrap();
+        vec![device::QueueState {
+            index: 0,
+            num: state.queue_num,
+            ready: state.queue_ready,
+            desc: state.queue_desc,
+            avail: state.queue_avail,
+            used: state.queue_used,
+            last_avail_idx: state.last_avail_idx,
+        }]
+    }
+
     fn is_interrupting(&self) -> bool {
         let state = self.state.lock().unwrap();
         state.interrupt_status != 0
     }
 
+    fn complete(&self, dram: &Dram) -> Result<(), MemoryError> {
+        if !self.finished.pending.swap(false, Ordering::Acquire) {
+            return Ok(());
+        }
+        let done = std::mem::take(&mut *self.finished.list.lock().unwrap());
+        let mut state = self.state.lock().unwrap();
+        Self::publish(&mut state, dram, done)
+    }
+
     fn read(&self, offset: u64) -> Result<u64, MemoryError> {
         let state = self.state.lock().unwrap();
         let val = match offset {
@@ -179,7 +644,7 @@ impl VirtioDevice for VirtioBlock {
             device::VENDOR_ID_OFFSET => device::VENDOR_ID,
             device::DEVICE_FEATURES_OFFSET => {
                 if state.device_features_sel == 0 {
-                    1u64 << device::VIRTIO_BLK_F_FLUSH
+                    self.device_features()
                 } else {
                     0
                 }
@@ -201,16 +666,12 @@ impl VirtioDevice for VirtioBlock {
             device::INTERRUPT_STATUS_OFFSET => state.interrupt_status as u64,
             device::STATUS_OFFSET => state.status as u64,
             device::CONFIG_GENERATION_OFFSET => 0,
-            _ if offset >= 0x100 => {
-                if offset == 0x100 {
-                    let cap = state.disk.len() as u64 / 512;
-                    cap & 0xffff_ffff
-                } else if offset == 0x104 {
-                    let cap = state.disk.len() as u64 / 512;
-                    cap >> 32
-                } else {
-                    0
-                }
+            _ if offset >= device::CONFIG_SPACE_OFFSET => {
+                let config = self.config_space();
+                let at = (offset - device::CONFIG_SPACE_OFFSET) as usize;
+                config.get(at..at + 4).map_or(0, |word| {
+                    u32::from_le_bytes(word.try_into().unwrap()) as u64
+                })
             }
             _ => 0,
         };
@@ -265,7 +726,7 @@ impl VirtioDevice for VirtioBlock {
             }
             device::QUEUE_NOTIFY_OFFSET => {
                 if val32 == 0 {
-                    Self::process_queue(&mut state, dram)?;
+                    self.process_queue(&mut state, dram)?;
                 }
             }
             device::INTERRUPT_ACK_OFFSET => {
@@ -278,6 +739,7 @@ impl VirtioDevice for VirtioBlock {
                     state.queue_ready = false;
                     state.interrupt_status = 0;
                     state.last_avail_idx = 0;
+                    state.generation += 1;
                 } else {
                     state.status = val32;
                 }
@@ -308,3 +770,443 @@ impl VirtioDevice for VirtioBlock {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC: u64 = 0x1000;
+    const AVAIL: u64 = 0x2000;
+    const USED: u64 = 0x3000;
+    const HEADER: u64 = 0x4000;
+    const DATA: u64 = 0x5000;
+    const STATUS: u64 = 0x6000;
+    const QUEUE_NUM: u64 = 64;
+
+    /// Queue a header/data/status request (no data for 0 sectors) and
+    /// return the status byte.
+    fn submit(blk: &VirtioBlock, dram: &Dram, blk_type: u32, sector: u64, sectors: u32) -> u8 {
+        submit_bytes(blk, dram, blk_type, sector, sectors * 512)
+    }
+
+    /// As `submit`, with a data buffer of `len` bytes at `DATA`.
+    fn submit_bytes(blk: &VirtioBlock, dram: &Dram, blk_type: u32, sector: u64, len: u32) -> u8 {
+        dram.store_32(HEADER, blk_type as u64).unwrap();
+        dram.store_64(HEADER + 8, sector).unwrap();
+        let mut descs = vec![(HEADER, 16, 0)];
+        if len > 0 {
+            let flags = if matches!(blk_type, VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID) {
+                device::VRING_DESC_F_WRITE
+            } else {
+                0
+            };
+            descs.push((DATA, len, flags));
+        }
+        descs.push((STATUS, 1, device::VRING_DESC_F_WRITE));
+        for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+            let off = DESC + i as u64 * 16;
+            let next = if i + 1 < descs.len() {
+                device::VRING_DESC_F_NEXT
+            } else {
+                0
+            };
+            dram.store_64(off, DRAM_BASE + addr).unwrap();
+            dram.store_32(off + 8, len as u64).unwrap();
+            dram.store_16(off + 12, flags | next).unwrap();
+            dram.store_16(off + 14, i as u64 + 1).unwrap();
+        }
+        let idx = dram.load_16(AVAIL + 2).unwrap();
+        dram.store_16(AVAIL + 4 + (idx as u64 % QUEUE_NUM) * 2, 0)
+            .unwrap();
+        dram.store_16(AVAIL + 2, idx as u64 + 1).unwrap();
+        dram.store_8(STATUS, 0xff).unwrap();
+        blk.write(device::QUEUE_NOTIFY_OFFSET, 0, dram).unwrap();
+        dram.load_8(STATUS).unwrap()
+    }
+
+    /// Guest memory with queue 0 of `blk` set up.
+    fn setup(blk: &VirtioBlock) -> Dram {
+        let dram = Dram::new(DRAM_BASE, 1 << 16);
+        blk.write(device::QUEUE_NUM_OFFSET, QUEUE_NUM, &dram)
+            .unwrap();
+        for (reg, addr) in [
+            (device::QUEUE_DESC_LOW_OFFSET, DESC),
+            (device::QUEUE_DRIVER_LOW_OFFSET, AVAIL),
+            (device::QUEUE_DEVICE_LOW_OFFSET, USED),
+        ] {
+            blk.write(reg, DRAM_BASE + addr, &dram).unwrap();
+        }
+        dram
+    }
+
+    /// Queue request `n` without notifying the device: descriptors `3n..`,
+    /// header and status at `HEADER + 32n`, `len` bytes of data at
+    /// `DATA + n * len`.
+    fn queue(dram: &Dram, n: u64, blk_type: u32, sector: u64, len: u32) {
+        let header = HEADER + n * 32;
+        dram.store_32(header, blk_type as u64).unwrap();
+        dram.store_64(header + 8, sector).unwrap();
+        dram.store_8(header + 16, 0xff).unwrap();
+        let data_flags = if blk_type == VIRTIO_BLK_T_IN {
+            device::VRING_DESC_F_WRITE
+        } else {
+            0
+        };
+        let descs = [
+            (header, 16, device::VRING_DESC_F_NEXT),
+            (
+                DATA + n * len as u64,
+                len,
+                data_flags | device::VRING_DESC_F_NEXT,
+            ),
+            (header + 16, 1, device::VRING_DESC_F_WRITE),
+        ];
+        for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+            let idx = n * 3 + i as u64;
+            let off = DESC + idx * 16;
+            dram.store_64(off, DRAM_BASE + addr).unwrap();
+            dram.store_32(off + 8, len as u64).unwrap();
+            dram.store_16(off + 12, flags).unwrap();
+            dram.store_16(off + 14, idx + 1).unwrap();
+        }
+        let idx = dram.load_16(AVAIL + 2).unwrap() as u64;
+        dram.store_16(AVAIL + 4 + (idx % QUEUE_NUM) * 2, n * 3)
+            .unwrap();
+        dram.store_16(AVAIL + 2, idx + 1).unwrap();
+    }
+
+    /// Status byte of request `n` from `queue`.
+    fn status_of(dram: &Dram, n: u64) -> u8 {
+        dram.load_8(HEADER + n * 32 + 16).unwrap()
+    }
+
+    /// A disk that counts backend calls, takes `delay` over each, and
+    /// blocks while `held`.
+    struct SlowDisk {
+        inner: MemoryBackend,
+        delay: std::time::Duration,
+        calls: std::sync::atomic::AtomicUsize,
+        held: Mutex<bool>,
+        released: std::sync::Condvar,
+    }
+
+    impl SlowDisk {
+        fn new(sectors: usize, delay: std::time::Duration) -> Self {
+            let image = (0..sectors * 512).map(|i| (i / 512) as u8).collect();
+            Self {
+                inner: MemoryBackend::new(image),
+                delay,
+                calls: Default::default(),
+                held: Mutex::new(false),
+                released: Default::default(),
+            }
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn hold(&self, held: bool) {
+            *self.held.lock().unwrap() = held;
+            self.released.notify_all();
+        }
+
+        fn call(&self) {
+            let mut held = self.held.lock().unwrap();
+            while *held {
+                held = self.released.wait(held).unwrap();
+            }
+            drop(held);
+            std::thread::sleep(self.delay);
+            self.calls.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl BlockBackend for SlowDisk {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            self.call();
+            self.inner.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+            self.call();
+            self.inner.write_at(offset, data)
+        }
+        fn flush(&self) -> std::io::Result<()> {
+            self.call();
+            Ok(())
+        }
+        fn describe(&self) -> String {
+            "slow".into()
+        }
+    }
+
+    /// An asynchronous device on `disk` and a channel its I/O thread
+    /// signals.
+    fn asynchronous(disk: Arc<SlowDisk>) -> (VirtioBlock, mpsc::Receiver<()>) {
+        let (tx, rx) = mpsc::channel();
+        let blk = VirtioBlock::with_backend(disk).with_io_thread(move || {
+            let _ = tx.send(());
+        });
+        (blk, rx)
+    }
+
+    /// Publish completions until `count` requests are used.
+    fn wait_used(blk: &VirtioBlock, dram: &Dram, done: &mpsc::Receiver<()>, count: u64) {
+        while (dram.load_16(USED + 2).unwrap() as u64) < count {
+            done.recv_timeout(std::time::Duration::from_secs(10))
+                .expect("I/O thread stalled");
+            blk.complete(dram).unwrap();
+        }
+    }
+
+    /// Store one discard/write-zeroes segment at `DATA`.
+    fn segment(dram: &Dram, sector: u64, sectors: u32, flags: u32) {
+        dram.store_64(DATA, sector).unwrap();
+        dram.store_32(DATA + 8, sectors as u64).unwrap();
+        dram.store_32(DATA + 12, flags as u64).unwrap();
+    }
+
+    #[test]
+    fn requests_reach_the_backend() {
+        let disk = Arc::new(MemoryBackend::new(vec![0; 4 * 512]));
+        let blk = VirtioBlock::with_backend(disk.clone());
+        let dram = setup(&blk);
+        assert_eq!(blk.read(0x100).unwrap(), 4);
+
+        dram.write_bytes(DATA, &[0xab; 512]).unwrap();
+        assert_eq!(submit(&blk, &dram, VIRTIO_BLK_T_OUT, 2, 1), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            submit(&blk, &dram, VIRTIO_BLK_T_FLUSH, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(&disk.contents()[1024..1536], &[0xab; 512]);
+
+        dram.write_bytes(DATA, &[0; 1024]).unwrap();
+        assert_eq!(submit(&blk, &dram, VIRTIO_BLK_T_IN, 1, 2), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            dram.read_range(DATA as usize + 512, 512).unwrap(),
+            [0xab; 512]
+        );
+        // Used length counts the data and the status byte.
+        assert_eq!(dram.load_32(USED + 4 + 2 * 8 + 4).unwrap(), 1025);
+
+        assert_eq!(
+            submit(&blk, &dram, VIRTIO_BLK_T_IN, 3, 2),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(submit(&blk, &dram, 2, 0, 0), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(dram.load_16(USED + 2).unwrap(), 5);
+    }
+
+    #[test]
+    fn zeroes_discard_and_serial() {
+        let disk = Arc::new(MemoryBackend::new(vec![0xff; 8 * 512]));
+        let blk = VirtioBlock::with_backend(disk.clone()).with_serial("disk-serial-0123456789");
+        let dram = setup(&blk);
+        let features = blk.read(device::DEVICE_FEATURES_OFFSET).unwrap();
+        assert_ne!(features & (1 << device::VIRTIO_BLK_F_WRITE_ZEROES), 0);
+        assert_eq!(features & (1 << device::VIRTIO_BLK_F_RO), 0);
+        // blk_size, then max_write_zeroes_seg.
+        assert_eq!(blk.read(0x100 + 20).unwrap(), 512);
+        assert_eq!(blk.read(0x100 + 52).unwrap(), SEG_MAX as u64);
+
+        segment(&dram, 2, 3, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP);
+        assert_eq!(
+            submit_bytes(&blk, &dram, VIRTIO_BLK_T_WRITE_ZEROES, 0, 16),
+            VIRTIO_BLK_S_OK
+        );
+        let contents = disk.contents();
+        assert!(contents[1024..2560].iter().all(|&b| b == 0));
+        assert_eq!((contents[1023], contents[2560]), (0xff, 0xff));
+
+        segment(&dram, 7, 2, 0);
+        assert_eq!(
+            submit_bytes(&blk, &dram, VIRTIO_BLK_T_DISCARD, 0, 16),
+            VIRTIO_BLK_S_IOERR
+        );
+        segment(&dram, 0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP);
+        assert_eq!(
+            submit_bytes(&blk, &dram, VIRTIO_BLK_T_DISCARD, 0, 16),
+            VIRTIO_BLK_S_UNSUPP
+        );
+
+        assert_eq!(
+            submit_bytes(&blk, &dram, VIRTIO_BLK_T_GET_ID, 0, 20),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(
+            dram.read_range(DATA as usize, 20).unwrap(),
+            b"disk-serial-01234567"
+        );
+    }
+
+    #[test]
+    fn read_only_disks_refuse_writes() {
+        struct ReadOnly(MemoryBackend);
+        impl BlockBackend for ReadOnly {
+            fn size(&self) -> u64 {
+                self.0.size()
+            }
+            fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+                self.0.read_at(offset, buf)
+            }
+            fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+                self.0.write_at(offset, data)
+            }
+            fn is_read_only(&self) -> bool {
+                true
+            }
+            fn describe(&self) -> String {
+                "read-only".into()
+            }
+        }
+
+        let disk = Arc::new(ReadOnly(MemoryBackend::new(vec![7; 2 * 512])));
+        let blk = VirtioBlock::with_backend(disk.clone());
+        let dram = setup(&blk);
+        let features = blk.read(device::DEVICE_FEATURES_OFFSET).unwrap();
+        assert_ne!(features & (1 << device::VIRTIO_BLK_F_RO), 0);
+        assert_eq!(features & (1 << device::VIRTIO_BLK_F_DISCARD), 0);
+
+        assert_eq!(
+            submit(&blk, &dram, VIRTIO_BLK_T_OUT, 0, 1),
+            VIRTIO_BLK_S_IOERR
+        );
+        segment(&dram, 0, 1, 0);
+        assert_eq!(
+            submit_bytes(&blk, &dram, VIRTIO_BLK_T_WRITE_ZEROES, 0, 16),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(disk.0.contents(), vec![7; 2 * 512]);
+        assert_eq!(submit(&blk, &dram, VIRTIO_BLK_T_IN, 1, 1), VIRTIO_BLK_S_OK);
+        // GET_ID without a serial reads as zeros.
+        assert_eq!(
+            submit_bytes(&blk, &dram, VIRTIO_BLK_T_GET_ID, 0, 20),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(dram.read_range(DATA as usize, 20).unwrap(), [0; 20]);
+    }
+
+    #[test]
+    fn adjacent_requests_are_merged() {
+        let disk = Arc::new(SlowDisk::new(16, std::time::Duration::ZERO));
+        let blk = VirtioBlock::with_backend(disk.clone());
+        let dram = setup(&blk);
+
+        for n in 0..4 {
+            dram.write_bytes(DATA + n * 512, &[0xa0 + n as u8; 512])
+                .unwrap();
+            queue(&dram, n, VIRTIO_BLK_T_OUT, 4 + n, 512);
+        }
+        blk.write(device::QUEUE_NOTIFY_OFFSET, 0, &dram).unwrap();
+        assert_eq!(disk.calls(), 1);
+        assert_eq!(disk.inner.contents()[6 * 512], 0xa2);
+
+        queue(&dram, 0, VIRTIO_BLK_T_FLUSH, 0, 0);
+        queue(&dram, 1, VIRTIO_BLK_T_FLUSH, 0, 0);
+        blk.write(device::QUEUE_NOTIFY_OFFSET, 0, &dram).unwrap();
+        assert_eq!(disk.calls(), 2);
+
+        // Reads from sectors 2..5, then a gap: two backend calls.
+        for (n, sector) in [2, 3, 4, 9].into_iter().enumerate() {
+            queue(&dram, n as u64, VIRTIO_BLK_T_IN, sector, 512);
+        }
+        blk.write(device::QUEUE_NOTIFY_OFFSET, 0, &dram).unwrap();
+        assert_eq!(disk.calls(), 4);
+        for (n, byte) in [2, 3, 0xa0, 9].into_iter().enumerate() {
+            let n = n as u64;
+            assert_eq!(status_of(&dram, n), VIRTIO_BLK_S_OK);
+            assert_eq!(
+                dram.read_range((DATA + n * 512) as usize, 512).unwrap(),
+                [byte; 512]
+            );
+        }
+        assert_eq!(dram.load_16(USED + 2).unwrap(), 10);
+        assert_eq!(dram.load_32(USED + 4 + 9 * 8 + 4).unwrap(), 513);
+    }
+
+    #[test]
+    fn io_thread_completes_off_the_hart() {
+        let disk = Arc::new(SlowDisk::new(4, std::time::Duration::ZERO));
+        let (blk, done) = asynchronous(disk.clone());
+        let dram = setup(&blk);
+
+        disk.hold(true);
+        queue(&dram, 0, VIRTIO_BLK_T_IN, 3, 512);
+        // Returns while the disk is still busy.
+        blk.write(device::QUEUE_NOTIFY_OFFSET, 0, &dram).unwrap();
+        blk.complete(&dram).unwrap();
+        assert_eq!(status_of(&dram, 0), 0xff);
+        assert_eq!(dram.load_16(USED + 2).unwrap(), 0);
+        assert!(!blk.is_interrupting());
+
+        disk.hold(false);
+        wait_used(&blk, &dram, &done, 1);
+        assert_eq!(status_of(&dram, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(dram.read_range(DATA as usize, 512).unwrap(), [3; 512]);
+        assert!(blk.is_interrupting());
+
+        // Requests in flight across a reset are dropped.
+        disk.hold(true);
+        queue(&dram, 1, VIRTIO_BLK_T_IN, 2, 512);
+        blk.write(device::QUEUE_NOTIFY_OFFSET, 0, &dram).unwrap();
+        blk.write(device::STATUS_OFFSET, 0, &dram).unwrap();
+        disk.hold(false);
+        done.recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        blk.complete(&dram).unwrap();
+        assert_eq!(status_of(&dram, 1), 0xff);
+        assert_eq!(dram.load_16(USED + 2).unwrap(), 1);
+    }
+
+    #[test]
+    fn io_thread_frees_the_hart_for_slow_disks() {
+        use std::time::{Duration, Instant};
+
+        const REQUESTS: u64 = 16;
+        const DELAY: Duration = Duration::from_millis(5);
+
+        // Scattered reads, so none merge. Returns the time the hart spent
+        // in queue notifies and the total time.
+        let run = |blk: &VirtioBlock, done: Option<&mpsc::Receiver<()>>| {
+            let dram = setup(blk);
+            let start = Instant::now();
+            let mut notifying = Duration::ZERO;
+            for n in 0..REQUESTS {
+                queue(&dram, n, VIRTIO_BLK_T_IN, 2 * n, 512);
+                let notify = Instant::now();
+                blk.write(device::QUEUE_NOTIFY_OFFSET, 0, &dram).unwrap();
+                notifying += notify.elapsed();
+            }
+            if let Some(done) = done {
+                wait_used(blk, &dram, done, REQUESTS);
+            }
+            assert_eq!(dram.load_16(USED + 2).unwrap() as u64, REQUESTS);
+            for n in 0..REQUESTS {
+                assert_eq!(status_of(&dram, n), VIRTIO_BLK_S_OK);
+                assert_eq!(dram.load_8(DATA + n * 512).unwrap() as u64, 2 * n);
+            }
+            (notifying, start.elapsed())
+        };
+
+        let sync_disk = Arc::new(SlowDisk::new(64, DELAY));
+        let (sync_notify, sync_total) = run(&VirtioBlock::with_backend(sync_disk), None);
+        let (blk, done) = asynchronous(Arc::new(SlowDisk::new(64, DELAY)));
+        let (async_notify, async_total) = run(&blk, Some(&done));
+
+        // Synchronous notifies wait for every backend call; asynchronous
+        // ones only copy descriptors, with the disk as fast as before.
+        assert!(sync_notify >= DELAY * REQUESTS as u32);
+        assert!(
+            async_notify * 4 < sync_notify,
+            "{async_notify:?} vs {sync_notify:?}"
+        );
+        assert!(
+            async_total < sync_total * 2,
+            "{async_total:?} vs {sync_total:?}"
+        );
+    }
+}
@@ -47,15 +47,14 @@ pub const VIRTIO_9P_DEVICE_ID: u32 = 9;
 // VirtIO Block Features
 #[allow(dead_code)]
 pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1;
-#[allow(dead_code)]
 pub const VIRTIO_BLK_F_SEG_MAX: u64 = 2;
 #[allow(dead_code)]
 pub const VIRTIO_BLK_F_GEOMETRY: u64 = 4;
-#[allow(dead_code)]
 pub const VIRTIO_BLK_F_RO: u64 = 5;
-#[allow(dead_code)]
 pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 6;
 pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 14;
 
 // VirtIO Net Features
 pub const VIRTIO_NET_F_MAC: u64 = 5; // Device has given MAC address
@@ -75,6 +74,19 @@ pub const QUEUE_SIZE: u32 = 16;
 pub const VRING_DESC_F_NEXT: u64 = 1;
 pub const VRING_DESC_F_WRITE: u64 = 2;
 
+/// Driver-visible state of one virtqueue, for debugging tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueState {
+    pub index: u32,
+    pub num: u32,
+    pub ready: bool,
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    /// Next available-ring index the device will consume.
+    pub last_avail_idx: u16,
+}
+
 /// Trait for all VirtIO devices to implement.
 ///
 /// Note: Methods take `&self` to allow concurrent access from multiple harts.
@@ -98,9 +110,29 @@ pub trait VirtioDevice: Send + Sync {
         Ok(())
     }
 
+    /// Write back work finished on a host thread (e.g. disk I/O) and raise
+    /// the interrupt for it. Called from interrupt polling, before
+    /// `is_interrupting`. Default implementation does nothing.
+    fn complete(&self, _dram: &Dram) -> Result<(), MemoryError> {
+        Ok(())
+    }
+
     /// Check if the backend (e.g., network) is connected.
     /// Only meaningful for network devices. Returns true by default.
     fn is_backend_connected(&self) -> bool {
         true
     }
+
+    /// Snapshot of the device's virtqueues (used by the monitor's
+    /// `info virtio`). Default implementation reports none.
+    fn queues(&self) -> Vec<QueueState> {
+        Vec::new()
+    }
+
+    /// The concrete device, for callers that need device-specific controls
+    /// (e.g. attaching a 9P share at runtime). Default implementation
+    /// exposes nothing.
+    fn as_any(&self) -> Option<&dyn std::any::Any> {
+        None
+    }
 }
@@ -443,6 +443,21 @@ impl VirtioDevice for VirtioInput {
         VIRTIO_INPUT_DEVICE_ID
     }
 
+    fn queues(&self) -> Vec<device::QueueState> {
+        let state = self.state.lock().unwrap();
+        (0..2)
+            .map(|q| device::QueueState {
+                index: q as u32,
+                num: state.queue_num[q],
+                ready: state.queue_ready[q],
+                desc: state.queue_desc[q],
+                avail: state.queue_avail[q],
+                used: state.queue_used[q],
+                last_avail_idx: state.last_avail_idx[q],
+            })
+            .collect()
+    }
+
     fn is_interrupting(&self) -> bool {
         self.state.lock().unwrap().interrupt_status != 0
     }
@@ -403,6 +403,23 @@ impl VirtioDevice for VirtioNet {
         device::VIRTIO_NET_DEVICE_ID
     }
 
+    fn queues(&self) -> Vec<device::QueueState> {
+        let state = self.state.lock().unwrap();
+        [&state.rx_queue, &state.tx_queue]
+            .into_iter()
+            .enumerate()
+            .map(|(i, q)| device::QueueState {
+                index: i as u32,
+                num: q.num,
+                ready: q.ready,
+                desc: q.desc,
+                avail: q.avail,
+                used: q.used,
+                last_avail_idx: q.last_avail_idx,
+            })
+            .collect()
+    }
+
     fn is_interrupting(&self) -> bool {
         let state = self.state.lock().unwrap();
         state.interrupt_status != 0
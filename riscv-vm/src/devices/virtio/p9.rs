@@ -19,7 +19,7 @@ use crate::dram::{Dram, MemoryError};
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions, ReadDir};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use super::device::{self, VirtioDevice};
@@ -119,6 +119,8 @@ struct P9State {
     // 9P specific state
     mount_tag: String,
     host_root: PathBuf,
+    /// False while no host directory is attached; only Tversion is served.
+    attached: bool,
     msize: u32,
     fids: HashMap<u32, FidEntry>,
     next_path_id: u64,
@@ -157,6 +159,7 @@ impl VirtioP9 {
                 last_avail_idx: 0,
                 mount_tag: tag.to_string(),
                 host_root: PathBuf::from(host_path),
+                attached: !host_path.is_empty(),
                 msize: 8192,
                 fids: HashMap::new(),
                 next_path_id: 1,
@@ -165,6 +168,34 @@ impl VirtioP9 {
         }
     }
 
+    /// Share `host_path` through this device, replacing any directory
+    /// attached before. Handles opened by the guest on the old directory
+    /// are dropped.
+    pub fn attach(&self, host_path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.host_root = host_path.to_path_buf();
+        state.fids.clear();
+        state.attached = true;
+    }
+
+    /// Stop sharing the host directory; guest requests fail with EINVAL
+    /// until [`Self::attach`] is called again.
+    pub fn detach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.fids.clear();
+        state.attached = false;
+    }
+
+    /// The shared host directory, if one is attached.
+    pub fn host_root(&self) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state.attached.then(|| state.host_root.clone())
+    }
+
+    pub fn mount_tag(&self) -> String {
+        self.state.lock().unwrap().mount_tag.clone()
+    }
+
     /// Enable debug logging
     pub fn set_debug(&self, enabled: bool) {
         let mut state = self.state.lock().unwrap();
@@ -262,6 +293,10 @@ impl VirtioP9 {
             eprintln!("[9P] Received msg type={} tag={} body_len={}", msg_type, tag, body.len());
         }
 
+        if !state.attached && msg_type != T_VERSION {
+            return Self::make_error(tag, "No host directory attached");
+        }
+
         let response_body = match msg_type {
             T_VERSION => Self::handle_version(state, body),
             T_ATTACH => Self::handle_attach(state, body),
@@ -791,6 +826,23 @@ impl VirtioDevice for VirtioP9 {
         device::VIRTIO_9P_DEVICE_ID
     }
 
+    fn as_any(&self) -> Option<&dyn std::any::Any> {
+        Some(self)
+    }
+
+    fn queues(&self) -> Vec<device::QueueState> {
+        let state = self.state.lock().unwrap();
+        vec![device::QueueState {
+            index: 0,
+            num: state.queue_num,
+            ready: state.queue_ready,
+            desc: state.queue_desc,
+            avail: state.queue_avail,
+            used: state.queue_used,
+            last_avail_idx: state.last_avail_idx,
+        }]
+    }
+
     fn is_interrupting(&self) -> bool {
         let state = self.state.lock().unwrap();
         state.interrupt_status != 0
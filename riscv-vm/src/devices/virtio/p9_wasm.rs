@@ -739,6 +739,19 @@ impl VirtioDevice for VirtioP9Wasm {
         device::VIRTIO_9P_DEVICE_ID
     }
 
+    fn queues(&self) -> Vec<device::QueueState> {
+        let state = self.state.lock().unwrap();
+        vec![device::QueueState {
+            index: 0,
+            num: state.queue_num,
+            ready: state.queue_ready,
+            desc: state.queue_desc,
+            avail: state.queue_avail,
+            used: state.queue_used,
+            last_avail_idx: state.last_avail_idx,
+        }]
+    }
+
     fn is_interrupting(&self) -> bool {
         let state = self.state.lock().unwrap();
         state.interrupt_status != 0
@@ -15,7 +15,16 @@
 //! When OpenSBI transfers control to S-mode kernel:
 //! - a0 = hartid (hardware thread ID)
 //! - a1 = DTB physical address (8-byte aligned)
+//!
+//! A DTB from the boot partition can stand in for the generated one, and
+//! overlays can be applied on top of either; see [`build_dtb`].
+
+mod tree;
+
+pub use tree::FdtNode;
 
+use crate::devices::plic::uart_irq;
+use crate::devices::uart::{UART_SIZE, uart_base};
 use crate::dram::Dram;
 
 /// DTB location in DRAM (2MB from start, leaves room before typical kernel load)
@@ -24,6 +33,9 @@ pub const DTB_ADDRESS: u64 = 0x8200_0000;
 /// Maximum DTB size
 pub const DTB_MAX_SIZE: usize = 64 * 1024;
 
+/// Kernel command line when nothing else is given.
+pub const DEFAULT_BOOTARGS: &str = "earlycon=sbi console=ttyS0";
+
 /// FDT header magic number
 const FDT_MAGIC: u32 = 0xd00dfeed;
 
@@ -54,6 +66,26 @@ pub struct D1DeviceConfig {
     pub has_audio: bool,
 }
 
+/// What `/chosen` tells the kernel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chosen {
+    /// Kernel command line; [`DEFAULT_BOOTARGS`] (or what a DTB from the
+    /// boot partition says) if unset.
+    pub bootargs: Option<String>,
+    /// Physical start and end of the initrd.
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// Everything besides the machine itself that goes into the DTB.
+#[derive(Debug, Clone, Default)]
+pub struct DtbOptions {
+    pub chosen: Chosen,
+    /// DTB used instead of the generated one.
+    pub base: Option<Vec<u8>>,
+    /// Overlays applied in order.
+    pub overlays: Vec<Vec<u8>>,
+}
+
 /// Generate a minimal DTB for the RISC-V VM.
 ///
 /// # Arguments
@@ -67,6 +99,28 @@ pub fn generate_dtb(
     num_harts: usize,
     memory_size: u64,
     d1_config: &D1DeviceConfig,
+) -> Vec<u8> {
+    generate_dtb_with_uarts(num_harts, memory_size, d1_config, 1)
+}
+
+/// Like [`generate_dtb`], with `num_uarts` 16550 nodes. UART 0 stays the
+/// console; the rest are numbered by `serialN` aliases (ttyS1, ttyS2, ...).
+pub fn generate_dtb_with_uarts(
+    num_harts: usize,
+    memory_size: u64,
+    d1_config: &D1DeviceConfig,
+    num_uarts: usize,
+) -> Vec<u8> {
+    generate_dtb_with_chosen(num_harts, memory_size, d1_config, num_uarts, &Chosen::default())
+}
+
+/// Like [`generate_dtb_with_uarts`], with `chosen` in `/chosen`.
+pub fn generate_dtb_with_chosen(
+    num_harts: usize,
+    memory_size: u64,
+    d1_config: &D1DeviceConfig,
+    num_uarts: usize,
+    chosen: &Chosen,
 ) -> Vec<u8> {
     let mut builder = DtbBuilder::new();
     
@@ -79,8 +133,19 @@ pub fn generate_dtb(
     
     // /chosen - kernel command line and console
     builder.begin_node("chosen");
-    builder.add_prop_string("bootargs", "earlycon=sbi console=ttyS0");
+    builder.add_prop_string("bootargs", chosen.bootargs.as_deref().unwrap_or(DEFAULT_BOOTARGS));
     builder.add_prop_string("stdout-path", "/soc/serial@10000000");
+    if let Some((start, end)) = chosen.initrd {
+        builder.add_prop("linux,initrd-start", &start.to_be_bytes());
+        builder.add_prop("linux,initrd-end", &end.to_be_bytes());
+    }
+    builder.end_node();
+
+    // /aliases - fixed ttySN numbering for the UARTs
+    builder.begin_node("aliases");
+    for i in 0..num_uarts {
+        builder.add_prop_string(&format!("serial{}", i), &format!("/soc/serial@{:x}", uart_base(i)));
+    }
     builder.end_node();
     
     // /cpus
@@ -146,26 +211,49 @@ pub fn generate_dtb(
     builder.add_prop_reg64(0x0C00_0000, 0x600000);
     builder.add_prop_u32("riscv,ndev", 127);
     builder.add_prop_u32("phandle", 100);
-    // interrupts-extended for PLIC
+    // interrupts-extended for PLIC, in context order (M then S per hart)
     let mut plic_ints = Vec::new();
     for hart in 0..num_harts {
         plic_ints.push((hart + 1) as u32); // phandle
-        plic_ints.push(9); // S-mode external interrupt
-        plic_ints.push((hart + 1) as u32);
         plic_ints.push(11); // M-mode external interrupt
+        plic_ints.push((hart + 1) as u32);
+        plic_ints.push(9); // S-mode external interrupt
     }
     builder.add_prop_u32_array("interrupts-extended", &plic_ints);
     builder.end_node();
     
-    // UART @ 0x1000_0000
-    builder.begin_node("serial@10000000");
-    builder.add_prop_string("compatible", "ns16550a");
-    builder.add_prop_reg64(0x1000_0000, 0x100);
-    builder.add_prop_u32("clock-frequency", 3686400);
-    builder.add_prop_u32("interrupts", 10);
-    builder.add_prop_u32("interrupt-parent", 100); // PLIC phandle
+    // Test finisher @ 0x0010_0000, used by firmware for poweroff and reboot
+    builder.begin_node("test@100000");
+    builder.add_prop("compatible", b"sifive,test1\0sifive,test0\0syscon\0");
+    builder.add_prop_reg64(0x0010_0000, 0x1000);
+    builder.add_prop_u32("phandle", 101);
+    builder.end_node();
+
+    builder.begin_node("poweroff");
+    builder.add_prop_string("compatible", "syscon-poweroff");
+    builder.add_prop_u32("regmap", 101);
+    builder.add_prop_u32("offset", 0);
+    builder.add_prop_u32("value", 0x5555);
+    builder.end_node();
+
+    builder.begin_node("reboot");
+    builder.add_prop_string("compatible", "syscon-reboot");
+    builder.add_prop_u32("regmap", 101);
+    builder.add_prop_u32("offset", 0);
+    builder.add_prop_u32("value", 0x7777);
     builder.end_node();
     
+    // UARTs @ 0x1000_0000, 0x1000_0100, ...
+    for i in 0..num_uarts {
+        builder.begin_node(&format!("serial@{:x}", uart_base(i)));
+        builder.add_prop_string("compatible", "ns16550a");
+        builder.add_prop_reg64(uart_base(i), UART_SIZE);
+        builder.add_prop_u32("clock-frequency", 3686400);
+        builder.add_prop_u32("interrupts", uart_irq(i)); // 10, 11, ...
+        builder.add_prop_u32("interrupt-parent", 100); // PLIC phandle
+        builder.end_node();
+    }
+    
     // VirtIO MMIO devices @ 0x1000_1000..0x1000_8000 (8 slots)
     // These are discovered by the kernel at runtime via DTB parsing
     for i in 0..8u64 {
@@ -259,16 +347,57 @@ pub fn generate_dtb(
     builder.finish()
 }
 
+/// The DTB for the machine described by the first four arguments with
+/// `options` applied: the generated tree, or `options.base` with its
+/// `/chosen` updated, then each overlay.
+pub fn build_dtb(
+    num_harts: usize,
+    memory_size: u64,
+    d1_config: &D1DeviceConfig,
+    num_uarts: usize,
+    options: &DtbOptions,
+) -> Result<Vec<u8>, String> {
+    let generated;
+    let base = match &options.base {
+        Some(base) => base,
+        None => {
+            generated = generate_dtb_with_chosen(num_harts, memory_size, d1_config, num_uarts, &options.chosen);
+            if options.overlays.is_empty() {
+                return Ok(generated);
+            }
+            &generated
+        }
+    };
+    let mut tree = FdtNode::parse(base).map_err(|e| format!("Invalid DTB: {}", e))?;
+    tree.set_chosen(&options.chosen);
+    for (i, overlay) in options.overlays.iter().enumerate() {
+        let overlay = FdtNode::parse(overlay).map_err(|e| format!("Invalid DTB overlay {}: {}", i + 1, e))?;
+        tree.apply_overlay(&overlay)
+            .map_err(|e| format!("Cannot apply DTB overlay {}: {}", i + 1, e))?;
+    }
+    let dtb = tree.to_blob();
+    if dtb.len() > DTB_MAX_SIZE {
+        return Err(format!("DTB is {} bytes, over the {} byte limit", dtb.len(), DTB_MAX_SIZE));
+    }
+    Ok(dtb)
+}
+
 /// Write the DTB to DRAM at the standard location.
 ///
 /// # Returns
 /// The physical address where the DTB was written.
 pub fn write_dtb_to_dram(dram: &Dram, dtb: &[u8]) -> u64 {
-    let offset = DTB_ADDRESS - 0x8000_0000; // Offset from DRAM base
+    write_dtb_to_dram_at(dram, dtb, DTB_ADDRESS)
+}
+
+/// Write the DTB to DRAM at `address`, e.g. above a kernel that reaches
+/// past [`DTB_ADDRESS`].
+pub fn write_dtb_to_dram_at(dram: &Dram, dtb: &[u8], address: u64) -> u64 {
+    let offset = address - 0x8000_0000; // Offset from DRAM base
     for (i, byte) in dtb.iter().enumerate() {
         let _ = dram.store_8(offset + i as u64, *byte as u64);
     }
-    DTB_ADDRESS
+    address
 }
 
 /// Simple DTB builder that constructs a valid FDT blob.
@@ -341,6 +470,16 @@ impl DtbBuilder {
         self.write_u32(size as u32);
     }
     
+    fn add_prop(&mut self, name: &str, value: &[u8]) {
+        let string_offset = self.get_string_offset(name);
+
+        self.write_u32(FDT_PROP);
+        self.write_u32(value.len() as u32);
+        self.write_u32(string_offset);
+        self.struct_block.extend_from_slice(value);
+        self.align4();
+    }
+
     fn add_prop_empty(&mut self, name: &str) {
         let string_offset = self.get_string_offset(name);
         
@@ -458,4 +597,34 @@ mod tests {
         let version = u32::from_be_bytes([dtb[20], dtb[21], dtb[22], dtb[23]]);
         assert_eq!(version, FDT_VERSION);
     }
+
+    #[test]
+    fn test_dtb_multiple_uarts() {
+        let contains = |dtb: &[u8], needle: &[u8]| dtb.windows(needle.len()).any(|w| w == needle);
+        let config = D1DeviceConfig::default();
+
+        let dtb = generate_dtb_with_uarts(1, 256 * 1024 * 1024, &config, 3);
+        assert!(contains(&dtb, b"serial@10000000\0"));
+        assert!(contains(&dtb, b"serial@10000100\0"));
+        assert!(contains(&dtb, b"serial@10000200\0"));
+        assert!(!contains(&dtb, b"serial@10000300\0"));
+        assert!(contains(&dtb, b"/soc/serial@10000200\0"));
+
+        let dtb = generate_dtb(1, 256 * 1024 * 1024, &config);
+        assert!(!contains(&dtb, b"serial@10000100\0"));
+    }
+
+    #[test]
+    fn test_dtb_plic_contexts_and_syscon() {
+        let contains = |dtb: &[u8], needle: &[u8]| dtb.windows(needle.len()).any(|w| w == needle);
+        let dtb = generate_dtb(1, 256 * 1024 * 1024, &D1DeviceConfig::default());
+
+        // Context 0 is hart 0's M-mode context, context 1 its S-mode one
+        let plic: Vec<u8> = [1u32, 11, 1, 9].iter().flat_map(|v| v.to_be_bytes()).collect();
+        assert!(contains(&dtb, &plic));
+
+        assert!(contains(&dtb, b"sifive,test1\0sifive,test0\0syscon\0"));
+        assert!(contains(&dtb, b"syscon-poweroff\0"));
+        assert!(contains(&dtb, b"syscon-reboot\0"));
+    }
 }
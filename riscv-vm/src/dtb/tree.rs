@@ -0,0 +1,483 @@
+//! Device tree editing: parse a DTB into nodes, change it, write it back.
+//!
+//! Used for DTBs from the boot partition, whose `/chosen` is rewritten,
+//! and for applying overlays. Memory reservation entries are dropped;
+//! trees describe reserved memory in `/reserved-memory` nodes instead.
+
+use super::{Chosen, DtbBuilder, FDT_BEGIN_NODE, FDT_END, FDT_END_NODE, FDT_MAGIC, FDT_PROP};
+
+const FDT_NOP: u32 = 0x00000004;
+
+/// A device tree node with its properties and children, in blob order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FdtNode {
+    /// `name@unit`; empty for the root.
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    /// Parse a flattened device tree blob, returning its root node.
+    pub fn parse(blob: &[u8]) -> Result<Self, String> {
+        let be32 = |at: usize| {
+            blob.get(at..at + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| "truncated".to_string())
+        };
+        if be32(0)? != FDT_MAGIC {
+            return Err("bad magic".to_string());
+        }
+        if be32(4)? as usize > blob.len() {
+            return Err("truncated".to_string());
+        }
+        let strings = be32(12)? as usize;
+        let c_string = |at: usize| {
+            let bytes = blob.get(at..).ok_or("truncated")?;
+            let end = bytes
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or("unterminated string")?;
+            Ok::<_, String>(String::from_utf8_lossy(&bytes[..end]).into_owned())
+        };
+
+        let mut pos = be32(8)? as usize;
+        let mut stack: Vec<FdtNode> = Vec::new();
+        loop {
+            let token = be32(pos)?;
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(pos)?;
+                    pos += (name.len() + 1).next_multiple_of(4);
+                    stack.push(FdtNode {
+                        name,
+                        ..Default::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or("unbalanced nodes")?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => {
+                            return if be32(pos)? == FDT_END {
+                                Ok(node)
+                            } else {
+                                Err("nodes after the root".to_string())
+                            };
+                        }
+                    }
+                }
+                FDT_PROP => {
+                    let len = be32(pos)? as usize;
+                    let name = c_string(strings + be32(pos + 4)? as usize)?;
+                    pos += 8;
+                    let value = blob.get(pos..pos + len).ok_or("truncated")?.to_vec();
+                    pos += len.next_multiple_of(4);
+                    stack
+                        .last_mut()
+                        .ok_or("property outside a node")?
+                        .props
+                        .push((name, value));
+                }
+                FDT_NOP => {}
+                FDT_END => return Err("unterminated node".to_string()),
+                token => return Err(format!("unknown token {:#x}", token)),
+            }
+        }
+    }
+
+    /// Write the tree out as a blob.
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut builder = DtbBuilder::new();
+        self.write(&mut builder);
+        builder.finish()
+    }
+
+    fn write(&self, builder: &mut DtbBuilder) {
+        builder.begin_node(&self.name);
+        for (name, value) in &self.props {
+            builder.add_prop(name, value);
+        }
+        for child in &self.children {
+            child.write(builder);
+        }
+        builder.end_node();
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// A string property, up to its first NUL.
+    pub fn prop_str(&self, name: &str) -> Option<&str> {
+        let value = self.prop(name)?;
+        let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        std::str::from_utf8(&value[..end]).ok()
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        let value: [u8; 4] = self.prop(name)?.try_into().ok()?;
+        Some(u32::from_be_bytes(value))
+    }
+
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        match self.props.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = value,
+            None => self.props.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    /// The node at `path` (`/soc/serial@10000000`). A component without a
+    /// unit address also matches a node that has one.
+    pub fn find(&self, path: &str) -> Option<&FdtNode> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(self, |node, component| {
+                node.child(component).or_else(|| {
+                    node.children
+                        .iter()
+                        .find(|c| c.name.split('@').next() == Some(component))
+                })
+            })
+    }
+
+    pub fn find_mut(&mut self, path: &str) -> Option<&mut FdtNode> {
+        let mut node = self;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let at = node
+                .children
+                .iter()
+                .position(|c| c.name == component)
+                .or_else(|| {
+                    node.children
+                        .iter()
+                        .position(|c| c.name.split('@').next() == Some(component))
+                })?;
+            node = &mut node.children[at];
+        }
+        Some(node)
+    }
+
+    /// Set `/chosen` properties from `chosen`, leaving the rest alone.
+    pub fn set_chosen(&mut self, chosen: &Chosen) {
+        if self.child("chosen").is_none() {
+            self.children.push(FdtNode {
+                name: "chosen".to_string(),
+                ..Default::default()
+            });
+        }
+        let node = self.find_mut("/chosen").unwrap();
+        if let Some(bootargs) = &chosen.bootargs {
+            node.set_prop("bootargs", [bootargs.as_bytes(), b"\0"].concat());
+        }
+        if let Some((start, end)) = chosen.initrd {
+            node.set_prop("linux,initrd-start", start.to_be_bytes().to_vec());
+            node.set_prop("linux,initrd-end", end.to_be_bytes().to_vec());
+        }
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle")
+            .or_else(|| self.prop_u32("linux,phandle"))
+    }
+
+    fn max_phandle(&self) -> u32 {
+        self.children
+            .iter()
+            .map(FdtNode::max_phandle)
+            .fold(self.phandle().unwrap_or(0), u32::max)
+    }
+
+    /// Path of the node whose phandle is `phandle`.
+    fn path_of(&self, phandle: u32) -> Option<String> {
+        if self.phandle() == Some(phandle) {
+            return Some("/".to_string());
+        }
+        self.children.iter().find_map(|child| {
+            let rest = child.path_of(phandle)?;
+            Some(format!("/{}{}", child.name, rest.trim_end_matches('/')))
+        })
+    }
+
+    /// Apply a compiled overlay (`dtc -@`): renumber its phandles above
+    /// ours, resolve its `__fixups__` through our `__symbols__`, then merge
+    /// each fragment's `__overlay__` into the node named by `target-path`
+    /// or `target`.
+    pub fn apply_overlay(&mut self, overlay: &FdtNode) -> Result<(), String> {
+        let mut overlay = overlay.clone();
+        let delta = self.max_phandle();
+        overlay.shift_phandles(delta);
+        if let Some(local) = overlay.child("__local_fixups__").cloned() {
+            overlay.local_fixups(&local, delta)?;
+        }
+
+        if let Some(fixups) = overlay.child("__fixups__").cloned() {
+            // Labelled nodes without a phandle get one above both trees'.
+            let mut free = overlay.max_phandle().max(delta) + 1;
+            for (label, refs) in &fixups.props {
+                let path = self
+                    .child("__symbols__")
+                    .and_then(|s| s.prop_str(label))
+                    .ok_or_else(|| format!("unknown label '{}'", label))?
+                    .to_string();
+                let target = self
+                    .find_mut(&path)
+                    .ok_or_else(|| format!("no node {}", path))?;
+                let phandle = target.phandle().unwrap_or_else(|| {
+                    target.set_prop("phandle", free.to_be_bytes().to_vec());
+                    free += 1;
+                    free - 1
+                });
+                for fixup in refs.split(|&b| b == 0).filter(|r| !r.is_empty()) {
+                    let fixup = String::from_utf8_lossy(fixup);
+                    let bad = || format!("bad fixup '{}'", fixup);
+                    let mut parts = fixup.rsplitn(3, ':');
+                    let (Some(offset), Some(prop), Some(node)) =
+                        (parts.next(), parts.next(), parts.next())
+                    else {
+                        return Err(bad());
+                    };
+                    let offset: usize = offset.parse().map_err(|_| bad())?;
+                    let value = overlay
+                        .find_mut(node)
+                        .and_then(|n| n.props.iter_mut().find(|(n, _)| n == prop))
+                        .and_then(|(_, v)| v.get_mut(offset..offset + 4))
+                        .ok_or_else(bad)?;
+                    value.copy_from_slice(&phandle.to_be_bytes());
+                }
+            }
+        }
+
+        for fragment in overlay
+            .children
+            .iter()
+            .filter(|c| !c.name.starts_with("__"))
+        {
+            let Some(content) = fragment.child("__overlay__") else {
+                continue;
+            };
+            let path = match (
+                fragment.prop_str("target-path"),
+                fragment.prop_u32("target"),
+            ) {
+                (Some(path), _) => path.to_string(),
+                (None, Some(phandle)) => self.path_of(phandle).ok_or_else(|| {
+                    format!("{}: no node with phandle {}", fragment.name, phandle)
+                })?,
+                (None, None) => return Err(format!("{}: no target", fragment.name)),
+            };
+            self.find_mut(&path)
+                .ok_or_else(|| format!("{}: no node {}", fragment.name, path))?
+                .merge(content);
+        }
+        Ok(())
+    }
+
+    fn shift_phandles(&mut self, delta: u32) {
+        for (name, value) in &mut self.props {
+            if (name == "phandle" || name == "linux,phandle") && value.len() == 4 {
+                let phandle = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                value.copy_from_slice(&phandle.wrapping_add(delta).to_be_bytes());
+            }
+        }
+        for child in &mut self.children {
+            child.shift_phandles(delta);
+        }
+    }
+
+    /// Add `delta` to the phandle references `local` (a `__local_fixups__`
+    /// subtree mirroring this node) lists by property and offset.
+    fn local_fixups(&mut self, local: &FdtNode, delta: u32) -> Result<(), String> {
+        for (prop, offsets) in &local.props {
+            let value = self
+                .props
+                .iter_mut()
+                .find(|(n, _)| n == prop)
+                .map(|(_, v)| v)
+                .ok_or_else(|| format!("local fixup of missing property {}", prop))?;
+            for offset in offsets.chunks_exact(4) {
+                let offset =
+                    u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]) as usize;
+                let cell = value
+                    .get_mut(offset..offset + 4)
+                    .ok_or_else(|| format!("local fixup beyond {}", prop))?;
+                let phandle = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+                cell.copy_from_slice(&phandle.wrapping_add(delta).to_be_bytes());
+            }
+        }
+        for sub in &local.children {
+            let child = self
+                .children
+                .iter_mut()
+                .find(|c| c.name == sub.name)
+                .ok_or_else(|| format!("local fixup of missing node {}", sub.name))?;
+            child.local_fixups(sub, delta)?;
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: &FdtNode) {
+        for (name, value) in &other.props {
+            self.set_prop(name, value.clone());
+        }
+        for child in &other.children {
+            match self.children.iter_mut().find(|c| c.name == child.name) {
+                Some(existing) => existing.merge(child),
+                None => self.children.push(child.clone()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtb::{D1DeviceConfig, generate_dtb};
+
+    fn node(name: &str, props: &[(&str, &[u8])], children: Vec<FdtNode>) -> FdtNode {
+        FdtNode {
+            name: name.to_string(),
+            props: props
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_vec()))
+                .collect(),
+            children,
+        }
+    }
+
+    #[test]
+    fn generated_dtb_round_trips() {
+        let dtb = generate_dtb(2, 256 << 20, &D1DeviceConfig::default());
+        let mut tree = FdtNode::parse(&dtb).unwrap();
+        assert_eq!(tree.to_blob(), dtb);
+        assert_eq!(
+            tree.find("/soc/serial").unwrap().prop_str("compatible"),
+            Some("ns16550a")
+        );
+        assert_eq!(
+            tree.path_of(100).as_deref(),
+            Some("/soc/interrupt-controller@c000000")
+        );
+
+        tree.set_chosen(&Chosen {
+            bootargs: Some("console=ttyS0 quiet".into()),
+            initrd: Some((0x9000_0000, 0x9100_0000)),
+        });
+        let tree = FdtNode::parse(&tree.to_blob()).unwrap();
+        let chosen = tree.find("/chosen").unwrap();
+        assert_eq!(chosen.prop_str("bootargs"), Some("console=ttyS0 quiet"));
+        assert_eq!(
+            chosen.prop("linux,initrd-end"),
+            Some(&0x9100_0000u64.to_be_bytes()[..])
+        );
+        assert!(FdtNode::parse(&dtb[..100]).is_err());
+    }
+
+    #[test]
+    fn overlays_resolve_labels_and_local_phandles() {
+        let mut base =
+            FdtNode::parse(&generate_dtb(1, 256 << 20, &D1DeviceConfig::default())).unwrap();
+        base.children.push(node(
+            "__symbols__",
+            &[
+                ("plic", b"/soc/interrupt-controller@c000000\0"),
+                ("uart0", b"/soc/serial@10000000\0"),
+            ],
+            vec![],
+        ));
+
+        // fragment@0 adds a node referring to another new node by phandle;
+        // fragment@1 and fragment@2 reach existing nodes by label.
+        let overlay = node(
+            "",
+            &[],
+            vec![
+                node(
+                    "fragment@0",
+                    &[("target-path", b"/soc\0")],
+                    vec![node(
+                        "__overlay__",
+                        &[],
+                        vec![
+                            node("gpio@0", &[("phandle", &1u32.to_be_bytes())], vec![]),
+                            node("led", &[("gpios", &[0, 0, 0, 1, 0, 0, 0, 7])], vec![]),
+                        ],
+                    )],
+                ),
+                node(
+                    "fragment@1",
+                    &[("target", &u32::MAX.to_be_bytes())],
+                    vec![node("__overlay__", &[("status", b"disabled\0")], vec![])],
+                ),
+                node(
+                    "fragment@2",
+                    &[("target", &u32::MAX.to_be_bytes())],
+                    vec![node(
+                        "__overlay__",
+                        &[("current-speed", &115200u32.to_be_bytes())],
+                        vec![],
+                    )],
+                ),
+                node(
+                    "__fixups__",
+                    &[
+                        ("plic", b"/fragment@1:target:0\0"),
+                        ("uart0", b"/fragment@2:target:0\0"),
+                    ],
+                    vec![],
+                ),
+                node(
+                    "__local_fixups__",
+                    &[],
+                    vec![node(
+                        "fragment@0",
+                        &[],
+                        vec![node(
+                            "__overlay__",
+                            &[],
+                            vec![node("led", &[("gpios", &0u32.to_be_bytes())], vec![])],
+                        )],
+                    )],
+                ),
+            ],
+        );
+        let overlay = FdtNode::parse(&overlay.to_blob()).unwrap();
+        base.apply_overlay(&overlay).unwrap();
+
+        assert_eq!(base.find("/soc/gpio@0").unwrap().phandle(), Some(102));
+        assert_eq!(
+            base.find("/soc/led").unwrap().prop("gpios"),
+            Some(&[0, 0, 0, 102, 0, 0, 0, 7][..])
+        );
+        let plic = base.find("/soc/interrupt-controller@c000000").unwrap();
+        assert_eq!(plic.prop_str("status"), Some("disabled"));
+        // The UART had no phandle, so it gets the next free one.
+        let uart = base.find("/soc/serial@10000000").unwrap();
+        assert_eq!(
+            (uart.prop_u32("current-speed"), uart.phandle()),
+            (Some(115200), Some(103))
+        );
+
+        let missing = node(
+            "",
+            &[],
+            vec![node(
+                "__fixups__",
+                &[("gpu", b"/fragment@0:target:0\0")],
+                vec![],
+            )],
+        );
+        assert_eq!(
+            base.apply_overlay(&missing),
+            Err("unknown label 'gpu'".to_string())
+        );
+    }
+}
@@ -9,6 +9,7 @@ use super::decoder::{self, Op};
 use super::microop::MicroOp;
 use crate::Trap;
 use crate::bus::Bus;
+use crate::cpu::pmp::Pmp;
 use crate::csr::Mode;
 use crate::mmu::{self, AccessType, Tlb};
 
@@ -32,6 +33,8 @@ pub struct Block {
     pub exec_count: u32,
     /// Generation counter (for cache invalidation).
     pub generation: u32,
+    /// Privilege mode the block was compiled (and fetch-checked) in.
+    pub mode: Mode,
     /// Next block PC for direct chaining (set when block ends with JAL or fallthrough).
     /// If Some(pc), executor can jump directly to cached block at pc without lookup.
     pub next_block_pc: Option<u64>,
@@ -48,6 +51,7 @@ impl Block {
             ops: [MicroOp::Fence; MAX_BLOCK_SIZE], // Dummy init
             exec_count: 0,
             generation,
+            mode: Mode::Machine,
             next_block_pc: None,
         }
     }
@@ -95,26 +99,23 @@ pub struct BlockCompiler<'a> {
     pub mstatus: u64,
     pub mode: Mode,
     pub tlb: &'a mut Tlb,
+    pub pmp: &'a Pmp,
+    /// Maximum number of instructions per block (at most `MAX_BLOCK_SIZE`).
+    /// Set to 1 to retire exactly one instruction per block, e.g. for tracing.
+    pub max_ops: usize,
 }
 
 impl<'a> BlockCompiler<'a> {
     /// Compile a basic block starting at `pc`.
     pub fn compile(&mut self, start_pc: u64, generation: u32) -> CompileResult {
         // Translate start PC to physical address
-        let start_pa = match mmu::translate(
-            self.bus,
-            self.tlb,
-            self.mode,
-            self.satp,
-            self.mstatus,
-            start_pc,
-            AccessType::Instruction,
-        ) {
+        let start_pa = match self.translate_fetch(start_pc) {
             Ok(pa) => pa,
             Err(trap) => return CompileResult::Trap(trap),
         };
 
         let mut block = Block::new(start_pc, start_pa, generation);
+        block.mode = self.mode;
         let mut pc = start_pc;
         let mut pc_offset: u16 = 0;
 
@@ -176,20 +177,15 @@ impl<'a> BlockCompiler<'a> {
 
             // Check page boundary - chain to next page
             let next_page = (start_pc & !0xFFF) + 0x1000;
-            if pc >= next_page {
+            if pc >= next_page || block.len as usize >= self.max_ops {
                 block.next_block_pc = Some(pc);
                 return CompileResult::Ok(block);
             }
         }
     }
 
-    /// Fetch and expand an instruction at the given PC.
-    fn fetch_insn(&mut self, pc: u64) -> Result<(u32, u8), Trap> {
-        if pc % 2 != 0 {
-            return Err(Trap::InstructionAddressMisaligned(pc));
-        }
-
-        // Translate PC
+    /// Translate an instruction fetch and check it against the PMP.
+    fn translate_fetch(&mut self, pc: u64) -> Result<u64, Trap> {
         let pa = mmu::translate(
             self.bus,
             self.tlb,
@@ -199,6 +195,20 @@ impl<'a> BlockCompiler<'a> {
             pc,
             AccessType::Instruction,
         )?;
+        if !self.pmp.allows(pa, self.mode, AccessType::Instruction) {
+            return Err(Trap::InstructionAccessFault(pc));
+        }
+        Ok(pa)
+    }
+
+    /// Fetch and expand an instruction at the given PC.
+    fn fetch_insn(&mut self, pc: u64) -> Result<(u32, u8), Trap> {
+        if pc % 2 != 0 {
+            return Err(Trap::InstructionAddressMisaligned(pc));
+        }
+
+        // Translate PC
+        let pa = self.translate_fetch(pc)?;
 
         // Optimization: if PC is 4-byte aligned, try to read 32 bits at once
         if pc % 4 == 0 {
@@ -228,15 +238,7 @@ impl<'a> BlockCompiler<'a> {
         } else {
             // 32-bit instruction; fetch high half
             let pc_hi = pc.wrapping_add(2);
-            let pa_hi = mmu::translate(
-                self.bus,
-                self.tlb,
-                self.mode,
-                self.satp,
-                self.mstatus,
-                pc_hi,
-                AccessType::Instruction,
-            )?;
+            let pa_hi = self.translate_fetch(pc_hi)?;
             let hi = self.bus.read16(pa_hi).map_err(|e| match e {
                 Trap::LoadAccessFault(_) => Trap::InstructionAccessFault(pc),
                 Trap::LoadAddressMisaligned(_) => Trap::InstructionAddressMisaligned(pc),
@@ -1,9 +1,12 @@
 //! Block Cache for the JIT-less Superblock Engine.
 //!
 //! Manages a cache of compiled basic blocks keyed by PC. Uses generation-based
-//! invalidation for efficient TLB flush handling.
+//! invalidation for efficient TLB flush handling. A block only matches in the
+//! privilege mode it was compiled in, since its fetch permission (PMP, PTE U
+//! bit) was checked for that mode.
 
 use super::block::Block;
+use crate::csr::Mode;
 #[cfg(test)]
 use super::microop::MicroOp;
 use std::collections::HashMap;
@@ -37,12 +40,12 @@ impl BlockCache {
         }
     }
 
-    /// Look up a block by PC.
+    /// Look up a block by PC for execution in `mode`.
     /// Returns a reference to the block if found and valid.
     #[inline]
-    pub fn get(&mut self, pc: u64) -> Option<&Block> {
+    pub fn get(&mut self, pc: u64, mode: Mode) -> Option<&Block> {
         if let Some(block) = self.blocks.get(&pc) {
-            if block.generation == self.generation {
+            if block.generation == self.generation && block.mode == mode {
                 self.hits += 1;
                 return Some(block);
             }
@@ -114,11 +117,11 @@ impl BlockCache {
 
     /// Look up a block and increment its exec_count in a single operation.
     /// This is more efficient than separate get() + get_mut() calls.
-    /// Returns a reference to the block if found and valid.
+    /// Returns a reference to the block if found and valid for `mode`.
     #[inline]
-    pub fn get_and_touch(&mut self, pc: u64) -> Option<&Block> {
+    pub fn get_and_touch(&mut self, pc: u64, mode: Mode) -> Option<&Block> {
         if let Some(block) = self.blocks.get_mut(&pc) {
-            if block.generation == self.generation {
+            if block.generation == self.generation && block.mode == mode {
                 self.hits += 1;
                 block.exec_count = block.exec_count.saturating_add(1);
                 return Some(&**block);
@@ -178,7 +181,7 @@ mod tests {
         let block = make_test_block(0x8000_0000, cache.generation);
         cache.insert(block);
 
-        let found = cache.get(0x8000_0000);
+        let found = cache.get(0x8000_0000, Mode::Machine);
         assert!(found.is_some());
         assert_eq!(cache.hits, 1);
         assert_eq!(cache.misses, 0);
@@ -187,7 +190,7 @@ mod tests {
     #[test]
     fn test_cache_miss() {
         let mut cache = BlockCache::new();
-        let found = cache.get(0x8000_0000);
+        let found = cache.get(0x8000_0000, Mode::Machine);
         assert!(found.is_none());
         assert_eq!(cache.hits, 0);
         assert_eq!(cache.misses, 1);
@@ -200,11 +203,11 @@ mod tests {
         cache.insert(block);
 
         // Block should be found before flush
-        assert!(cache.get(0x8000_0000).is_some());
+        assert!(cache.get(0x8000_0000, Mode::Machine).is_some());
 
         // Flush and try again
         cache.flush();
-        assert!(cache.get(0x8000_0000).is_none());
+        assert!(cache.get(0x8000_0000, Mode::Machine).is_none());
         assert_eq!(cache.invalidations, 1);
     }
 
@@ -219,7 +222,18 @@ mod tests {
         cache.blocks.insert(0x8000_0000, Box::new(block));
 
         // Should not find it due to generation mismatch
-        assert!(cache.get(0x8000_0000).is_none());
+        assert!(cache.get(0x8000_0000, Mode::Machine).is_none());
+    }
+
+    #[test]
+    fn test_cache_mode_check() {
+        let mut cache = BlockCache::new();
+        cache.insert(make_test_block(0x8000_0000, cache.generation));
+
+        // Compiled in M-mode, so S-mode must recompile (and re-check PMP)
+        assert!(cache.get(0x8000_0000, Mode::Supervisor).is_none());
+        assert!(cache.get_and_touch(0x8000_0000, Mode::Supervisor).is_none());
+        assert!(cache.get(0x8000_0000, Mode::Machine).is_some());
     }
 
     #[test]
@@ -229,10 +243,10 @@ mod tests {
         cache.insert(block);
 
         // Hit
-        cache.get(0x8000_0000);
+        cache.get(0x8000_0000, Mode::Machine);
         // Miss
-        cache.get(0x8000_1000);
-        cache.get(0x8000_2000);
+        cache.get(0x8000_1000, Mode::Machine);
+        cache.get(0x8000_2000, Mode::Machine);
 
         let (hits, misses, size, hit_rate) = cache.stats();
         assert_eq!(hits, 1);
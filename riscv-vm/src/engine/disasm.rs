@@ -0,0 +1,844 @@
+//! RISC-V disassembler.
+//!
+//! Renders RV64IMAC, Zicsr, Zifencei and privileged instructions as GNU
+//! objdump-style assembly: ABI register names, CSR names, and the standard
+//! aliases (`li`, `mv`, `ret`, `beqz`, `csrr`, ...). Compressed instructions
+//! are shown as their 32-bit expansion, as objdump does. Encodings outside
+//! the supported ISA are printed as `.2byte`/`.4byte` directives.
+//!
+//! [`disassemble`] resolves branch and jump targets to absolute addresses;
+//! the `Display` impls for [`Op`] and [`MicroOp`] have no PC and print
+//! targets relative to the instruction (`.+8`, `.-4`).
+
+use super::decoder::{self, Op, Register};
+use super::microop::MicroOp;
+use crate::Mode;
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::csr::{CSR_SATP, csr_name};
+use crate::mmu;
+use std::fmt;
+
+/// ABI names of the integer registers.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Length in bytes of the instruction whose low half-word is `raw`.
+pub fn insn_len(raw: u32) -> u8 {
+    if raw & 0x3 == 0x3 { 4 } else { 2 }
+}
+
+/// Disassemble one instruction located at `pc`.
+///
+/// `raw` holds a 16-bit encoding in its low half or a full 32-bit one.
+pub fn disassemble(pc: u64, raw: u32) -> String {
+    render_raw(Target::At(pc), raw, true)
+}
+
+/// Like [`disassemble`], but without aliases (`addi a0,zero,1` rather
+/// than `li a0,1`), matching `objdump -M no-aliases` for 32-bit encodings.
+pub fn disassemble_no_aliases(pc: u64, raw: u32) -> String {
+    render_raw(Target::At(pc), raw, false)
+}
+
+/// Read the raw instruction at virtual address `pc` without side effects,
+/// translating with `satp` as seen from `mode`. Returns the bits and the
+/// instruction length.
+pub fn fetch(bus: &dyn Bus, mode: Mode, satp: u64, pc: u64) -> Option<(u32, u8)> {
+    let pa = mmu::peek_translate(bus, mode, satp, pc)?;
+    let lo = bus.read16(pa).ok()? as u32;
+    if insn_len(lo) == 2 {
+        return Some((lo, 2));
+    }
+    let pa_hi = mmu::peek_translate(bus, mode, satp, pc.wrapping_add(2))?;
+    let hi = bus.read16(pa_hi).ok()? as u32;
+    Some((lo | (hi << 16), 4))
+}
+
+/// Disassemble the instruction at `pc` in `cpu`'s current address space.
+pub fn disassemble_at(cpu: &Cpu, bus: &dyn Bus, pc: u64) -> Option<String> {
+    let satp = cpu.csrs[CSR_SATP as usize];
+    let (raw, _) = fetch(bus, cpu.mode, satp, pc)?;
+    Some(disassemble(pc, raw))
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match render_op(self, Target::Relative, true) {
+            Some(text) => f.write_str(&text),
+            None => write!(f, "<invalid {:?}>", self),
+        }
+    }
+}
+
+impl fmt::Display for MicroOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        to_op(self).fmt(f)
+    }
+}
+
+/// How to print PC-relative targets.
+#[derive(Clone, Copy)]
+enum Target {
+    At(u64),
+    Relative,
+}
+
+impl Target {
+    fn show(self, offset: i64) -> String {
+        match self {
+            Target::At(pc) => format!("0x{:x}", pc.wrapping_add(offset as u64)),
+            Target::Relative if offset < 0 => format!(".-{}", offset.unsigned_abs()),
+            Target::Relative => format!(".+{}", offset),
+        }
+    }
+}
+
+fn render_raw(target: Target, raw: u32, aliases: bool) -> String {
+    if insn_len(raw) == 2 {
+        let half = raw as u16;
+        if half == 0 && aliases {
+            return "unimp".to_string();
+        }
+        return match decoder::expand_compressed(half) {
+            Ok(insn) => render_raw(target, insn, aliases),
+            Err(_) => format!(".2byte 0x{:x}", half),
+        };
+    }
+    if raw == 0xc000_1073 && aliases {
+        return "unimp".to_string();
+    }
+
+    // The decoder ignores a few must-be-zero fields; reject those here so
+    // every rendered instruction re-assembles to the same bits.
+    let text = match (raw & 0x7f, raw & 0x000f_8f80) {
+        (0x0f, 0) => render_fence(raw),
+        (0x0f, _) => None,
+        (0x67, _) if (raw >> 12) & 0x7 != 0 => None,
+        _ => decoder::decode(raw)
+            .ok()
+            .and_then(|op| render_op(&op, target, aliases)),
+    };
+    text.unwrap_or_else(|| format!(".4byte 0x{:x}", raw))
+}
+
+fn render_fence(raw: u32) -> Option<String> {
+    match (raw >> 12) & 0x7 {
+        0 => {
+            let fm = raw >> 28;
+            let pred = (raw >> 24) & 0xf;
+            let succ = (raw >> 20) & 0xf;
+            Some(match (fm, pred, succ) {
+                (0b1000, 0b0011, 0b0011) => "fence.tso".to_string(),
+                (0, 0xf, 0xf) => "fence".to_string(),
+                (0, _, _) => format!("fence {},{}", fence_set(pred), fence_set(succ)),
+                _ => return None,
+            })
+        }
+        1 if raw >> 20 == 0 => Some("fence.i".to_string()),
+        _ => None,
+    }
+}
+
+fn fence_set(bits: u32) -> String {
+    let set: String = "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| bits & (8 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if set.is_empty() { "0".to_string() } else { set }
+}
+
+fn reg(r: Register) -> &'static str {
+    ABI_NAMES[r.to_usize()]
+}
+
+fn csr(addr: u32) -> String {
+    let addr = (addr & 0xfff) as u16;
+    match csr_name(addr) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:x}", addr),
+    }
+}
+
+fn render_op(op: &Op, target: Target, aliases: bool) -> Option<String> {
+    use Register::X0;
+    const RA: Register = Register::X1;
+
+    let text = match *op {
+        Op::Lui { rd, imm } => format!("lui {},0x{:x}", reg(rd), (imm >> 12) & 0xfffff),
+        Op::Auipc { rd, imm } => format!("auipc {},0x{:x}", reg(rd), (imm >> 12) & 0xfffff),
+        Op::Jal { rd, imm } => match rd {
+            X0 if aliases => format!("j {}", target.show(imm)),
+            RA if aliases => format!("jal {}", target.show(imm)),
+            _ => format!("jal {},{}", reg(rd), target.show(imm)),
+        },
+        Op::Jalr { rd, rs1, imm } => match (rd, rs1, imm) {
+            (X0, RA, 0) if aliases => "ret".to_string(),
+            (X0, _, 0) if aliases => format!("jr {}", reg(rs1)),
+            (RA, _, 0) if aliases => format!("jalr {}", reg(rs1)),
+            _ => format!("jalr {},{}({})", reg(rd), imm, reg(rs1)),
+        },
+        Op::Branch {
+            rs1,
+            rs2,
+            imm,
+            funct3,
+        } => {
+            let name = match funct3 {
+                0 => "beq",
+                1 => "bne",
+                4 => "blt",
+                5 => "bge",
+                6 => "bltu",
+                7 => "bgeu",
+                _ => return None,
+            };
+            let to = target.show(imm);
+            match (name, rs1, rs2) {
+                ("beq" | "bne" | "blt" | "bge", _, X0) if aliases => {
+                    let alias = match name {
+                        "beq" => "beqz",
+                        "bne" => "bnez",
+                        "blt" => "bltz",
+                        _ => "bgez",
+                    };
+                    format!("{} {},{}", alias, reg(rs1), to)
+                }
+                ("blt", X0, _) if aliases => format!("bgtz {},{}", reg(rs2), to),
+                ("bge", X0, _) if aliases => format!("blez {},{}", reg(rs2), to),
+                _ => format!("{} {},{},{}", name, reg(rs1), reg(rs2), to),
+            }
+        }
+        Op::Load {
+            rd,
+            rs1,
+            imm,
+            funct3,
+        } => {
+            let name = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"].get(funct3 as usize)?;
+            format!("{} {},{}({})", name, reg(rd), imm, reg(rs1))
+        }
+        Op::Store {
+            rs1,
+            rs2,
+            imm,
+            funct3,
+        } => {
+            let name = ["sb", "sh", "sw", "sd"].get(funct3 as usize)?;
+            format!("{} {},{}({})", name, reg(rs2), imm, reg(rs1))
+        }
+        Op::OpImm {
+            rd,
+            rs1,
+            imm,
+            funct3,
+            ..
+        } => {
+            let (rd_name, rs_name) = (reg(rd), reg(rs1));
+            let shamt = imm & 0x3f;
+            match funct3 {
+                0 if aliases && rd == X0 && rs1 == X0 && imm == 0 => "nop".to_string(),
+                0 if aliases && rs1 == X0 => format!("li {},{}", rd_name, imm),
+                0 if aliases && imm == 0 => format!("mv {},{}", rd_name, rs_name),
+                3 if aliases && imm == 1 => format!("seqz {},{}", rd_name, rs_name),
+                4 if aliases && imm == -1 => format!("not {},{}", rd_name, rs_name),
+                1 if imm >> 6 == 0 => format!("slli {},{},0x{:x}", rd_name, rs_name, shamt),
+                5 if imm >> 6 == 0 => format!("srli {},{},0x{:x}", rd_name, rs_name, shamt),
+                5 if imm >> 6 == 0x10 => format!("srai {},{},0x{:x}", rd_name, rs_name, shamt),
+                1 | 5 => return None,
+                _ => {
+                    let name = ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"];
+                    format!("{} {},{},{}", name[funct3 as usize], rd_name, rs_name, imm)
+                }
+            }
+        }
+        Op::Op {
+            rd,
+            rs1,
+            rs2,
+            funct3,
+            funct7,
+        } => {
+            let name = match (funct7, funct3) {
+                (0x00, 0) => "add",
+                (0x20, 0) => "sub",
+                (0x00, 1) => "sll",
+                (0x00, 2) => "slt",
+                (0x00, 3) => "sltu",
+                (0x00, 4) => "xor",
+                (0x00, 5) => "srl",
+                (0x20, 5) => "sra",
+                (0x00, 6) => "or",
+                (0x00, 7) => "and",
+                (0x01, 0) => "mul",
+                (0x01, 1) => "mulh",
+                (0x01, 2) => "mulhsu",
+                (0x01, 3) => "mulhu",
+                (0x01, 4) => "div",
+                (0x01, 5) => "divu",
+                (0x01, 6) => "rem",
+                (0x01, 7) => "remu",
+                _ => return None,
+            };
+            match (name, rs1, rs2) {
+                // c.mv expands to add rd,zero,rs2
+                ("add", X0, _) if aliases => format!("mv {},{}", reg(rd), reg(rs2)),
+                ("sub", X0, _) if aliases => format!("neg {},{}", reg(rd), reg(rs2)),
+                ("sltu", X0, _) if aliases => format!("snez {},{}", reg(rd), reg(rs2)),
+                ("slt", _, X0) if aliases => format!("sltz {},{}", reg(rd), reg(rs1)),
+                ("slt", X0, _) if aliases => format!("sgtz {},{}", reg(rd), reg(rs2)),
+                _ => format!("{} {},{},{}", name, reg(rd), reg(rs1), reg(rs2)),
+            }
+        }
+        Op::OpImm32 {
+            rd,
+            rs1,
+            imm,
+            funct3,
+            ..
+        } => {
+            let shamt = imm & 0x1f;
+            match funct3 {
+                0 if aliases && imm == 0 => format!("sext.w {},{}", reg(rd), reg(rs1)),
+                0 => format!("addiw {},{},{}", reg(rd), reg(rs1), imm),
+                1 if imm >> 5 == 0 => format!("slliw {},{},0x{:x}", reg(rd), reg(rs1), shamt),
+                5 if imm >> 5 == 0 => format!("srliw {},{},0x{:x}", reg(rd), reg(rs1), shamt),
+                5 if imm >> 5 == 0x20 => format!("sraiw {},{},0x{:x}", reg(rd), reg(rs1), shamt),
+                _ => return None,
+            }
+        }
+        Op::Op32 {
+            rd,
+            rs1,
+            rs2,
+            funct3,
+            funct7,
+        } => {
+            let name = match (funct7, funct3) {
+                (0x00, 0) => "addw",
+                (0x20, 0) => "subw",
+                (0x00, 1) => "sllw",
+                (0x00, 5) => "srlw",
+                (0x20, 5) => "sraw",
+                (0x01, 0) => "mulw",
+                (0x01, 4) => "divw",
+                (0x01, 5) => "divuw",
+                (0x01, 6) => "remw",
+                (0x01, 7) => "remuw",
+                _ => return None,
+            };
+            if aliases && name == "subw" && rs1 == X0 {
+                format!("negw {},{}", reg(rd), reg(rs2))
+            } else {
+                format!("{} {},{},{}", name, reg(rd), reg(rs1), reg(rs2))
+            }
+        }
+        Op::System {
+            rd,
+            rs1,
+            funct3,
+            imm,
+        } => render_system(rd, rs1, funct3, imm, aliases)?,
+        Op::Amo {
+            rd,
+            rs1,
+            rs2,
+            funct3,
+            funct5,
+            aq,
+            rl,
+        } => {
+            let width = match funct3 {
+                2 => "w",
+                3 => "d",
+                _ => return None,
+            };
+            let ordering = match (aq, rl) {
+                (false, false) => "",
+                (true, false) => ".aq",
+                (false, true) => ".rl",
+                (true, true) => ".aqrl",
+            };
+            let name = match funct5 {
+                0x02 if rs2 == X0 => {
+                    return Some(format!(
+                        "lr.{}{} {},({})",
+                        width,
+                        ordering,
+                        reg(rd),
+                        reg(rs1)
+                    ));
+                }
+                0x03 => "sc",
+                0x01 => "amoswap",
+                0x00 => "amoadd",
+                0x04 => "amoxor",
+                0x0c => "amoand",
+                0x08 => "amoor",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return None,
+            };
+            format!(
+                "{}.{}{} {},{},({})",
+                name,
+                width,
+                ordering,
+                reg(rd),
+                reg(rs2),
+                reg(rs1)
+            )
+        }
+        Op::Fence => "fence".to_string(),
+    };
+    Some(text)
+}
+
+fn render_system(
+    rd: Register,
+    rs1: Register,
+    funct3: u32,
+    imm: u32,
+    aliases: bool,
+) -> Option<String> {
+    use Register::X0;
+
+    if funct3 == 0 {
+        let rs2 = Register::from_u32(imm & 0x1f);
+        return match (imm, rs1, rd) {
+            (0x000, X0, X0) => Some("ecall".to_string()),
+            (0x001, X0, X0) => Some("ebreak".to_string()),
+            (0x102, X0, X0) => Some("sret".to_string()),
+            (0x302, X0, X0) => Some("mret".to_string()),
+            (0x105, X0, X0) => Some("wfi".to_string()),
+            (imm, _, X0) if imm >> 5 == 0x09 => Some(match (rs1, rs2) {
+                (X0, X0) if aliases => "sfence.vma".to_string(),
+                (_, X0) if aliases => format!("sfence.vma {}", reg(rs1)),
+                _ => format!("sfence.vma {},{}", reg(rs1), reg(rs2)),
+            }),
+            _ => None,
+        };
+    }
+
+    let name = [
+        "", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci",
+    ]
+    .get(funct3 as usize)
+    .filter(|n| !n.is_empty())?;
+    let csr = csr(imm);
+    let source = if funct3 >= 5 {
+        rs1.to_usize().to_string()
+    } else {
+        reg(rs1).to_string()
+    };
+
+    if aliases {
+        if funct3 == 2 && rs1 == X0 {
+            return Some(match imm {
+                0xc00 => format!("rdcycle {}", reg(rd)),
+                0xc01 => format!("rdtime {}", reg(rd)),
+                0xc02 => format!("rdinstret {}", reg(rd)),
+                _ => format!("csrr {},{}", reg(rd), csr),
+            });
+        }
+        if rd == X0 {
+            let alias = ["", "csrw", "csrs", "csrc", "", "csrwi", "csrsi", "csrci"];
+            return Some(format!("{} {},{}", alias[funct3 as usize], csr, source));
+        }
+    }
+    Some(format!("{} {},{},{}", name, reg(rd), csr, source))
+}
+
+/// Rebuild the decoded form of a micro-op for display. Fields the micro-op
+/// does not keep (AMO ordering bits, `sfence.vma` operands) print as zero.
+fn to_op(op: &MicroOp) -> Op {
+    let r = |x: u8| Register::from_u32(x as u32);
+    let imm_op = |funct3: u32, rd: u8, rs1: u8, imm: i64| Op::OpImm {
+        rd: r(rd),
+        rs1: r(rs1),
+        imm,
+        funct3,
+        funct7: 0,
+    };
+    let imm32_op = |funct3: u32, rd: u8, rs1: u8, imm: i64| Op::OpImm32 {
+        rd: r(rd),
+        rs1: r(rs1),
+        imm,
+        funct3,
+        funct7: 0,
+    };
+    let reg_op = |funct7: u32, funct3: u32, rd: u8, rs1: u8, rs2: u8| Op::Op {
+        rd: r(rd),
+        rs1: r(rs1),
+        rs2: r(rs2),
+        funct3,
+        funct7,
+    };
+    let reg32_op = |funct7: u32, funct3: u32, rd: u8, rs1: u8, rs2: u8| Op::Op32 {
+        rd: r(rd),
+        rs1: r(rs1),
+        rs2: r(rs2),
+        funct3,
+        funct7,
+    };
+    let load = |funct3: u32, rd: u8, rs1: u8, imm: i64| Op::Load {
+        rd: r(rd),
+        rs1: r(rs1),
+        imm,
+        funct3,
+    };
+    let store = |funct3: u32, rs1: u8, rs2: u8, imm: i64| Op::Store {
+        rs1: r(rs1),
+        rs2: r(rs2),
+        imm,
+        funct3,
+    };
+    let branch = |funct3: u32, rs1: u8, rs2: u8, imm: i64| Op::Branch {
+        rs1: r(rs1),
+        rs2: r(rs2),
+        imm,
+        funct3,
+    };
+    let system = |funct3: u32, rd: u8, rs1: u8, imm: u32| Op::System {
+        rd: r(rd),
+        rs1: r(rs1),
+        funct3,
+        imm,
+    };
+    let amo = |funct5: u32, is_word: bool, rd: u8, rs1: u8, rs2: u8| Op::Amo {
+        rd: r(rd),
+        rs1: r(rs1),
+        rs2: r(rs2),
+        funct3: if is_word { 2 } else { 3 },
+        funct5,
+        aq: false,
+        rl: false,
+    };
+
+    match *op {
+        MicroOp::Addi { rd, rs1, imm } => imm_op(0, rd, rs1, imm),
+        MicroOp::Slti { rd, rs1, imm } => imm_op(2, rd, rs1, imm),
+        MicroOp::Sltiu { rd, rs1, imm } => imm_op(3, rd, rs1, imm),
+        MicroOp::Xori { rd, rs1, imm } => imm_op(4, rd, rs1, imm),
+        MicroOp::Ori { rd, rs1, imm } => imm_op(6, rd, rs1, imm),
+        MicroOp::Andi { rd, rs1, imm } => imm_op(7, rd, rs1, imm),
+        MicroOp::Slli { rd, rs1, shamt } => imm_op(1, rd, rs1, shamt as i64),
+        MicroOp::Srli { rd, rs1, shamt } => imm_op(5, rd, rs1, shamt as i64),
+        MicroOp::Srai { rd, rs1, shamt } => imm_op(5, rd, rs1, 0x400 | shamt as i64),
+        MicroOp::Add { rd, rs1, rs2 } => reg_op(0x00, 0, rd, rs1, rs2),
+        MicroOp::Sub { rd, rs1, rs2 } => reg_op(0x20, 0, rd, rs1, rs2),
+        MicroOp::Sll { rd, rs1, rs2 } => reg_op(0x00, 1, rd, rs1, rs2),
+        MicroOp::Slt { rd, rs1, rs2 } => reg_op(0x00, 2, rd, rs1, rs2),
+        MicroOp::Sltu { rd, rs1, rs2 } => reg_op(0x00, 3, rd, rs1, rs2),
+        MicroOp::Xor { rd, rs1, rs2 } => reg_op(0x00, 4, rd, rs1, rs2),
+        MicroOp::Srl { rd, rs1, rs2 } => reg_op(0x00, 5, rd, rs1, rs2),
+        MicroOp::Sra { rd, rs1, rs2 } => reg_op(0x20, 5, rd, rs1, rs2),
+        MicroOp::Or { rd, rs1, rs2 } => reg_op(0x00, 6, rd, rs1, rs2),
+        MicroOp::And { rd, rs1, rs2 } => reg_op(0x00, 7, rd, rs1, rs2),
+        MicroOp::Mul { rd, rs1, rs2 } => reg_op(0x01, 0, rd, rs1, rs2),
+        MicroOp::Mulh { rd, rs1, rs2 } => reg_op(0x01, 1, rd, rs1, rs2),
+        MicroOp::Mulhsu { rd, rs1, rs2 } => reg_op(0x01, 2, rd, rs1, rs2),
+        MicroOp::Mulhu { rd, rs1, rs2 } => reg_op(0x01, 3, rd, rs1, rs2),
+        MicroOp::Div { rd, rs1, rs2 } => reg_op(0x01, 4, rd, rs1, rs2),
+        MicroOp::Divu { rd, rs1, rs2 } => reg_op(0x01, 5, rd, rs1, rs2),
+        MicroOp::Rem { rd, rs1, rs2 } => reg_op(0x01, 6, rd, rs1, rs2),
+        MicroOp::Remu { rd, rs1, rs2 } => reg_op(0x01, 7, rd, rs1, rs2),
+        MicroOp::Addiw { rd, rs1, imm } => imm32_op(0, rd, rs1, imm as i64),
+        MicroOp::Slliw { rd, rs1, shamt } => imm32_op(1, rd, rs1, shamt as i64),
+        MicroOp::Srliw { rd, rs1, shamt } => imm32_op(5, rd, rs1, shamt as i64),
+        MicroOp::Sraiw { rd, rs1, shamt } => imm32_op(5, rd, rs1, 0x400 | shamt as i64),
+        MicroOp::Addw { rd, rs1, rs2 } => reg32_op(0x00, 0, rd, rs1, rs2),
+        MicroOp::Subw { rd, rs1, rs2 } => reg32_op(0x20, 0, rd, rs1, rs2),
+        MicroOp::Sllw { rd, rs1, rs2 } => reg32_op(0x00, 1, rd, rs1, rs2),
+        MicroOp::Srlw { rd, rs1, rs2 } => reg32_op(0x00, 5, rd, rs1, rs2),
+        MicroOp::Sraw { rd, rs1, rs2 } => reg32_op(0x20, 5, rd, rs1, rs2),
+        MicroOp::Mulw { rd, rs1, rs2 } => reg32_op(0x01, 0, rd, rs1, rs2),
+        MicroOp::Divw { rd, rs1, rs2 } => reg32_op(0x01, 4, rd, rs1, rs2),
+        MicroOp::Divuw { rd, rs1, rs2 } => reg32_op(0x01, 5, rd, rs1, rs2),
+        MicroOp::Remw { rd, rs1, rs2 } => reg32_op(0x01, 6, rd, rs1, rs2),
+        MicroOp::Remuw { rd, rs1, rs2 } => reg32_op(0x01, 7, rd, rs1, rs2),
+        MicroOp::Lui { rd, imm } => Op::Lui { rd: r(rd), imm },
+        MicroOp::Auipc { rd, imm, .. } => Op::Auipc { rd: r(rd), imm },
+        MicroOp::Lb { rd, rs1, imm, .. } => load(0, rd, rs1, imm),
+        MicroOp::Lh { rd, rs1, imm, .. } => load(1, rd, rs1, imm),
+        MicroOp::Lw { rd, rs1, imm, .. } => load(2, rd, rs1, imm),
+        MicroOp::Ld { rd, rs1, imm, .. } => load(3, rd, rs1, imm),
+        MicroOp::Lbu { rd, rs1, imm, .. } => load(4, rd, rs1, imm),
+        MicroOp::Lhu { rd, rs1, imm, .. } => load(5, rd, rs1, imm),
+        MicroOp::Lwu { rd, rs1, imm, .. } => load(6, rd, rs1, imm),
+        MicroOp::Sb { rs1, rs2, imm, .. } => store(0, rs1, rs2, imm),
+        MicroOp::Sh { rs1, rs2, imm, .. } => store(1, rs1, rs2, imm),
+        MicroOp::Sw { rs1, rs2, imm, .. } => store(2, rs1, rs2, imm),
+        MicroOp::Sd { rs1, rs2, imm, .. } => store(3, rs1, rs2, imm),
+        MicroOp::Jal { rd, imm, .. } => Op::Jal { rd: r(rd), imm },
+        MicroOp::Jalr { rd, rs1, imm, .. } => Op::Jalr {
+            rd: r(rd),
+            rs1: r(rs1),
+            imm,
+        },
+        MicroOp::Beq { rs1, rs2, imm, .. } => branch(0, rs1, rs2, imm),
+        MicroOp::Bne { rs1, rs2, imm, .. } => branch(1, rs1, rs2, imm),
+        MicroOp::Blt { rs1, rs2, imm, .. } => branch(4, rs1, rs2, imm),
+        MicroOp::Bge { rs1, rs2, imm, .. } => branch(5, rs1, rs2, imm),
+        MicroOp::Bltu { rs1, rs2, imm, .. } => branch(6, rs1, rs2, imm),
+        MicroOp::Bgeu { rs1, rs2, imm, .. } => branch(7, rs1, rs2, imm),
+        MicroOp::Ecall { .. } => system(0, 0, 0, 0x000),
+        MicroOp::Ebreak { .. } => system(0, 0, 0, 0x001),
+        MicroOp::Sret { .. } => system(0, 0, 0, 0x102),
+        MicroOp::Mret { .. } => system(0, 0, 0, 0x302),
+        MicroOp::Wfi { .. } => system(0, 0, 0, 0x105),
+        MicroOp::SfenceVma { .. } => system(0, 0, 0, 0x09 << 5),
+        MicroOp::Csrrw { rd, rs1, csr, .. } => system(1, rd, rs1, csr as u32),
+        MicroOp::Csrrs { rd, rs1, csr, .. } => system(2, rd, rs1, csr as u32),
+        MicroOp::Csrrc { rd, rs1, csr, .. } => system(3, rd, rs1, csr as u32),
+        MicroOp::Csrrwi { rd, zimm, csr, .. } => system(5, rd, zimm, csr as u32),
+        MicroOp::Csrrsi { rd, zimm, csr, .. } => system(6, rd, zimm, csr as u32),
+        MicroOp::Csrrci { rd, zimm, csr, .. } => system(7, rd, zimm, csr as u32),
+        MicroOp::Fence => Op::Fence,
+        MicroOp::LrW { rd, rs1, .. } => amo(0x02, true, rd, rs1, 0),
+        MicroOp::LrD { rd, rs1, .. } => amo(0x02, false, rd, rs1, 0),
+        MicroOp::ScW { rd, rs1, rs2, .. } => amo(0x03, true, rd, rs1, rs2),
+        MicroOp::ScD { rd, rs1, rs2, .. } => amo(0x03, false, rd, rs1, rs2),
+        MicroOp::AmoSwap {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x01, is_word, rd, rs1, rs2),
+        MicroOp::AmoAdd {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x00, is_word, rd, rs1, rs2),
+        MicroOp::AmoXor {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x04, is_word, rd, rs1, rs2),
+        MicroOp::AmoAnd {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x0c, is_word, rd, rs1, rs2),
+        MicroOp::AmoOr {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x08, is_word, rd, rs1, rs2),
+        MicroOp::AmoMin {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x10, is_word, rd, rs1, rs2),
+        MicroOp::AmoMax {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x14, is_word, rd, rs1, rs2),
+        MicroOp::AmoMinu {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x18, is_word, rd, rs1, rs2),
+        MicroOp::AmoMaxu {
+            rd,
+            rs1,
+            rs2,
+            is_word,
+            ..
+        } => amo(0x1c, is_word, rd, rs1, rs2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u64 = 0x8000_0000;
+
+    #[test]
+    fn aliases_match_objdump() {
+        let cases: &[(u32, &str)] = &[
+            (0x0000_0013, "nop"),
+            (0x02a0_0293, "li t0,42"),
+            (0x0005_8513, "mv a0,a1"),
+            (0xfff5_4513, "not a0,a0"),
+            (0x0000_8067, "ret"),
+            (0x0007_8067, "jr a5"),
+            (0x0007_80e7, "jalr a5"),
+            (0x0080_006f, "j 0x80000008"),
+            (0xff9f_f0ef, "jal 0x7ffffff8"),
+            (0x0005_0463, "beqz a0,0x80000008"),
+            (0x00b5_0463, "beq a0,a1,0x80000008"),
+            (0x0053_3023, "sd t0,0(t1)"),
+            (0x0083_3383, "ld t2,8(t1)"),
+            (0x1234_5137, "lui sp,0x12345"),
+            (0x0205_1513, "slli a0,a0,0x20"),
+            (0x4035_5513, "srai a0,a0,0x3"),
+            (0x0005_051b, "sext.w a0,a0"),
+            (0x02b5_0533, "mul a0,a0,a1"),
+            (0x40a0_0533, "neg a0,a0"),
+        ];
+        for &(raw, text) in cases {
+            assert_eq!(disassemble(PC, raw), text, "raw 0x{:08x}", raw);
+        }
+        assert_eq!(disassemble_no_aliases(PC, 0x02a0_0293), "addi t0,zero,42");
+        assert_eq!(disassemble_no_aliases(PC, 0x0000_8067), "jalr zero,0(ra)");
+    }
+
+    #[test]
+    fn privileged_csr_and_fence() {
+        let cases: &[(u32, &str)] = &[
+            (0x0000_0073, "ecall"),
+            (0x0010_0073, "ebreak"),
+            (0x1020_0073, "sret"),
+            (0x3020_0073, "mret"),
+            (0x1050_0073, "wfi"),
+            (0x1200_0073, "sfence.vma"),
+            (0x12b5_0073, "sfence.vma a0,a1"),
+            (0x3000_2573, "csrr a0,mstatus"),
+            (0x1805_9073, "csrw satp,a1"),
+            (0x1004_6073, "csrsi sstatus,8"),
+            (0x3405_95f3, "csrrw a1,mscratch,a1"),
+            (0xc010_2573, "rdtime a0"),
+            (0x7c00_2573, "csrr a0,0x7c0"),
+            (0x0ff0_000f, "fence"),
+            (0x0330_000f, "fence rw,rw"),
+            (0x8330_000f, "fence.tso"),
+            (0x0000_100f, "fence.i"),
+            (0x1005_252f, "lr.w a0,(a0)"),
+            (0x1eb5_352f, "sc.d.aqrl a0,a1,(a0)"),
+            (0x00b5_252f, "amoadd.w a0,a1,(a0)"),
+            (0xc000_1073, "unimp"),
+            (0x0000_0000, "unimp"),
+            (0xffff_ffff, ".4byte 0xffffffff"),
+            (0x0000_0007, ".4byte 0x7"),
+        ];
+        for &(raw, text) in cases {
+            assert_eq!(disassemble(PC, raw), text, "raw 0x{:08x}", raw);
+        }
+    }
+
+    #[test]
+    fn compressed_expands_like_objdump() {
+        let cases: &[(u16, &str)] = &[
+            (0x0800, "addi s0,sp,16"),
+            (0x0585, "addi a1,a1,1"),
+            (0x56fd, "li a3,-1"),
+            (0x6705, "lui a4,0x1"),
+            (0x8082, "ret"),
+            (0x852e, "mv a0,a1"),
+            (0x9002, "ebreak"),
+            (0xa001, "j 0x80000000"),
+            (0xc119, "beqz a0,0x80000006"),
+            (0xe406, "sd ra,8(sp)"),
+            (0x6522, "ld a0,8(sp)"),
+            (0x0001, "nop"),
+            (0x2000, ".2byte 0x2000"),
+        ];
+        for &(raw, text) in cases {
+            assert_eq!(insn_len(raw as u32), 2);
+            assert_eq!(disassemble(PC, raw as u32), text, "raw 0x{:04x}", raw);
+        }
+    }
+
+    #[test]
+    fn renders_ops_and_microops() {
+        let op = decoder::decode(0x00b5_0463).unwrap();
+        assert_eq!(op.to_string(), "beq a0,a1,.+8");
+        let op = decoder::decode(0xff9f_f06f).unwrap();
+        assert_eq!(op.to_string(), "j .-8");
+
+        let micro = [
+            (
+                MicroOp::Addi {
+                    rd: 10,
+                    rs1: 0,
+                    imm: 5,
+                },
+                "li a0,5",
+            ),
+            (
+                MicroOp::Srai {
+                    rd: 10,
+                    rs1: 10,
+                    shamt: 3,
+                },
+                "srai a0,a0,0x3",
+            ),
+            (
+                MicroOp::Sraiw {
+                    rd: 10,
+                    rs1: 11,
+                    shamt: 31,
+                },
+                "sraiw a0,a1,0x1f",
+            ),
+            (
+                MicroOp::Ld {
+                    rd: 1,
+                    rs1: 2,
+                    imm: 8,
+                    pc_offset: 0,
+                },
+                "ld ra,8(sp)",
+            ),
+            (
+                MicroOp::Bne {
+                    rs1: 10,
+                    rs2: 0,
+                    imm: -4,
+                    pc_offset: 0,
+                    insn_len: 2,
+                },
+                "bnez a0,.-4",
+            ),
+            (
+                MicroOp::Csrrs {
+                    rd: 10,
+                    rs1: 0,
+                    csr: 0x142,
+                    pc_offset: 0,
+                },
+                "csrr a0,scause",
+            ),
+            (
+                MicroOp::AmoMaxu {
+                    rd: 5,
+                    rs1: 6,
+                    rs2: 7,
+                    is_word: false,
+                    pc_offset: 0,
+                },
+                "amomaxu.d t0,t2,(t1)",
+            ),
+            (MicroOp::Mret { pc_offset: 0 }, "mret"),
+            (MicroOp::Fence, "fence"),
+        ];
+        for (op, text) in micro {
+            assert_eq!(op.to_string(), text);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 pub mod block;
 pub mod cache;
 pub mod decoder;
+pub mod disasm;
 pub mod microop;
 
 
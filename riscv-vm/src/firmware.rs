@@ -0,0 +1,95 @@
+//! M-mode firmware boot (`--bios`).
+//!
+//! Like QEMU's `virt` machine, harts reset into a small ROM at
+//! [`BOOT_ROM_BASE`] that jumps to the firmware with `a0` = hart ID,
+//! `a1` = DTB and `a2` = an OpenSBI `fw_dynamic_info` naming the kernel.
+//! `fw_jump` builds ignore `a2` and use their compiled-in kernel address.
+
+use crate::bus::BOOT_ROM_BASE;
+
+/// `fw_dynamic_info.magic` ("OSBI").
+pub const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534f;
+/// `fw_dynamic_info.version` understood by OpenSBI 1.0 and later.
+pub const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+/// `fw_dynamic_info.next_mode` for a kernel entered in S-mode.
+pub const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+/// Offsets of the data words after the reset vector code.
+const START_ADDR_OFFSET: usize = 24;
+const FDT_ADDR_OFFSET: usize = 32;
+const FW_DYNAMIC_INFO_OFFSET: usize = 40;
+
+/// Address of the `fw_dynamic_info` in the reset vector ROM.
+pub const FW_DYNAMIC_INFO_ADDRESS: u64 = BOOT_ROM_BASE + FW_DYNAMIC_INFO_OFFSET as u64;
+
+/// The reset vector ROM: jump to `firmware_entry` in M-mode, telling the
+/// firmware to start the kernel at `kernel_entry` in S-mode on hart 0.
+pub fn reset_vector(firmware_entry: u64, dtb_address: u64, kernel_entry: u64) -> Vec<u8> {
+    let code: [u32; 6] = [
+        0x0000_0297,                                         // auipc t0, 0
+        0x0002_8613 | (FW_DYNAMIC_INFO_OFFSET as u32) << 20, // addi  a2, t0, 40
+        0xf140_2573,                                         // csrr  a0, mhartid
+        0x0002_b583 | (FDT_ADDR_OFFSET as u32) << 20,        // ld    a1, 32(t0)
+        0x0002_b283 | (START_ADDR_OFFSET as u32) << 20,      // ld    t0, 24(t0)
+        0x0002_8067,                                         // jr    t0
+    ];
+    let data: [u64; 8] = [
+        firmware_entry,
+        dtb_address,
+        // struct fw_dynamic_info
+        FW_DYNAMIC_INFO_MAGIC,
+        FW_DYNAMIC_INFO_VERSION,
+        kernel_entry,
+        FW_DYNAMIC_INFO_NEXT_MODE_S,
+        0, // options
+        0, // boot_hart
+    ];
+
+    let mut rom: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+    debug_assert_eq!(rom.len(), START_ADDR_OFFSET);
+    rom.extend(data.iter().flat_map(|word| word.to_le_bytes()));
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, SystemBus};
+    use crate::cpu::{Cpu, Mode};
+
+    #[test]
+    fn reset_vector_jumps_to_firmware() {
+        let mut bus = SystemBus::new(0x8000_0000, 1024 * 1024);
+        bus.set_boot_rom(reset_vector(0x8000_0000, 0x8220_0000, 0x8020_0000))
+            .unwrap();
+        let mut cpu = Cpu::new(BOOT_ROM_BASE, 3);
+        // The block engine may run the whole vector in one step
+        for _ in 0..6 {
+            if cpu.pc == 0x8000_0000 {
+                break;
+            }
+            cpu.step(&bus).unwrap();
+        }
+
+        assert_eq!(cpu.pc, 0x8000_0000);
+        assert_eq!(cpu.mode, Mode::Machine);
+        assert_eq!(cpu.regs[10], 3);
+        assert_eq!(cpu.regs[11], 0x8220_0000);
+        assert_eq!(cpu.regs[12], FW_DYNAMIC_INFO_ADDRESS);
+
+        let info: Vec<u64> = (0..6)
+            .map(|i| bus.read64(FW_DYNAMIC_INFO_ADDRESS + i * 8).unwrap())
+            .collect();
+        assert_eq!(
+            info,
+            [
+                FW_DYNAMIC_INFO_MAGIC,
+                FW_DYNAMIC_INFO_VERSION,
+                0x8020_0000,
+                1,
+                0,
+                0
+            ]
+        );
+    }
+}
@@ -1,17 +1,23 @@
+pub mod blockdev;
 pub mod bus;
+pub mod coredump;
 pub mod cpu;
 pub mod devices;
 pub mod dram;
 pub mod dtb;
 pub mod engine;
+pub mod firmware;
+pub mod hart;
 pub mod mmu;
 pub mod sbi;
 pub use devices::{clint, plic, uart};
 pub mod loader;
 pub mod net;
 pub mod sdboot;  // SD card boot support (MBR, FAT32)
+pub mod semihosting;
 pub mod shared_mem;
 pub mod snapshot;
+pub mod trace;
 pub mod vm;
 
 pub use cpu::{Mode, Trap, csr};
@@ -20,9 +26,15 @@ pub use cpu::{Mode, Trap, csr};
 #[cfg(all(feature = "napi", not(target_arch = "wasm32")))]
 pub mod napi_bindings;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod chardev;
+
 #[cfg(not(target_arch = "wasm32"))]
 pub mod console;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod profiler;
+
 #[cfg(target_arch = "wasm32")]
 pub mod worker;
 
@@ -1,7 +1,60 @@
 //! Binary and ELF loading utilities.
 
-use crate::bus::SystemBus;
-use goblin::elf::{Elf, program_header::PT_LOAD};
+use crate::bus::{DRAM_BASE, SystemBus};
+use goblin::elf::{Elf, program_header::PT_LOAD, sym};
+
+/// The 64-byte header of a Linux RISC-V `Image`
+/// (Documentation/arch/riscv/boot-image-header.rst).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxImageHeader {
+    /// Offset of the load address from a 2 MiB aligned base.
+    pub text_offset: u64,
+    /// Memory the kernel occupies once running, bss included; 0 if unknown.
+    pub image_size: u64,
+}
+
+impl LinuxImageHeader {
+    /// Parse the header, if `image` has the `RSC\x05` (or older `RISCV`)
+    /// magic.
+    pub fn parse(image: &[u8]) -> Option<Self> {
+        let header = image.get(..64)?;
+        if &header[56..60] != b"RSC\x05" && &header[48..56] != b"RISCV\0\0\0" {
+            return None;
+        }
+        let le64 = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        Some(Self {
+            text_offset: le64(8),
+            image_size: le64(16),
+        })
+    }
+}
+
+/// Where a raw kernel goes: `text_offset` into DRAM for a Linux `Image`,
+/// else the start of DRAM.
+pub fn raw_kernel_load_address(kernel: &[u8]) -> u64 {
+    DRAM_BASE + LinuxImageHeader::parse(kernel).map_or(0, |h| h.text_offset)
+}
+
+/// The memory a kernel loaded at `load_addr` occupies, as (start, end):
+/// its ELF segments, or for a raw image its `image_size` or length.
+pub fn kernel_extent(kernel: &[u8], load_addr: u64) -> Result<(u64, u64), String> {
+    if kernel.starts_with(b"\x7FELF") {
+        let elf = Elf::parse(kernel).map_err(|e| format!("ELF parse error: {}", e))?;
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD && ph.p_memsz != 0)
+            .map(|ph| {
+                let start = if ph.p_paddr != 0 { ph.p_paddr } else { ph.p_vaddr };
+                (start, start + ph.p_memsz)
+            });
+        return Ok(segments
+            .reduce(|(s0, e0), (s1, e1)| (s0.min(s1), e0.max(e1)))
+            .unwrap_or((elf.entry, elf.entry)));
+    }
+    let image_size = LinuxImageHeader::parse(kernel).map_or(0, |h| h.image_size);
+    Ok((load_addr, load_addr + image_size.max(kernel.len() as u64)))
+}
 
 /// Load an ELF kernel into DRAM (Native version).
 ///
@@ -125,14 +178,151 @@ pub fn load_elf_wasm(buffer: &[u8], bus: &SystemBus) -> Result<u64, String> {
     Ok(elf.entry)
 }
 
+/// A named ELF symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Symbols extracted from an ELF's `.symtab`, used to turn guest addresses
+/// into function names (profiler, tracing, debug output) and to locate
+/// well-known symbols by name.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    /// Function symbols sorted by address.
+    functions: Vec<Symbol>,
+    /// Every named, non-zero symbol (functions and objects) in file order.
+    all: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Parse the symbol table of an ELF image.
+    pub fn from_elf(buffer: &[u8]) -> Result<Self, String> {
+        let elf = Elf::parse(buffer).map_err(|e| format!("ELF parse error: {}", e))?;
+        let mut table = SymbolTable::default();
+
+        for s in elf.syms.iter() {
+            if s.st_value == 0 || s.st_type() == sym::STT_SECTION || s.st_type() == sym::STT_FILE {
+                continue;
+            }
+            let name = match elf.strtab.get_at(s.st_name) {
+                Some(n) if !n.is_empty() => n,
+                _ => continue,
+            };
+            let symbol = Symbol {
+                name: name.to_string(),
+                addr: s.st_value,
+                size: s.st_size,
+            };
+            if s.st_type() == sym::STT_FUNC {
+                table.functions.push(symbol.clone());
+            }
+            table.all.push(symbol);
+        }
+
+        table.functions.sort_by_key(|s| s.addr);
+        table.functions.dedup_by_key(|s| s.addr);
+        Ok(table)
+    }
 
+    /// Number of function symbols.
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
 
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
 
+    /// Find the function containing `addr`, returning it with the offset
+    /// of `addr` into the function.
+    ///
+    /// Symbols with a zero size are assumed to extend to the next symbol.
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let idx = self.functions.partition_point(|s| s.addr <= addr);
+        let sym = self.functions.get(idx.checked_sub(1)?)?;
+        let offset = addr - sym.addr;
+        if sym.size != 0 && offset >= sym.size {
+            return None;
+        }
+        Some((sym, offset))
+    }
 
+    /// Find a symbol (function or object) by exact name.
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.all.iter().find(|s| s.name == name)
+    }
 
+    /// Addresses of the HTIF `tohost` and (optional) `fromhost` words.
+    pub fn htif(&self) -> Option<(u64, Option<u64>)> {
+        let tohost = self.find("tohost")?.addr;
+        Some((tohost, self.find("fromhost").map(|s| s.addr)))
+    }
 
+    /// Format `addr` as `name+0xoff`, or as a bare hex address if unknown.
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, off)) => format!("{}+0x{:x}", sym.name, off),
+            None => format!("0x{:x}", addr),
+        }
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn linux_image_header() {
+        let mut image = vec![0u8; 4096];
+        assert_eq!(LinuxImageHeader::parse(&image), None);
+        assert_eq!(raw_kernel_load_address(&image), DRAM_BASE);
+        assert_eq!(kernel_extent(&image, DRAM_BASE), Ok((DRAM_BASE, DRAM_BASE + 4096)));
 
+        image[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        image[16..24].copy_from_slice(&0x180_0000u64.to_le_bytes());
+        image[48..56].copy_from_slice(b"RISCV\0\0\0");
+        image[56..60].copy_from_slice(b"RSC\x05");
+        let header = LinuxImageHeader::parse(&image).unwrap();
+        assert_eq!((header.text_offset, header.image_size), (0x20_0000, 0x180_0000));
+        assert_eq!(raw_kernel_load_address(&image), 0x8020_0000);
+        assert_eq!(kernel_extent(&image, 0x8020_0000), Ok((0x8020_0000, 0x81a0_0000)));
+        assert_eq!(LinuxImageHeader::parse(&image[..63]), None);
+    }
 
+    fn table() -> SymbolTable {
+        let f = |name: &str, addr, size| Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        SymbolTable {
+            functions: vec![
+                f("_start", 0x1000, 0x10),
+                f("main", 0x1010, 0),
+                f("exit", 0x1100, 0x20),
+            ],
+            all: vec![f("tohost", 0x2000, 8)],
+        }
+    }
 
+    #[test]
+    fn test_symbol_lookup() {
+        let t = table();
+        assert_eq!(t.lookup(0x0fff), None);
+        assert_eq!(
+            t.lookup(0x1004).map(|(s, o)| (s.name.as_str(), o)),
+            Some(("_start", 4))
+        );
+        // Zero-sized symbol extends to the next one.
+        assert_eq!(t.lookup(0x10fe).map(|(s, _)| s.name.as_str()), Some("main"));
+        assert_eq!(t.lookup(0x1120), None);
+        assert_eq!(t.describe(0x1100), "exit");
+        assert_eq!(t.describe(0x1108), "exit+0x8");
+        assert_eq!(t.describe(0x3000), "0x3000");
+        assert_eq!(t.find("tohost").map(|s| s.addr), Some(0x2000));
+    }
+}
@@ -1,13 +1,22 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{BufReader, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use riscv_vm::sdboot;
-use riscv_vm::vm::native::NativeVm;
+use regex::bytes::Regex;
+
+use riscv_vm::blockdev::{self, BlockBackend, DriveSpec, HttpBackend, Qcow2Backend};
+use riscv_vm::chardev;
+use riscv_vm::loader::{self, SymbolTable};
+use riscv_vm::profiler::ProfilerConfig;
+use riscv_vm::sdboot::{self, PartitionSelector};
+use riscv_vm::trace::{TraceFilter, TraceLog};
+use riscv_vm::Mode;
+use riscv_vm::vm::native::{NativeVm, RunLimits, RunOutcome};
 
-#[cfg(feature = "gui")]
-use std::sync::Arc;
 #[cfg(feature = "gui")]
 use std::thread;
 #[cfg(feature = "gui")]
@@ -17,11 +26,65 @@ use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions, Scale};
 #[command(name = "riscv-vm")]
 #[command(about = "RISCV emulator with SMP support")]
 #[command(version)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path or URL to SD card image (contains kernel + filesystem)
     /// Supports local files or http:// / https:// URLs
-    #[arg(short, long)]
-    sdcard: String,
+    #[arg(short, long, required_unless_present = "kernel")]
+    sdcard: Option<String>,
+
+    /// Boot this kernel (ELF, or a raw or Linux `Image` binary) instead of
+    /// the one on the SD card, which then becomes optional. The SD card's
+    /// boot config is not read; give --append and --initrd instead
+    #[arg(long, value_name = "FILE")]
+    kernel: Option<PathBuf>,
+
+    /// Load this initrd high in DRAM (replaces the boot config's)
+    #[arg(long, value_name = "FILE")]
+    initrd: Option<PathBuf>,
+
+    /// Kernel command line (replaces the boot config's and the default
+    /// "earlycon=sbi console=ttyS0")
+    #[arg(long, value_name = "CMDLINE")]
+    append: Option<String>,
+
+    /// Run this M-mode firmware (OpenSBI fw_dynamic or fw_jump, ELF or
+    /// raw at 0x80000000) instead of the built-in SBI
+    #[arg(long, value_name = "FILE")]
+    bios: Option<PathBuf>,
+
+    /// Keep the SD card image unchanged and write guest changes to this
+    /// qcow2 overlay instead (created if missing)
+    #[arg(long, value_name = "FILE")]
+    overlay: Option<PathBuf>,
+
+    /// Cache for an SD card image given as a URL
+    /// (default: ~/.cache/riscv-vm/<hash of the URL>.img)
+    #[arg(long, value_name = "FILE")]
+    sdcard_cache: Option<PathBuf>,
+
+    /// Verify an SD card image given as a URL against this SHA-256
+    /// manifest (file or URL; see `riscv-vm manifest`)
+    #[arg(long, value_name = "FILE|URL")]
+    sdcard_manifest: Option<String>,
+
+    /// SD card partition to boot from: a number, label=NAME, or
+    /// type=esp|linux|xbootldr|GUID (default: the first FAT partition)
+    #[arg(long, value_name = "SPEC", value_parser = PartitionSelector::parse, conflicts_with = "kernel")]
+    boot_partition: Option<PartitionSelector>,
+
+    /// Boot config entry to boot (a `label` in extlinux/extlinux.conf on
+    /// the boot partition; default: the config's default entry)
+    #[arg(long, value_name = "LABEL", conflicts_with = "kernel")]
+    boot_entry: Option<String>,
+
+    /// Add a virtio-blk disk: file=PATH[,ro=on][,serial=ID] (raw or qcow2).
+    /// Repeatable; each takes the next free VirtIO slot
+    #[arg(long, value_name = "SPEC", value_parser = DriveSpec::parse)]
+    drive: Vec<DriveSpec>,
 
     /// Number of harts (CPUs), 0 for auto-detect
     #[arg(short = 'n', long, default_value = "0")]
@@ -50,6 +113,231 @@ struct Args {
     /// Enable debug output
     #[arg(long)]
     debug: bool,
+
+    /// Sample guest PCs and write folded stacks (flamegraph input) to this file
+    #[arg(long, value_name = "OUT.folded")]
+    profile: Option<PathBuf>,
+
+    /// Profiler sampling interval in microseconds
+    #[arg(long, default_value = "1000", requires = "profile")]
+    profile_interval_us: u64,
+
+    /// Walk the guest frame-pointer chain when sampling (guest built with -fno-omit-frame-pointer)
+    #[arg(long, requires = "profile")]
+    profile_fp: bool,
+
+    /// ELF to resolve profile symbols against (defaults to the kernel)
+    #[arg(long, requires = "profile")]
+    profile_symbols: Option<PathBuf>,
+
+    /// Write a Spike-compatible commit log of retired instructions to this file
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Only trace PCs in START:END (hex, end exclusive)
+    #[arg(long, value_parser = parse_pc_range, requires = "trace")]
+    trace_pc: Option<Range<u64>>,
+
+    /// Only trace these privilege modes (comma-separated: m,s,u)
+    #[arg(long, value_delimiter = ',', value_parser = parse_mode, requires = "trace")]
+    trace_priv: Vec<Mode>,
+
+    /// Only trace these harts (comma-separated)
+    #[arg(long, value_delimiter = ',', requires = "trace")]
+    trace_hart: Vec<usize>,
+
+    /// Skip this many retired instructions per hart before tracing
+    #[arg(long, default_value = "0", requires = "trace")]
+    trace_skip: u64,
+
+    /// Stop tracing after this many instructions per hart
+    #[arg(long, requires = "trace")]
+    trace_count: Option<u64>,
+
+    /// Also log the disassembly of each traced instruction (Spike `-l` style)
+    #[arg(long, requires = "trace")]
+    trace_disasm: bool,
+
+    /// Serial port backend: stdio, pty, tcp:[HOST]:PORT[,telnet],
+    /// unix:PATH, ws:[HOST]:PORT, file:PATH, pipe:PATH or null.
+    /// Repeat to add UARTs; the first is the console (ttyS0)
+    #[arg(long, value_name = "SPEC", default_value = "stdio")]
+    serial: Vec<String>,
+
+    /// Also log console (ttyS0) output here, each line timestamped
+    #[arg(long, value_name = "FILE")]
+    serial_log: Option<PathBuf>,
+
+    /// ELF core file written on fatal traps, Ctrl-A d and the monitor's
+    /// `dump` [default: riscv-vm.core]
+    #[arg(long, value_name = "FILE")]
+    core_file: Option<PathBuf>,
+
+    /// Serve the JSON-RPC control protocol on this UNIX socket
+    #[cfg(unix)]
+    #[arg(long, value_name = "SOCKET")]
+    control: Option<PathBuf>,
+
+    /// Stop after this much host time (e.g. 500ms, 90s, 10m) and exit with 124
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Stop after this much guest (CLINT) time and exit with 124
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    guest_timeout: Option<Duration>,
+
+    /// Stop after the harts retire this many instructions in total and exit with 124
+    #[arg(long, value_name = "N")]
+    max_instructions: Option<u64>,
+
+    /// Stop and exit with 0 once a console line matches this regex.
+    /// Repeat to require several matches, in order
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    expect: Vec<Regex>,
+
+    /// Stop and exit with 125 as soon as a console line matches this regex (repeatable)
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    fail_on: Vec<Regex>,
+
+    /// Don't print the banner or the [VM] setup and exit messages
+    #[arg(long)]
+    no_banner: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Find the first divergence between two Spike-format commit logs
+    TraceDiff {
+        /// Reference log (e.g. from `spike --log-commits`)
+        reference: PathBuf,
+        /// Log to check (e.g. from `riscv-vm --trace`)
+        actual: PathBuf,
+        /// Only compare commits from this hart
+        #[arg(long)]
+        hart: Option<usize>,
+    },
+
+    /// Run a bare-metal test ELF (riscv-arch-test, riscv-tests, semihosted
+    /// C library tests) and report pass/fail through the exit status
+    ArchTest {
+        /// Test ELF; begin_signature/end_signature symbols are needed for --signature
+        elf: PathBuf,
+        /// Write the signature to this file (riscof format)
+        #[arg(long)]
+        signature: Option<PathBuf>,
+        /// Bytes per signature line
+        #[arg(long, default_value = "4")]
+        signature_granularity: usize,
+        /// Step budget before the test is considered hung
+        #[arg(long, default_value_t = riscv_vm::vm::arch_test::DEFAULT_MAX_STEPS)]
+        max_steps: u64,
+        /// Write a Spike-compatible commit log to this file
+        #[arg(long, value_name = "FILE")]
+        trace: Option<PathBuf>,
+        /// Service semihosting calls, sandboxing semihosting and HTIF file
+        /// access to this directory
+        #[arg(long, value_name = "DIR")]
+        semihosting: Option<PathBuf>,
+        /// Command line returned to the program by SYS_GET_CMDLINE
+        #[arg(long, requires = "semihosting")]
+        semihosting_cmdline: Option<String>,
+    },
+
+    /// Run a riscv64 Linux ELF directly, emulating Linux system calls on
+    /// the host (no kernel or disk image needed)
+    #[cfg(target_os = "linux")]
+    User {
+        /// Directory holding the guest's dynamic linker and libraries
+        /// (e.g. /usr/riscv64-linux-gnu); absolute paths are looked up here first
+        #[arg(long, value_name = "DIR")]
+        sysroot: Option<PathBuf>,
+        /// Guest memory in MiB
+        #[arg(long, default_value_t = riscv_vm::vm::user::DEFAULT_MEMORY >> 20)]
+        memory: usize,
+        /// Set a guest environment variable (repeatable); the host environment is inherited
+        #[arg(long = "env", value_name = "KEY=VALUE")]
+        env: Vec<String>,
+        /// Executable to run
+        elf: PathBuf,
+        /// Arguments passed to the program
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// Manage copy-on-write qcow2 overlays of disk images
+    #[command(subcommand)]
+    Overlay(OverlayCommand),
+
+    /// Print the per-MiB SHA-256 manifest of a disk image, for serving it
+    /// over HTTP with --sdcard-manifest
+    Manifest {
+        /// Raw image, exactly as it will be served (qcow2 is rejected)
+        image: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OverlayCommand {
+    /// Create an empty overlay over a raw or qcow2 base image
+    Create {
+        /// Image the overlay reads through to; it is never written
+        base: PathBuf,
+        /// New qcow2 file
+        overlay: PathBuf,
+    },
+    /// Write the overlay's changes into its base image
+    Commit {
+        overlay: PathBuf,
+        /// Empty the overlay afterwards
+        #[arg(long)]
+        discard: bool,
+    },
+    /// Throw away the overlay's changes
+    Discard { overlay: PathBuf },
+}
+
+/// Parse a `START:END` hex address range.
+fn parse_pc_range(s: &str) -> Result<Range<u64>, String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| "expected START:END".to_string())?;
+    let hex = |v: &str| {
+        u64::from_str_radix(v.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("invalid address '{}': {}", v, e))
+    };
+    Ok(hex(start)?..hex(end)?)
+}
+
+/// Parse a privilege mode name.
+fn parse_mode(s: &str) -> Result<Mode, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "m" | "machine" => Ok(Mode::Machine),
+        "s" | "supervisor" => Ok(Mode::Supervisor),
+        "u" | "user" => Ok(Mode::User),
+        _ => Err(format!("unknown privilege mode '{}'", s)),
+    }
+}
+
+/// Parse a duration such as `90`, `90s`, `500ms`, `10m` or `1h` (plain
+/// numbers are seconds).
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => Err(format!("unknown unit '{}' in duration '{}' (use ms, s, m or h)", unit, s)),
+    }
+}
+
+/// Parse a console pattern.
+fn parse_regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| e.to_string())
 }
 
 /// Write to stdout with \r\n line endings (for raw terminal mode)
@@ -75,71 +363,97 @@ macro_rules! uart_println {
     }};
 }
 
-/// Load SD card data from a URL or local file path.
+/// Open the SD card image at a URL or local file path.
 /// 
 /// Supports:
-/// - Local file paths (absolute or relative)
-/// - HTTP/HTTPS URLs (downloads with progress display)
-fn load_sdcard_data(source: &str, debug: bool) -> Result<Vec<u8>, String> {
-    if source.starts_with("http://") || source.starts_with("https://") {
-        // Download from URL
+/// - Local file paths (absolute or relative), used in place so guest
+///   writes persist
+/// - HTTP/HTTPS URLs, fetched in chunks as the guest reads them and cached
+///   in `cache` (see `blockdev::http`), optionally verified against a
+///   `manifest`
+///
+/// Raw and qcow2 images are told apart by content. With `overlay`, the
+/// image is opened read-only and writes go to the overlay. Writes to a URL
+/// image always go to an overlay, a temporary one without `overlay`.
+fn open_sdcard(
+    source: &str,
+    overlay: Option<&Path>,
+    cache: Option<&Path>,
+    manifest: Option<&str>,
+    boot: Option<&PartitionSelector>,
+    debug: bool,
+) -> Result<Arc<dyn BlockBackend>, String> {
+    let is_url = source.starts_with("http://") || source.starts_with("https://");
+    if is_url {
+        let cache = match cache {
+            Some(cache) => cache.to_path_buf(),
+            None => blockdev::http::default_cache_path(source)?,
+        };
+        let manifest = manifest.map(blockdev::http::read_manifest).transpose()?;
+        let image = HttpBackend::open(source, &cache, manifest)?;
         if debug {
-            eprintln!("[CLI] Downloading SD card from {}...", source);
-        } else {
-            eprintln!("Downloading SD card image...");
-        }
-        
-        let agent = ureq::AgentBuilder::new()
-            .timeout_connect(std::time::Duration::from_secs(30))
-            .build();
-        
-        let response = agent.get(source).call()
-            .map_err(|e| format!("Failed to download SD card from '{}': {}", source, e))?;
-        
-        // Check for success status
-        let status = response.status();
-        if status != 200 {
-            return Err(format!("HTTP {} when downloading '{}'", status, source));
-        }
-        
-        // Get content length if available for progress display
-        let content_length = response.header("Content-Length")
-            .and_then(|s| s.parse::<usize>().ok());
-        
-        if let Some(len) = content_length {
-            if debug {
-                eprintln!("[CLI] Expected size: {} bytes", len);
-            }
+            eprintln!("[CLI] SD card {} ({} bytes), cache {}", source, image.size(), cache.display());
         }
-        
-        // Read the response body
-        let mut data = if let Some(len) = content_length {
-            Vec::with_capacity(len)
-        } else {
-            Vec::new()
+        match image.prefetch_boot_partition(boot) {
+            Ok(Some(_)) => {}
+            Ok(None) if debug => eprintln!("[CLI] No boot partition to prefetch in {}", source),
+            Ok(None) => {}
+            Err(e) => return Err(format!("Failed to read '{}': {}", source, e)),
+        }
+        let image: Arc<dyn BlockBackend> = Arc::new(image);
+
+        let (path, temporary) = match overlay {
+            Some(overlay) => (overlay.to_path_buf(), false),
+            None => {
+                let name = format!("riscv-vm-sdcard-{}.qcow2", std::process::id());
+                (std::env::temp_dir().join(name), true)
+            }
         };
-        
-        response.into_reader().read_to_end(&mut data)
-            .map_err(|e| format!("Failed to read response body: {}", e))?;
-        
-        eprintln!("Downloaded {} bytes", data.len());
-        
-        Ok(data)
-    } else {
-        // Read from local file
-        let path = Path::new(source);
         if !path.exists() {
-            return Err(format!("SD card image not found at '{}'", source));
+            Qcow2Backend::create(&path, image.size(), Some((source, "raw")))?;
+            if !temporary {
+                eprintln!("Created overlay {}", path.display());
+            }
+        }
+        let overlay = Qcow2Backend::open_with_backing(&path, false, Some(image));
+        if temporary {
+            // Writes last until exit, as the guest has the file open.
+            let _ = fs::remove_file(&path);
+        }
+        let overlay = overlay?;
+        if overlay.backing_file() != Some(Path::new(source)) {
+            eprintln!("Warning: overlay {} is not based on {}", path.display(), source);
         }
-        
-        fs::read(path)
-            .map_err(|e| format!("Failed to read SD card image '{}': {}", source, e))
+        return Ok(Arc::new(overlay));
     }
+    if let Some(overlay) = overlay {
+        let base = Path::new(source);
+        if !overlay.exists() {
+            Qcow2Backend::create_overlay(overlay, base)?;
+            eprintln!("Created overlay {}", overlay.display());
+        }
+        let image = Qcow2Backend::open(overlay, false)?;
+        let backing = image.backing_file().and_then(|b| std::path::absolute(b).ok());
+        if backing != std::path::absolute(base).ok() {
+            eprintln!("Warning: overlay {} is not based on {}", overlay.display(), source);
+        }
+        return Ok(Arc::new(image));
+    }
+    // Use a local file in place
+    let path = Path::new(source);
+    if !path.exists() {
+        return Err(format!("SD card image not found at '{}'", source));
+    }
+
+    blockdev::open_image(path, false)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
+    if let Some(command) = &args.command {
+        return run_command(command);
+    }
     // Check if GUI is requested but feature not enabled
     #[cfg(not(feature = "gui"))]
     if args.enable_gpu {
@@ -148,19 +462,46 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
 
-    // Initialize logging
+    // Initialize logging; --no-banner also hides the VM's [VM] info lines
     if args.debug {
         env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
+    } else if args.no_banner {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     } else {
         env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     }
 
     // Load SD card image (from URL or local file)
-    let sdcard_data = load_sdcard_data(&args.sdcard, args.debug)?;
+    let sdcard_disk = match &args.sdcard {
+        Some(sdcard) => Some(open_sdcard(
+            sdcard,
+            args.overlay.as_deref(),
+            args.sdcard_cache.as_deref(),
+            args.sdcard_manifest.as_deref(),
+            args.boot_partition.as_ref(),
+            args.debug,
+        )?),
+        None => None,
+    };
 
-    // Parse SD card: find kernel on boot partition
-    let boot_info = sdboot::parse_sdcard(&sdcard_data)
-        .map_err(|e| format!("Failed to parse SD card: {}", e))?;
+    // Parse SD card: find kernel on boot partition, unless given --kernel
+    let mut boot_info = match (&args.kernel, &sdcard_disk) {
+        (None, Some(disk)) => Some(
+            sdboot::parse_sdcard_with(&**disk, args.boot_partition.as_ref(), args.boot_entry.as_deref())
+                .map_err(|e| format!("Failed to parse SD card: {}", e))?,
+        ),
+        _ => None,
+    };
+    let (kernel, kernel_load_addr) = match (&args.kernel, &mut boot_info) {
+        (Some(path), _) => {
+            let kernel = fs::read(path)
+                .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+            let load_addr = loader::raw_kernel_load_address(&kernel);
+            (kernel, load_addr)
+        }
+        (None, Some(info)) => (std::mem::take(&mut info.kernel_data), info.kernel_load_addr),
+        (None, None) => unreachable!("--sdcard is required without --kernel"),
+    };
 
     // Determine hart count
     let num_harts = if args.harts == 0 {
@@ -174,44 +515,94 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .max(1);
 
     // Print banner
-    uart_println!();
-    uart_println!("╔══════════════════════════════════════════════════════════════╗");
-    if args.enable_gpu {
-        uart_println!("║  RISCV-VM with OpenSBI (GUI)                                 ║");
-    } else {
-        uart_println!("║  RISCV-VM with OpenSBI                                       ║");
-    }
-    uart_println!("╠══════════════════════════════════════════════════════════════╣");
-    // Extract display name from path or URL
-    let sdcard_display = args.sdcard.rsplit('/').next()
-        .unwrap_or(&args.sdcard);
-    let sdcard_display = if sdcard_display.len() > 52 {
-        &sdcard_display[..52]
-    } else {
-        sdcard_display
-    };
-    uart_println!(
-        "║  SD Card: {:52} ║",
-        sdcard_display
-    );
-    uart_println!("║  Kernel:  {} bytes @ {:#x}{:>23} ║", 
-        boot_info.kernel_data.len(),
-        boot_info.kernel_load_addr,
-        ""
-    );
-    uart_println!("║  Harts:   {:52} ║", num_harts);
-    if let Some(relay) = &args.net_webtransport {
-        uart_println!("║  Network: {:52} ║", relay);
-    }
-    uart_println!("╚══════════════════════════════════════════════════════════════╝");
-    uart_println!();
+    let quiet = args.no_banner;
+    if !quiet {
+        uart_println!();
+        uart_println!("╔══════════════════════════════════════════════════════════════╗");
+        if args.enable_gpu {
+            uart_println!("║  RISCV-VM with OpenSBI (GUI)                                 ║");
+        } else {
+            uart_println!("║  RISCV-VM with OpenSBI                                       ║");
+        }
+        uart_println!("╠══════════════════════════════════════════════════════════════╣");
+        if let Some(sdcard) = &args.sdcard {
+            // Extract display name from path or URL
+            let sdcard_display = sdcard.rsplit('/').next()
+                .unwrap_or(sdcard);
+            let sdcard_display = if sdcard_display.len() > 52 {
+                &sdcard_display[..52]
+            } else {
+                sdcard_display
+            };
+            uart_println!(
+                "║  SD Card: {:52} ║",
+                sdcard_display
+            );
+        }
+        uart_println!("║  Kernel:  {} bytes @ {:#x}{:>23} ║", 
+            kernel.len(),
+            kernel_load_addr,
+            ""
+        );
+        if let Some(bios) = &args.bios {
+            let name = bios.file_name().unwrap_or(bios.as_os_str()).to_string_lossy();
+            uart_println!("║  BIOS:    {:52} ║", name.chars().take(52).collect::<String>());
+        }
+        uart_println!("║  Harts:   {:52} ║", num_harts);
+        if let Some(relay) = &args.net_webtransport {
+            uart_println!("║  Network: {:52} ║", relay);
+        }
+        uart_println!("╚══════════════════════════════════════════════════════════════╝");
+        uart_println!();
+    }
 
-    // Create VM with kernel from SD card
-    let mut vm = NativeVm::new(&boot_info.kernel_data, num_harts)?;
+    // Create VM with the kernel, and give it the boot config's (or the
+    // command line's) initrd, command line and DTB
+    let mut vm = NativeVm::new_at(&kernel, num_harts, kernel_load_addr)?;
+    if let Some(entry) = boot_info.as_ref().and_then(|info| info.entry.as_ref()) {
+        if !quiet {
+            uart_println!("[VM] Boot entry: {}", entry);
+        }
+    }
+    let bootargs = args.append.clone().or_else(|| boot_info.as_ref()?.kernel_cmdline());
+    if let Some(bootargs) = &bootargs {
+        vm.set_bootargs(bootargs)?;
+    }
+    let initrd = match &args.initrd {
+        Some(path) => Some(
+            fs::read(path).map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?,
+        ),
+        None => boot_info.as_mut().and_then(|info| info.initrd.take()),
+    };
+    if let Some(initrd) = &initrd {
+        vm.load_initrd(initrd)?;
+    }
+    if let Some(info) = &boot_info {
+        if let Some(dtb) = &info.dtb {
+            vm.set_dtb(dtb.clone())?;
+        }
+        for overlay in &info.dtb_overlays {
+            vm.add_dtb_overlay(overlay.clone())?;
+        }
+    }
+    if let Some(path) = &args.bios {
+        let firmware = fs::read(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+        vm.load_firmware(&firmware)?;
+    }
 
     // Load entire SD card as block device (for filesystem partition)
-    vm.load_disk(sdcard_data);
-    uart_println!("[VM] SD card mounted (fs partition at sector {})", boot_info.fs_partition_start);
+    if let Some(disk) = sdcard_disk {
+        vm.attach_disk(disk);
+    }
+    if let (Some(info), false) = (&boot_info, quiet) {
+        uart_println!("[VM] SD card mounted (fs partition at sector {})", info.fs_partition_start);
+        if let Some(root) = info.root_arg()
+            && bootargs.as_deref().is_some_and(|args| args.split(' ').any(|arg| arg == root))
+        {
+            uart_println!("[VM] Root filesystem: partition {} ({})", info.fs_partition.number, root);
+        }
+    }
 
     // Enable GPU if requested
     if args.enable_gpu {
@@ -224,43 +615,293 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         vm.enable_9p(&path_str, None);
     }
 
+    for drive in &args.drive {
+        let disk = drive.open()?;
+        let description = disk.describe();
+        let slot = vm.add_drive(disk, drive.serial.as_deref())?;
+        if !quiet {
+            uart_println!("[VM] VirtIO block device in slot {}: {}", slot, description);
+        }
+    }
+
     // Connect to WebTransport relay if specified
     if let Some(relay_url) = &args.net_webtransport {
         vm.connect_webtransport(relay_url, args.cert_hash.clone());
     }
 
+    // Enable the sampling profiler if requested
+    if let Some(output) = &args.profile {
+        let symbols = match &args.profile_symbols {
+            Some(path) => {
+                let elf = fs::read(path)
+                    .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+                SymbolTable::from_elf(&elf)?
+            }
+            None => SymbolTable::from_elf(&kernel).unwrap_or_default(),
+        };
+        if symbols.is_empty() {
+            uart_println!("[Profile] No symbols found, reporting raw addresses");
+        }
+        let mut config = ProfilerConfig::new(output);
+        config.interval = std::time::Duration::from_micros(args.profile_interval_us.max(1));
+        config.frame_pointers = args.profile_fp;
+        vm.enable_profiler(config, symbols);
+    }
+
+    // Enable the instruction commit trace if requested
+    if let Some(path) = &args.trace {
+        let filter = TraceFilter {
+            pc_range: args.trace_pc.clone(),
+            modes: args.trace_priv.clone(),
+            harts: args.trace_hart.clone(),
+            skip: args.trace_skip,
+            count: args.trace_count,
+            disasm: args.trace_disasm,
+        };
+        let log = TraceLog::create(path, filter)
+            .map_err(|e| format!("Failed to create trace '{}': {}", path.display(), e))?;
+        vm.enable_trace(log);
+        if !quiet {
+            uart_println!("[VM] Tracing retired instructions to {}", path.display());
+        }
+    }
+
+    if let Some(path) = &args.core_file {
+        vm.set_core_file(path);
+    }
+
+    for (index, spec) in args.serial.iter().enumerate() {
+        let mut serial = chardev::open(spec)?;
+        if !serial.is_stdio() && !quiet {
+            uart_println!("[VM] ttyS{} on {}", index, serial.describe());
+        }
+        if index > 0 {
+            vm.add_serial(serial)?;
+            continue;
+        }
+        if let Some(path) = &args.serial_log {
+            serial = Box::new(chardev::Logged::new(serial, path)?);
+            if !quiet {
+                uart_println!("[VM] Logging serial output to {}", path.display());
+            }
+        }
+        vm.set_serial(serial);
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.control {
+        // Give `attach-9p` a device to attach to.
+        if args.mount.is_none() {
+            vm.enable_9p("", None);
+        }
+        vm.enable_control(path)?;
+        if !quiet {
+            uart_println!("[VM] Control socket listening on {}", path.display());
+        }
+    }
+
+    vm.set_limits(RunLimits {
+        wall_time: args.timeout,
+        guest_time: args.guest_timeout,
+        max_instructions: args.max_instructions,
+        expect: args.expect.clone(),
+        fail_on: args.fail_on.clone(),
+    });
+
     // Run VM - with or without GUI
     #[cfg(feature = "gui")]
     if args.enable_gpu {
-        run_with_gui(vm, args.scale)?;
+        run_with_gui(vm, args.scale, quiet)?;
     } else {
-        run_headless(vm);
+        run_headless(vm, quiet);
     }
 
     #[cfg(not(feature = "gui"))]
-    run_headless(vm);
+    run_headless(vm, quiet);
 
     Ok(())
 }
 
+/// Run a subcommand that does not boot the VM.
+fn run_command(command: &Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::TraceDiff {
+            reference,
+            actual,
+            hart,
+        } => {
+            let open = |path: &PathBuf| {
+                fs::File::open(path)
+                    .map(BufReader::new)
+                    .map_err(|e| format!("Failed to open '{}': {}", path.display(), e))
+            };
+            let divergence = riscv_vm::trace::first_divergence(open(reference)?, open(actual)?, *hart)?;
+            let Some(d) = divergence else {
+                println!("No divergence found");
+                return Ok(());
+            };
+
+            println!("First divergence at commit #{}", d.commit);
+            for (label, side) in [("reference", &d.expected), ("actual", &d.actual)] {
+                match side {
+                    Some((line, text)) => println!("  {:9} line {:>8}: {}", label, line, text),
+                    None => println!("  {:9} <end of log>", label),
+                }
+            }
+            std::process::exit(1);
+        }
+        Command::ArchTest {
+            elf,
+            signature,
+            signature_granularity,
+            max_steps,
+            trace,
+            semihosting,
+            semihosting_cmdline,
+        } => {
+            use riscv_vm::semihosting::SemihostingConfig;
+            use riscv_vm::vm::arch_test::{ArchTest, ArchTestExit};
+
+            let data = fs::read(elf)
+                .map_err(|e| format!("Failed to read '{}': {}", elf.display(), e))?;
+            let mut test = ArchTest::load(&data)?;
+            if let Some(root) = semihosting {
+                test.emu.bus.set_htif_root(root.clone());
+                let mut config = SemihostingConfig::new(root);
+                config.cmdline = semihosting_cmdline
+                    .clone()
+                    .unwrap_or_else(|| elf.display().to_string());
+                test.emu.enable_semihosting(config);
+            }
+            let trace_log = match trace {
+                Some(path) => Some(
+                    TraceLog::create(path, TraceFilter::default())
+                        .map_err(|e| format!("Failed to create trace '{}': {}", path.display(), e))?,
+                ),
+                None => None,
+            };
+            if let Some(log) = &trace_log {
+                test.enable_trace(log);
+            }
+
+            let exit = test.run(*max_steps);
+            if let Some(log) = &trace_log {
+                log.flush();
+            }
+            let console = test.emu.drain_uart_output();
+            if !console.is_empty() {
+                std::io::stdout().write_all(&console)?;
+            }
+            if let Some(path) = signature {
+                fs::write(path, test.signature(*signature_granularity)?)
+                    .map_err(|e| format!("Failed to write '{}': {}", path.display(), e))?;
+            }
+
+            match exit? {
+                ArchTestExit::Timeout => {
+                    eprintln!("[ArchTest] Timed out after {} steps at PC=0x{:x}", max_steps, test.emu.cpu.pc);
+                    std::process::exit(2);
+                }
+                exit if exit.passed() => println!("[ArchTest] PASS"),
+                exit => {
+                    println!("[ArchTest] FAIL ({:?})", exit);
+                    std::process::exit(exit.exit_status());
+                }
+            }
+            Ok(())
+        }
+        #[cfg(target_os = "linux")]
+        Command::User {
+            sysroot,
+            memory,
+            env,
+            elf,
+            args,
+        } => {
+            use riscv_vm::vm::user::{UserConfig, UserProcess};
+
+            let mut vars: Vec<(String, String)> = std::env::vars().collect();
+            for var in env {
+                let (key, value) = var
+                    .split_once('=')
+                    .ok_or_else(|| format!("--env expects KEY=VALUE, got '{}'", var))?;
+                vars.retain(|(k, _)| k != key);
+                vars.push((key.to_string(), value.to_string()));
+            }
+            let config = UserConfig {
+                args: std::iter::once(elf.display().to_string())
+                    .chain(args.iter().cloned())
+                    .collect(),
+                env: vars.iter().map(|(k, v)| format!("{}={}", k, v)).collect(),
+                sysroot: sysroot.clone(),
+                memory: memory << 20,
+            };
+            let mut process = UserProcess::load(elf, config)?;
+            let status = process.run()?;
+            std::io::stdout().flush()?;
+            std::process::exit(status);
+        }
+        Command::Overlay(OverlayCommand::Create { base, overlay }) => {
+            Qcow2Backend::create_overlay(overlay, base)?;
+            println!("Created {} over {}", overlay.display(), base.display());
+            Ok(())
+        }
+        Command::Overlay(OverlayCommand::Commit { overlay, discard }) => {
+            let image = Qcow2Backend::open_for_commit(overlay)?;
+            let copied = image.commit()?;
+            let base = image.backing_file().map(|p| p.display().to_string());
+            drop(image);
+            println!("Committed {} bytes to {}", copied, base.unwrap_or_default());
+            if *discard {
+                Qcow2Backend::discard(overlay)?;
+            }
+            Ok(())
+        }
+        Command::Overlay(OverlayCommand::Discard { overlay }) => {
+            Qcow2Backend::discard(overlay)?;
+            Ok(())
+        }
+        Command::Manifest { image } => {
+            // The HTTP backend serves raw images, so the manifest must cover
+            // the file's bytes as they are, not a qcow2 image's contents.
+            if blockdev::is_qcow2(image)? {
+                return Err(format!(
+                    "'{}' is a qcow2 image; convert it to raw before serving it over HTTP",
+                    image.display()
+                )
+                .into());
+            }
+            let image = blockdev::FileBackend::open(image, true)?;
+            print!("{}", blockdev::http::manifest(&image)?);
+            Ok(())
+        }
+    }
+}
+
 /// Run VM in headless mode (no GUI)
-fn run_headless(mut vm: NativeVm) {
+fn run_headless(mut vm: NativeVm, quiet: bool) {
     vm.run();
+    let outcome = vm.outcome();
+    drop(vm);
+    exit_with(outcome, quiet);
+}
 
-    // Report exit status
-    let halt_code = vm.shared.halt_code();
-    if halt_code == 0x5555 {
+/// Report how the run ended and exit with the matching status: the guest's
+/// own (test finisher, HTIF or SBI shutdown) or one for a limit or pattern.
+fn exit_with(outcome: RunOutcome, quiet: bool) {
+    let status = outcome.exit_status();
+    if status != 0 || !quiet {
         uart_println!();
-        uart_println!("[VM] Clean shutdown (PASS)");
-    } else {
-        uart_println!();
-        uart_println!("[VM] Shutdown with code: {:#x}", halt_code);
+        uart_println!("[VM] {}", outcome);
+    }
+    if status != 0 {
+        std::process::exit(status);
     }
 }
 
 /// Run VM with GUI window
 #[cfg(feature = "gui")]
-fn run_with_gui(mut vm: NativeVm, scale_factor: u8) -> Result<(), Box<dyn std::error::Error>> {
+fn run_with_gui(mut vm: NativeVm, scale_factor: u8, quiet: bool) -> Result<(), Box<dyn std::error::Error>> {
     let (width, height) = (1024, 768);
     let scale = match scale_factor {
         2 => Scale::X2,
@@ -290,6 +931,7 @@ fn run_with_gui(mut vm: NativeVm, scale_factor: u8) -> Result<(), Box<dyn std::e
     // Run the VM in a separate thread
     let vm_thread = thread::spawn(move || {
         vm.run();
+        vm.outcome()
     });
 
     // Main GUI loop - polls framebuffer and updates window
@@ -297,8 +939,8 @@ fn run_with_gui(mut vm: NativeVm, scale_factor: u8) -> Result<(), Box<dyn std::e
     let mut last_mouse_pressed = false;
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        // Check for VM halt
-        if shared.is_halted() {
+        // Check for VM halt (or a limit stopping it)
+        if shared.should_stop() {
             break;
         }
 
@@ -452,15 +1094,9 @@ fn run_with_gui(mut vm: NativeVm, scale_factor: u8) -> Result<(), Box<dyn std::e
     uart_println!();
     uart_println!("[GUI] Window closed, waiting for VM to stop...");
     
-    if let Err(e) = vm_thread.join() {
-        eprintln!("[GUI] VM thread panicked: {:?}", e);
-    }
-
-    let halt_code = shared.halt_code();
-    if halt_code == 0x5555 {
-        uart_println!("[VM] Clean shutdown (PASS)");
-    } else if halt_code != 0 {
-        uart_println!("[VM] Shutdown with code: {:#x}", halt_code);
+    match vm_thread.join() {
+        Ok(outcome) => exit_with(outcome, quiet),
+        Err(e) => eprintln!("[GUI] VM thread panicked: {:?}", e),
     }
 
     Ok(())
@@ -250,6 +250,16 @@ impl Tlb {
             };
         }
     }
+
+    /// Number of slots in the TLB.
+    pub const fn capacity(&self) -> usize {
+        TLB_SIZE
+    }
+
+    /// Iterate over the valid entries.
+    pub fn entries(&self) -> impl Iterator<Item = &TlbEntry> {
+        self.entries.iter().filter(|entry| entry.valid)
+    }
 }
 
 /// Sv39/Sv48 translation + A/D bit updates.
@@ -266,6 +276,7 @@ pub fn translate(
     access_type: AccessType,
 ) -> Result<u64, Trap> {
     // No translation in Machine mode (always Bare).
+    let mode = effective_mode(mode, mstatus, access_type);
     if mode == Mode::Machine {
         return Ok(addr);
     }
@@ -443,6 +454,132 @@ pub fn translate(
     Err(page_fault(access_type, addr))
 }
 
+/// Privilege an access is translated and checked with: loads and stores in
+/// M-mode with `mstatus.MPRV` set use the mode in MPP.
+#[inline(always)]
+pub fn effective_mode(mode: Mode, mstatus: u64, access_type: AccessType) -> Mode {
+    if mode == Mode::Machine
+        && (mstatus >> 17) & 1 == 1
+        && !matches!(access_type, AccessType::Instruction)
+    {
+        Mode::from_mpp(mstatus >> 11)
+    } else {
+        mode
+    }
+}
+
+/// Side-effect-free Sv39/Sv48 page walk for debugging and tooling.
+///
+/// Unlike [`translate`] this never consults or fills a TLB, never sets A/D
+/// bits and performs no permission checks, so it can be used to peek at guest
+/// memory (profiler stack walks, monitor commands) without perturbing the
+/// hart. Returns `None` if the address is not mapped.
+pub fn peek_translate(bus: &dyn Bus, mode: Mode, satp: u64, addr: u64) -> Option<u64> {
+    if mode == Mode::Machine {
+        return Some(addr);
+    }
+
+    let levels = match (satp >> 60) & 0xF {
+        8 => 3,
+        9 => 4,
+        _ => return Some(addr),
+    };
+
+    let root_ppn = satp & ((1u64 << 44) - 1);
+    let mut a = root_ppn * PAGE_SIZE;
+
+    for i in (0..levels).rev() {
+        let vpn = (addr >> (12 + 9 * i as u64)) & 0x1FF;
+        let pte = bus.load(a + vpn * PTE_SIZE, 8).ok()?;
+
+        if pte & 1 == 0 {
+            return None;
+        }
+
+        let ppn = (pte >> 10) & 0xFFF_FFFF_FFFF;
+        if (pte >> 1) & 0b101 == 0 {
+            // Non-leaf: R=X=0.
+            a = ppn * PAGE_SIZE;
+            continue;
+        }
+
+        let vpn_mask = (1u64 << (9 * i)) - 1;
+        let result_ppn = (ppn & !vpn_mask) | ((addr >> 12) & vpn_mask);
+        return Some((result_ppn << 12) | (addr & 0xFFF));
+    }
+
+    None
+}
+
+/// A run of virtual memory found by [`walk_page_table`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageMapping {
+    pub va: u64,
+    pub pa: u64,
+    pub len: u64,
+    /// Low flag bits of the leaf PTE(s): V, R, W, X, U, G, A, D.
+    pub flags: u8,
+}
+
+/// Side-effect-free walk of the Sv39/Sv48 page table rooted at `satp`.
+///
+/// Returns every leaf mapping in virtual address order, with adjacent leaves
+/// merged when both addresses are contiguous and the flags match. Virtual
+/// addresses are sign-extended. Returns nothing for bare translation.
+pub fn walk_page_table(bus: &dyn Bus, satp: u64) -> Vec<PageMapping> {
+    let levels = match (satp >> 60) & 0xF {
+        8 => 3,
+        9 => 4,
+        _ => return Vec::new(),
+    };
+    let root = (satp & ((1u64 << 44) - 1)) * PAGE_SIZE;
+    let mut out = Vec::new();
+    walk_table(bus, root, levels - 1, levels, 0, &mut out);
+    out
+}
+
+fn walk_table(
+    bus: &dyn Bus,
+    table: u64,
+    level: u32,
+    levels: u32,
+    prefix: u64,
+    out: &mut Vec<PageMapping>,
+) {
+    let va_bits = 12 + 9 * levels;
+    for i in 0..512u64 {
+        let Ok(pte) = bus.load(table + i * PTE_SIZE, 8) else {
+            return;
+        };
+        if pte & 1 == 0 {
+            continue;
+        }
+        let shift = 12 + 9 * level;
+        let va = prefix | (i << shift);
+        let pa = ((pte >> 10) & 0xFFF_FFFF_FFFF) * PAGE_SIZE;
+        if (pte >> 1) & 0b111 == 0 {
+            if level > 0 {
+                walk_table(bus, pa, level - 1, levels, va, out);
+            }
+            continue;
+        }
+
+        let len = 1u64 << shift;
+        let flags = pte as u8;
+        let va = (((va << (64 - va_bits)) as i64) >> (64 - va_bits)) as u64;
+        match out.last_mut() {
+            Some(last)
+                if last.va.wrapping_add(last.len) == va
+                    && last.pa + last.len == pa
+                    && last.flags == flags =>
+            {
+                last.len += len;
+            }
+            _ => out.push(PageMapping { va, pa, len, flags }),
+        }
+    }
+}
+
 #[inline(always)]
 fn check_permission_tlb(
     mode: Mode,
@@ -495,7 +632,7 @@ fn page_fault(access_type: AccessType, addr: u64) -> Trap {
 }
 
 #[inline]
-fn access_fault(access_type: AccessType, addr: u64) -> Trap {
+pub(crate) fn access_fault(access_type: AccessType, addr: u64) -> Trap {
     match access_type {
         AccessType::Instruction => Trap::InstructionAccessFault(addr),
         AccessType::Load => Trap::LoadAccessFault(addr),
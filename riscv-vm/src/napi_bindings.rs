@@ -1,7 +1,8 @@
 //! Node.js native addon bindings via napi-rs.
 //!
 //! This module exposes WebTransport client functionality to Node.js,
-//! reusing the existing native WebTransport implementation.
+//! reusing the existing native WebTransport implementation, and a scripted
+//! guest console for integration tests.
 
 use napi_derive::napi;
 use napi_rs::bindgen_prelude::*;
@@ -14,6 +15,8 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use tokio::runtime::Runtime;
+use crate::vm::expect::{Expect, Match};
+use crate::vm::native::{NativeConsole, NativeVm, SharedState};
 use wtransport::ClientConfig;
 use wtransport::Endpoint;
 use wtransport::tls::Sha256Digest;
@@ -620,3 +623,113 @@ impl WebTransportClient {
         log::info!("[WebTransport] Shutdown signaled");
     }
 }
+
+/// Result of `GuestConsole.waitFor`.
+#[napi(object)]
+pub struct ConsoleMatch {
+    /// Output between the previous match and this one
+    pub before: String,
+    pub matched: String,
+    /// Capture groups (null for groups that did not participate)
+    pub groups: Vec<Option<String>>,
+}
+
+impl From<Match> for ConsoleMatch {
+    fn from(found: Match) -> Self {
+        Self {
+            before: found.before,
+            matched: found.matched,
+            groups: found.groups,
+        }
+    }
+}
+
+/// Console of a VM running on a background thread, scripted expect-style.
+///
+/// Timeouts are in milliseconds of guest time. Failures throw with the last
+/// lines of console output in the message.
+#[napi]
+pub struct GuestConsole {
+    console: Expect<NativeConsole>,
+    shared: Arc<SharedState>,
+    runner: Option<thread::JoinHandle<()>>,
+}
+
+#[napi]
+impl GuestConsole {
+    /// Boot a kernel and attach to its console.
+    ///
+    /// @param kernelPath - ELF or raw kernel image
+    /// @param harts - Number of harts (default 1)
+    #[napi(constructor)]
+    pub fn new(kernel_path: String, harts: Option<u32>) -> Result<Self> {
+        let kernel = std::fs::read(&kernel_path).map_err(|e| {
+            Error::from_reason(format!("Failed to read '{}': {}", kernel_path, e))
+        })?;
+        let mut vm = NativeVm::new(&kernel, harts.unwrap_or(1) as usize).map_err(Error::from_reason)?;
+        let console = vm.expect_console(0).map_err(Error::from_reason)?;
+        let shared = Arc::clone(&vm.shared);
+        let runner = thread::spawn(move || vm.run());
+        Ok(Self {
+            console,
+            shared,
+            runner: Some(runner),
+        })
+    }
+
+    /// Wait until the console output matches a regex.
+    #[napi]
+    pub fn wait_for(&mut self, pattern: String, timeout_ms: u32) -> Result<ConsoleMatch> {
+        self.console
+            .wait_for(&pattern, Duration::from_millis(timeout_ms as u64))
+            .map(ConsoleMatch::from)
+            .map_err(Error::from_reason)
+    }
+
+    /// Type text into the console.
+    #[napi]
+    pub fn send(&mut self, text: String) {
+        self.console.send(&text);
+    }
+
+    /// Type a line followed by Enter.
+    #[napi]
+    pub fn send_line(&mut self, line: String) {
+        self.console.send_line(&line);
+    }
+
+    /// Set the shell prompt regex used by `runCommandAndCapture`.
+    #[napi]
+    pub fn set_prompt(&mut self, pattern: String) -> Result<()> {
+        self.console.set_prompt(&pattern).map_err(Error::from_reason)
+    }
+
+    /// Run a command at the prompt and return its output.
+    #[napi]
+    pub fn run_command_and_capture(&mut self, command: String, timeout_ms: u32) -> Result<String> {
+        self.console
+            .run_command_and_capture(&command, Duration::from_millis(timeout_ms as u64))
+            .map_err(Error::from_reason)
+    }
+
+    /// Everything printed and typed so far.
+    #[napi]
+    pub fn transcript(&self) -> String {
+        self.console.transcript()
+    }
+
+    /// Stop the VM and wait for its thread to exit.
+    #[napi]
+    pub fn shutdown(&mut self) {
+        self.shared.request_halt();
+        if let Some(runner) = self.runner.take() {
+            runner.join().ok();
+        }
+    }
+}
+
+impl Drop for GuestConsole {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -209,8 +209,6 @@ mod native {
 
     impl WebTransportBackend {
         pub fn new(url: &str, cert_hash: Option<String>) -> Self {
-            log::warn!("[WebTransport] Creating backend for URL: {}", url);
-
             // Generate a random MAC address (locally administered, unicast)
             // Use system time + process id for randomness
             let now = std::time::SystemTime::now()
@@ -236,6 +234,14 @@ mod native {
             mac[4] = ((seed >> 16) & 0xff) as u8;
             mac[5] = (seed & 0xff) as u8;
 
+            Self::with_mac(url, cert_hash, mac)
+        }
+
+        /// Create a backend that registers with the relay as `mac` (e.g. the
+        /// MAC address the guest's NIC already uses).
+        pub fn with_mac(url: &str, cert_hash: Option<String>, mac: [u8; 6]) -> Self {
+            log::warn!("[WebTransport] Creating backend for URL: {}", url);
+
             let (tx_to_transport, rx_to_transport) = channel::<Vec<u8>>();
             let (tx_from_transport, rx_from_transport) = channel::<Vec<u8>>();
 
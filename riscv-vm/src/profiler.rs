@@ -0,0 +1,296 @@
+//! Guest PC sampling profiler.
+//!
+//! A ticker thread bumps a shared epoch counter at a fixed interval. Each
+//! hart checks the epoch between execution batches and, when it has moved,
+//! records its current PC and (optionally) the return addresses found by
+//! walking the guest frame-pointer chain. Samples are symbolised against an
+//! ELF symbol table on shutdown and written as folded stacks, the input
+//! format of `flamegraph.pl` and `inferno-flamegraph`.
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::csr::CSR_SATP;
+use crate::loader::SymbolTable;
+use crate::mmu;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Profiler settings.
+#[derive(Debug, Clone)]
+pub struct ProfilerConfig {
+    /// Where to write folded stacks on shutdown.
+    pub output: PathBuf,
+    /// Time between samples.
+    pub interval: Duration,
+    /// Walk the guest frame-pointer (s0) chain to collect call stacks.
+    /// Requires a guest built with `-fno-omit-frame-pointer`.
+    pub frame_pointers: bool,
+    /// Maximum number of frames recorded per sample.
+    pub max_depth: usize,
+    /// Number of functions listed in the shutdown report.
+    pub top_n: usize,
+}
+
+impl ProfilerConfig {
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        Self {
+            output: output.into(),
+            interval: Duration::from_millis(1),
+            frame_pointers: false,
+            max_depth: 64,
+            top_n: 20,
+        }
+    }
+}
+
+/// Sampling profiler shared by all hart threads.
+pub struct Profiler {
+    config: ProfilerConfig,
+    symbols: SymbolTable,
+    epoch: AtomicU64,
+    stop: AtomicBool,
+    ticker: Mutex<Option<JoinHandle<()>>>,
+    /// Raw stacks (leaf first) and their sample counts.
+    stacks: Mutex<HashMap<Vec<u64>, u64>>,
+}
+
+impl Profiler {
+    /// Create a profiler and start its sampling ticker.
+    pub fn start(config: ProfilerConfig, symbols: SymbolTable) -> Arc<Self> {
+        let profiler = Arc::new(Self::new(config, symbols));
+
+        let ticker = Arc::clone(&profiler);
+        let handle = thread::Builder::new()
+            .name("profiler".to_string())
+            .spawn(move || {
+                while !ticker.stop.load(Ordering::Relaxed) {
+                    thread::sleep(ticker.config.interval);
+                    ticker.epoch.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .expect("Failed to spawn profiler thread");
+        *profiler.ticker.lock().unwrap() = Some(handle);
+
+        println!(
+            "[Profile] Sampling every {:?} ({} symbols)",
+            profiler.config.interval,
+            profiler.symbols.len()
+        );
+        profiler
+    }
+
+    fn new(config: ProfilerConfig, symbols: SymbolTable) -> Self {
+        Self {
+            config,
+            symbols,
+            epoch: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+            ticker: Mutex::new(None),
+            stacks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a sample if the ticker has fired since this hart last sampled.
+    ///
+    /// `last_epoch` is per-hart state owned by the caller. This is cheap
+    /// enough to call once per execution batch.
+    #[inline]
+    pub fn maybe_sample(&self, last_epoch: &mut u64, cpu: &Cpu, bus: &dyn Bus) {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        if epoch != *last_epoch {
+            *last_epoch = epoch;
+            self.sample(cpu, bus);
+        }
+    }
+
+    /// Record the hart's current call stack.
+    pub fn sample(&self, cpu: &Cpu, bus: &dyn Bus) {
+        let stack = if self.config.frame_pointers {
+            self.walk_frames(cpu, bus)
+        } else {
+            vec![cpu.pc]
+        };
+        *self.stacks.lock().unwrap().entry(stack).or_insert(0) += 1;
+    }
+
+    /// Walk the standard RISC-V frame record: with frame pointers enabled,
+    /// `fp - 8` holds the return address and `fp - 16` the caller's fp.
+    fn walk_frames(&self, cpu: &Cpu, bus: &dyn Bus) -> Vec<u64> {
+        let satp = cpu.csrs[CSR_SATP as usize];
+        let read = |va: u64| {
+            mmu::peek_translate(bus, cpu.mode, satp, va).and_then(|pa| bus.read64(pa).ok())
+        };
+
+        let mut stack = vec![cpu.pc];
+        let mut fp = cpu.regs[8];
+        while stack.len() < self.config.max_depth {
+            if fp == 0 || fp & 0x7 != 0 {
+                break;
+            }
+            let (Some(ra), Some(prev_fp)) = (read(fp.wrapping_sub(8)), read(fp.wrapping_sub(16)))
+            else {
+                break;
+            };
+            if ra == 0 {
+                break;
+            }
+            // Point inside the call instruction so calls at the very end of
+            // a function still resolve to the caller.
+            stack.push(ra.wrapping_sub(1));
+            // The stack grows down, so caller frames live at higher addresses.
+            if prev_fp <= fp {
+                break;
+            }
+            fp = prev_fp;
+        }
+        stack
+    }
+
+    /// Total number of samples taken so far.
+    pub fn sample_count(&self) -> u64 {
+        self.stacks.lock().unwrap().values().sum()
+    }
+
+    /// Symbolised stacks in folded format (`outer;inner count`), sorted.
+    pub fn folded(&self) -> Vec<(String, u64)> {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (stack, count) in self.stacks.lock().unwrap().iter() {
+            let line = stack
+                .iter()
+                .rev()
+                .map(|&addr| self.symbol_name(addr))
+                .collect::<Vec<_>>()
+                .join(";");
+            *folded.entry(line).or_insert(0) += count;
+        }
+        let mut lines: Vec<_> = folded.into_iter().collect();
+        lines.sort();
+        lines
+    }
+
+    /// Hottest functions as `(name, self samples, total samples)`, sorted by
+    /// self samples.
+    pub fn top_functions(&self, n: usize) -> Vec<(String, u64, u64)> {
+        let mut counts: HashMap<String, (u64, u64)> = HashMap::new();
+        for (stack, &count) in self.stacks.lock().unwrap().iter() {
+            let names: Vec<String> = stack.iter().map(|&a| self.symbol_name(a)).collect();
+            if let Some(leaf) = names.first() {
+                counts.entry(leaf.clone()).or_default().0 += count;
+            }
+            let mut seen: Vec<&String> = Vec::new();
+            for name in &names {
+                // Count recursive frames once per sample.
+                if !seen.contains(&name) {
+                    seen.push(name);
+                    counts.entry(name.clone()).or_default().1 += count;
+                }
+            }
+        }
+        let mut top: Vec<_> = counts
+            .into_iter()
+            .map(|(name, (own, total))| (name, own, total))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    fn symbol_name(&self, addr: u64) -> String {
+        match self.symbols.lookup(addr) {
+            Some((sym, _)) => sym.name.clone(),
+            None => format!("0x{:x}", addr),
+        }
+    }
+
+    /// Stop sampling, write the folded output and print the hot function report.
+    pub fn finish(&self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.ticker.lock().unwrap().take() {
+            handle.join().ok();
+        }
+
+        let mut out = BufWriter::new(File::create(&self.config.output)?);
+        for (line, count) in self.folded() {
+            writeln!(out, "{} {}", line, count)?;
+        }
+        out.flush()?;
+
+        let total = self.sample_count();
+        println!(
+            "[Profile] {} samples written to {}",
+            total,
+            self.config.output.display()
+        );
+        if total == 0 {
+            return Ok(());
+        }
+        println!("[Profile] Top {} functions:", self.config.top_n);
+        println!("[Profile]    self%   total%  function");
+        for (name, own, all) in self.top_functions(self.config.top_n) {
+            println!(
+                "[Profile]  {:6.2}%  {:6.2}%  {}",
+                own as f64 * 100.0 / total as f64,
+                all as f64 * 100.0 / total as f64,
+                name
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{DRAM_BASE, SystemBus};
+
+    fn profiler(frame_pointers: bool) -> Profiler {
+        let mut config = ProfilerConfig::new("/dev/null");
+        config.frame_pointers = frame_pointers;
+        Profiler::new(config, SymbolTable::default())
+    }
+
+    #[test]
+    fn test_sample_pc_only() {
+        let bus = SystemBus::new(DRAM_BASE, 1024 * 1024);
+        let p = profiler(false);
+        let mut cpu = Cpu::new(DRAM_BASE, 0);
+        p.sample(&cpu, &bus);
+        p.sample(&cpu, &bus);
+        cpu.pc = DRAM_BASE + 0x40;
+        p.sample(&cpu, &bus);
+
+        assert_eq!(p.sample_count(), 3);
+        let folded = p.folded();
+        assert_eq!(folded[0], ("0x80000000".to_string(), 2));
+        assert_eq!(folded[1], ("0x80000040".to_string(), 1));
+        assert_eq!(p.top_functions(1)[0], ("0x80000000".to_string(), 2, 2));
+    }
+
+    #[test]
+    fn test_frame_pointer_walk() {
+        let bus = SystemBus::new(DRAM_BASE, 1024 * 1024);
+        let p = profiler(true);
+        let mut cpu = Cpu::new(DRAM_BASE + 0x100, 0);
+        cpu.mode = crate::Mode::Machine;
+
+        // Two frame records: inner (fp=0x8000_1000) -> outer (fp=0x8000_1100) -> end.
+        let inner_fp = DRAM_BASE + 0x1000;
+        let outer_fp = DRAM_BASE + 0x1100;
+        bus.write64(inner_fp - 8, DRAM_BASE + 0x204).unwrap();
+        bus.write64(inner_fp - 16, outer_fp).unwrap();
+        bus.write64(outer_fp - 8, DRAM_BASE + 0x304).unwrap();
+        bus.write64(outer_fp - 16, 0).unwrap();
+        cpu.regs[8] = inner_fp;
+
+        p.sample(&cpu, &bus);
+        let folded = p.folded();
+        assert_eq!(folded.len(), 1);
+        assert_eq!(folded[0].0, "0x80000303;0x80000203;0x80000100");
+    }
+}
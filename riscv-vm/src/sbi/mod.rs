@@ -137,6 +137,17 @@ impl SbiRet {
 // Main SBI Dispatcher
 // ============================================================================
 
+/// Host halt code if the pending ECALL powers the system off (SRST shutdown
+/// or the legacy shutdown call). The caller stops the VM with it instead of
+/// calling [`handle_sbi_call`].
+pub fn shutdown_request(cpu: &Cpu) -> Option<u64> {
+    match cpu.read_reg(Register::X17) {
+        EID_LEGACY_SHUTDOWN => Some(crate::bus::FINISHER_PASS),
+        EID_SRST => srst::shutdown_code(cpu, cpu.read_reg(Register::X16)),
+        _ => None,
+    }
+}
+
 /// Handle an SBI call from S-mode.
 ///
 /// This function is called when the CPU is in S-mode and executes ECALL.
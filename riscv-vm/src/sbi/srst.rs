@@ -3,6 +3,7 @@
 //! Provides system reset functionality per SBI v2.0 spec.
 
 use super::SbiRet;
+use crate::bus::{FINISHER_PASS, finisher_code};
 use crate::cpu::Cpu;
 use crate::engine::decoder::Register;
 
@@ -84,6 +85,19 @@ pub fn system_reset(cpu: &Cpu) -> SbiRet {
     }
 }
 
+/// Halt code for a System Reset call that powers the machine off, in test
+/// finisher encoding: a plain shutdown passes, any other reason exits with
+/// status 1. `None` for reboots and other functions.
+pub fn shutdown_code(cpu: &Cpu, fid: u64) -> Option<u64> {
+    if fid != FID_SYSTEM_RESET || cpu.read_reg(Register::X10) != RESET_TYPE_SHUTDOWN {
+        return None;
+    }
+    match cpu.read_reg(Register::X11) {
+        RESET_REASON_NONE => Some(FINISHER_PASS),
+        _ => Some(finisher_code(1)),
+    }
+}
+
 // ============================================================================
 // Tests
 // ============================================================================
@@ -98,4 +112,15 @@ mod tests {
         assert_eq!(RESET_TYPE_COLD_REBOOT, 1);
         assert_eq!(RESET_TYPE_WARM_REBOOT, 2);
     }
+
+    #[test]
+    fn test_shutdown_code() {
+        let mut cpu = Cpu::new(0, 0);
+        assert_eq!(shutdown_code(&cpu, FID_SYSTEM_RESET), Some(FINISHER_PASS));
+        cpu.write_reg(Register::X11, RESET_REASON_SYSTEM_FAILURE);
+        assert_eq!(shutdown_code(&cpu, FID_SYSTEM_RESET), Some(finisher_code(1)));
+        assert_eq!(shutdown_code(&cpu, 1), None);
+        cpu.write_reg(Register::X10, RESET_TYPE_COLD_REBOOT);
+        assert_eq!(shutdown_code(&cpu, FID_SYSTEM_RESET), None);
+    }
 }
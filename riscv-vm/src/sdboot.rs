@@ -1,7 +1,21 @@
 //! SD Card Boot Support
 //!
-//! Parses MBR partition table and FAT32 filesystem to load kernel from SD card.
-//! Used by all VM platforms (native, Node.js, browser).
+//! Parses the partition table (MBR or GPT) and FAT filesystem to load
+//! kernel from SD card. Used by all VM platforms (native, Node.js, browser).
+//!
+//! An `extlinux/extlinux.conf` on the boot partition picks the kernel,
+//! initrd, command line and DTB; without one, `kernel.bin` is booted.
+
+use crate::blockdev::BlockBackend;
+use crate::loader::raw_kernel_load_address;
+
+mod config;
+mod fat;
+mod gpt;
+
+pub use config::{BootConfig, BootEntry, CONFIG_PATHS};
+pub use fat::{FatDirEntry, FatFs, FatType};
+pub use gpt::{GptPartition, Guid, is_protective_mbr, parse_gpt};
 
 /// MBR partition entry (16 bytes)
 #[derive(Debug, Clone, Copy, Default)]
@@ -40,193 +54,177 @@ pub fn parse_mbr(sector0: &[u8]) -> Result<[PartitionEntry; 4], &'static str> {
     Ok(partitions)
 }
 
-/// Find boot partition (FAT32 or FAT16)
-pub fn find_boot_partition(partitions: &[PartitionEntry; 4]) -> Option<&PartitionEntry> {
-    for part in partitions {
-        // FAT32 types: 0x0B (CHS), 0x0C (LBA)
-        // FAT16 types: 0x06 (CHS), 0x0E (LBA)
-        if matches!(part.partition_type, 0x0B | 0x0C | 0x06 | 0x0E) && part.sector_count > 0 {
-            return Some(part);
-        }
-    }
-    None
+/// Partition type, from whichever table the disk has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
 }
 
-/// Minimal FAT32 boot sector parsing
-#[derive(Debug, Clone)]
-pub struct Fat32BootSector {
-    pub bytes_per_sector: u16,
-    pub sectors_per_cluster: u8,
-    pub reserved_sectors: u16,
-    pub num_fats: u8,
-    pub sectors_per_fat: u32,
-    pub root_cluster: u32,
+/// A partition of an MBR or GPT disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// 1-based number, as in `/dev/mmcblk0pN`.
+    pub number: u32,
+    pub start_lba: u64,
+    pub sector_count: u64,
+    pub partition_type: PartitionType,
+    /// GPT partition name; empty on MBR disks.
+    pub label: String,
+    /// What Linux reports as PARTUUID: the GPT unique partition GUID, or
+    /// `SSSSSSSS-NN` from the MBR disk signature (none if it is zero).
+    pub partuuid: Option<String>,
 }
 
-impl Fat32BootSector {
-    pub fn parse(boot_sector: &[u8]) -> Result<Self, &'static str> {
-        if boot_sector.len() < 512 {
-            return Err("Boot sector too small");
+impl Partition {
+    /// Whether the type is one used for FAT boot partitions.
+    pub fn is_fat(&self) -> bool {
+        match self.partition_type {
+            PartitionType::Mbr(t) => matches!(t, 0x0B | 0x0C | 0x06 | 0x0E | 0xEF),
+            PartitionType::Gpt(guid) => guid == Guid::EFI_SYSTEM || guid == Guid::BASIC_DATA,
         }
-        
-        // Check for FAT signature
-        if boot_sector[510] != 0x55 || boot_sector[511] != 0xAA {
-            return Err("Invalid FAT boot sector signature");
-        }
-        
-        let bytes_per_sector = u16::from_le_bytes([boot_sector[11], boot_sector[12]]);
-        let sectors_per_cluster = boot_sector[13];
-        let reserved_sectors = u16::from_le_bytes([boot_sector[14], boot_sector[15]]);
-        let num_fats = boot_sector[16];
-        
-        // FAT32: sectors per FAT is at offset 36 (4 bytes)
-        let sectors_per_fat = u32::from_le_bytes([
-            boot_sector[36], boot_sector[37], boot_sector[38], boot_sector[39]
-        ]);
-        
-        // Root cluster at offset 44
-        let root_cluster = u32::from_le_bytes([
-            boot_sector[44], boot_sector[45], boot_sector[46], boot_sector[47]
-        ]);
-        
-        Ok(Self {
-            bytes_per_sector,
-            sectors_per_cluster,
-            reserved_sectors,
-            num_fats,
-            sectors_per_fat,
-            root_cluster,
-        })
     }
-    
-    /// Get the first sector of the data region
-    pub fn data_start_sector(&self) -> u32 {
-        self.reserved_sectors as u32 + (self.num_fats as u32 * self.sectors_per_fat)
+
+    /// Whether the type is one used for Linux root filesystems.
+    pub fn is_linux(&self) -> bool {
+        match self.partition_type {
+            PartitionType::Mbr(t) => t == 0x83,
+            PartitionType::Gpt(guid) => {
+                guid == Guid::LINUX_FILESYSTEM || guid == Guid::LINUX_ROOT_RISCV64
+            }
+        }
     }
-    
-    /// Convert cluster number to sector number
-    pub fn cluster_to_sector(&self, cluster: u32) -> u32 {
-        self.data_start_sector() + (cluster - 2) * self.sectors_per_cluster as u32
+}
+
+/// Read the partition table: GPT behind a protective MBR, MBR otherwise.
+pub fn read_partitions(disk: &dyn BlockBackend) -> Result<Vec<Partition>, &'static str> {
+    let sector0 = read_disk(disk, 0, 512, "Disk image too small")?;
+    if is_protective_mbr(&sector0) {
+        return Ok(parse_gpt(disk)?
+            .into_iter()
+            .map(|p| Partition {
+                number: p.number,
+                start_lba: p.first_lba,
+                sector_count: (p.last_lba + 1).saturating_sub(p.first_lba),
+                partition_type: PartitionType::Gpt(p.type_guid),
+                label: p.name,
+                partuuid: Some(p.unique_guid.to_string()),
+            })
+            .collect());
     }
+    let signature = u32::from_le_bytes([sector0[440], sector0[441], sector0[442], sector0[443]]);
+    Ok(parse_mbr(&sector0)?
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.partition_type != 0)
+        .map(|(i, p)| Partition {
+            number: i as u32 + 1,
+            start_lba: p.start_lba as u64,
+            sector_count: p.sector_count as u64,
+            partition_type: PartitionType::Mbr(p.partition_type),
+            label: String::new(),
+            partuuid: (signature != 0).then(|| format!("{:08x}-{:02x}", signature, i + 1)),
+        })
+        .collect())
 }
 
-/// FAT32 directory entry (32 bytes)
-#[derive(Debug, Clone)]
-pub struct DirEntry {
-    pub name: [u8; 11],
-    pub attr: u8,
-    pub cluster_high: u16,
-    pub cluster_low: u16,
-    pub file_size: u32,
+/// Which partition to boot from (`--boot-partition`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionSelector {
+    Number(u32),
+    Label(String),
+    Type(Guid),
 }
 
-impl DirEntry {
-    pub fn parse(data: &[u8]) -> Option<Self> {
-        if data.len() < 32 {
-            return None;
+impl PartitionSelector {
+    /// Parse `N`, `label=NAME`, or `type=esp|linux|GUID`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(label) = spec.strip_prefix("label=") {
+            return Ok(Self::Label(label.to_string()));
         }
-        
-        // Skip deleted entries and end marker
-        if data[0] == 0x00 || data[0] == 0xE5 {
-            return None;
+        if let Some(kind) = spec.strip_prefix("type=") {
+            return match kind {
+                "esp" | "efi" => Ok(Self::Type(Guid::EFI_SYSTEM)),
+                "linux" => Ok(Self::Type(Guid::LINUX_FILESYSTEM)),
+                "xbootldr" => Ok(Self::Type(Guid::LINUX_XBOOTLDR)),
+                _ => Guid::parse(kind)
+                    .map(Self::Type)
+                    .ok_or_else(|| format!("'{}' is not esp, linux, xbootldr or a GUID", kind)),
+            };
         }
-        
-        // Skip long name entries
-        if data[11] == 0x0F {
-            return None;
+        match spec.parse() {
+            Ok(n) if n > 0 => Ok(Self::Number(n)),
+            _ => Err(format!(
+                "expected a partition number, label=NAME or type=TYPE, got '{}'",
+                spec
+            )),
         }
-        
-        let mut name = [0u8; 11];
-        name.copy_from_slice(&data[0..11]);
-        
-        Some(Self {
-            name,
-            attr: data[11],
-            cluster_high: u16::from_le_bytes([data[20], data[21]]),
-            cluster_low: u16::from_le_bytes([data[26], data[27]]),
-            file_size: u32::from_le_bytes([data[28], data[29], data[30], data[31]]),
-        })
-    }
-    
-    pub fn cluster(&self) -> u32 {
-        ((self.cluster_high as u32) << 16) | (self.cluster_low as u32)
     }
-    
-    /// Check if this entry matches a short filename (8.3 format)
-    pub fn matches_name(&self, name: &str) -> bool {
-        let name_upper = name.to_uppercase();
-        let parts: Vec<&str> = name_upper.split('.').collect();
-        
-        let (basename, ext) = if parts.len() == 2 {
-            (parts[0], parts[1])
-        } else {
-            (parts[0], "")
-        };
-        
-        // Build 8.3 format name
-        let mut fat_name = [b' '; 11];
-        for (i, &b) in basename.as_bytes().iter().take(8).enumerate() {
-            fat_name[i] = b;
-        }
-        for (i, &b) in ext.as_bytes().iter().take(3).enumerate() {
-            fat_name[8 + i] = b;
+
+    pub fn matches(&self, partition: &Partition) -> bool {
+        match self {
+            Self::Number(n) => partition.number == *n,
+            Self::Label(label) => partition.label == *label,
+            Self::Type(guid) => partition.partition_type == PartitionType::Gpt(*guid),
         }
-        
-        self.name == fat_name
     }
-    
-    pub fn is_directory(&self) -> bool {
-        (self.attr & 0x10) != 0
+}
+
+/// Pick the boot partition: the one `boot` selects, or the first FAT one.
+pub fn select_boot_partition<'a>(
+    partitions: &'a [Partition],
+    boot: Option<&PartitionSelector>,
+) -> Result<&'a Partition, &'static str> {
+    match boot {
+        Some(selector) => partitions
+            .iter()
+            .find(|p| selector.matches(p))
+            .ok_or("No partition matches --boot-partition"),
+        None => partitions
+            .iter()
+            .find(|p| p.is_fat() && p.sector_count > 0)
+            .ok_or("No FAT32 boot partition found"),
     }
 }
 
-/// Load a file from FAT32 filesystem
+/// Pick the boot partition (see [`select_boot_partition`]) and the root
+/// filesystem partition (the first other Linux one, or else any other
+/// partition).
+pub fn select_partitions(
+    partitions: &[Partition],
+    boot: Option<&PartitionSelector>,
+) -> Result<(Partition, Partition), &'static str> {
+    let boot_part = select_boot_partition(partitions, boot)?;
+    let others = || partitions.iter().filter(|p| p.number != boot_part.number);
+    let fs_part = others()
+        .find(|p| p.is_linux())
+        .or_else(|| others().next())
+        .ok_or("No filesystem partition found")?;
+    Ok((boot_part.clone(), fs_part.clone()))
+}
+
+/// Read `len` bytes at `offset`, or fail with `err`.
+fn read_disk(
+    disk: &dyn BlockBackend,
+    offset: u64,
+    len: usize,
+    err: &'static str,
+) -> Result<Vec<u8>, &'static str> {
+    let mut buf = vec![0; len];
+    disk.read_at(offset, &mut buf).map_err(|_| err)?;
+    Ok(buf)
+}
+
+/// Load a file from a FAT12/16/32 filesystem
 ///
-/// Returns the file contents if found.
+/// `path` may name a file in a subdirectory (`/boot/Image`); long and
+/// short names both match, ignoring case.
 pub fn load_file_from_fat32(
-    disk: &[u8],
-    partition_start_sector: u32,
-    filename: &str,
+    disk: &dyn BlockBackend,
+    partition_start_sector: u64,
+    path: &str,
 ) -> Result<Vec<u8>, &'static str> {
-    // Read FAT32 boot sector
-    let boot_offset = (partition_start_sector as usize) * 512;
-    if boot_offset + 512 > disk.len() {
-        return Err("Partition beyond disk");
-    }
-    
-    let fat32 = Fat32BootSector::parse(&disk[boot_offset..boot_offset + 512])?;
-    
-    // Read root directory
-    let root_sector = fat32.cluster_to_sector(fat32.root_cluster);
-    let root_offset = boot_offset + (root_sector as usize) * 512;
-    
-    // Search directory entries (read one cluster)
-    let cluster_size = fat32.sectors_per_cluster as usize * 512;
-    if root_offset + cluster_size > disk.len() {
-        return Err("Root directory beyond disk");
-    }
-    
-    let dir_data = &disk[root_offset..root_offset + cluster_size];
-    
-    for i in (0..cluster_size).step_by(32) {
-        if let Some(entry) = DirEntry::parse(&dir_data[i..]) {
-            if entry.matches_name(filename) && !entry.is_directory() {
-                // Found the file! Read its contents
-                let file_cluster = entry.cluster();
-                let file_sector = fat32.cluster_to_sector(file_cluster);
-                let file_offset = boot_offset + (file_sector as usize) * 512;
-                let file_size = entry.file_size as usize;
-                
-                if file_offset + file_size > disk.len() {
-                    return Err("File data beyond disk");
-                }
-                
-                return Ok(disk[file_offset..file_offset + file_size].to_vec());
-            }
-        }
-    }
-    
-    Err("File not found")
+    FatFs::open(disk, partition_start_sector)?.read_file(path)
 }
 
 /// Boot information extracted from SD card
@@ -234,38 +232,119 @@ pub fn load_file_from_fat32(
 pub struct SdBootInfo {
     pub kernel_data: Vec<u8>,
     pub kernel_load_addr: u64,
-    pub fs_partition_start: u32,
-    pub fs_partition_sectors: u32,
+    pub fs_partition_start: u64,
+    pub fs_partition_sectors: u64,
+    /// Partition the kernel was loaded from.
+    pub boot_partition: Partition,
+    /// Partition holding the root filesystem.
+    pub fs_partition: Partition,
+    /// Label of the boot config entry used, if there is a config file.
+    pub entry: Option<String>,
+    pub initrd: Option<Vec<u8>>,
+    /// Kernel command line, replacing the default `/chosen/bootargs`.
+    pub bootargs: Option<String>,
+    /// DTB to boot with instead of the generated one.
+    pub dtb: Option<Vec<u8>>,
+    pub dtb_overlays: Vec<Vec<u8>>,
 }
 
-/// Parse SD card image and extract boot information
-pub fn parse_sdcard(disk: &[u8]) -> Result<SdBootInfo, &'static str> {
-    if disk.len() < 512 {
-        return Err("Disk image too small");
+impl SdBootInfo {
+    /// `root=PARTUUID=...` for the kernel command line, if the root
+    /// partition has a PARTUUID.
+    pub fn root_arg(&self) -> Option<String> {
+        let partuuid = self.fs_partition.partuuid.as_ref()?;
+        Some(format!("root=PARTUUID={}", partuuid))
     }
-    
-    // Parse MBR
-    let partitions = parse_mbr(&disk[0..512])?;
-    
-    // Find boot partition
-    let boot_part = find_boot_partition(&partitions)
-        .ok_or("No FAT32 boot partition found")?;
-    
-    // Find filesystem partition (type 0x83 Linux or raw)
-    let fs_part = partitions.iter()
-        .find(|p| p.partition_type != 0 && p.start_lba != boot_part.start_lba)
-        .ok_or("No filesystem partition found")?;
-    
-    // Load kernel from boot partition
-    let kernel_data = load_file_from_fat32(disk, boot_part.start_lba, "kernel.bin")
-        .or_else(|_| load_file_from_fat32(disk, boot_part.start_lba, "KERNEL.BIN"))?;
-    
-    Ok(SdBootInfo {
-        kernel_data,
-        kernel_load_addr: 0x8020_0000,  // After OpenSBI reservation
+
+    /// Kernel command line to boot with: the boot config's `append`, or
+    /// failing that [`DEFAULT_BOOTARGS`] plus [`Self::root_arg`]. `None`
+    /// leaves the command line to the DTB.
+    ///
+    /// [`DEFAULT_BOOTARGS`]: crate::dtb::DEFAULT_BOOTARGS
+    pub fn kernel_cmdline(&self) -> Option<String> {
+        if let Some(bootargs) = &self.bootargs {
+            return Some(bootargs.clone());
+        }
+        // A DTB from the boot partition brings its own command line.
+        if self.dtb.is_some() {
+            return None;
+        }
+        Some(format!("{} {}", crate::dtb::DEFAULT_BOOTARGS, self.root_arg()?))
+    }
+}
+
+/// Parse SD card image and extract boot information
+pub fn parse_sdcard(disk: &dyn BlockBackend) -> Result<SdBootInfo, String> {
+    parse_sdcard_with(disk, None, None)
+}
+
+/// Like [`parse_sdcard`], booting from the partition `boot` selects and
+/// the boot config entry labelled `entry`.
+pub fn parse_sdcard_with(
+    disk: &dyn BlockBackend,
+    boot: Option<&PartitionSelector>,
+    entry: Option<&str>,
+) -> Result<SdBootInfo, String> {
+    let partitions = read_partitions(disk)?;
+    let (boot_part, fs_part) = select_partitions(&partitions, boot)?;
+    let fs = FatFs::open(disk, boot_part.start_lba)?;
+
+    let mut info = SdBootInfo {
+        kernel_data: Vec::new(),
+        kernel_load_addr: 0,
         fs_partition_start: fs_part.start_lba,
         fs_partition_sectors: fs_part.sector_count,
-    })
+        boot_partition: boot_part,
+        fs_partition: fs_part,
+        entry: None,
+        initrd: None,
+        bootargs: None,
+        dtb: None,
+        dtb_overlays: Vec::new(),
+    };
+    let config = CONFIG_PATHS
+        .iter()
+        .find_map(|&path| Some((path, fs.read_file(path).ok()?)));
+    let Some((path, text)) = config else {
+        if entry.is_some() {
+            return Err("No boot config to choose an entry from".to_string());
+        }
+        info.kernel_data = fs.read_file("kernel.bin")?;
+        info.kernel_load_addr = raw_kernel_load_address(&info.kernel_data);
+        return Ok(info);
+    };
+
+    let config = BootConfig::parse(&String::from_utf8_lossy(&text))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let chosen = config.entry(entry)?;
+    let read = |file: &str| {
+        fs.read_file(&config::resolve(path, file))
+            .map_err(|e| format!("{}: {}", file, e))
+    };
+    let kernel = chosen
+        .kernel
+        .as_deref()
+        .ok_or_else(|| format!("Boot entry '{}' has no kernel", chosen.label))?;
+    info.kernel_data = read(kernel)?;
+    if !chosen.initrd.is_empty() {
+        let mut initrd = Vec::new();
+        for file in &chosen.initrd {
+            initrd.extend(read(file)?);
+        }
+        info.initrd = Some(initrd);
+    }
+    info.bootargs = chosen.append.clone();
+    info.dtb = chosen.fdt.as_deref().map(read).transpose()?;
+    info.dtb_overlays = chosen
+        .fdt_overlays
+        .iter()
+        .map(|file| read(file))
+        .collect::<Result<_, _>>()?;
+    info.kernel_load_addr = chosen
+        .load_address
+        .unwrap_or_else(|| raw_kernel_load_address(&info.kernel_data));
+    info.entry = Some(chosen.label.clone());
+    Ok(info)
 }
 
 #[cfg(test)]
@@ -273,17 +352,117 @@ mod tests {
     use super::*;
     
     #[test]
-    fn test_dir_entry_name_match() {
-        let mut entry = DirEntry {
-            name: *b"KERNEL  BIN",
-            attr: 0,
-            cluster_high: 0,
-            cluster_low: 2,
-            file_size: 1024,
+    fn partitions_from_mbr_and_gpt() {
+        use crate::blockdev::MemoryBackend;
+
+        // MBR: FAT32 boot partition, Linux root, disk signature 0xdeadbeef.
+        let mut image = vec![0u8; 1 << 20];
+        image[440..444].copy_from_slice(&0xdead_beefu32.to_le_bytes());
+        for (i, (kind, start)) in [(0x0c, 8u32), (0x83, 64)].into_iter().enumerate() {
+            let e = &mut image[446 + i * 16..][..16];
+            e[4] = kind;
+            e[8..12].copy_from_slice(&start.to_le_bytes());
+            e[12..16].copy_from_slice(&32u32.to_le_bytes());
+        }
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let parts = read_partitions(&MemoryBackend::new(image.clone())).unwrap();
+        let (boot, root) = select_partitions(&parts, None).unwrap();
+        assert_eq!((boot.number, boot.start_lba), (1, 8));
+        assert_eq!(root.partuuid.as_deref(), Some("deadbeef-02"));
+
+        // GPT: ESP, a Linux /boot and the riscv64 root, found by type.
+        let gpt = |number, type_guid, first_lba, name: &str| GptPartition {
+            number,
+            type_guid,
+            unique_guid: Guid([number as u8 * 0x11; 16]),
+            first_lba,
+            last_lba: first_lba + 99,
+            attributes: 0,
+            name: name.to_string(),
         };
-        
-        assert!(entry.matches_name("kernel.bin"));
-        assert!(entry.matches_name("KERNEL.BIN"));
-        assert!(!entry.matches_name("other.bin"));
+        image.fill(0);
+        gpt::write_gpt(
+            &mut image,
+            &[
+                gpt(1, Guid::EFI_SYSTEM, 100, "EFI"),
+                gpt(2, Guid::LINUX_XBOOTLDR, 200, "boot"),
+                gpt(3, Guid::LINUX_ROOT_RISCV64, 300, "root"),
+            ],
+        );
+        let parts = read_partitions(&MemoryBackend::new(image)).unwrap();
+        let (boot, root) = select_partitions(&parts, None).unwrap();
+        assert_eq!((boot.number, boot.sector_count), (1, 100));
+        assert_eq!(root.partuuid.as_deref(), Some("33333333-3333-3333-3333-333333333333"));
+
+        let by_label = PartitionSelector::parse("label=boot").unwrap();
+        let (boot, root) = select_partitions(&parts, Some(&by_label)).unwrap();
+        assert_eq!((boot.number, root.number), (2, 3));
+        let by_type = PartitionSelector::parse("type=xbootldr").unwrap();
+        assert_eq!(select_partitions(&parts, Some(&by_type)).unwrap().0.number, 2);
+        let by_guid = PartitionSelector::parse("type=72EC70A6-CF74-40E6-BD49-4BDA08E8F224").unwrap();
+        assert_eq!(select_partitions(&parts, Some(&by_guid)).unwrap().0.number, 3);
+        assert_eq!(PartitionSelector::parse("2"), Ok(PartitionSelector::Number(2)));
+        assert!(PartitionSelector::parse("type=ntfs").is_err());
+        assert!(select_partitions(&parts, Some(&PartitionSelector::Number(9))).is_err());
+    }
+
+    /// An MBR disk with `volume` as a FAT partition at sector 8 and an
+    /// empty Linux partition after it.
+    fn sdcard(volume: Vec<u8>) -> crate::blockdev::MemoryBackend {
+        let sectors = (volume.len() / 512) as u32;
+        let mut image = vec![0u8; 8 * 512];
+        image[440..444].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+        for (i, (kind, start, count)) in [(0x0e, 8, sectors), (0x83, 8 + sectors, 8)].into_iter().enumerate() {
+            let e = &mut image[446 + i * 16..][..16];
+            e[4] = kind;
+            e[8..12].copy_from_slice(&start.to_le_bytes());
+            e[12..16].copy_from_slice(&count.to_le_bytes());
+        }
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        image.extend(volume);
+        image.resize(image.len() + 8 * 512, 0);
+        crate::blockdev::MemoryBackend::new(image)
+    }
+
+    #[test]
+    fn boot_config_selects_kernel_initrd_and_bootargs() {
+        let config = b"default main\n\
+            label main\n  kernel ../Image\n  initrd ../a.cpio,../b.cpio\n  append console=ttyS0 root=/dev/mmcblk0p2\n\
+            label dtb\n  kernel /boot/Image\n  fdt /boot/vm.dtb\n  fdtoverlays /boot/x.dtbo\n  loadaddr 0x80200000\n";
+        let disk = sdcard(fat::format_fat(
+            FatType::Fat16,
+            &[
+                ("/boot/extlinux/extlinux.conf", config),
+                ("/boot/Image", b"kernel"),
+                ("/boot/a.cpio", b"aa"),
+                ("/boot/b.cpio", b"bb"),
+                ("/boot/vm.dtb", b"dtb"),
+                ("/boot/x.dtbo", b"overlay"),
+            ],
+        ));
+
+        let info = parse_sdcard(&disk).unwrap();
+        assert_eq!(info.entry.as_deref(), Some("main"));
+        assert_eq!((info.kernel_data.as_slice(), info.kernel_load_addr), (&b"kernel"[..], 0x8000_0000));
+        assert_eq!(info.initrd.as_deref(), Some(&b"aabb"[..]));
+        assert_eq!(info.bootargs.as_deref(), Some("console=ttyS0 root=/dev/mmcblk0p2"));
+        assert_eq!(info.kernel_cmdline(), info.bootargs);
+        assert_eq!(info.fs_partition.number, 2);
+
+        let info = parse_sdcard_with(&disk, None, Some("dtb")).unwrap();
+        assert_eq!((info.dtb.as_deref(), info.dtb_overlays.as_slice()), (Some(&b"dtb"[..]), &[b"overlay".to_vec()][..]));
+        assert_eq!(info.kernel_cmdline(), None);
+        assert_eq!((info.kernel_load_addr, info.initrd, info.bootargs), (0x8020_0000, None, None));
+        assert!(parse_sdcard_with(&disk, None, Some("nope")).unwrap_err().contains("have: main, dtb"));
+
+        // Without a config, KERNEL.BIN in the root is booted as before.
+        let disk = sdcard(fat::format_fat(FatType::Fat12, &[("/KERNEL.BIN", b"legacy")]));
+        let info = parse_sdcard(&disk).unwrap();
+        assert_eq!((info.kernel_data.as_slice(), info.entry.as_deref()), (&b"legacy"[..], None));
+        assert_eq!(
+            info.kernel_cmdline().as_deref(),
+            Some("earlycon=sbi console=ttyS0 root=PARTUUID=1234abcd-02")
+        );
+        assert!(parse_sdcard_with(&disk, None, Some("main")).is_err());
     }
 }
@@ -0,0 +1,232 @@
+//! Boot configuration files in extlinux.conf syntax.
+//!
+//! Keywords are case-insensitive; `linux` and `devicetree` are accepted for
+//! `kernel` and `fdt`, and a comma-separated `initrd` list is concatenated.
+//! `loadaddr` is an extension giving the raw kernel's load address. Lines
+//! this loader has no use for (`timeout`, `menu title`, `fdtdir`, ...) are
+//! skipped.
+
+/// Where the boot partition is searched for a configuration file.
+pub const CONFIG_PATHS: [&str; 2] = ["/extlinux/extlinux.conf", "/boot/extlinux/extlinux.conf"];
+
+/// One `label` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootEntry {
+    pub label: String,
+    /// `menu label`, for display.
+    pub menu_label: Option<String>,
+    pub kernel: Option<String>,
+    pub initrd: Vec<String>,
+    /// Kernel command line.
+    pub append: Option<String>,
+    /// DTB used instead of the generated one.
+    pub fdt: Option<String>,
+    pub fdt_overlays: Vec<String>,
+    /// Load address for a raw kernel image.
+    pub load_address: Option<u64>,
+}
+
+/// A parsed configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootConfig {
+    /// Label named by `default` or marked `menu default`.
+    pub default: Option<String>,
+    pub entries: Vec<BootEntry>,
+}
+
+impl BootConfig {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = BootConfig::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let keyword = keyword.to_ascii_lowercase();
+            let value = value.trim();
+            let error = |msg: &str| format!("line {}: {}", n + 1, msg);
+
+            match keyword.as_str() {
+                "default" => config.default = Some(value.to_string()),
+                "label" => {
+                    if value.is_empty() {
+                        return Err(error("label needs a name"));
+                    }
+                    config.entries.push(BootEntry {
+                        label: value.to_string(),
+                        ..Default::default()
+                    });
+                }
+                "menu" => {
+                    let (what, rest) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+                    match (
+                        what.to_ascii_lowercase().as_str(),
+                        config.entries.last_mut(),
+                    ) {
+                        ("label", Some(entry)) => entry.menu_label = Some(rest.trim().to_string()),
+                        ("default", Some(entry)) => config.default = Some(entry.label.clone()),
+                        _ => {}
+                    }
+                }
+                "kernel" | "linux" | "initrd" | "append" | "fdt" | "devicetree" | "fdtoverlays"
+                | "loadaddr" => {
+                    let Some(entry) = config.entries.last_mut() else {
+                        return Err(error(&format!("'{}' outside a label", keyword)));
+                    };
+                    match keyword.as_str() {
+                        "kernel" | "linux" => entry.kernel = Some(value.to_string()),
+                        "initrd" => {
+                            entry.initrd = value
+                                .split(',')
+                                .map(str::trim)
+                                .filter(|s| !s.is_empty())
+                                .map(String::from)
+                                .collect()
+                        }
+                        "append" => entry.append = Some(value.to_string()),
+                        "fdt" | "devicetree" => entry.fdt = Some(value.to_string()),
+                        "fdtoverlays" => {
+                            entry.fdt_overlays =
+                                value.split_whitespace().map(String::from).collect()
+                        }
+                        _ => {
+                            let digits = value
+                                .strip_prefix("0x")
+                                .or_else(|| value.strip_prefix("0X"));
+                            let address = match digits {
+                                Some(hex) => u64::from_str_radix(hex, 16),
+                                None => value.parse(),
+                            };
+                            entry.load_address =
+                                Some(address.map_err(|_| {
+                                    error(&format!("bad load address '{}'", value))
+                                })?);
+                        }
+                    }
+                }
+                _ => log::debug!("[SD Boot] Ignoring boot config line {}: {}", n + 1, line),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The entry labelled `label`, or else the default one, or else the
+    /// first.
+    pub fn entry(&self, label: Option<&str>) -> Result<&BootEntry, String> {
+        if let Some(label) = label {
+            return self
+                .entries
+                .iter()
+                .find(|e| e.label == label)
+                .or_else(|| {
+                    self.entries
+                        .iter()
+                        .find(|e| e.label.eq_ignore_ascii_case(label))
+                })
+                .ok_or_else(|| {
+                    let labels: Vec<&str> = self.entries.iter().map(|e| e.label.as_str()).collect();
+                    format!("No boot entry '{}' (have: {})", label, labels.join(", "))
+                });
+        }
+        let default = self.default.as_deref();
+        self.entries
+            .iter()
+            .find(|e| Some(e.label.as_str()) == default)
+            .or(self.entries.first())
+            .ok_or_else(|| "Boot config has no entries".to_string())
+    }
+}
+
+/// Resolve `path` from the config file at `config_path`: absolute paths
+/// start at the partition root, others at the file's directory.
+pub fn resolve(config_path: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return path.to_string();
+    }
+    let dir = config_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    format!("{}/{}", dir, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTLINUX: &str = "\
+# Generated by u-boot-menu
+default l0
+menu title U-Boot menu
+timeout 50
+
+label l0
+\tmenu label Debian GNU/Linux, kernel 6.6.0-riscv64
+\tlinux /boot/vmlinuz-6.6.0-riscv64
+\tinitrd /boot/initrd.img-6.6.0-riscv64
+\tfdtdir /usr/lib/linux-image-6.6.0-riscv64/
+\tappend root=PARTUUID=1234-02 rw console=ttyS0 earlycon=sbi
+
+LABEL rescue
+    KERNEL ../Image
+    INITRD ../rootfs.cpio, ../modules.cpio
+    DEVICETREE ../vm.dtb
+    FDTOVERLAYS ../a.dtbo ../b.dtbo
+    LOADADDR 0x80200000
+    MENU DEFAULT
+";
+
+    #[test]
+    fn extlinux_entries_and_defaults() {
+        let config = BootConfig::parse(EXTLINUX).unwrap();
+        assert_eq!(config.entries.len(), 2);
+        assert_eq!(config.default.as_deref(), Some("rescue"));
+
+        let debian = config.entry(Some("l0")).unwrap();
+        assert_eq!(
+            debian.menu_label.as_deref(),
+            Some("Debian GNU/Linux, kernel 6.6.0-riscv64")
+        );
+        assert_eq!(
+            debian.kernel.as_deref(),
+            Some("/boot/vmlinuz-6.6.0-riscv64")
+        );
+        assert_eq!(debian.initrd, ["/boot/initrd.img-6.6.0-riscv64"]);
+        assert_eq!(
+            debian.append.as_deref(),
+            Some("root=PARTUUID=1234-02 rw console=ttyS0 earlycon=sbi")
+        );
+        assert_eq!((debian.fdt.as_ref(), debian.load_address), (None, None));
+
+        let rescue = config.entry(None).unwrap();
+        assert_eq!(rescue, config.entry(Some("RESCUE")).unwrap());
+        assert_eq!(rescue.initrd, ["../rootfs.cpio", "../modules.cpio"]);
+        assert_eq!(rescue.fdt.as_deref(), Some("../vm.dtb"));
+        assert_eq!(rescue.fdt_overlays, ["../a.dtbo", "../b.dtbo"]);
+        assert_eq!(rescue.load_address, Some(0x8020_0000));
+        assert_eq!(
+            resolve(CONFIG_PATHS[1], "../Image"),
+            "/boot/extlinux/../Image"
+        );
+        assert_eq!(resolve(CONFIG_PATHS[0], "/Image"), "/Image");
+
+        assert!(
+            config
+                .entry(Some("other"))
+                .unwrap_err()
+                .contains("have: l0, rescue")
+        );
+    }
+
+    #[test]
+    fn bad_configs_are_rejected() {
+        assert_eq!(
+            BootConfig::parse("kernel /Image").unwrap_err(),
+            "line 1: 'kernel' outside a label"
+        );
+        assert!(
+            BootConfig::parse("label a\nloadaddr nowhere")
+                .unwrap_err()
+                .starts_with("line 2:")
+        );
+        assert!(BootConfig::parse("timeout 1").unwrap().entry(None).is_err());
+    }
+}
@@ -0,0 +1,788 @@
+//! Read-only FAT12/16/32.
+//!
+//! Files and directories are read by following their cluster chains, so
+//! fragmented files and directories larger than a cluster work. VFAT long
+//! names are used when their checksum matches the short entry after them,
+//! and path components match either name without regard to case.
+
+use crate::blockdev::BlockBackend;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+const SECTOR: u64 = 512;
+const ENTRY_LEN: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// UCS-2 characters held by one long-name entry.
+const LFN_CHARS: usize = 13;
+
+/// FAT variant, from the cluster count as Linux decides it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A file or directory listed in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatDirEntry {
+    /// Long name if there is one, else the 8.3 name (`KERNEL.BIN`).
+    pub name: String,
+    pub short_name: String,
+    pub is_dir: bool,
+    pub size: u32,
+    /// First cluster; 0 for empty files and the root directory.
+    pub cluster: u32,
+}
+
+impl FatDirEntry {
+    fn root() -> Self {
+        Self {
+            name: String::new(),
+            short_name: String::new(),
+            is_dir: true,
+            size: 0,
+            cluster: 0,
+        }
+    }
+
+    /// Whether `name` is this entry's long or short name, ignoring case.
+    pub fn matches(&self, name: &str) -> bool {
+        self.short_name.eq_ignore_ascii_case(name)
+            || self.name.to_lowercase() == name.to_lowercase()
+    }
+}
+
+/// A FAT volume on a partition of `disk`.
+pub struct FatFs<'a> {
+    disk: &'a dyn BlockBackend,
+    /// Byte offset of the volume on the disk; the offsets below are
+    /// relative to it.
+    base: u64,
+    fat_type: FatType,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    fat_start: u64,
+    /// Fixed root directory of FAT12/16.
+    root_start: u64,
+    root_len: u64,
+    /// FAT32 root directory cluster.
+    root_cluster: u32,
+    data_start: u64,
+    /// Highest cluster number the volume and its FAT can hold.
+    max_cluster: u32,
+    /// The FAT sector read last, as (offset, contents).
+    fat_cache: RefCell<Option<(u64, Vec<u8>)>>,
+}
+
+impl<'a> FatFs<'a> {
+    /// Open the volume starting at 512-byte sector `partition_start_sector`.
+    pub fn open(
+        disk: &'a dyn BlockBackend,
+        partition_start_sector: u64,
+    ) -> Result<Self, &'static str> {
+        let base = partition_start_sector * SECTOR;
+        let mut bs = [0u8; 512];
+        disk.read_at(base, &mut bs)
+            .map_err(|_| "Partition beyond disk")?;
+        if bs[510] != 0x55 || bs[511] != 0xAA {
+            return Err("Invalid FAT boot sector signature");
+        }
+        let le16 = |at: usize| u16::from_le_bytes([bs[at], bs[at + 1]]) as u64;
+        let le32 = |at: usize| u32::from_le_bytes([bs[at], bs[at + 1], bs[at + 2], bs[at + 3]]);
+
+        let bytes_per_sector = le16(11);
+        if !bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bytes_per_sector) {
+            return Err("Unsupported FAT sector size");
+        }
+        let sectors_per_cluster = bs[13] as u64;
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err("Invalid FAT cluster size");
+        }
+        let reserved = le16(14);
+        let num_fats = bs[16] as u64;
+        let root_entries = le16(17);
+        let fat16_sectors = le16(22);
+        let fat_sectors = if fat16_sectors != 0 {
+            fat16_sectors
+        } else {
+            le32(36) as u64
+        };
+        let total_sectors = match le16(19) {
+            0 => le32(32) as u64,
+            n => n,
+        };
+        let root_sectors = (root_entries * ENTRY_LEN as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved + num_fats * fat_sectors + root_sectors;
+        if reserved == 0 || num_fats == 0 || fat_sectors == 0 || data_sector >= total_sectors {
+            return Err("Invalid FAT boot sector");
+        }
+
+        let clusters = (total_sectors - data_sector) / sectors_per_cluster;
+        let fat_type = if fat16_sectors == 0 {
+            FatType::Fat32
+        } else if clusters < 4085 {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        };
+        let entry_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let fat_entries = fat_sectors * bytes_per_sector * 8 / entry_bits;
+        let max_cluster = (clusters + 1).min(fat_entries - 1).min(0x0FFF_FFF6) as u32;
+        let root_cluster = match fat_type {
+            FatType::Fat32 => le32(44) & 0x0FFF_FFFF,
+            _ => 0,
+        };
+
+        Ok(Self {
+            disk,
+            base,
+            fat_type,
+            bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_start: reserved * bytes_per_sector,
+            root_start: (reserved + num_fats * fat_sectors) * bytes_per_sector,
+            root_len: root_entries * ENTRY_LEN as u64,
+            root_cluster,
+            data_start: data_sector * bytes_per_sector,
+            max_cluster,
+            fat_cache: RefCell::new(None),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Look up a file or directory by path (`/boot/Image`).
+    pub fn metadata(&self, path: &str) -> Result<FatDirEntry, &'static str> {
+        let mut components = Vec::new();
+        for component in path.split(['/', '\\']) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                name => components.push(name),
+            }
+        }
+        let mut entry = FatDirEntry::root();
+        for name in components {
+            if !entry.is_dir {
+                return Err("Not a directory");
+            }
+            entry = self
+                .list(entry.cluster)?
+                .into_iter()
+                .find(|e| e.matches(name))
+                .ok_or("File not found")?;
+        }
+        Ok(entry)
+    }
+
+    /// List a directory, without its `.` and `..` entries.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<FatDirEntry>, &'static str> {
+        let entry = self.metadata(path)?;
+        if !entry.is_dir {
+            return Err("Not a directory");
+        }
+        self.list(entry.cluster)
+    }
+
+    /// Read a whole file.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, &'static str> {
+        let entry = self.metadata(path)?;
+        if entry.is_dir {
+            return Err("Is a directory");
+        }
+        if entry.size == 0 {
+            return Ok(Vec::new());
+        }
+        self.read_chain(entry.cluster, Some(entry.size as u64))
+    }
+
+    /// Entries of the directory at `cluster` (0 for the root).
+    fn list(&self, cluster: u32) -> Result<Vec<FatDirEntry>, &'static str> {
+        let data = match (cluster, self.fat_type) {
+            (0, FatType::Fat32) => self.read_chain(self.root_cluster, None)?,
+            (0, _) => self.read(
+                self.root_start,
+                self.root_len as usize,
+                "Root directory beyond disk",
+            )?,
+            (cluster, _) => self.read_chain(cluster, None)?,
+        };
+        Ok(parse_dir(&data))
+    }
+
+    /// Read the chain starting at `first`: `len` bytes of it, or all of it.
+    fn read_chain(&self, first: u32, len: Option<u64>) -> Result<Vec<u8>, &'static str> {
+        let wanted = len.map(|len| len.div_ceil(self.cluster_size) as usize);
+        let chain = self.chain(first, wanted)?;
+        let chain_len = chain.len() as u64 * self.cluster_size;
+        let len = len.unwrap_or(chain_len);
+        if chain_len < len {
+            return Err("Cluster chain shorter than file");
+        }
+
+        let mut data = vec![0; len as usize];
+        let mut done = 0;
+        let mut i = 0;
+        // Read runs of consecutive clusters with one call each.
+        while done < data.len() {
+            let mut run = 1;
+            while i + run < chain.len() && chain[i + run] == chain[i] + run as u32 {
+                run += 1;
+            }
+            let offset = self.data_start + (chain[i] as u64 - 2) * self.cluster_size;
+            let n = (run as u64 * self.cluster_size).min((data.len() - done) as u64) as usize;
+            self.disk
+                .read_at(self.base + offset, &mut data[done..done + n])
+                .map_err(|_| "File data beyond disk")?;
+            done += n;
+            i += run;
+        }
+        Ok(data)
+    }
+
+    /// Clusters of the chain starting at `first`, at most `limit` of them.
+    fn chain(&self, first: u32, limit: Option<usize>) -> Result<Vec<u32>, &'static str> {
+        if !(2..=self.max_cluster).contains(&first) {
+            return Err("Invalid FAT cluster number");
+        }
+        let mut chain = vec![first];
+        let mut seen = HashSet::from([first]);
+        let mut cluster = first;
+        while limit.is_none_or(|limit| chain.len() < limit) {
+            let Some(next) = self.next_cluster(cluster)? else {
+                break;
+            };
+            if !seen.insert(next) {
+                return Err("FAT cluster chain loops");
+            }
+            chain.push(next);
+            cluster = next;
+        }
+        Ok(chain)
+    }
+
+    /// The FAT entry for `cluster`: the next cluster, or None at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        let c = cluster as u64;
+        let (value, end) = match self.fat_type {
+            FatType::Fat12 => {
+                let raw = u16::from_le_bytes(self.fat_bytes(c * 3 / 2)?);
+                let value = if cluster & 1 == 1 {
+                    raw >> 4
+                } else {
+                    raw & 0xFFF
+                };
+                (value as u32, 0xFF8)
+            }
+            FatType::Fat16 => (u16::from_le_bytes(self.fat_bytes(c * 2)?) as u32, 0xFFF8),
+            FatType::Fat32 => (
+                u32::from_le_bytes(self.fat_bytes(c * 4)?) & 0x0FFF_FFFF,
+                0x0FFF_FFF8,
+            ),
+        };
+        if value >= end {
+            Ok(None)
+        } else if (2..=self.max_cluster).contains(&value) {
+            Ok(Some(value))
+        } else {
+            Err("Broken FAT cluster chain")
+        }
+    }
+
+    /// `N` bytes at `offset` in the first FAT.
+    fn fat_bytes<const N: usize>(&self, offset: u64) -> Result<[u8; N], &'static str> {
+        let mut out = [0; N];
+        let sector = offset - offset % self.bytes_per_sector;
+        let at = (offset - sector) as usize;
+        if at + N > self.bytes_per_sector as usize {
+            // A FAT12 entry straddling two sectors.
+            self.disk
+                .read_at(self.base + self.fat_start + offset, &mut out)
+                .map_err(|_| "FAT beyond disk")?;
+            return Ok(out);
+        }
+        let mut cache = self.fat_cache.borrow_mut();
+        if cache.as_ref().is_none_or(|(cached, _)| *cached != sector) {
+            let data = self.read(
+                self.fat_start + sector,
+                self.bytes_per_sector as usize,
+                "FAT beyond disk",
+            )?;
+            *cache = Some((sector, data));
+        }
+        let (_, data) = cache.as_ref().unwrap();
+        out.copy_from_slice(&data[at..at + N]);
+        Ok(out)
+    }
+
+    fn read(&self, offset: u64, len: usize, err: &'static str) -> Result<Vec<u8>, &'static str> {
+        let mut buf = vec![0; len];
+        self.disk
+            .read_at(self.base + offset, &mut buf)
+            .map_err(|_| err)?;
+        Ok(buf)
+    }
+}
+
+/// Parse the entries of a directory, pairing long names with the short
+/// entries they precede.
+fn parse_dir(data: &[u8]) -> Vec<FatDirEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Vec<u16> = Vec::new();
+    // Checksum of the long name being collected, and the sequence number
+    // of the long-name entry expected next.
+    let mut long_sum: Option<u8> = None;
+    let mut long_next = 0;
+
+    for raw in data.chunks_exact(ENTRY_LEN) {
+        match raw[0] {
+            0x00 => break,
+            0xE5 => {
+                long_sum = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attr = raw[11];
+        if attr & 0x3F == ATTR_LONG_NAME {
+            let ord = (raw[0] & 0x1F) as usize;
+            if raw[0] & 0x40 != 0 {
+                long_name = vec![0xFFFF; ord * LFN_CHARS];
+                long_sum = Some(raw[13]);
+                long_next = ord;
+            }
+            if ord == 0 || ord != long_next || long_sum != Some(raw[13]) {
+                long_sum = None;
+                continue;
+            }
+            let units = [&raw[1..11], &raw[14..26], &raw[28..32]]
+                .concat()
+                .chunks_exact(2)
+                .map(|u| u16::from_le_bytes([u[0], u[1]]))
+                .collect::<Vec<_>>();
+            long_name[(ord - 1) * LFN_CHARS..ord * LFN_CHARS].copy_from_slice(&units);
+            long_next = ord - 1;
+            continue;
+        }
+
+        let long = long_sum
+            .take()
+            .filter(|&sum| long_next == 0 && sum == short_name_checksum(&raw[..11]))
+            .map(|_| {
+                let end = long_name.iter().position(|&u| u == 0 || u == 0xFFFF);
+                String::from_utf16_lossy(&long_name[..end.unwrap_or(long_name.len())])
+            });
+        if attr & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short_name = short_name(raw);
+        if short_name == "." || short_name == ".." {
+            continue;
+        }
+        entries.push(FatDirEntry {
+            name: long.unwrap_or_else(|| short_name.clone()),
+            short_name,
+            is_dir: attr & ATTR_DIRECTORY != 0,
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+            cluster: (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                | u16::from_le_bytes([raw[26], raw[27]]) as u32,
+        });
+    }
+    entries
+}
+
+/// `BASE.EXT` from a short entry, lowercased where the NT case flags say.
+fn short_name(raw: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut s: String = bytes.iter().map(|&b| b as char).collect();
+        s.truncate(s.trim_end_matches(' ').len());
+        if lower { s.to_lowercase() } else { s }
+    };
+    let mut base = part(&raw[0..8], raw[12] & 0x08 != 0);
+    if raw[0] == 0x05 {
+        base.replace_range(..1, "\u{e5}");
+    }
+    let ext = part(&raw[8..11], raw[12] & 0x10 != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Format a small FAT volume holding `files` (`("/boot/Image", data)`;
+/// a path ending in `/` makes an empty directory). Files of two or more
+/// clusters are split in two with a free cluster between the halves.
+#[cfg(test)]
+pub(crate) fn format_fat(fat_type: FatType, files: &[(&str, &[u8])]) -> Vec<u8> {
+    #[derive(Default)]
+    struct Dir(Vec<(String, Node)>);
+    enum Node {
+        Dir(Dir),
+        File(Vec<u8>),
+    }
+
+    struct Volume {
+        image: Vec<u8>,
+        fat_type: FatType,
+        fat_start: usize,
+        fat_len: usize,
+        data_start: usize,
+        next: u32,
+    }
+
+    impl Volume {
+        fn set_fat(&mut self, cluster: u32, value: u32) {
+            for fat in [self.fat_start, self.fat_start + self.fat_len] {
+                let c = cluster as usize;
+                match self.fat_type {
+                    FatType::Fat12 => {
+                        let at = fat + c * 3 / 2;
+                        let v = value & 0xFFF;
+                        if c.is_multiple_of(2) {
+                            self.image[at] = v as u8;
+                            self.image[at + 1] = (self.image[at + 1] & 0xF0) | (v >> 8) as u8;
+                        } else {
+                            self.image[at] = (self.image[at] & 0x0F) | ((v & 0xF) << 4) as u8;
+                            self.image[at + 1] = (v >> 4) as u8;
+                        }
+                    }
+                    FatType::Fat16 => self.image[fat + c * 2..][..2]
+                        .copy_from_slice(&(value as u16).to_le_bytes()),
+                    FatType::Fat32 => {
+                        self.image[fat + c * 4..][..4].copy_from_slice(&value.to_le_bytes())
+                    }
+                }
+            }
+        }
+
+        fn alloc(&mut self, len: usize) -> Vec<u32> {
+            let count = len.div_ceil(SECTOR as usize) as u32;
+            let chain: Vec<u32> = (0..count)
+                .map(|i| self.next + i + u32::from(i >= count / 2 && count > 1))
+                .collect();
+            self.next += count + u32::from(count > 1);
+            for pair in chain.windows(2) {
+                self.set_fat(pair[0], pair[1]);
+            }
+            if let Some(&last) = chain.last() {
+                self.set_fat(last, 0x0FFF_FFFF);
+            }
+            chain
+        }
+
+        fn write(&mut self, chain: &[u32], data: &[u8]) {
+            for (&cluster, chunk) in chain.iter().zip(data.chunks(SECTOR as usize)) {
+                let at = self.data_start + (cluster as usize - 2) * SECTOR as usize;
+                self.image[at..at + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+
+        /// Entries for the children of `dir`, which is at `cluster`.
+        fn entries(&mut self, dir: &Dir, cluster: u32) -> Vec<u8> {
+            let mut out = Vec::new();
+            for (n, (name, node)) in dir.0.iter().enumerate() {
+                let (chain, size, attr) = match node {
+                    Node::File(data) => {
+                        let chain = self.alloc(data.len());
+                        self.write(&chain, data);
+                        (chain, data.len(), 0x20)
+                    }
+                    Node::Dir(sub) => (self.dir(sub, cluster), 0, ATTR_DIRECTORY),
+                };
+                let first = chain.first().copied().unwrap_or(0);
+                let (short, needs_long) = short_entry_name(name, n + 1);
+                if needs_long {
+                    let mut units: Vec<u16> = name.encode_utf16().collect();
+                    if units.len() % LFN_CHARS != 0 {
+                        units.push(0);
+                    }
+                    units.resize(units.len().div_ceil(LFN_CHARS) * LFN_CHARS, 0xFFFF);
+                    let count = units.len() / LFN_CHARS;
+                    for ord in (1..=count).rev() {
+                        let mut e = [0u8; ENTRY_LEN];
+                        e[0] = ord as u8 | if ord == count { 0x40 } else { 0 };
+                        e[11] = ATTR_LONG_NAME;
+                        e[13] = short_name_checksum(&short);
+                        let chars = &units[(ord - 1) * LFN_CHARS..ord * LFN_CHARS];
+                        let slots = (1..11)
+                            .step_by(2)
+                            .chain((14..26).step_by(2))
+                            .chain((28..32).step_by(2));
+                        for (at, u) in slots.zip(chars) {
+                            e[at..at + 2].copy_from_slice(&u.to_le_bytes());
+                        }
+                        out.extend_from_slice(&e);
+                    }
+                }
+                out.extend_from_slice(&short_entry(&short, attr, first, size as u32));
+            }
+            out
+        }
+
+        /// Write a subdirectory; returns its chain.
+        fn dir(&mut self, dir: &Dir, parent: u32) -> Vec<u32> {
+            let children: usize = dir
+                .0
+                .iter()
+                .map(|(name, _)| 2 + name.len() / LFN_CHARS)
+                .sum();
+            let chain = self.alloc((2 + children) * ENTRY_LEN);
+            let mut data = short_entry(b".          ", ATTR_DIRECTORY, chain[0], 0).to_vec();
+            data.extend_from_slice(&short_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+            data.extend(self.entries(dir, chain[0]));
+            assert!(data.len() <= chain.len() * SECTOR as usize);
+            self.write(&chain, &data);
+            chain
+        }
+    }
+
+    fn short_entry(name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; ENTRY_LEN] {
+        let mut e = [0u8; ENTRY_LEN];
+        e[..11].copy_from_slice(name);
+        e[11] = attr;
+        e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        e[28..32].copy_from_slice(&size.to_le_bytes());
+        e
+    }
+
+    /// The 8.3 name for `name`, and whether it needs a long name too.
+    fn short_entry_name(name: &str, n: usize) -> ([u8; 11], bool) {
+        let valid = |s: &str, max: usize| {
+            s.len() <= max
+                && s.bytes()
+                    .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+        };
+        let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+        let mut short = [b' '; 11];
+        let fits = !base.is_empty() && valid(base, 8) && valid(ext, 3);
+        let base = if fits {
+            base.to_string()
+        } else {
+            let clean = |s: &str, max| {
+                s.chars()
+                    .filter(char::is_ascii_alphanumeric)
+                    .take(max)
+                    .collect::<String>()
+                    .to_uppercase()
+            };
+            let tail = format!("~{n}");
+            format!("{}{tail}", clean(base, 8 - tail.len()))
+        };
+        short[..base.len()].copy_from_slice(base.as_bytes());
+        let ext = ext
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .take(3)
+            .collect::<String>()
+            .to_uppercase();
+        short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        (short, !fits)
+    }
+
+    let (total, reserved, root_entries, fat_sectors): (usize, usize, usize, usize) = match fat_type
+    {
+        FatType::Fat12 => (2048, 1, 64, 6),
+        FatType::Fat16 => (8192, 1, 64, 33),
+        FatType::Fat32 => (8192, 32, 0, 65),
+    };
+    let sector = SECTOR as usize;
+    let root_start = (reserved + 2 * fat_sectors) * sector;
+    let mut v = Volume {
+        image: vec![0; total * sector],
+        fat_type,
+        fat_start: reserved * sector,
+        fat_len: fat_sectors * sector,
+        data_start: root_start + root_entries * ENTRY_LEN,
+        next: 2,
+    };
+
+    let bs = &mut v.image[..sector];
+    bs[..11].copy_from_slice(b"\xEB\x3C\x90RISCVVM ");
+    bs[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    bs[13] = 1;
+    bs[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    bs[16] = 2;
+    bs[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    bs[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+    bs[21] = 0xF8;
+    match fat_type {
+        FatType::Fat32 => bs[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes()),
+        _ => bs[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes()),
+    }
+    bs[510] = 0x55;
+    bs[511] = 0xAA;
+    v.set_fat(0, 0x0FFF_FFF8);
+    v.set_fat(1, 0x0FFF_FFFF);
+
+    let mut root = Dir::default();
+    for (path, data) in files {
+        let mut dir = &mut root;
+        let mut names = path.trim_start_matches('/').split('/').peekable();
+        while let Some(name) = names.next() {
+            if name.is_empty() {
+                break;
+            }
+            let last = names.peek().is_none();
+            let at = match dir.0.iter().position(|(n, _)| n == name) {
+                Some(at) => at,
+                None => {
+                    let node = if last {
+                        Node::File(data.to_vec())
+                    } else {
+                        Node::Dir(Dir::default())
+                    };
+                    dir.0.push((name.to_string(), node));
+                    dir.0.len() - 1
+                }
+            };
+            match &mut dir.0[at].1 {
+                Node::Dir(sub) => dir = sub,
+                Node::File(_) => break,
+            }
+        }
+    }
+
+    if fat_type == FatType::Fat32 {
+        let entries: usize = root
+            .0
+            .iter()
+            .map(|(name, _)| 2 + name.len() / LFN_CHARS)
+            .sum();
+        let chain = v.alloc(entries.max(1) * ENTRY_LEN);
+        v.image[44..48].copy_from_slice(&chain[0].to_le_bytes());
+        let data = v.entries(&root, 0);
+        v.write(&chain, &data);
+    } else {
+        let data = v.entries(&root, 0);
+        assert!(data.len() <= root_entries * ENTRY_LEN);
+        v.image[root_start..root_start + data.len()].copy_from_slice(&data);
+    }
+    v.image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::MemoryBackend;
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn nested_paths_long_names_and_fragmented_files() {
+        let kernel = pattern(5000, 1);
+        let readme = pattern(700, 2);
+        let many: Vec<(String, Vec<u8>)> = (0..40)
+            .map(|i| (format!("/many/file{i:02}.txt"), vec![i as u8]))
+            .collect();
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut files: Vec<(&str, &[u8])> = vec![
+                ("/boot/Image", &kernel),
+                ("/boot/A rather long README name.md", &readme),
+                ("/KERNEL.BIN", b"short"),
+                ("/empty/", b""),
+                ("/zero.bin", b""),
+            ];
+            files.extend(many.iter().map(|(p, d)| (p.as_str(), d.as_slice())));
+            let disk = MemoryBackend::new(format_fat(fat_type, &files));
+            let fs = FatFs::open(&disk, 0).unwrap();
+            assert_eq!(fs.fat_type(), fat_type);
+
+            assert_eq!(fs.read_file("/boot/Image").unwrap(), kernel);
+            assert_eq!(fs.read_file("boot/image").unwrap(), kernel);
+            assert_eq!(
+                fs.read_file("/BOOT/a rather long readme NAME.md").unwrap(),
+                readme
+            );
+            assert_eq!(fs.read_file("/boot/ARATHE~2.MD").unwrap(), readme);
+            assert_eq!(fs.read_file("/kernel.bin").unwrap(), b"short");
+            assert_eq!(fs.read_file("/boot/../zero.bin").unwrap(), b"");
+
+            let boot = fs.read_dir("/boot").unwrap();
+            let names: Vec<&str> = boot.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(names, ["Image", "A rather long README name.md"]);
+            assert_eq!(
+                (boot[0].short_name.as_str(), boot[0].size),
+                ("IMAGE~1", 5000)
+            );
+            assert!(fs.read_dir("/empty").unwrap().is_empty());
+            assert!(fs.metadata("/empty").unwrap().is_dir);
+
+            // 40 entries with long names span several one-sector clusters.
+            let listing = fs.read_dir("/many").unwrap();
+            assert_eq!(listing.len(), 40);
+            assert_eq!(
+                (listing[39].name.as_str(), listing[39].short_name.as_str()),
+                ("file39.txt", "FILE3~40.TXT")
+            );
+            assert_eq!(fs.read_file("/many/file39.txt").unwrap(), [39]);
+
+            assert_eq!(fs.read_file("/boot"), Err("Is a directory"));
+            assert_eq!(fs.read_dir("/KERNEL.BIN"), Err("Not a directory"));
+            assert_eq!(fs.read_file("/boot/Image/x"), Err("Not a directory"));
+            assert_eq!(fs.read_file("/missing"), Err("File not found"));
+        }
+    }
+
+    #[test]
+    fn broken_chains_are_errors() {
+        let kernel = pattern(3000, 3);
+        let mut image = format_fat(FatType::Fat16, &[("/Image", &kernel)]);
+        let (fat_start, cluster) = {
+            let disk = MemoryBackend::new(image.clone());
+            let fs = FatFs::open(&disk, 0).unwrap();
+            (
+                fs.fat_start as usize,
+                fs.metadata("/Image").unwrap().cluster as usize,
+            )
+        };
+        let mut read = |value: u16| {
+            image[fat_start + cluster * 2..][..2].copy_from_slice(&value.to_le_bytes());
+            let disk = MemoryBackend::new(image.clone());
+            FatFs::open(&disk, 0).unwrap().read_file("/Image")
+        };
+        assert_eq!(read(cluster as u16), Err("FAT cluster chain loops"));
+        assert_eq!(read(0), Err("Broken FAT cluster chain"));
+        assert_eq!(read(0xFFFF), Err("Cluster chain shorter than file"));
+    }
+
+    #[test]
+    fn long_names_need_a_matching_checksum() {
+        let mut image = format_fat(FatType::Fat12, &[("/vmlinux-6.6", b"k")]);
+        let disk = MemoryBackend::new(image.clone());
+        let fs = FatFs::open(&disk, 0).unwrap();
+        assert_eq!(fs.read_dir("/").unwrap()[0].name, "vmlinux-6.6");
+        let root = fs.root_start as usize;
+
+        // Rename the short entry behind the long name's back.
+        image[root + ENTRY_LEN] = b'X';
+        let disk = MemoryBackend::new(image);
+        let fs = FatFs::open(&disk, 0).unwrap();
+        let entry = &fs.read_dir("/").unwrap()[0];
+        assert_eq!(
+            (entry.name.as_str(), entry.short_name.as_str()),
+            ("XMLINU~1.6", "XMLINU~1.6")
+        );
+    }
+}
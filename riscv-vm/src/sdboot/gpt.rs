@@ -0,0 +1,370 @@
+//! GUID Partition Table parsing.
+//!
+//! The primary header (LBA 1) and its entry array are checked against
+//! their CRC32s; if either is damaged the backup header, normally in the
+//! last sector, is used instead.
+
+use crate::blockdev::BlockBackend;
+use std::fmt;
+
+const SECTOR: u64 = 512;
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Bytes of the header covered by its CRC in revision 1.0.
+const MIN_HEADER_LEN: u32 = 92;
+const MIN_ENTRY_LEN: u32 = 128;
+/// Largest entry array read, far above the usual 16 KiB.
+const MAX_ENTRIES_LEN: u64 = 1 << 20;
+
+/// A GUID in its on-disk byte order (the first three fields little-endian).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// EFI System Partition (FAT).
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(
+        0xc12a_7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+    /// Microsoft basic data (FAT or NTFS), used for boot partitions too.
+    pub const BASIC_DATA: Guid = Guid::from_fields(
+        0xebd0_a0a2,
+        0xb9e5,
+        0x4433,
+        [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7],
+    );
+    /// Linux filesystem data.
+    pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(
+        0x0fc6_3daf,
+        0x8483,
+        0x4772,
+        [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+    );
+    /// Linux root filesystem for riscv64 (Discoverable Partitions spec).
+    pub const LINUX_ROOT_RISCV64: Guid = Guid::from_fields(
+        0x72ec_70a6,
+        0xcf74,
+        0x40e6,
+        [0xbd, 0x49, 0x4b, 0xda, 0x08, 0xe8, 0xf2, 0x24],
+    );
+    /// Linux extended boot (`/boot`).
+    pub const LINUX_XBOOTLDR: Guid = Guid::from_fields(
+        0xbc13_c2ff,
+        0x59e6,
+        0x4262,
+        [0xa3, 0x52, 0xb2, 0x75, 0xfd, 0x6f, 0x71, 0x72],
+    );
+
+    const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    /// Parse the textual form, `c12a7328-f81f-11d2-ba4b-00a0c93ec93b`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let groups: Vec<&str> = text.split('-').collect();
+        let lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        if lens != [8, 4, 4, 4, 12] || !text.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let a = u32::from_str_radix(groups[0], 16).ok()?;
+        let b = u16::from_str_radix(groups[1], 16).ok()?;
+        let c = u16::from_str_radix(groups[2], 16).ok()?;
+        let mut d = [0; 8];
+        hex::decode_to_slice(format!("{}{}", groups[3], groups[4]), &mut d).ok()?;
+        Some(Self::from_fields(a, b, c, d))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{}-{}",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            hex::encode(&g[8..10]),
+            hex::encode(&g[10..16])
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A used entry of the partition array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// 1-based index in the entry array.
+    pub number: u32,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    /// Partition name (label).
+    pub name: String,
+}
+
+struct Header {
+    alternate_lba: u64,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_len: u32,
+    entries_crc: u32,
+}
+
+/// Whether `sector0` is a protective MBR, announcing a GPT disk.
+pub fn is_protective_mbr(sector0: &[u8]) -> bool {
+    sector0.len() >= 512
+        && sector0[510..512] == [0x55, 0xaa]
+        && (0..4).any(|i| sector0[446 + i * 16 + 4] == 0xee)
+}
+
+/// Read the partition entries of a GPT disk, from the backup table if the
+/// primary one is damaged.
+pub fn parse_gpt(disk: &dyn BlockBackend) -> Result<Vec<GptPartition>, &'static str> {
+    let sectors = disk.size() / SECTOR;
+    if sectors < 3 {
+        return Err("Disk too small for GPT");
+    }
+    let primary = read_header(disk, 1);
+    let primary_err = match &primary {
+        Ok(header) => match read_entries(disk, header) {
+            Ok(partitions) => return Ok(partitions),
+            Err(e) => e,
+        },
+        Err(e) => e,
+    };
+    // The primary header names the backup; otherwise it is the last sector.
+    let mut candidates = vec![sectors - 1];
+    if let Ok(header) = &primary
+        && header.alternate_lba != sectors - 1
+    {
+        candidates.insert(0, header.alternate_lba);
+    }
+    for lba in candidates {
+        if let Ok(header) = read_header(disk, lba)
+            && let Ok(partitions) = read_entries(disk, &header)
+        {
+            log::warn!(
+                "[sdboot] Primary GPT is damaged ({}); using the backup",
+                primary_err
+            );
+            return Ok(partitions);
+        }
+    }
+    Err(primary_err)
+}
+
+fn read_sectors(disk: &dyn BlockBackend, offset: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut buf = vec![0; len];
+    disk.read_at(offset, &mut buf)
+        .map_err(|_| "GPT beyond disk")?;
+    Ok(buf)
+}
+
+fn read_header(disk: &dyn BlockBackend, lba: u64) -> Result<Header, &'static str> {
+    let mut sector = read_sectors(disk, lba * SECTOR, SECTOR as usize)?;
+    if &sector[..8] != SIGNATURE {
+        return Err("No GPT header signature");
+    }
+    let u32_at = |s: &[u8], at: usize| u32::from_le_bytes(s[at..at + 4].try_into().unwrap());
+    let u64_at = |s: &[u8], at: usize| u64::from_le_bytes(s[at..at + 8].try_into().unwrap());
+    let header_len = u32_at(&sector, 12);
+    if !(MIN_HEADER_LEN..=SECTOR as u32).contains(&header_len) {
+        return Err("Bad GPT header size");
+    }
+    let crc = u32_at(&sector, 16);
+    sector[16..20].fill(0);
+    if crc32(&sector[..header_len as usize]) != crc {
+        return Err("GPT header checksum mismatch");
+    }
+    if u64_at(&sector, 24) != lba {
+        return Err("GPT header in the wrong place");
+    }
+    let header = Header {
+        alternate_lba: u64_at(&sector, 32),
+        entries_lba: u64_at(&sector, 72),
+        num_entries: u32_at(&sector, 80),
+        entry_len: u32_at(&sector, 84),
+        entries_crc: u32_at(&sector, 88),
+    };
+    if header.entry_len < MIN_ENTRY_LEN
+        || !header.entry_len.is_multiple_of(8)
+        || header.num_entries as u64 * header.entry_len as u64 > MAX_ENTRIES_LEN
+    {
+        return Err("Bad GPT partition entry array");
+    }
+    Ok(header)
+}
+
+fn read_entries(
+    disk: &dyn BlockBackend,
+    header: &Header,
+) -> Result<Vec<GptPartition>, &'static str> {
+    let len = header.num_entries as usize * header.entry_len as usize;
+    let offset = header
+        .entries_lba
+        .checked_mul(SECTOR)
+        .ok_or("GPT beyond disk")?;
+    let entries = read_sectors(disk, offset, len)?;
+    if crc32(&entries) != header.entries_crc {
+        return Err("GPT partition entries checksum mismatch");
+    }
+    let guid = |e: &[u8], at: usize| Guid(e[at..at + 16].try_into().unwrap());
+    let u64_at = |e: &[u8], at: usize| u64::from_le_bytes(e[at..at + 8].try_into().unwrap());
+    Ok(entries
+        .chunks_exact(header.entry_len as usize)
+        .enumerate()
+        .filter(|(_, entry)| !guid(entry, 0).is_zero())
+        .map(|(i, entry)| {
+            let name: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect();
+            GptPartition {
+                number: i as u32 + 1,
+                type_guid: guid(entry, 0),
+                unique_guid: guid(entry, 16),
+                first_lba: u64_at(entry, 32),
+                last_lba: u64_at(entry, 40),
+                attributes: u64_at(entry, 48),
+                name: String::from_utf16_lossy(&name),
+            }
+        })
+        .collect())
+}
+
+/// CRC-32 (IEEE 802.3), as GPT uses.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Write a GPT with primary and backup tables to `image` (tests only).
+#[cfg(test)]
+pub(crate) fn write_gpt(image: &mut [u8], partitions: &[GptPartition]) {
+    let sectors = image.len() as u64 / SECTOR;
+    let mut entries = vec![0u8; 128 * 128];
+    for p in partitions {
+        let e = &mut entries[(p.number as usize - 1) * 128..][..128];
+        e[0..16].copy_from_slice(&p.type_guid.0);
+        e[16..32].copy_from_slice(&p.unique_guid.0);
+        e[32..40].copy_from_slice(&p.first_lba.to_le_bytes());
+        e[40..48].copy_from_slice(&p.last_lba.to_le_bytes());
+        e[48..56].copy_from_slice(&p.attributes.to_le_bytes());
+        for (i, c) in p.name.encode_utf16().enumerate() {
+            e[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32(&entries);
+    // Protective MBR.
+    image[446 + 4] = 0xee;
+    image[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    image[446 + 12..446 + 16].copy_from_slice(&((sectors - 1) as u32).to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xaa]);
+    for (lba, alternate, entries_lba) in [(1, sectors - 1, 2), (sectors - 1, 1, sectors - 33)] {
+        let mut h = [0u8; 92];
+        h[..8].copy_from_slice(SIGNATURE);
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&lba.to_le_bytes());
+        h[32..40].copy_from_slice(&alternate.to_le_bytes());
+        h[40..48].copy_from_slice(&34u64.to_le_bytes());
+        h[48..56].copy_from_slice(&(sectors - 34).to_le_bytes());
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&128u32.to_le_bytes());
+        h[84..88].copy_from_slice(&128u32.to_le_bytes());
+        h[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&h);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        image[(lba * SECTOR) as usize..][..92].copy_from_slice(&h);
+        image[(entries_lba * SECTOR) as usize..][..entries.len()].copy_from_slice(&entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::MemoryBackend;
+
+    fn partition(number: u32, type_guid: Guid, first_lba: u64, name: &str) -> GptPartition {
+        GptPartition {
+            number,
+            type_guid,
+            unique_guid: Guid([number as u8; 16]),
+            first_lba,
+            last_lba: first_lba + 99,
+            attributes: 0,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn guids_and_crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        let esp = Guid::parse("C12A7328-F81F-11D2-BA4B-00A0C93EC93B").unwrap();
+        assert_eq!(esp, Guid::EFI_SYSTEM);
+        assert_eq!(esp.0[..4], [0x28, 0x73, 0x2a, 0xc1]);
+        assert_eq!(esp.to_string(), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert_eq!(Guid::parse("c12a7328-f81f-11d2-ba4b"), None);
+        assert_eq!(Guid::parse("+12a7328-f81f-11d2-ba4b-00a0c93ec93b"), None);
+    }
+
+    #[test]
+    fn backup_table_replaces_a_damaged_primary() {
+        let mut image = vec![0; 1 << 20];
+        let partitions = vec![
+            partition(1, Guid::EFI_SYSTEM, 2048, "boot"),
+            partition(3, Guid::LINUX_FILESYSTEM, 4096, "rootfs"),
+        ];
+        write_gpt(&mut image, &partitions);
+        assert!(is_protective_mbr(&image[..512]));
+        let disk = MemoryBackend::new(image.clone());
+        assert_eq!(parse_gpt(&disk).unwrap(), partitions);
+
+        // A damaged entry array is caught by its checksum.
+        image[2 * 512 + 130] ^= 1;
+        assert_eq!(
+            parse_gpt(&MemoryBackend::new(image.clone())).unwrap(),
+            partitions
+        );
+        // So is a damaged header.
+        image[512 + 40] ^= 1;
+        assert_eq!(
+            parse_gpt(&MemoryBackend::new(image.clone())).unwrap(),
+            partitions
+        );
+
+        let last = image.len() - 512;
+        image[last] = 0;
+        assert_eq!(
+            parse_gpt(&MemoryBackend::new(image)),
+            Err("GPT header checksum mismatch")
+        );
+    }
+}
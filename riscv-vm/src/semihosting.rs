@@ -0,0 +1,583 @@
+//! RISC-V semihosting.
+//!
+//! A semihosting call is an `ebreak` wrapped in a magic instruction sequence:
+//!
+//! ```text
+//! slli x0, x0, 0x1f   # 0x01f01013
+//! ebreak              # 0x00100073
+//! srai x0, x0, 7      # 0x40705013
+//! ```
+//!
+//! `a0` holds the operation number and `a1` a pointer to an XLEN-sized
+//! parameter block (or a single parameter). The result is returned in `a0`.
+//! Operation numbers and semantics follow the ARM semihosting specification,
+//! which the RISC-V convention reuses. File names are resolved inside a
+//! sandbox directory; absolute paths, `..` and symlinks leading out of the
+//! sandbox are rejected.
+//!
+//! Console handles (opened as `:tt`) write to the same console as the UART
+//! (see [`Bus::console_write`]); stdout and stderr are not distinguished.
+//! Console input is not forwarded and always reads as end-of-file.
+
+use crate::Trap;
+use crate::bus::{Bus, finisher_code};
+use crate::cpu::Cpu;
+use crate::cpu::csr::CSR_SATP;
+use crate::mmu;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+/// `slli x0, x0, 0x1f` - precedes the semihosting `ebreak`.
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013;
+/// `srai x0, x0, 7` - follows the semihosting `ebreak`.
+pub const SEMIHOSTING_EXIT: u32 = 0x4070_5013;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0A;
+const SYS_FLEN: u64 = 0x0C;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+/// `ADP_Stopped_ApplicationExit`: normal program exit, subcode is the status.
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Handles of the console streams returned for `:tt`.
+const HANDLE_STDIN: u64 = 1;
+const HANDLE_STDOUT: u64 = 2;
+const HANDLE_STDERR: u64 = 3;
+
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+
+/// Largest single transfer serviced (guards against bogus lengths).
+const MAX_IO_LEN: u64 = 16 * 1024 * 1024;
+
+/// Semihosting settings.
+#[derive(Debug, Clone)]
+pub struct SemihostingConfig {
+    /// Host directory that guest file names are resolved against.
+    pub root: PathBuf,
+    /// Command line returned by `SYS_GET_CMDLINE`.
+    pub cmdline: String,
+    /// `[heap_base, heap_limit, stack_base, stack_limit]` returned by
+    /// `SYS_HEAPINFO`. Zeros tell the C library to use its linker symbols.
+    pub heap_info: [u64; 4],
+}
+
+impl SemihostingConfig {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            cmdline: String::new(),
+            heap_info: [0; 4],
+        }
+    }
+}
+
+struct State {
+    files: HashMap<u64, File>,
+    errno: i32,
+}
+
+/// Host side of the semihosting interface, shared by all harts.
+pub struct Semihosting {
+    config: SemihostingConfig,
+    start_ms: u64,
+    state: Mutex<State>,
+}
+
+impl Semihosting {
+    pub fn new(config: SemihostingConfig) -> Self {
+        Self {
+            config,
+            start_ms: host_time_ms(),
+            state: Mutex::new(State {
+                files: HashMap::new(),
+                errno: 0,
+            }),
+        }
+    }
+
+    /// Whether the `ebreak` at `pc` is wrapped in the semihosting sequence.
+    pub fn is_call(cpu: &Cpu, bus: &dyn Bus, pc: u64) -> bool {
+        let mem = GuestMem::new(cpu, bus);
+        mem.read_u32(pc.wrapping_sub(4)) == Some(SEMIHOSTING_ENTRY)
+            && mem.read_u32(pc.wrapping_add(4)) == Some(SEMIHOSTING_EXIT)
+    }
+
+    /// Perform the call described by `a0`/`a1` and return the value for `a0`.
+    ///
+    /// Exit requests surface as `Trap::RequestedTrap` with the test finisher
+    /// encoding, like the test finisher device and HTIF.
+    pub fn call(&self, cpu: &Cpu, bus: &dyn Bus) -> Result<u64, Trap> {
+        let mem = GuestMem::new(cpu, bus);
+        let op = cpu.regs[10];
+        let param = cpu.regs[11];
+        let arg = |i: u64| mem.read_u64(param.wrapping_add(8 * i));
+
+        let ret = match op {
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                let code = match (arg(0), arg(1)) {
+                    (Some(ADP_STOPPED_APPLICATION_EXIT), Some(status)) => status,
+                    _ => 1,
+                };
+                return Err(Trap::RequestedTrap(finisher_code(code)));
+            }
+            SYS_WRITEC => match mem.read_bytes(param, 1) {
+                Some(c) => {
+                    bus.console_write(&c);
+                    0
+                }
+                None => -1,
+            },
+            SYS_WRITE0 => match mem.read_cstr(param) {
+                Some(s) => {
+                    bus.console_write(&s);
+                    0
+                }
+                None => -1,
+            },
+            SYS_CLOCK => ((host_time_ms() - self.start_ms) / 10) as i64,
+            SYS_TIME => (host_time_ms() / 1000) as i64,
+            SYS_ERRNO => self.state.lock().unwrap().errno as i64,
+            SYS_GET_CMDLINE => self.get_cmdline(&mem, param),
+            SYS_HEAPINFO => match mem.read_u64(param) {
+                Some(block) => {
+                    let bytes: Vec<u8> = self
+                        .config
+                        .heap_info
+                        .iter()
+                        .flat_map(|w| w.to_le_bytes())
+                        .collect();
+                    if mem.write_bytes(block, &bytes) {
+                        0
+                    } else {
+                        -1
+                    }
+                }
+                None => -1,
+            },
+            _ => {
+                let result = match op {
+                    SYS_OPEN => self.open(&mem, arg(0), arg(1), arg(2)),
+                    SYS_CLOSE => self.close(arg(0)),
+                    SYS_WRITE => self.write(&mem, arg(0), arg(1), arg(2)),
+                    SYS_READ => self.read(&mem, arg(0), arg(1), arg(2)),
+                    SYS_ISTTY => Ok(matches!(
+                        arg(0),
+                        Some(HANDLE_STDIN | HANDLE_STDOUT | HANDLE_STDERR)
+                    ) as i64),
+                    SYS_SEEK => self.with_file(arg(0), |f| {
+                        f.seek(SeekFrom::Start(arg(1).unwrap_or(0)))?;
+                        Ok(0)
+                    }),
+                    SYS_FLEN => self.with_file(arg(0), |f| Ok(f.metadata()?.len() as i64)),
+                    _ => {
+                        log::warn!("[Semihosting] Unsupported operation 0x{:x}", op);
+                        Err(EINVAL)
+                    }
+                };
+                result.unwrap_or_else(|errno| {
+                    self.state.lock().unwrap().errno = errno;
+                    -1
+                })
+            }
+        };
+        Ok(ret as u64)
+    }
+
+    fn open(
+        &self,
+        mem: &GuestMem,
+        name: Option<u64>,
+        mode: Option<u64>,
+        len: Option<u64>,
+    ) -> Result<i64, i32> {
+        let (name, mode, len) = (name.ok_or(EFAULT)?, mode.ok_or(EFAULT)?, len.ok_or(EFAULT)?);
+        if len > MAX_IO_LEN {
+            return Err(EINVAL);
+        }
+        let name = mem.read_bytes(name, len).ok_or(EFAULT)?;
+        let name = std::str::from_utf8(&name).map_err(|_| EINVAL)?;
+
+        if name == ":tt" {
+            return Ok(match mode {
+                0..=3 => HANDLE_STDIN,
+                4..=7 => HANDLE_STDOUT,
+                _ => HANDLE_STDERR,
+            } as i64);
+        }
+
+        let path = self.sandbox_path(name).ok_or(EACCES)?;
+        let mut options = OpenOptions::new();
+        // Modes are fopen() strings: r, r+, w, w+, a, a+ (each with a "b" twin).
+        match mode / 2 {
+            0 => options.read(true),
+            1 => options.read(true).write(true),
+            2 => options.write(true).create(true).truncate(true),
+            3 => options.read(true).write(true).create(true).truncate(true),
+            4 => options.append(true).create(true),
+            5 => options.read(true).append(true).create(true),
+            _ => return Err(EINVAL),
+        };
+        let file = options.open(path).map_err(host_errno)?;
+
+        let mut state = self.state.lock().unwrap();
+        let handle = (HANDLE_STDERR + 1..)
+            .find(|h| !state.files.contains_key(h))
+            .unwrap();
+        state.files.insert(handle, file);
+        Ok(handle as i64)
+    }
+
+    fn close(&self, handle: Option<u64>) -> Result<i64, i32> {
+        match handle.ok_or(EFAULT)? {
+            HANDLE_STDIN | HANDLE_STDOUT | HANDLE_STDERR => Ok(0),
+            h => match self.state.lock().unwrap().files.remove(&h) {
+                Some(_) => Ok(0),
+                None => Err(EBADF),
+            },
+        }
+    }
+
+    /// Returns the number of bytes *not* written, as the spec requires.
+    fn write(
+        &self,
+        mem: &GuestMem,
+        handle: Option<u64>,
+        buf: Option<u64>,
+        len: Option<u64>,
+    ) -> Result<i64, i32> {
+        let (handle, buf, len) = (
+            handle.ok_or(EFAULT)?,
+            buf.ok_or(EFAULT)?,
+            len.ok_or(EFAULT)?,
+        );
+        if len > MAX_IO_LEN {
+            return Err(EINVAL);
+        }
+        let data = mem.read_bytes(buf, len).ok_or(EFAULT)?;
+        if handle == HANDLE_STDOUT || handle == HANDLE_STDERR {
+            mem.bus.console_write(&data);
+            return Ok(0);
+        }
+        self.with_file(Some(handle), |f| {
+            f.write_all(&data)?;
+            Ok(0)
+        })
+    }
+
+    /// Returns the number of bytes *not* read, as the spec requires.
+    fn read(
+        &self,
+        mem: &GuestMem,
+        handle: Option<u64>,
+        buf: Option<u64>,
+        len: Option<u64>,
+    ) -> Result<i64, i32> {
+        let (handle, buf, len) = (
+            handle.ok_or(EFAULT)?,
+            buf.ok_or(EFAULT)?,
+            len.ok_or(EFAULT)?,
+        );
+        if len > MAX_IO_LEN {
+            return Err(EINVAL);
+        }
+        if handle == HANDLE_STDIN {
+            return Ok(len as i64);
+        }
+        let mut data = vec![0u8; len as usize];
+        let n = self.with_file(Some(handle), |f| Ok(f.read(&mut data)? as i64))? as usize;
+        if !mem.write_bytes(buf, &data[..n]) {
+            return Err(EFAULT);
+        }
+        Ok((len as usize - n) as i64)
+    }
+
+    fn get_cmdline(&self, mem: &GuestMem, param: u64) -> i64 {
+        let (Some(buf), Some(size)) = (mem.read_u64(param), mem.read_u64(param.wrapping_add(8)))
+        else {
+            return -1;
+        };
+        let mut cmdline = self.config.cmdline.as_bytes().to_vec();
+        if cmdline.len() as u64 >= size {
+            return -1;
+        }
+        let len = cmdline.len() as u64;
+        cmdline.push(0);
+        if !mem.write_bytes(buf, &cmdline)
+            || !mem.write_bytes(param.wrapping_add(8), &len.to_le_bytes())
+        {
+            return -1;
+        }
+        0
+    }
+
+    fn with_file(
+        &self,
+        handle: Option<u64>,
+        f: impl FnOnce(&mut File) -> std::io::Result<i64>,
+    ) -> Result<i64, i32> {
+        let handle = handle.ok_or(EFAULT)?;
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.get_mut(&handle).ok_or(EBADF)?;
+        f(file).map_err(host_errno)
+    }
+
+    /// Resolve a guest file name inside the sandbox root.
+    fn sandbox_path(&self, name: &str) -> Option<PathBuf> {
+        sandbox_path(&self.config.root, name)
+    }
+}
+
+/// Resolve a guest file name below `root`, rejecting absolute paths and `..`.
+///
+/// Shared with the HTIF syscall proxy so both host interfaces apply the same
+/// sandbox rules. The result is canonicalized and must still lie below the
+/// canonical root, so symlinks inside the sandbox cannot lead out of it.
+pub(crate) fn sandbox_path(root: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    // The file may not exist yet: canonicalize the longest existing prefix
+    // and re-append the rest. A dangling symlink is refused outright, since
+    // creating the file would follow it.
+    let root = root.canonicalize().ok()?;
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(p) => break p,
+            Err(_) if existing.symlink_metadata().is_ok() => return None,
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    };
+    let resolved = missing.iter().rev().fold(resolved, |p, part| p.join(part));
+    resolved.starts_with(&root).then_some(resolved)
+}
+
+fn host_errno(e: std::io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EINVAL)
+}
+
+fn host_time_ms() -> u64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now() as u64
+    }
+}
+
+/// Guest virtual memory as seen by the calling hart.
+struct GuestMem<'a> {
+    bus: &'a dyn Bus,
+    mode: crate::Mode,
+    satp: u64,
+}
+
+impl<'a> GuestMem<'a> {
+    fn new(cpu: &Cpu, bus: &'a dyn Bus) -> Self {
+        Self {
+            bus,
+            mode: cpu.mode,
+            satp: cpu.csrs[CSR_SATP as usize],
+        }
+    }
+
+    fn phys(&self, addr: u64) -> Option<u64> {
+        mmu::peek_translate(self.bus, self.mode, self.satp, addr)
+    }
+
+    fn read_u32(&self, addr: u64) -> Option<u32> {
+        let bytes = self.read_bytes(addr, 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn read_u64(&self, addr: u64) -> Option<u64> {
+        let bytes = self.read_bytes(addr, 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn read_bytes(&self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        (0..len)
+            .map(|i| self.bus.read8(self.phys(addr.wrapping_add(i))?).ok())
+            .collect()
+    }
+
+    fn read_cstr(&self, addr: u64) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        for i in 0..MAX_IO_LEN {
+            match self.bus.read8(self.phys(addr.wrapping_add(i))?).ok()? {
+                0 => return Some(out),
+                b => out.push(b),
+            }
+        }
+        None
+    }
+
+    fn write_bytes(&self, addr: u64, data: &[u8]) -> bool {
+        data.iter().enumerate().all(|(i, &b)| {
+            self.phys(addr.wrapping_add(i as u64))
+                .is_some_and(|pa| self.bus.write8(pa, b).is_ok())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{DRAM_BASE, FINISHER_PASS, SystemBus};
+
+    fn set_params(bus: &SystemBus, params: &[u64]) {
+        for (i, p) in params.iter().enumerate() {
+            bus.write64(DRAM_BASE + 0x1000 + 8 * i as u64, *p).unwrap();
+        }
+    }
+
+    fn setup(op: u64, params: &[u64]) -> (Cpu, SystemBus) {
+        let bus = SystemBus::new(DRAM_BASE, 0x10000);
+        let mut cpu = Cpu::new(DRAM_BASE, 0);
+        set_params(&bus, params);
+        cpu.regs[10] = op;
+        cpu.regs[11] = DRAM_BASE + 0x1000;
+        (cpu, bus)
+    }
+
+    #[test]
+    fn test_detect_sequence() {
+        let (cpu, bus) = setup(0, &[]);
+        for (i, insn) in [SEMIHOSTING_ENTRY, 0x0010_0073, SEMIHOSTING_EXIT]
+            .iter()
+            .enumerate()
+        {
+            bus.write32(DRAM_BASE + 4 * i as u64, *insn).unwrap();
+        }
+        assert!(Semihosting::is_call(&cpu, &bus, DRAM_BASE + 4));
+        bus.write32(DRAM_BASE + 8, 0x0000_0013).unwrap();
+        assert!(!Semihosting::is_call(&cpu, &bus, DRAM_BASE + 4));
+    }
+
+    #[test]
+    fn test_exit_and_sandbox() {
+        let dir = std::env::temp_dir().join(format!("semihosting-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let sh = Semihosting::new(SemihostingConfig::new(&dir));
+        let (cpu, bus) = setup(SYS_EXIT, &[ADP_STOPPED_APPLICATION_EXIT, 0]);
+        assert_eq!(sh.call(&cpu, &bus), Err(Trap::RequestedTrap(FINISHER_PASS)));
+        let (cpu, bus) = setup(SYS_EXIT_EXTENDED, &[ADP_STOPPED_APPLICATION_EXIT, 3]);
+        assert_eq!(
+            sh.call(&cpu, &bus),
+            Err(Trap::RequestedTrap(finisher_code(3)))
+        );
+
+        assert_eq!(
+            sh.sandbox_path("out/log.txt"),
+            Some(dir.join("out/log.txt"))
+        );
+        assert_eq!(sh.sandbox_path("new/dir/f"), Some(dir.join("new/dir/f")));
+        assert_eq!(sh.sandbox_path("../etc/passwd"), None);
+        assert_eq!(sh.sandbox_path("/etc/passwd"), None);
+
+        #[cfg(unix)]
+        {
+            let outside = std::env::temp_dir();
+            std::os::unix::fs::symlink(&outside, dir.join("escape")).unwrap();
+            std::os::unix::fs::symlink(outside.join("no-such-file"), dir.join("dangling")).unwrap();
+            std::os::unix::fs::symlink(dir.join("out"), dir.join("inner")).unwrap();
+            assert_eq!(sh.sandbox_path("escape/passwd"), None);
+            assert_eq!(sh.sandbox_path("dangling"), None);
+            assert_eq!(
+                sh.sandbox_path("inner/log.txt"),
+                Some(dir.join("out/log.txt"))
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("semihosting-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = SemihostingConfig::new(&dir);
+        config.cmdline = "prog arg".to_string();
+        let sh = Semihosting::new(config);
+
+        let name = DRAM_BASE + 0x2000;
+        let buf = DRAM_BASE + 0x3000;
+        let (mut cpu, bus) = setup(SYS_OPEN, &[name, 4, 5]);
+        for (i, b) in b"a.txt\0hello".iter().enumerate() {
+            bus.write8(name + i as u64, *b).unwrap();
+        }
+        let handle = sh.call(&cpu, &bus).unwrap();
+        assert!(handle > HANDLE_STDERR);
+
+        cpu.regs[10] = SYS_WRITE;
+        set_params(&bus, &[handle, name + 6, 5]);
+        assert_eq!(sh.call(&cpu, &bus), Ok(0));
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"hello");
+
+        cpu.regs[10] = SYS_GET_CMDLINE;
+        set_params(&bus, &[buf, 64]);
+        assert_eq!(sh.call(&cpu, &bus), Ok(0));
+        assert_eq!(bus.read64(DRAM_BASE + 0x1008).unwrap(), 8);
+        assert_eq!(bus.read64(buf).unwrap().to_le_bytes(), *b"prog arg");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_console_goes_to_uart() {
+        let sh = Semihosting::new(SemihostingConfig::new("/tmp/sandbox"));
+        let text = DRAM_BASE + 0x2000;
+        let (mut cpu, bus) = setup(SYS_WRITE0, &[]);
+        for (i, b) in b"hi\0".iter().enumerate() {
+            bus.write8(text + i as u64, *b).unwrap();
+        }
+        cpu.regs[11] = text;
+        assert_eq!(sh.call(&cpu, &bus), Ok(0));
+
+        cpu.regs[10] = SYS_WRITEC;
+        cpu.regs[11] = text + 1;
+        assert_eq!(sh.call(&cpu, &bus), Ok(0));
+
+        cpu.regs[10] = SYS_WRITE;
+        cpu.regs[11] = DRAM_BASE + 0x1000;
+        set_params(&bus, &[HANDLE_STDERR, text, 2]);
+        assert_eq!(sh.call(&cpu, &bus), Ok(0));
+
+        let out: Vec<u8> = std::iter::from_fn(|| bus.uart.pop_output()).collect();
+        assert_eq!(out, b"hiihi");
+    }
+}
@@ -1,19 +1,40 @@
+use crate::bus::SystemBus;
+use crate::cpu::Cpu;
 use crate::csr::Mode;
+use crate::devices::uart::Uart;
+use crate::dram::Dram;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
 /// Version identifier for snapshot compatibility checks.
-pub const SNAPSHOT_VERSION: &str = "2.0";
+pub const SNAPSHOT_VERSION: &str = "2.2";
 
 /// Full emulator snapshot including CPU, devices and DRAM.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
     pub version: String,
+    /// Hart 0.
     pub cpu: CpuSnapshot,
+    /// Secondary harts (1..n) of a multi-hart VM, in order.
+    pub harts: Vec<CpuSnapshot>,
     pub devices: DeviceSnapshot,
     pub memory: Vec<MemRegionSnapshot>,
 }
 
+impl Snapshot {
+    /// Check that this snapshot was written by a compatible version.
+    pub fn check_version(&self) -> Result<(), String> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version mismatch: expected {}, found {}",
+                SNAPSHOT_VERSION, self.version
+            ));
+        }
+        Ok(())
+    }
+}
+
 /// Serializable CPU state.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CpuSnapshot {
@@ -29,6 +50,8 @@ pub struct DeviceSnapshot {
     pub clint: ClintSnapshot,
     pub plic: PlicSnapshot,
     pub uart: UartSnapshot,
+    /// UARTs 1 and up.
+    pub extra_uarts: Vec<UartSnapshot>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,14 +94,152 @@ pub struct MemRegionSnapshot {
     pub data: Option<Vec<u8>>,
 }
 
+impl CpuSnapshot {
+    pub fn capture(cpu: &Cpu) -> Self {
+        Self {
+            pc: cpu.pc,
+            mode: cpu.mode,
+            regs: cpu.regs,
+            csrs: cpu.export_csrs(),
+        }
+    }
+
+    /// Load this state into `cpu`, dropping any cached translations and
+    /// compiled blocks that belonged to the old state.
+    pub fn restore(&self, cpu: &mut Cpu) {
+        cpu.pc = self.pc;
+        cpu.mode = self.mode;
+        cpu.regs = self.regs;
+        cpu.import_csrs(&self.csrs);
+        cpu.tlb.flush();
+        cpu.invalidate_blocks();
+    }
+}
 
+impl DeviceSnapshot {
+    pub fn capture(bus: &SystemBus) -> Self {
+        let clint = ClintSnapshot {
+            msip: bus.clint.get_msip_array().to_vec(),
+            mtime: bus.clint.mtime(),
+            mtimecmp: bus.clint.get_mtimecmp_array().to_vec(),
+        };
+
+        let plic = PlicSnapshot {
+            priority: bus.plic.get_priority(),
+            pending: bus.plic.get_pending(),
+            enable: bus.plic.get_enable(),
+            threshold: bus.plic.get_threshold(),
+            active: bus.plic.get_active(),
+        };
+
+        let uart = UartSnapshot::capture(&bus.uart);
+        let extra_uarts = bus.extra_uarts.iter().map(UartSnapshot::capture).collect();
+
+        Self {
+            clint,
+            plic,
+            uart,
+            extra_uarts,
+        }
+    }
+
+    pub fn restore(&self, bus: &SystemBus) {
+        // Restore CLINT.
+        bus.clint.set_msip_array(&self.clint.msip);
+        bus.clint.set_mtime(self.clint.mtime);
+        bus.clint.set_mtimecmp_array(&self.clint.mtimecmp);
+
+        // Restore PLIC.
+        bus.plic.set_priority(&self.plic.priority);
+        bus.plic.set_pending(self.plic.pending);
+        bus.plic.set_enable(&self.plic.enable);
+        bus.plic.set_threshold(&self.plic.threshold);
+        bus.plic.set_active(&self.plic.active);
+
+        // Restore UARTs. A snapshot of a VM with more UARTs than this one
+        // drops the extra state.
+        self.uart.restore(&bus.uart);
+        for (snap, uart) in self.extra_uarts.iter().zip(&bus.extra_uarts) {
+            snap.restore(uart);
+        }
+    }
+}
 
+impl UartSnapshot {
+    pub fn capture(uart: &Uart) -> Self {
+        let (ier, iir, fcr, lcr, mcr, lsr, msr, scr, dll, dlm) = uart.get_registers();
+        Self {
+            rx_fifo: uart.get_input(),
+            tx_fifo: uart.get_output(),
+            ier,
+            iir,
+            fcr,
+            lcr,
+            mcr,
+            lsr,
+            msr,
+            scr,
+            dll,
+            dlm,
+        }
+    }
+
+    pub fn restore(&self, uart: &Uart) {
+        uart.set_input(&self.rx_fifo);
+        uart.set_output(&self.tx_fifo);
+        uart.set_registers(
+            self.ier, self.iir, self.fcr, self.lcr, self.mcr, self.lsr, self.msr, self.scr, self.dll, self.dlm,
+        );
+    }
+}
 
-
-
-
-
-
-
-
-
+impl MemRegionSnapshot {
+    /// Capture the whole of `dram` inline, with its SHA-256.
+    pub fn capture(dram: &Dram) -> Self {
+        let data = dram.get_data();
+        let mut hasher = Sha256::new();
+        hasher.update(&data);
+        Self {
+            base: dram.base,
+            size: dram.size() as u64,
+            hash: hex::encode(hasher.finalize()),
+            data: Some(data),
+        }
+    }
+
+    /// Copy this region back into `dram`, which must have the same base and
+    /// size.
+    pub fn restore(&self, dram: &Dram) -> Result<(), String> {
+        let data = self
+            .data
+            .as_ref()
+            .ok_or_else(|| "snapshot memory region has no inline data".to_string())?;
+
+        if dram.base != self.base {
+            return Err(format!(
+                "snapshot DRAM base mismatch: emulator=0x{:x}, snapshot=0x{:x}",
+                dram.base, self.base
+            ));
+        }
+        if dram.size() != data.len() {
+            return Err(format!(
+                "snapshot DRAM size mismatch: emulator={} bytes, snapshot={} bytes",
+                dram.size(),
+                data.len()
+            ));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        let current_hash = hex::encode(hasher.finalize());
+        if current_hash != self.hash {
+            return Err(format!(
+                "snapshot DRAM hash mismatch for base 0x{:x}",
+                self.base
+            ));
+        }
+
+        dram.set_data(data)
+            .map_err(|e| format!("failed to restore DRAM: {}", e))
+    }
+}
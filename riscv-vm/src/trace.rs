@@ -0,0 +1,519 @@
+//! Instruction commit trace in Spike's `--log-commits` format.
+//!
+//! Each retired instruction produces one line:
+//!
+//! ```text
+//! core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002a
+//! core   0: 3 0x0000000080000004 (0x00533023) mem 0x0000000080000100 0x000000000000002a
+//! ```
+//!
+//! i.e. hart, privilege level, PC, raw instruction bits, then register and
+//! CSR writebacks followed by memory reads (`mem addr`) and writes
+//! (`mem addr value`). The format matches Spike closely enough that the two
+//! logs can be compared line by line with [`first_divergence`].
+//!
+//! While tracing, the CPU compiles single-instruction blocks with chaining
+//! disabled, so the block engine and the interpreter both retire exactly one
+//! instruction per `step()` and produce identical traces.
+
+use crate::Mode;
+use crate::Trap;
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::csr::{CSR_SATP, csr_name};
+use crate::engine::decoder::{self, Op};
+use crate::engine::disasm;
+use crate::mmu;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Which retired instructions end up in the trace.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// Only log instructions whose PC lies in this range.
+    pub pc_range: Option<Range<u64>>,
+    /// Only log instructions executed in these privilege modes (empty = all).
+    pub modes: Vec<Mode>,
+    /// Only trace these harts (empty = all).
+    pub harts: Vec<usize>,
+    /// Number of retired instructions (per hart) to skip before logging.
+    pub skip: u64,
+    /// Number of retired instructions (per hart) to cover after `skip`.
+    pub count: Option<u64>,
+    /// Precede each commit line with a Spike `-l` style disassembly line
+    /// (`core   0: 0x0000000080000000 (0x02a00293) li t0,42`), which
+    /// [`first_divergence`] skips.
+    pub disasm: bool,
+}
+
+impl TraceFilter {
+    fn wants_hart(&self, hart_id: usize) -> bool {
+        self.harts.is_empty() || self.harts.contains(&hart_id)
+    }
+
+    /// The instruction-count window applies to every retired instruction,
+    /// before the PC and privilege filters.
+    fn in_window(&self, retired: u64) -> bool {
+        retired >= self.skip && self.count.is_none_or(|n| retired - self.skip < n)
+    }
+
+    fn wants(&self, pc: u64, mode: Mode) -> bool {
+        self.pc_range.as_ref().is_none_or(|r| r.contains(&pc))
+            && (self.modes.is_empty() || self.modes.contains(&mode))
+    }
+}
+
+/// Trace output shared by all harts.
+pub struct TraceLog {
+    filter: TraceFilter,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TraceLog {
+    pub fn new(out: Box<dyn Write + Send>, filter: TraceFilter) -> Arc<Self> {
+        Arc::new(Self {
+            filter,
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Create a trace log writing to `path`.
+    pub fn create(path: &std::path::Path, filter: TraceFilter) -> io::Result<Arc<Self>> {
+        let file = std::fs::File::create(path)?;
+        Ok(Self::new(Box::new(io::BufWriter::new(file)), filter))
+    }
+
+    /// Create the per-hart tracer, or `None` if the hart is filtered out.
+    pub fn tracer(self: &Arc<Self>, hart_id: usize) -> Option<HartTracer> {
+        if !self.filter.wants_hart(hart_id) {
+            return None;
+        }
+        Some(HartTracer {
+            hart_id,
+            log: Arc::clone(self),
+            retired: 0,
+            line: String::with_capacity(128),
+        })
+    }
+
+    pub fn flush(&self) {
+        if let Ok(mut out) = self.out.lock() {
+            out.flush().ok();
+        }
+    }
+
+    fn write_line(&self, line: &str) {
+        if let Ok(mut out) = self.out.lock() {
+            writeln!(out, "{}", line).ok();
+        }
+    }
+}
+
+/// Per-hart tracing state. Replaces `cpu.step()` in the hart loop.
+pub struct HartTracer {
+    hart_id: usize,
+    log: Arc<TraceLog>,
+    retired: u64,
+    line: String,
+}
+
+impl HartTracer {
+    /// Execute one instruction and log it if it retired.
+    pub fn step(&mut self, cpu: &mut Cpu, bus: &dyn Bus) -> Result<(), Trap> {
+        cpu.set_single_insn_blocks(true);
+
+        let pc = cpu.pc;
+        let mode = cpu.mode;
+        let regs = cpu.regs;
+        let insn = disasm::fetch(bus, mode, cpu.csrs[CSR_SATP as usize], pc);
+
+        let result = cpu.step(bus);
+
+        // Exceptions and interrupts do not retire; WFI does.
+        if matches!(result, Ok(()) | Err(Trap::Wfi)) {
+            let retired = self.retired;
+            self.retired += 1;
+            let filter = &self.log.filter;
+            if filter.in_window(retired)
+                && filter.wants(pc, mode)
+                && let Some((raw, len)) = insn
+            {
+                if filter.disasm {
+                    self.format_disasm(pc, raw, len);
+                    self.log.write_line(&self.line);
+                }
+                self.format(cpu, bus, pc, mode, &regs, raw, len);
+                self.log.write_line(&self.line);
+            }
+        }
+
+        result
+    }
+
+    /// Number of instructions this hart has retired while traced.
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    fn format_disasm(&mut self, pc: u64, raw: u32, len: u8) {
+        self.line.clear();
+        let _ = write!(self.line, "core{:4}: 0x{:016x} ", self.hart_id, pc);
+        if len == 2 {
+            let _ = write!(self.line, "(0x{:04x})", raw);
+        } else {
+            let _ = write!(self.line, "(0x{:08x})", raw);
+        }
+        let _ = write!(self.line, " {}", disasm::disassemble(pc, raw));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn format(
+        &mut self,
+        cpu: &Cpu,
+        bus: &dyn Bus,
+        pc: u64,
+        mode: Mode,
+        pre: &[u64; 32],
+        raw: u32,
+        len: u8,
+    ) {
+        let line = &mut self.line;
+        line.clear();
+
+        let _ = write!(
+            line,
+            "core{:4}: {} 0x{:016x} ",
+            self.hart_id,
+            mode.to_mpp(),
+            pc
+        );
+        if len == 2 {
+            let _ = write!(line, "(0x{:04x})", raw);
+        } else {
+            let _ = write!(line, "(0x{:08x})", raw);
+        }
+
+        let expanded = if len == 2 {
+            decoder::expand_compressed(raw as u16).ok()
+        } else {
+            Some(raw)
+        };
+        let Some(op) = expanded.and_then(|insn| decoder::decode(insn).ok()) else {
+            return;
+        };
+
+        if let Some(rd) = dest_reg(&op)
+            && rd != 0
+        {
+            let _ = write!(line, " x{:<2} 0x{:016x}", rd, cpu.regs[rd]);
+        }
+
+        match op {
+            Op::System {
+                rs1, funct3, imm, ..
+            } if funct3 != 0 && funct3 != 4 => {
+                // csrrs/csrrc (and immediate forms) with a zero source only read.
+                let writes = matches!(funct3, 1 | 5) || rs1.to_usize() != 0;
+                if writes {
+                    let addr = (imm & 0xFFF) as u16;
+                    let value = cpu
+                        .csrs
+                        .read(addr, Mode::Machine)
+                        .unwrap_or(cpu.csrs[addr as usize]);
+                    let _ = write!(
+                        line,
+                        " c{}_{} 0x{:016x}",
+                        addr,
+                        csr_name(addr).unwrap_or("unknown"),
+                        value
+                    );
+                }
+            }
+            Op::Load { rs1, imm, .. } => {
+                let addr = pre[rs1.to_usize()].wrapping_add(imm as u64);
+                let _ = write!(line, " mem 0x{:016x}", addr);
+            }
+            Op::Store {
+                rs1,
+                rs2,
+                imm,
+                funct3,
+            } => {
+                let addr = pre[rs1.to_usize()].wrapping_add(imm as u64);
+                let size = 1usize << (funct3 & 3);
+                write_mem_store(line, addr, pre[rs2.to_usize()], size);
+            }
+            Op::Amo {
+                rd,
+                rs1,
+                rs2,
+                funct3,
+                funct5,
+                ..
+            } => {
+                let addr = pre[rs1.to_usize()];
+                let size = if funct3 == 2 { 4 } else { 8 };
+                match funct5 {
+                    // LR
+                    0b00010 => {
+                        let _ = write!(line, " mem 0x{:016x}", addr);
+                    }
+                    // SC: only logged when it succeeded (rd == 0).
+                    0b00011 => {
+                        if rd.to_usize() == 0 || cpu.regs[rd.to_usize()] == 0 {
+                            write_mem_store(line, addr, pre[rs2.to_usize()], size);
+                        }
+                    }
+                    // AMO read-modify-write: log the value left in memory.
+                    _ => {
+                        let _ = write!(line, " mem 0x{:016x}", addr);
+                        let satp = cpu.csrs[CSR_SATP as usize];
+                        let value = mmu::peek_translate(bus, cpu.mode, satp, addr)
+                            .and_then(|pa| bus.load(pa, size as u64).ok())
+                            .unwrap_or(0);
+                        write_mem_store(line, addr, value, size);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_mem_store(line: &mut String, addr: u64, value: u64, size: usize) {
+    let value = if size == 8 {
+        value
+    } else {
+        value & ((1u64 << (size * 8)) - 1)
+    };
+    let _ = write!(
+        line,
+        " mem 0x{:016x} 0x{:0width$x}",
+        addr,
+        value,
+        width = size * 2
+    );
+}
+
+/// Destination register of an instruction, if it writes one.
+fn dest_reg(op: &Op) -> Option<usize> {
+    match op {
+        Op::Lui { rd, .. }
+        | Op::Auipc { rd, .. }
+        | Op::Jal { rd, .. }
+        | Op::Jalr { rd, .. }
+        | Op::Load { rd, .. }
+        | Op::OpImm { rd, .. }
+        | Op::Op { rd, .. }
+        | Op::OpImm32 { rd, .. }
+        | Op::Op32 { rd, .. }
+        | Op::Amo { rd, .. } => Some(rd.to_usize()),
+        Op::System { rd, funct3, .. } if *funct3 != 0 => Some(rd.to_usize()),
+        _ => None,
+    }
+}
+
+// ============================================================================
+// Trace comparison
+// ============================================================================
+
+/// First point at which two commit logs disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// 1-based index of the first differing commit.
+    pub commit: usize,
+    /// Line number (1-based) and text in the reference log, if not exhausted.
+    pub expected: Option<(usize, String)>,
+    /// Line number (1-based) and text in the log under test, if not exhausted.
+    pub actual: Option<(usize, String)>,
+}
+
+/// Normalise a commit line, or return `None` for anything else (Spike's
+/// `-l` disassembly lines, console output, blank lines).
+fn parse_commit(line: &str, hart: Option<usize>) -> Option<String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.len() < 5 || tokens[0] != "core" {
+        return None;
+    }
+    let line_hart: usize = tokens[1].strip_suffix(':')?.parse().ok()?;
+    if hart.is_some_and(|h| h != line_hart) {
+        return None;
+    }
+    let is_priv = tokens[2].len() == 1 && tokens[2].as_bytes()[0].is_ascii_digit();
+    if !is_priv || !tokens[3].starts_with("0x") || !tokens[4].starts_with("(0x") {
+        return None;
+    }
+    Some(tokens.join(" ").to_ascii_lowercase())
+}
+
+fn commits(
+    reader: impl BufRead,
+    hart: Option<usize>,
+) -> impl Iterator<Item = io::Result<(usize, String)>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter_map(move |(n, line)| match line {
+            Ok(l) => parse_commit(&l, hart).map(|c| Ok((n + 1, c))),
+            Err(e) => Some(Err(e)),
+        })
+}
+
+/// Compare two commit logs and return the first divergence, if any.
+///
+/// Non-commit lines are ignored. With `hart` set, only that hart's commits
+/// are compared.
+pub fn first_divergence(
+    reference: impl BufRead,
+    actual: impl BufRead,
+    hart: Option<usize>,
+) -> io::Result<Option<Divergence>> {
+    let mut expected = commits(reference, hart);
+    let mut actual = commits(actual, hart);
+
+    let mut commit = 0;
+    loop {
+        commit += 1;
+        let e = expected.next().transpose()?;
+        let a = actual.next().transpose()?;
+        match (&e, &a) {
+            (None, None) => return Ok(None),
+            (Some((_, x)), Some((_, y))) if x == y => continue,
+            _ => {
+                return Ok(Some(Divergence {
+                    commit,
+                    expected: e,
+                    actual: a,
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{DRAM_BASE, SystemBus};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_traced(filter: TraceFilter, steps: usize) -> (String, Cpu) {
+        let bus = SystemBus::new(DRAM_BASE, 1024 * 1024);
+        bus.write32(DRAM_BASE, 0x02a0_0293).unwrap(); // addi x5, x0, 42
+        bus.write32(DRAM_BASE + 4, 0x0053_3023).unwrap(); // sd x5, 0(x6)
+        bus.write32(DRAM_BASE + 8, 0x0003_3383).unwrap(); // ld x7, 0(x6)
+
+        let buf = SharedBuf::default();
+        let log = TraceLog::new(Box::new(buf.clone()), filter);
+        let mut tracer = log.tracer(0).unwrap();
+        let mut cpu = Cpu::new(DRAM_BASE, 0);
+        cpu.regs[6] = DRAM_BASE + 0x100;
+        for _ in 0..steps {
+            tracer.step(&mut cpu, &bus).unwrap();
+        }
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        (out, cpu)
+    }
+
+    #[test]
+    fn test_commit_log_format() {
+        let (out, cpu) = run_traced(TraceFilter::default(), 3);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002a",
+                "core   0: 3 0x0000000080000004 (0x00533023) mem 0x0000000080000100 0x000000000000002a",
+                "core   0: 3 0x0000000080000008 (0x00033383) x7  0x000000000000002a mem 0x0000000080000100",
+            ]
+        );
+        // One instruction per step even with the block engine enabled.
+        assert!(cpu.use_blocks);
+        assert_eq!(cpu.pc, DRAM_BASE + 12);
+    }
+
+    #[test]
+    fn test_trace_window_and_pc_filter() {
+        let filter = TraceFilter {
+            skip: 1,
+            count: Some(1),
+            ..Default::default()
+        };
+        let (out, _) = run_traced(filter, 3);
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("0x0000000080000004"));
+
+        let filter = TraceFilter {
+            pc_range: Some(DRAM_BASE + 8..DRAM_BASE + 12),
+            ..Default::default()
+        };
+        let (out, _) = run_traced(filter, 3);
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("0x0000000080000008"));
+    }
+
+    #[test]
+    fn test_disasm_lines() {
+        let filter = TraceFilter {
+            disasm: true,
+            ..Default::default()
+        };
+        let (out, _) = run_traced(filter, 2);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "core   0: 0x0000000080000000 (0x02a00293) li t0,42"
+        );
+        assert_eq!(
+            lines[2],
+            "core   0: 0x0000000080000004 (0x00533023) sd t0,0(t1)"
+        );
+
+        let (plain, _) = run_traced(TraceFilter::default(), 2);
+        assert_eq!(
+            first_divergence(out.as_bytes(), plain.as_bytes(), None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let reference = "core   0: 0x0000000080000000 (0x02a00293) addi t0, zero, 42\n\
+                         core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002a\n\
+                         core   0: 3 0x0000000080000004 (0x00533023) mem 0x0000000080000100 0x000000000000002a\n";
+        let same = "core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002A\n\
+                    core   0: 3 0x0000000080000004 (0x00533023) mem 0x0000000080000100 0x000000000000002a\n";
+        assert_eq!(
+            first_divergence(reference.as_bytes(), same.as_bytes(), None).unwrap(),
+            None
+        );
+
+        let differs = "core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002b\n";
+        let d = first_divergence(reference.as_bytes(), differs.as_bytes(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.commit, 1);
+        assert_eq!(d.expected.unwrap().0, 2);
+        assert_eq!(d.actual.unwrap().0, 1);
+
+        let truncated = "core   0: 3 0x0000000080000000 (0x02a00293) x5  0x000000000000002a\n";
+        let d = first_divergence(reference.as_bytes(), truncated.as_bytes(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.commit, 2);
+        assert!(d.actual.is_none());
+    }
+}
@@ -0,0 +1,239 @@
+//! Runner for the RISC-V architectural test suite (riscv-arch-test / riscof).
+//!
+//! A compliance test is a bare-metal M-mode ELF that writes its results
+//! between the `begin_signature` and `end_signature` symbols and then halts,
+//! either by writing the test finisher or through the HTIF `tohost` symbol
+//! (see [`crate::devices::htif`]). The signature is dumped in the riscof
+//! format (one little-endian word per line, lowest address first) and
+//! compared against a reference model by riscof. The plugin lives in
+//! `riscof/bavy/`.
+//!
+//! Tests without signature symbols, such as the classic riscv-tests
+//! (`rv64ui-p-*`), run the same way and only report pass/fail.
+
+use super::emulator::Emulator;
+use crate::Trap;
+use crate::bus::{FINISHER_FAIL, FINISHER_PASS};
+use crate::engine::disasm::disassemble_at;
+use crate::loader::SymbolTable;
+use crate::trace::{HartTracer, TraceLog};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Default step budget before a test is considered hung.
+pub const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+
+/// How an architectural test run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchTestExit {
+    /// The test halted with this finisher value (HTIF exits are encoded
+    /// the same way, see [`crate::bus::finisher_code`]).
+    Finisher(u64),
+    /// The step budget ran out.
+    Timeout,
+}
+
+impl ArchTestExit {
+    /// Whether the test reported success.
+    pub fn passed(&self) -> bool {
+        *self == ArchTestExit::Finisher(FINISHER_PASS)
+    }
+
+    /// Process exit status for this result: 0 on success, the test's own
+    /// exit code for HTIF failures (riscv-tests report the failing test
+    /// number), 1 for other failures and 2 on timeout.
+    pub fn exit_status(&self) -> i32 {
+        match *self {
+            ArchTestExit::Finisher(FINISHER_PASS) => 0,
+            ArchTestExit::Finisher(code) if code & 0xffff == FINISHER_FAIL => {
+                i32::try_from(code >> 16).unwrap_or(1).max(1)
+            }
+            ArchTestExit::Finisher(_) => 1,
+            ArchTestExit::Timeout => 2,
+        }
+    }
+}
+
+/// A loaded architectural test.
+pub struct ArchTest {
+    pub emu: Emulator,
+    signature: Option<Range<u64>>,
+    tracer: Option<HartTracer>,
+}
+
+impl ArchTest {
+    /// Load a test ELF and locate its signature symbols, if any.
+    pub fn load(elf: &[u8]) -> Result<Self, String> {
+        let symbols = SymbolTable::from_elf(elf)?;
+        let begin = symbols.find("begin_signature").map(|s| s.addr);
+        let end = symbols.find("end_signature").map(|s| s.addr);
+        let signature = match (begin, end) {
+            (Some(begin), Some(end)) if end < begin => {
+                return Err(format!(
+                    "end_signature 0x{:x} precedes begin_signature 0x{:x}",
+                    end, begin
+                ));
+            }
+            (Some(begin), Some(end)) => Some(begin..end),
+            (None, None) => None,
+            _ => return Err("ELF has only one of begin_signature/end_signature".to_string()),
+        };
+
+        let mut emu = Emulator::new();
+        emu.load_elf_bytes(elf)?;
+        if let Some(range) = &signature {
+            emu.set_signature_region(range.start, range.end - range.start);
+        }
+
+        Ok(Self {
+            emu,
+            signature,
+            tracer: None,
+        })
+    }
+
+    /// Address range of the signature, if the test has one.
+    pub fn signature_range(&self) -> Option<Range<u64>> {
+        self.signature.clone()
+    }
+
+    /// Log every retired instruction to `log`.
+    pub fn enable_trace(&mut self, log: &Arc<TraceLog>) {
+        self.tracer = log.tracer(0);
+    }
+
+    /// Run until the test halts or `max_steps` steps have executed.
+    ///
+    /// Architectural traps are delivered to the test's own trap handler;
+    /// only fatal emulator errors abort the run.
+    pub fn run(&mut self, max_steps: u64) -> Result<ArchTestExit, String> {
+        let cpu = &mut self.emu.cpu;
+        let bus = &self.emu.bus;
+
+        for _ in 0..max_steps {
+            let result = match self.tracer.as_mut() {
+                Some(t) => t.step(cpu, bus),
+                None => cpu.step(bus),
+            };
+            match result {
+                Err(Trap::RequestedTrap(code)) => return Ok(ArchTestExit::Finisher(code)),
+                Err(Trap::Fatal(msg)) => {
+                    let insn =
+                        disassemble_at(cpu, bus, cpu.pc).unwrap_or_else(|| "unmapped".to_string());
+                    return Err(format!(
+                        "fatal error at PC=0x{:x} ({}): {}",
+                        cpu.pc, insn, msg
+                    ));
+                }
+                Err(Trap::Wfi) => cpu.pc = cpu.pc.wrapping_add(4),
+                _ => {}
+            }
+        }
+
+        Ok(ArchTestExit::Timeout)
+    }
+
+    /// Signature in riscof format with `granularity`-byte words.
+    pub fn signature(&self, granularity: usize) -> Result<String, String> {
+        if self.signature.is_none() {
+            return Err("ELF has no begin_signature/end_signature symbols".to_string());
+        }
+        let data = self.emu.read_signature()?;
+        Ok(format_signature(&data, granularity))
+    }
+}
+
+/// Format signature bytes as riscof expects: one little-endian word of
+/// `granularity` bytes per line, printed as lowercase hex, lowest address first.
+pub fn format_signature(data: &[u8], granularity: usize) -> String {
+    let granularity = granularity.max(1);
+    let mut out = String::with_capacity(data.len() / granularity * (granularity * 2 + 1));
+    for word in data.chunks(granularity) {
+        for byte in word.iter().rev() {
+            out.push_str(&format!("{:02x}", byte));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, DRAM_BASE};
+
+    #[test]
+    fn test_format_signature() {
+        let data = [0x78, 0x56, 0x34, 0x12, 0xef, 0xbe, 0xad, 0xde];
+        assert_eq!(format_signature(&data, 4), "12345678\ndeadbeef\n");
+        assert_eq!(format_signature(&data, 8), "deadbeef12345678\n");
+    }
+
+    #[test]
+    fn test_run_until_tohost() {
+        let mut emu = Emulator::new();
+        let program = [
+            0x0000_1297u32, // auipc x5, 0x1      -> tohost at DRAM_BASE + 0x1000
+            0x0010_0313,    // addi x6, x0, 1
+            0x0062_b023,    // sd x6, 0(x5)
+            0x0000_006f,    // j .
+        ];
+        for (i, insn) in program.iter().enumerate() {
+            emu.bus.write32(DRAM_BASE + 4 * i as u64, *insn).unwrap();
+        }
+        emu.bus.write32(DRAM_BASE + 0x2000, 0xcafe_f00d).unwrap();
+        emu.set_signature_region(DRAM_BASE + 0x2000, 4);
+        emu.bus
+            .enable_htif(DRAM_BASE + 0x1000, Some(DRAM_BASE + 0x1008));
+
+        let mut test = ArchTest {
+            emu,
+            signature: Some(DRAM_BASE + 0x2000..DRAM_BASE + 0x2004),
+            tracer: None,
+        };
+        let exit = test.run(1000).unwrap();
+        assert_eq!(exit, ArchTestExit::Finisher(FINISHER_PASS));
+        assert!(exit.passed());
+        assert_eq!(exit.exit_status(), 0);
+        assert_eq!(test.signature(4).unwrap(), "cafef00d\n");
+    }
+
+    #[test]
+    fn test_tohost_narrow_and_amo_stores() {
+        for store in [
+            0x0062_9023u32, // sh x6, 0(x5)
+            0x0862_b02f,    // amoswap.d x0, x6, (x5)
+        ] {
+            let mut emu = Emulator::new();
+            let program = [0x0000_1297u32, 0x0010_0313, store, 0x0000_006f];
+            for (i, insn) in program.iter().enumerate() {
+                emu.bus.write32(DRAM_BASE + 4 * i as u64, *insn).unwrap();
+            }
+            emu.bus
+                .enable_htif(DRAM_BASE + 0x1000, Some(DRAM_BASE + 0x1008));
+
+            let mut test = ArchTest {
+                emu,
+                signature: None,
+                tracer: None,
+            };
+            assert_eq!(
+                test.run(1000).unwrap(),
+                ArchTestExit::Finisher(FINISHER_PASS),
+                "store {:#010x}",
+                store
+            );
+        }
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(ArchTestExit::Finisher(FINISHER_PASS).exit_status(), 0);
+        assert_eq!(
+            ArchTestExit::Finisher((5 << 16) | FINISHER_FAIL).exit_status(),
+            5
+        );
+        assert_eq!(ArchTestExit::Finisher(0x1234).exit_status(), 1);
+        assert_eq!(ArchTestExit::Timeout.exit_status(), 2);
+    }
+}
@@ -1,14 +1,14 @@
 use crate::Trap;
 use crate::bus::{DRAM_BASE, SystemBus};
+use crate::coredump::{HartState, write_core_file};
 use crate::cpu::Cpu;
-use crate::snapshot::{
-    ClintSnapshot, CpuSnapshot, DeviceSnapshot, MemRegionSnapshot, PlicSnapshot, SNAPSHOT_VERSION,
-    Snapshot, UartSnapshot,
-};
-use sha2::{Digest, Sha256};
+use crate::semihosting::{Semihosting, SemihostingConfig};
+use crate::snapshot::{CpuSnapshot, DeviceSnapshot, MemRegionSnapshot, SNAPSHOT_VERSION, Snapshot};
+use crate::trace::{HartTracer, TraceLog};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::Arc;
 
 /// Default DRAM size used when constructing an [`Emulator`] via [`Emulator::new`].
 ///
@@ -50,6 +50,9 @@ pub struct Emulator {
     /// This provides a deterministic, buffered integration point for hosts
     /// (CLI, web UI, tests) without requiring them to poll the UART FIFO.
     uart_callback: Option<Box<dyn FnMut(u8) + 'static>>,
+
+    /// Instruction commit tracer, if enabled via [`enable_trace`].
+    tracer: Option<HartTracer>,
 }
 
 impl Emulator {
@@ -75,6 +78,7 @@ impl Emulator {
             trapped: false,
             last_trap: None,
             uart_callback: None,
+            tracer: None,
         }
     }
 
@@ -127,7 +131,11 @@ impl Emulator {
     /// On success, returns `Ok(())`. On architectural traps, this records the
     /// trap in [`last_trap`] and sets [`trapped`] before returning `Err(trap)`.
     pub fn step(&mut self) -> Result<(), Trap> {
-        match self.cpu.step(&self.bus) {
+        let result = match self.tracer.as_mut() {
+            Some(t) => t.step(&mut self.cpu, &self.bus),
+            None => self.cpu.step(&self.bus),
+        };
+        match result {
             Ok(()) => {
                 // Deliver UART bytes to host callback if registered.
                 if let Some(cb) = self.uart_callback.as_mut() {
@@ -146,6 +154,18 @@ impl Emulator {
         }
     }
 
+    /// Log every retired instruction to `log` in Spike commit format.
+    pub fn enable_trace(&mut self, log: &Arc<TraceLog>) {
+        self.tracer = log.tracer(0);
+    }
+
+    /// Service semihosting calls (see [`crate::semihosting`]) instead of
+    /// trapping on the wrapped `ebreak`.
+    pub fn enable_semihosting(&mut self, config: SemihostingConfig) {
+        self.cpu
+            .set_semihosting(Some(Arc::new(Semihosting::new(config))));
+    }
+
     /// Load an ELF image from disk into DRAM and update the CPU's PC to the
     /// ELF entry point.
     ///
@@ -155,11 +175,22 @@ impl Emulator {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
+        Ok(self.load_elf_bytes(&buffer)?)
+    }
+
+    /// Load an in-memory ELF image into DRAM and set the PC to its entry point.
+    pub fn load_elf_bytes(&mut self, buffer: &[u8]) -> Result<u64, String> {
         #[cfg(not(target_arch = "wasm32"))]
-        let entry_pc = crate::loader::load_elf_into_dram(&buffer, &self.bus)?;
+        let entry_pc = crate::loader::load_elf_into_dram(buffer, &self.bus)?;
 
         #[cfg(target_arch = "wasm32")]
-        let entry_pc = crate::loader::load_elf_wasm(&buffer, &self.bus)?;
+        let entry_pc = crate::loader::load_elf_wasm(buffer, &self.bus)?;
+
+        // Spike-style binaries talk to the host through `tohost`/`fromhost`.
+        let symbols = crate::loader::SymbolTable::from_elf(buffer)?;
+        if let Some((tohost, fromhost)) = symbols.htif() {
+            self.bus.enable_htif(tohost, fromhost);
+        }
 
         self.cpu.pc = entry_pc;
         Ok(entry_pc)
@@ -224,153 +255,38 @@ impl Emulator {
 
     /// Capture a complete, deterministic snapshot of the current emulator state.
     pub fn snapshot(&self) -> Snapshot {
-        let cpu = CpuSnapshot {
-            pc: self.cpu.pc,
-            mode: self.cpu.mode,
-            regs: self.cpu.regs,
-            csrs: self.cpu.export_csrs(),
-        };
-
-        let clint = ClintSnapshot {
-            msip: self.bus.clint.get_msip_array().to_vec(),
-            mtime: self.bus.clint.mtime(),
-            mtimecmp: self.bus.clint.get_mtimecmp_array().to_vec(),
-        };
-
-        let plic = PlicSnapshot {
-            priority: self.bus.plic.get_priority(),
-            pending: self.bus.plic.get_pending(),
-            enable: self.bus.plic.get_enable(),
-            threshold: self.bus.plic.get_threshold(),
-            active: self.bus.plic.get_active(),
-        };
-
-        let (ier, iir, fcr, lcr, mcr, lsr, msr, scr, dll, dlm) = self.bus.uart.get_registers();
-        let uart = UartSnapshot {
-            rx_fifo: self.bus.uart.get_input(),
-            tx_fifo: self.bus.uart.get_output(),
-            ier,
-            iir,
-            fcr,
-            lcr,
-            mcr,
-            lsr,
-            msr,
-            scr,
-            dll,
-            dlm,
-        };
-
-        let dram_data = self.bus.dram.get_data();
-        let mut hasher = Sha256::new();
-        hasher.update(&dram_data);
-        let hash = hex::encode(hasher.finalize());
-
-        let region = MemRegionSnapshot {
-            base: self.bus.dram.base,
-            size: self.bus.dram.size() as u64,
-            hash,
-            data: Some(dram_data),
-        };
-
         Snapshot {
             version: SNAPSHOT_VERSION.to_string(),
-            cpu,
-            devices: DeviceSnapshot { clint, plic, uart },
-            memory: vec![region],
+            cpu: CpuSnapshot::capture(&self.cpu),
+            harts: Vec::new(),
+            devices: DeviceSnapshot::capture(&self.bus),
+            memory: vec![MemRegionSnapshot::capture(&self.bus.dram)],
         }
     }
 
     /// Restore emulator state from a previously captured snapshot.
     pub fn apply_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
-        if snapshot.version != SNAPSHOT_VERSION {
+        snapshot.check_version()?;
+        if !snapshot.harts.is_empty() {
             return Err(format!(
-                "snapshot version mismatch: expected {}, found {}",
-                SNAPSHOT_VERSION, snapshot.version
+                "snapshot has {} harts; the emulator runs one",
+                snapshot.harts.len() + 1
             ));
         }
 
         // Restore CPU core.
-        self.cpu.pc = snapshot.cpu.pc;
-        self.cpu.mode = snapshot.cpu.mode;
-        self.cpu.regs = snapshot.cpu.regs;
-        self.cpu.import_csrs(&snapshot.cpu.csrs);
+        snapshot.cpu.restore(&mut self.cpu);
         self.trapped = false;
         self.last_trap = None;
 
-        // Restore CLINT.
-        self.bus.clint.set_msip_array(&snapshot.devices.clint.msip);
-        self.bus.clint.set_mtime(snapshot.devices.clint.mtime);
-        self.bus
-            .clint
-            .set_mtimecmp_array(&snapshot.devices.clint.mtimecmp);
-
-        // Restore PLIC.
-        self.bus.plic.set_priority(&snapshot.devices.plic.priority);
-        self.bus.plic.set_pending(snapshot.devices.plic.pending);
-        self.bus.plic.set_enable(&snapshot.devices.plic.enable);
-        self.bus
-            .plic
-            .set_threshold(&snapshot.devices.plic.threshold);
-        self.bus.plic.set_active(&snapshot.devices.plic.active);
-
-        // Restore UART.
-        self.bus.uart.set_input(&snapshot.devices.uart.rx_fifo);
-        self.bus.uart.set_output(&snapshot.devices.uart.tx_fifo);
-        self.bus.uart.set_registers(
-            snapshot.devices.uart.ier,
-            snapshot.devices.uart.iir,
-            snapshot.devices.uart.fcr,
-            snapshot.devices.uart.lcr,
-            snapshot.devices.uart.mcr,
-            snapshot.devices.uart.lsr,
-            snapshot.devices.uart.msr,
-            snapshot.devices.uart.scr,
-            snapshot.devices.uart.dll,
-            snapshot.devices.uart.dlm,
-        );
+        snapshot.devices.restore(&self.bus);
 
         // Restore DRAM.
-        let region = snapshot
+        snapshot
             .memory
-            .get(0)
-            .ok_or_else(|| "snapshot missing primary memory region".to_string())?;
-
-        let data = region
-            .data
-            .as_ref()
-            .ok_or_else(|| "snapshot memory region has no inline data".to_string())?;
-
-        if self.bus.dram.base != region.base {
-            return Err(format!(
-                "snapshot DRAM base mismatch: emulator=0x{:x}, snapshot=0x{:x}",
-                self.bus.dram.base, region.base
-            ));
-        }
-        if self.bus.dram.size() != data.len() {
-            return Err(format!(
-                "snapshot DRAM size mismatch: emulator={} bytes, snapshot={} bytes",
-                self.bus.dram.size(),
-                data.len()
-            ));
-        }
-
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let current_hash = hex::encode(hasher.finalize());
-        if current_hash != region.hash {
-            return Err(format!(
-                "snapshot DRAM hash mismatch for base 0x{:x}",
-                region.base
-            ));
-        }
-
-        self.bus
-            .dram
-            .set_data(data)
-            .map_err(|e| format!("failed to restore DRAM: {}", e))?;
-
-        Ok(())
+            .first()
+            .ok_or_else(|| "snapshot missing primary memory region".to_string())?
+            .restore(&self.bus.dram)
     }
 
     /// Construct a new emulator instance from a snapshot.
@@ -411,6 +327,15 @@ impl Emulator {
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         Ok(emu)
     }
+
+    /// Write an ELF core file of the current guest state (see
+    /// [`crate::coredump`]). The hart is marked as faulted if the last trap
+    /// was fatal.
+    pub fn write_core<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut hart = HartState::capture(&self.cpu);
+        hart.faulted = matches!(self.last_trap, Some(Trap::Fatal(_)));
+        write_core_file(path.as_ref(), &self.bus.dram, &[hart])
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,423 @@
+//! Expect-style scripting of a guest console for integration tests.
+//!
+//! [`Expect`] drives anything that implements [`Transport`]: an
+//! [`Emulator`] it steps itself, or a running `NativeVm` through
+//! `NativeVm::expect_console`. Timeouts count guest time (CLINT `mtime`),
+//! so a VM that is paused or starved of host CPU does not time out early.
+//!
+//! ```ignore
+//! let mut console = vm.expect_console(0)?;
+//! thread::spawn(move || vm.run());
+//! console.wait_for("login: ", Duration::from_secs(60))?;
+//! console.send_line("root");
+//! console.set_prompt(r"# $")?;
+//! console.wait_for_prompt(Duration::from_secs(10))?;
+//! assert_eq!(console.run_command_and_capture("echo hi", Duration::from_secs(5))?, "hi");
+//! ```
+
+use super::emulator::Emulator;
+use crate::devices::clint::TICKS_PER_MS;
+use regex::bytes::Regex;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// Lines of console output quoted in failure messages by default.
+const CONTEXT_LINES: usize = 20;
+
+/// Instructions an [`Emulator`] runs per poll.
+const EMULATOR_SLICE: u64 = 10_000;
+
+/// A guest console [`Expect`] can read from and type into.
+pub trait Transport {
+    /// Let the guest run for a short while and append new console output to
+    /// `out`. Fails once the guest has stopped and all output is delivered.
+    fn poll(&mut self, out: &mut Vec<u8>) -> Result<(), String>;
+
+    /// Type `data` into the console.
+    fn send(&mut self, data: &[u8]);
+
+    /// Guest time in CLINT ticks.
+    fn mtime(&self) -> u64;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn poll(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        (**self).poll(out)
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        (**self).send(data)
+    }
+
+    fn mtime(&self) -> u64 {
+        (**self).mtime()
+    }
+}
+
+/// Steps the emulator directly. Output goes to [`Expect`] only if no UART
+/// callback is registered.
+impl Transport for Emulator {
+    fn poll(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        for _ in 0..EMULATOR_SLICE {
+            if self.trapped() || self.step().is_err() {
+                break;
+            }
+        }
+        let output = self.drain_uart_output();
+        match self.last_trap() {
+            // Hand over the last output before reporting the stop.
+            Some(trap) if output.is_empty() => Err(format!("guest stopped: {:?}", trap)),
+            _ => {
+                out.extend(output);
+                Ok(())
+            }
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.push_key(byte);
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        self.bus.clint.mtime()
+    }
+}
+
+/// A successful [`Expect::wait_for`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Output between the previous match and this one.
+    pub before: String,
+    /// The text that matched.
+    pub matched: String,
+    /// Capture groups, `None` for groups that did not participate.
+    pub groups: Vec<Option<String>>,
+}
+
+/// Scripted access to a guest console.
+pub struct Expect<T> {
+    transport: T,
+    /// Output not consumed by a match yet.
+    pending: Vec<u8>,
+    /// All output and input, in order.
+    transcript: Vec<u8>,
+    log: Option<File>,
+    prompt: Option<Regex>,
+    context_lines: usize,
+}
+
+impl<T: Transport> Expect<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            pending: Vec::new(),
+            transcript: Vec::new(),
+            log: None,
+            prompt: None,
+            context_lines: CONTEXT_LINES,
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Regex matching the shell prompt, used by [`Self::run_command_and_capture`].
+    pub fn set_prompt(&mut self, pattern: &str) -> Result<(), String> {
+        self.prompt = Some(compile(pattern)?);
+        Ok(())
+    }
+
+    /// Lines of output quoted in failure messages.
+    pub fn set_context_lines(&mut self, lines: usize) {
+        self.context_lines = lines;
+    }
+
+    /// Also write the transcript to `path` as it grows.
+    pub fn log_transcript(&mut self, path: &Path) -> Result<(), String> {
+        let mut file = File::create(path)
+            .map_err(|e| format!("Failed to create transcript '{}': {}", path.display(), e))?;
+        file.write_all(&self.transcript).ok();
+        self.log = Some(file);
+        Ok(())
+    }
+
+    /// Everything the guest printed and everything sent to it so far.
+    pub fn transcript(&self) -> String {
+        String::from_utf8_lossy(&self.transcript).into_owned()
+    }
+
+    /// The last `lines` lines of the transcript.
+    pub fn tail(&self, lines: usize) -> String {
+        let text = self.transcript().replace('\r', "");
+        let all: Vec<&str> = text.lines().collect();
+        all[all.len().saturating_sub(lines)..].join("\n")
+    }
+
+    fn record(&mut self, data: &[u8]) {
+        self.transcript.extend_from_slice(data);
+        if let Some(log) = &mut self.log {
+            log.write_all(data).ok();
+        }
+    }
+
+    /// Type `text` into the console.
+    pub fn send(&mut self, text: &str) {
+        self.record(text.as_bytes());
+        self.transport.send(text.as_bytes());
+    }
+
+    /// Type `line` followed by Enter.
+    pub fn send_line(&mut self, line: &str) {
+        self.send(line);
+        self.send("\r");
+    }
+
+    /// Wait until the output matches `pattern`, for at most `timeout` of
+    /// guest time.
+    pub fn wait_for(&mut self, pattern: &str, timeout: Duration) -> Result<Match, String> {
+        let regex = compile(pattern)?;
+        self.wait_for_regex(&regex, timeout)
+    }
+
+    /// [`Self::wait_for`] with a compiled regex. Output up to the end of the
+    /// match is consumed.
+    pub fn wait_for_regex(&mut self, regex: &Regex, timeout: Duration) -> Result<Match, String> {
+        let limit = duration_to_ticks(timeout);
+        let start = self.transport.mtime();
+        loop {
+            // Search the whole buffer each time, a match may span chunks.
+            if let Some(caps) = regex.captures(&self.pending) {
+                let whole = caps.get(0).unwrap();
+                let found = Match {
+                    before: lossy(&self.pending[..whole.start()]),
+                    matched: lossy(whole.as_bytes()),
+                    groups: caps
+                        .iter()
+                        .skip(1)
+                        .map(|g| g.map(|g| lossy(g.as_bytes())))
+                        .collect(),
+                };
+                let end = whole.end();
+                self.pending.drain(..end);
+                return Ok(found);
+            }
+
+            let elapsed = self.transport.mtime().saturating_sub(start);
+            if elapsed >= limit {
+                return Err(self.failure(&format!(
+                    "timed out after {:.3}s of guest time waiting for /{}/",
+                    ticks_to_secs(elapsed),
+                    regex.as_str()
+                )));
+            }
+            let mut output = Vec::new();
+            if let Err(e) = self.transport.poll(&mut output) {
+                return Err(self.failure(&format!("{} while waiting for /{}/", e, regex.as_str())));
+            }
+            self.record(&output);
+            self.pending.extend(output);
+        }
+    }
+
+    /// Wait for the prompt set with [`Self::set_prompt`].
+    pub fn wait_for_prompt(&mut self, timeout: Duration) -> Result<Match, String> {
+        let prompt = self.prompt.clone().ok_or("no prompt set")?;
+        self.wait_for_regex(&prompt, timeout)
+    }
+
+    /// Run `command` at the prompt and return its output, without the echoed
+    /// command line, carriage returns or the trailing newline. Expects the
+    /// prompt to be showing (or about to be) when called.
+    pub fn run_command_and_capture(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<String, String> {
+        let prompt = self.prompt.clone().ok_or("no prompt set")?;
+        // Drop anything printed since the last match, e.g. the prompt the
+        // caller already waited for.
+        self.pending.clear();
+        self.send_line(command);
+        let found = self.wait_for_regex(&prompt, timeout)?;
+
+        let output = found.before.replace('\r', "");
+        let output = match output.split_once('\n') {
+            Some((echo, rest)) if echo.ends_with(command.trim()) => rest,
+            _ => output.as_str(),
+        };
+        Ok(output.strip_suffix('\n').unwrap_or(output).to_string())
+    }
+
+    fn failure(&self, reason: &str) -> String {
+        format!(
+            "{}\n--- last {} lines of console output ---\n{}\n---",
+            reason,
+            self.context_lines,
+            self.tail(self.context_lines)
+        )
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("invalid pattern /{}/: {}", pattern, e))
+}
+
+fn duration_to_ticks(timeout: Duration) -> u64 {
+    (timeout.as_micros() as u64).saturating_mul(TICKS_PER_MS / 1000)
+}
+
+fn ticks_to_secs(ticks: u64) -> f64 {
+    ticks as f64 / (TICKS_PER_MS * 1000) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Replays scripted output, one chunk per poll, answering each line sent
+    /// with the next chunk. Every poll advances guest time by 1 ms.
+    struct Script {
+        chunks: VecDeque<&'static str>,
+        sent: Vec<u8>,
+        mtime: u64,
+    }
+
+    impl Script {
+        fn new(chunks: &[&'static str]) -> Self {
+            Self {
+                chunks: chunks.iter().copied().collect(),
+                sent: Vec::new(),
+                mtime: 0,
+            }
+        }
+    }
+
+    impl Transport for Script {
+        fn poll(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+            self.mtime += TICKS_PER_MS;
+            match self.chunks.pop_front() {
+                Some(chunk) => {
+                    out.extend_from_slice(chunk.as_bytes());
+                    Ok(())
+                }
+                None if self.mtime > 1000 * TICKS_PER_MS => Err("guest stopped".to_string()),
+                None => Ok(()),
+            }
+        }
+
+        fn send(&mut self, data: &[u8]) {
+            self.sent.extend_from_slice(data);
+        }
+
+        fn mtime(&self) -> u64 {
+            self.mtime
+        }
+    }
+
+    #[test]
+    fn wait_for_spans_chunks_and_consumes() {
+        let mut console = Expect::new(Script::new(&["Welcome\r\nbuild", "root log", "in: "]));
+        let found = console
+            .wait_for(r"(\w+) login: ", Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(found.before, "Welcome\r\n");
+        assert_eq!(found.matched, "buildroot login: ");
+        assert_eq!(found.groups, [Some("buildroot".to_string())]);
+
+        console.send_line("root");
+        assert_eq!(console.transport().sent, b"root\r");
+        assert!(console.transcript().ends_with("login: root\r"));
+    }
+
+    #[test]
+    fn run_command_strips_echo_and_prompt() {
+        let mut console = Expect::new(Script::new(&["# ", "uname -r\r\n6.6.0\r\n# "]));
+        console.set_prompt(r"# $").unwrap();
+        console.wait_for_prompt(Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            console
+                .run_command_and_capture("uname -r", Duration::from_secs(1))
+                .unwrap(),
+            "6.6.0"
+        );
+        assert!(
+            console
+                .run_command_and_capture("true", Duration::from_millis(5))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn timeout_reports_guest_time_and_tail() {
+        let chunks: Vec<&'static str> = (0..30)
+            .map(|i| -> &'static str { format!("line {}\n", i).leak() })
+            .collect();
+        let mut console = Expect::new(Script::new(&chunks));
+        console.set_context_lines(3);
+        let err = console
+            .wait_for("never", Duration::from_millis(40))
+            .unwrap_err();
+        assert!(
+            err.starts_with("timed out after 0.040s of guest time waiting for /never/"),
+            "{}",
+            err
+        );
+        assert!(err.contains("last 3 lines"));
+        assert!(err.contains("line 29") && err.contains("line 27") && !err.contains("line 26"));
+
+        let err = console
+            .wait_for("never", Duration::from_secs(10))
+            .unwrap_err();
+        assert!(err.starts_with("guest stopped while waiting"), "{}", err);
+        assert!(
+            console
+                .wait_for("(", Duration::from_secs(1))
+                .unwrap_err()
+                .contains("invalid pattern")
+        );
+    }
+
+    #[test]
+    fn drives_emulator() {
+        // Print "ok\n" on the UART, then spin.
+        let program: Vec<u8> = [
+            0x100002b7u32, // lui t0, 0x10000
+            0x06f00313,    // li t1, 'o'
+            0x00628023,    // sb t1, 0(t0)
+            0x06b00313,    // li t1, 'k'
+            0x00628023,    // sb t1, 0(t0)
+            0x00a00313,    // li t1, '\n'
+            0x00628023,    // sb t1, 0(t0)
+            0x0000006f,    // j .
+        ]
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes())
+        .collect();
+        let mut emu = Emulator::with_memory(1024 * 1024);
+        emu.bus.dram.load(&program, 0).unwrap();
+
+        let mut console = Expect::new(&mut emu);
+        let found = console.wait_for(r"o(k)\n", Duration::from_secs(5)).unwrap();
+        assert_eq!(found.groups, [Some("k".to_string())]);
+        console.send("x");
+        assert_eq!(emu.bus.uart.get_input(), b"x");
+    }
+}
@@ -1,21 +1,16 @@
 //! Virtual Machine implementations.
 
+pub mod arch_test;
 pub mod emulator;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod expect;
+
 #[cfg(not(target_arch = "wasm32"))]
 pub mod native;
 
+#[cfg(target_os = "linux")]
+pub mod user;
+
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
-
-
-
-
-
-
-
-
-
-
-
-
@@ -1,15 +1,37 @@
-use crate::Trap;
-use crate::bus::{DRAM_BASE, SystemBus};
+use crate::{Mode, Trap};
+use crate::blockdev::{BlockBackend, MemoryBackend};
+use crate::bus::{BOOT_ROM_BASE, Bus, DRAM_BASE, FINISHER_FAIL, SystemBus};
+use crate::chardev::{Chardev, Stdio};
 use crate::console::Console;
+use crate::coredump::{CoreDumper, HartState, write_core_file};
 use crate::cpu::Cpu;
+use crate::csr::{CSR_MENVCFG, CSR_MIE, CSR_MIP, CSR_SATP, CSR_STIMECMP};
 use crate::devices::clint::TICKS_PER_MS;
-use crate::loader::load_elf_into_dram;
-use std::io::{self, Write};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use crate::devices::uart::Uart;
+use crate::engine::disasm;
+use crate::hart::{self, HartConfig, HartContext};
+use crate::loader::{SymbolTable, load_elf_into_dram};
+use crate::profiler::{Profiler, ProfilerConfig};
+use crate::trace::{HartTracer, TraceLog};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
+#[cfg(unix)]
+mod control;
+mod expect;
+mod limits;
+mod monitor;
+
+#[cfg(unix)]
+pub use control::ControlClient;
+pub use expect::NativeConsole;
+pub use limits::{EXIT_CONSOLE, EXIT_FATAL, EXIT_TIMEOUT, Limit, RunLimits, RunOutcome};
+use limits::LimitCheck;
+use monitor::Monitor;
+
 /// Shared state between main thread and worker threads.
 ///
 /// 
@@ -33,6 +55,7 @@ impl SharedState {
     const HALT_REQUESTED: u8 = 0x01;
     const HALTED: u8 = 0x02;
     const WORKERS_CAN_START: u8 = 0x04;
+    const PAUSED: u8 = 0x08;
 
     pub fn new() -> Self {
         Self {
@@ -80,6 +103,20 @@ impl SharedState {
     pub fn can_workers_start(&self) -> bool {
         (self.flags.load(Ordering::Acquire) & Self::WORKERS_CAN_START) != 0
     }
+
+    /// Ask every hart to stop executing guest code after its current batch.
+    pub fn pause(&self) {
+        self.flags.fetch_or(Self::PAUSED, Ordering::Release);
+    }
+
+    pub fn resume(&self) {
+        self.flags.fetch_and(!Self::PAUSED, Ordering::Release);
+    }
+
+    #[inline(always)]
+    pub fn is_paused(&self) -> bool {
+        (self.flags.load(Ordering::Acquire) & Self::PAUSED) != 0
+    }
 }
 
 impl Default for SharedState {
@@ -93,6 +130,161 @@ enum HaltReason {
     Fatal(String, u64),
 }
 
+/// The first fatal trap (or failure exit) of any hart.
+#[derive(Debug, Clone)]
+struct Fault {
+    hart: usize,
+    pc: u64,
+    message: String,
+}
+
+type FaultRecord = Arc<Mutex<Option<Fault>>>;
+
+fn record_fault(record: &FaultRecord, hart: usize, pc: u64, message: &str) {
+    record.lock().unwrap().get_or_insert_with(|| Fault {
+        hart,
+        pc,
+        message: message.to_string(),
+    });
+}
+
+/// Record a guest exit through the test finisher with a failure code.
+fn record_failure_exit(record: &FaultRecord, hart: usize, pc: u64, code: u64) {
+    if code & 0xffff == FINISHER_FAIL {
+        record_fault(record, hart, pc, &format!("exit code {}", code >> 16));
+    }
+}
+
+/// Work queued for a hart thread; it runs against the hart's `Cpu`
+/// between batches.
+type HartRequest = Box<dyn FnOnce(&mut Cpu) + Send>;
+
+/// How long to wait for a hart thread to pick up a mailbox request.
+const HART_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Core file written when no `--core-file` was given.
+const DEFAULT_CORE_FILE: &str = "riscv-vm.core";
+
+/// Per-hart queue used to reach CPUs owned by other threads.
+#[derive(Default)]
+struct HartMailbox {
+    pending: AtomicBool,
+    requests: Mutex<Vec<HartRequest>>,
+}
+
+impl HartMailbox {
+    fn post(&self, request: HartRequest) {
+        self.requests.lock().unwrap().push(request);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// Run the queued requests, if any. Cheap when the mailbox is empty.
+    #[inline]
+    fn service(&self, cpu: &mut Cpu) {
+        if self.pending.load(Ordering::Acquire) {
+            self.pending.store(false, Ordering::Relaxed);
+            let requests = std::mem::take(&mut *self.requests.lock().unwrap());
+            for request in requests {
+                request(cpu);
+            }
+        }
+    }
+}
+
+/// Run `count` instructions on `cpu`, one block per instruction.
+fn step_cpu(cpu: &mut Cpu, bus: &SystemBus, hart_id: usize, count: u64) -> (u64, Option<HaltReason>) {
+    let was_single = cpu.single_insn_blocks;
+    cpu.set_single_insn_blocks(true);
+    let result = execute_batch_worker(cpu, bus, &mut None, hart_id, count);
+    cpu.set_single_insn_blocks(was_single);
+    result
+}
+
+/// Pause, resume and single-step a [`NativeVm`] from any thread, including
+/// while [`NativeVm::run`] is executing.
+#[derive(Clone)]
+pub struct VmHandle {
+    bus: Arc<SystemBus>,
+    shared: Arc<SharedState>,
+    harts: Arc<Vec<HartContext>>,
+    mailboxes: Arc<Vec<HartMailbox>>,
+}
+
+impl VmHandle {
+    /// Stop every hart between blocks and freeze the CLINT's mtime.
+    /// Returns once all running harts have parked.
+    pub fn pause(&self) -> Result<(), String> {
+        self.park(0)
+    }
+
+    /// Continue all harts after [`Self::pause`].
+    pub fn resume(&self) {
+        self.bus.clint.thaw();
+        self.shared.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shared.is_paused()
+    }
+
+    /// Execute `count` instructions on `hart` while the others stay frozen.
+    /// Returns the number retired, which is smaller if the guest halted.
+    pub fn step_hart(&self, hart: usize, count: u64) -> Result<u64, String> {
+        let bus = Arc::clone(&self.bus);
+        let (steps, halt) = self.call(hart, move |cpu| step_cpu(cpu, &bus, hart, count))?;
+        self.finish_step(hart, steps, halt);
+        Ok(steps)
+    }
+
+    /// Set the pause flag, then wait for harts `first..` to acknowledge it.
+    /// Harts that have not started or have exited are already quiescent.
+    fn park(&self, first: usize) -> Result<(), String> {
+        self.shared.pause();
+        self.bus.clint.freeze();
+        for (hart, context) in self.harts.iter().enumerate().skip(first) {
+            if matches!(context.state(), hart::HartState::Running | hart::HartState::Parked) {
+                self.call(hart, |_| ())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` against `hart`'s CPU on the thread that owns it. Only for
+    /// callers that are not that thread.
+    fn call<R, F>(&self, hart: usize, f: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Cpu) -> R + Send + 'static,
+    {
+        let context = self
+            .harts
+            .get(hart)
+            .ok_or_else(|| format!("No hart {} (have {})", hart, self.harts.len()))?;
+        match context.state() {
+            hart::HartState::Uninitialized => return Err(format!("Hart {} has not started", hart)),
+            hart::HartState::Stopped => return Err(format!("Hart {} has stopped", hart)),
+            _ => {}
+        }
+        let (tx, rx) = mpsc::channel();
+        self.mailboxes[hart].post(Box::new(move |cpu| {
+            tx.send(f(cpu)).ok();
+        }));
+        // Cut a WFI sleep short so the request is picked up promptly.
+        self.bus.clint.wake_hart(hart);
+        rx.recv_timeout(HART_TIMEOUT)
+            .map_err(|_| format!("Hart {} did not respond", hart))
+    }
+
+    fn finish_step(&self, hart: usize, steps: u64, halt: Option<HaltReason>) {
+        self.harts[hart].add_instructions(steps);
+        match halt {
+            Some(HaltReason::Shutdown(code)) => self.shared.signal_halted(code),
+            Some(HaltReason::Fatal(..)) => self.shared.signal_halted(0xDEAD),
+            None => {}
+        }
+    }
+}
+
 /// Native multi-threaded VM.
 ///
 /// Manages one thread per hart, with hart 0 running on the main thread
@@ -106,6 +298,40 @@ pub struct NativeVm {
     entry_pc: u64,
     /// WebTransport network backend (if connected)
     wt_backend: Option<crate::net::webtransport::WebTransportBackend>,
+    /// Guest PC sampling profiler (if enabled)
+    profiler: Option<Arc<Profiler>>,
+    /// Instruction commit trace (if enabled)
+    trace: Option<Arc<TraceLog>>,
+    /// Hart register snapshots and queued core dumps
+    core: Arc<CoreDumper>,
+    /// Where to write a core file when a hart hits a fatal trap
+    core_file: Option<PathBuf>,
+    /// One mailbox per hart, serviced between batches
+    mailboxes: Arc<Vec<HartMailbox>>,
+    /// Monitor console state (Ctrl-A c)
+    monitor: Monitor,
+    /// Host side of each UART, in UART order
+    serial: Vec<Box<dyn Chardev>>,
+    /// Per-hart run state and retired instruction counts
+    harts: Arc<Vec<HartContext>>,
+    /// First fatal trap, reported to control clients as `guest-panic`
+    fault: FaultRecord,
+    /// Time, instruction and console limits on the run
+    limits: RunLimits,
+    /// How a limit ended the run, shared with the console watch
+    verdict: Arc<Mutex<limits::Verdict>>,
+    /// JSON control socket (if enabled)
+    #[cfg(unix)]
+    control: Option<control::Control>,
+    /// Command line, initrd, DTB and overlays for the DTB
+    dtb_options: crate::dtb::DtbOptions,
+    /// Where the DTB lives: `DTB_ADDRESS`, or above a kernel reaching past it
+    dtb_address: u64,
+    /// Start and end of the memory the kernel occupies
+    kernel_start: u64,
+    kernel_end: u64,
+    /// Harts reset into M-mode firmware (see [`Self::load_firmware`])
+    firmware: bool,
 }
 
 impl NativeVm {
@@ -115,36 +341,47 @@ impl NativeVm {
     /// * `kernel` - Kernel binary (ELF or raw)
     /// * `num_harts` - Number of harts (CPUs) to create
     pub fn new(kernel: &[u8], num_harts: usize) -> Result<Self, String> {
+        Self::new_at(kernel, num_harts, crate::loader::raw_kernel_load_address(kernel))
+    }
+
+    /// Like [`Self::new`], loading a raw kernel at `load_addr` (ELF kernels
+    /// go where their program headers say). [`Self::new`] uses
+    /// [`raw_kernel_load_address`].
+    ///
+    /// [`raw_kernel_load_address`]: crate::loader::raw_kernel_load_address
+    pub fn new_at(kernel: &[u8], num_harts: usize, load_addr: u64) -> Result<Self, String> {
         const DRAM_SIZE: usize = 512 * 1024 * 1024;
-        let bus = SystemBus::new(DRAM_BASE, DRAM_SIZE);
+        let mut bus = SystemBus::new(DRAM_BASE, DRAM_SIZE);
 
         bus.set_num_harts(num_harts);
 
         let entry_pc = if kernel.starts_with(b"\x7FELF") {
+            if let Some((tohost, fromhost)) = SymbolTable::from_elf(kernel)?.htif() {
+                bus.enable_htif(tohost, fromhost);
+            }
             load_elf_into_dram(kernel, &bus)?
         } else {
             bus.dram
-                .load(kernel, 0)
+                .load(kernel, load_addr.wrapping_sub(DRAM_BASE))
                 .map_err(|e| format!("Failed to load kernel: {:?}", e))?;
-            DRAM_BASE
+            load_addr
         };
 
-        // Generate and write DTB to DRAM for OpenSBI compliance
-        // D1 EMAC is always enabled for kernel probing
-        let d1_config = crate::dtb::D1DeviceConfig {
-            has_display: false, // Will be updated via enable_gpu()
-            has_mmc: false,     // Will be updated via load_disk()
-            has_emac: true,     // Always enabled for kernel probing
-            has_touch: true,    // Touch input always enabled
-            has_audio: false,   // Will be updated via enable_audio()
+        // Generate and write DTB to DRAM for OpenSBI compliance, moving it
+        // above a kernel whose image (bss included) would cover it
+        let (kernel_start, kernel_end) = crate::loader::kernel_extent(kernel, load_addr)?;
+        let dtb_address = if kernel_start < crate::dtb::DTB_ADDRESS + crate::dtb::DTB_MAX_SIZE as u64
+            && kernel_end > crate::dtb::DTB_ADDRESS
+        {
+            kernel_end.next_multiple_of(0x1000)
+        } else {
+            crate::dtb::DTB_ADDRESS
         };
-        let dtb = crate::dtb::generate_dtb(num_harts, DRAM_SIZE as u64, &d1_config);
-        let dtb_address = crate::dtb::write_dtb_to_dram(&bus.dram, &dtb);
-        
-        println!(
-            "[VM] Generated DTB ({} bytes) at 0x{:x}",
-            dtb.len(), dtb_address
-        );
+        if dtb_address + crate::dtb::DTB_MAX_SIZE as u64 > DRAM_BASE + DRAM_SIZE as u64 {
+            return Err(format!("Kernel ends at 0x{:x}, leaving no room for the DTB", kernel_end));
+        }
+        let dtb_options = crate::dtb::DtbOptions::default();
+        write_dtb(&bus, num_harts, &dtb_options, dtb_address)?;
 
         // Always initialize D1 EMAC so kernel can probe it (regardless of network connection)
         {
@@ -158,7 +395,7 @@ impl NativeVm {
         let mut primary_cpu = Cpu::new(entry_pc, 0);
         primary_cpu.setup_smode_boot_with_dtb(dtb_address); // Enable S-mode operation with DTB
 
-        println!(
+        log::info!(
             "[VM] Created with {} harts, entry=0x{:x}, dtb=0x{:x}",
             num_harts, entry_pc, dtb_address
         );
@@ -171,6 +408,28 @@ impl NativeVm {
             num_harts,
             entry_pc,
             wt_backend: None,
+            profiler: None,
+            trace: None,
+            core: Arc::new(CoreDumper::new()),
+            core_file: None,
+            mailboxes: Arc::new((0..num_harts).map(|_| HartMailbox::default()).collect()),
+            monitor: Monitor::default(),
+            serial: vec![Box::new(Stdio)],
+            harts: Arc::new(
+                (0..num_harts)
+                    .map(|id| HartContext::new(id, HartConfig::multi(num_harts).role_for_hart(id)))
+                    .collect(),
+            ),
+            fault: FaultRecord::default(),
+            limits: RunLimits::default(),
+            verdict: Arc::default(),
+            #[cfg(unix)]
+            control: None,
+            dtb_options,
+            dtb_address,
+            kernel_start,
+            kernel_end,
+            firmware: false,
         })
     }
 
@@ -186,12 +445,20 @@ impl NativeVm {
 
     /// Load a disk image and attach as D1 MMC device.
     pub fn load_disk(&mut self, disk: Vec<u8>) {
+        self.attach_disk(Arc::new(MemoryBackend::new(disk)));
+    }
+
+    /// Attach `disk` as the D1 MMC device, e.g. a [`FileBackend`] so guest
+    /// writes go straight to the image.
+    ///
+    /// [`FileBackend`]: crate::blockdev::FileBackend
+    pub fn attach_disk(&mut self, disk: Arc<dyn BlockBackend>) {
         use crate::devices::d1_mmc::D1MmcEmulated;
 
         if let Some(bus) = Arc::get_mut(&mut self.bus) {
-            let mmc = D1MmcEmulated::new(disk);
+            log::info!("[VM] D1 MMC loaded with disk image ({})", disk.describe());
+            let mmc = D1MmcEmulated::with_backend(disk);
             *bus.d1_mmc.write().unwrap() = Some(mmc);
-            println!("[VM] D1 MMC loaded with disk image");
         } else {
             eprintln!("[VM] Cannot load disk: workers already running");
         }
@@ -214,8 +481,8 @@ impl NativeVm {
             // Create EMAC with the same MAC address as the backend
             let emac = D1EmacEmulated::with_mac(mac);
             *bus.d1_emac.write().unwrap() = Some(emac);
-            println!("[VM] D1 EMAC enabled for network: {}", url);
-            println!("[VM] D1 EMAC MAC: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            log::info!("[VM] D1 EMAC enabled for network: {}", url);
+            log::info!("[VM] D1 EMAC MAC: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
                 mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
         } else {
             eprintln!("[VM] Cannot configure network: workers already running");
@@ -244,8 +511,8 @@ impl NativeVm {
             *bus.d1_display.write().unwrap() = Some(display);
             *bus.d1_touch.write().unwrap() = Some(touch);
             
-            println!("[VM] D1 Display enabled (1024x768)");
-            println!("[VM] D1 Touch enabled");
+            log::info!("[VM] D1 Display enabled (1024x768)");
+            log::info!("[VM] D1 Touch enabled");
         } else {
             eprintln!("[VM] Cannot enable display: workers already running");
         }
@@ -260,7 +527,7 @@ impl NativeVm {
         if let Some(bus) = Arc::get_mut(&mut self.bus) {
             let vinput = VirtioInput::new();
             bus.virtio_devices.push(Box::new(vinput));
-            println!("[VM] VirtIO Input device enabled");
+            log::info!("[VM] VirtIO Input device enabled");
         } else {
             eprintln!("[VM] Cannot enable input: workers already running");
         }
@@ -274,6 +541,9 @@ impl NativeVm {
     /// * `host_path` - Path to the host directory to share
     /// * `mount_tag` - Mount tag for guest identification (default: "hostfs")
     ///
+    /// An empty `host_path` adds the device with no directory attached;
+    /// one can be attached later through the control socket (`attach-9p`).
+    ///
     /// Must be called before `run()` / `start_workers()`.
     pub fn enable_9p(&mut self, host_path: &str, mount_tag: Option<&str>) {
         use crate::devices::virtio::VirtioP9;
@@ -282,18 +552,284 @@ impl NativeVm {
             let tag = mount_tag.unwrap_or("hostfs");
             let p9dev = VirtioP9::new(host_path, tag);
             bus.virtio_devices.push(Box::new(p9dev));
-            println!("[VM] VirtIO 9P device enabled: {} -> {}", host_path, tag);
+            log::info!("[VM] VirtIO 9P device enabled: {} -> {}", host_path, tag);
         } else {
             eprintln!("[VM] Cannot enable 9P: workers already running");
         }
     }
 
+    /// Attach `disk` as a virtio-blk drive in the next free VirtIO slot,
+    /// reporting `serial` to the guest if given. Read-only backends appear
+    /// read-only. Returns the slot, which the guest sees at
+    /// `VIRTIO_BASE + slot * VIRTIO_STRIDE` with PLIC source `1 + slot`.
+    ///
+    /// Must be called before `run()` / `start_workers()`.
+    pub fn add_drive(
+        &mut self,
+        disk: Arc<dyn BlockBackend>,
+        serial: Option<&str>,
+    ) -> Result<usize, String> {
+        use crate::bus::VIRTIO_SLOTS;
+        use crate::devices::virtio::VirtioBlock;
+
+        let Some(bus) = Arc::get_mut(&mut self.bus) else {
+            return Err("Cannot add a drive: workers already running".to_string());
+        };
+        let slot = bus.virtio_devices.len();
+        if slot >= VIRTIO_SLOTS {
+            return Err(format!(
+                "Cannot add drive {}: all {} VirtIO slots are in use",
+                disk.describe(),
+                VIRTIO_SLOTS
+            ));
+        }
+        let mut blk = VirtioBlock::with_backend(disk);
+        if let Some(serial) = serial {
+            blk = blk.with_serial(serial);
+        }
+        // Disk I/O runs off the hart threads; wake them when it is done.
+        let waker = bus.clint.waker();
+        bus.virtio_devices.push(Box::new(blk.with_io_thread(move || waker.wake_all())));
+        Ok(slot)
+    }
+
+    /// Enable the guest PC sampling profiler.
+    ///
+    /// Samples are resolved against `symbols` and written to the configured
+    /// output file when the VM shuts down.
+    ///
+    /// Must be called before `run()` / `start_workers()`.
+    pub fn enable_profiler(&mut self, config: ProfilerConfig, symbols: SymbolTable) {
+        if !self.handles.is_empty() {
+            eprintln!("[VM] Cannot enable profiler: workers already running");
+            return;
+        }
+        self.profiler = Some(Profiler::start(config, symbols));
+    }
+
+    /// Enable the instruction commit trace.
+    ///
+    /// Must be called before `run()` / `start_workers()`.
+    pub fn enable_trace(&mut self, log: Arc<TraceLog>) {
+        if !self.handles.is_empty() {
+            eprintln!("[VM] Cannot enable trace: workers already running");
+            return;
+        }
+        self.trace = Some(log);
+    }
+
+    /// Connect the console UART to `serial` instead of the terminal. Ctrl-A
+    /// escapes and the monitor stay on the terminal either way.
+    pub fn set_serial(&mut self, serial: Box<dyn Chardev>) {
+        self.serial[0] = serial;
+    }
+
+    /// Add a UART backed by `serial` and describe it in the DTB. Returns
+    /// the UART's index, which is also its `ttyS` number in the guest.
+    pub fn add_serial(&mut self, serial: Box<dyn Chardev>) -> Result<usize, String> {
+        let Some(bus) = Arc::get_mut(&mut self.bus) else {
+            return Err("Cannot add a UART: workers already running".to_string());
+        };
+        let index = bus.add_uart()?;
+        write_dtb(bus, self.num_harts, &self.dtb_options, self.dtb_address)?;
+        self.serial.push(serial);
+        Ok(index)
+    }
+
+    /// Set the kernel command line (`/chosen/bootargs`).
+    pub fn set_bootargs(&mut self, bootargs: &str) -> Result<(), String> {
+        self.update_dtb(|options| options.chosen.bootargs = Some(bootargs.to_string()))
+    }
+
+    /// Load `initrd` at the top of DRAM and point `/chosen` at it.
+    pub fn load_initrd(&mut self, initrd: &[u8]) -> Result<(), String> {
+        let dram_end = DRAM_BASE + self.bus.dram_size() as u64;
+        let start = dram_end.saturating_sub(initrd.len() as u64) & !0xfff;
+        let used = self.kernel_end.max(self.dtb_address + crate::dtb::DTB_MAX_SIZE as u64);
+        if start < used {
+            return Err(format!("Initrd of {} bytes does not fit in DRAM", initrd.len()));
+        }
+        if !self.handles.is_empty() {
+            return Err("Cannot load an initrd: workers already running".to_string());
+        }
+        self.bus
+            .dram
+            .load(initrd, start - DRAM_BASE)
+            .map_err(|e| format!("Failed to load initrd: {:?}", e))?;
+        let end = start + initrd.len() as u64;
+        self.update_dtb(|options| options.chosen.initrd = Some((start, end)))?;
+        log::info!("[VM] Initrd ({} bytes) at 0x{:x}", initrd.len(), start);
+        Ok(())
+    }
+
+    /// Boot through M-mode `firmware` (OpenSBI `fw_dynamic` or `fw_jump`)
+    /// instead of the built-in SBI. An ELF goes where its program headers
+    /// say, a raw binary at the start of DRAM; it must not overlap the
+    /// kernel or DTB. All harts start at the reset vector, which passes
+    /// the firmware the DTB and a `fw_dynamic_info` naming the kernel.
+    pub fn load_firmware(&mut self, firmware: &[u8]) -> Result<(), String> {
+        let Some(bus) = Arc::get_mut(&mut self.bus) else {
+            return Err("Cannot load firmware: workers already running".to_string());
+        };
+        let (start, end) = crate::loader::kernel_extent(firmware, DRAM_BASE)?;
+        let overlaps = |from: u64, to: u64| start < to && from < end;
+        if overlaps(self.kernel_start, self.kernel_end) {
+            return Err(format!(
+                "Firmware at 0x{:x}-0x{:x} overlaps the kernel at 0x{:x}-0x{:x}",
+                start, end, self.kernel_start, self.kernel_end
+            ));
+        }
+        if overlaps(self.dtb_address, self.dtb_address + crate::dtb::DTB_MAX_SIZE as u64) {
+            return Err(format!("Firmware at 0x{:x}-0x{:x} overlaps the DTB at 0x{:x}", start, end, self.dtb_address));
+        }
+
+        let entry = if firmware.starts_with(b"\x7FELF") {
+            load_elf_into_dram(firmware, bus)?
+        } else {
+            bus.dram
+                .load(firmware, 0)
+                .map_err(|e| format!("Failed to load firmware: {:?}", e))?;
+            DRAM_BASE
+        };
+        bus.set_boot_rom(crate::firmware::reset_vector(entry, self.dtb_address, self.entry_pc))?;
+        self.primary_cpu = Some(firmware_cpu(0));
+        self.firmware = true;
+        log::info!(
+            "[VM] Firmware ({} bytes) at 0x{:x}-0x{:x}, entry=0x{:x}",
+            firmware.len(),
+            start,
+            end,
+            entry
+        );
+        Ok(())
+    }
+
+    /// Describe the machine to the kernel with `dtb` instead of the
+    /// generated tree; its `/chosen` still gets the command line and initrd.
+    pub fn set_dtb(&mut self, dtb: Vec<u8>) -> Result<(), String> {
+        self.update_dtb(|options| options.base = Some(dtb))
+    }
+
+    /// Apply a DTB overlay on top of the tree.
+    pub fn add_dtb_overlay(&mut self, overlay: Vec<u8>) -> Result<(), String> {
+        self.update_dtb(|options| options.overlays.push(overlay))
+    }
+
+    /// Change the DTB options and rewrite the DTB, keeping the old options
+    /// if the new ones do not make a valid tree.
+    fn update_dtb(&mut self, change: impl FnOnce(&mut crate::dtb::DtbOptions)) -> Result<(), String> {
+        let Some(bus) = Arc::get_mut(&mut self.bus) else {
+            return Err("Cannot change the DTB: workers already running".to_string());
+        };
+        let mut options = self.dtb_options.clone();
+        change(&mut options);
+        write_dtb(bus, self.num_harts, &options, self.dtb_address)?;
+        self.dtb_options = options;
+        Ok(())
+    }
+
+    /// Write ELF core files (fatal traps, `Ctrl-A d`, `dump`) to `path`
+    /// instead of `riscv-vm.core`.
+    pub fn set_core_file(&mut self, path: impl Into<PathBuf>) {
+        self.core_file = Some(path.into());
+    }
+
+    /// Where core files go: the configured path or `riscv-vm.core`.
+    fn core_path(&self) -> PathBuf {
+        self.core_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CORE_FILE))
+    }
+
+    /// Queue a core dump of the running guest; hart 0 writes it to `path`
+    /// at its next console poll. Outside `run()`, use [`Self::dump_core`].
+    pub fn request_core_dump(&self, path: impl Into<PathBuf>) {
+        self.core.request(path);
+    }
+
+    /// Write a core file of the stopped VM (before `run()` or after it
+    /// returns) to `path`.
+    pub fn dump_core(&self, path: &Path) -> Result<(), String> {
+        let harts = match &self.primary_cpu {
+            Some(cpu) => vec![HartState::capture(cpu)],
+            None => self.core.states(),
+        };
+        write_core_file(path, &self.bus.dram, &harts)
+    }
+
+    /// Disassemble `count` instructions starting at virtual address `vaddr`
+    /// in `hart`'s address space.
+    ///
+    /// Translation uses the hart's last known `satp` and privilege mode;
+    /// harts that have not run yet are read physically. Each line looks like
+    /// `0x0000000080000000: 02a00293  li t0,42`.
+    pub fn disassemble(&self, hart: usize, vaddr: u64, count: usize) -> Result<Vec<String>, String> {
+        if hart >= self.num_harts {
+            return Err(format!("No hart {} (have {})", hart, self.num_harts));
+        }
+        let (mode, satp) = match &self.primary_cpu {
+            Some(cpu) if hart == 0 => (cpu.mode, cpu.csrs[CSR_SATP as usize]),
+            _ => self
+                .core
+                .states()
+                .into_iter()
+                .find(|s| s.hart_id == hart)
+                .map(|s| (s.mode, s.csr(CSR_SATP).unwrap_or(0)))
+                .unwrap_or((Mode::Machine, 0)),
+        };
+
+        Ok(disassembly(&*self.bus, mode, satp, vaddr, count))
+    }
 
     /// Get the number of harts.
     pub fn num_harts(&self) -> usize {
         self.num_harts
     }
 
+    /// Handle for pausing and stepping this VM from other threads.
+    pub fn handle(&self) -> VmHandle {
+        VmHandle {
+            bus: Arc::clone(&self.bus),
+            shared: Arc::clone(&self.shared),
+            harts: Arc::clone(&self.harts),
+            mailboxes: Arc::clone(&self.mailboxes),
+        }
+    }
+
+    /// Stop every hart between blocks and freeze mtime; returns once all
+    /// running harts have parked.
+    ///
+    /// Hart 0 runs [`Self::run`], so this is for use before or after it and
+    /// from monitor/control commands; other threads use [`Self::handle`].
+    pub fn pause(&self) -> Result<(), String> {
+        self.harts[0].set_state(hart::HartState::Parked);
+        self.handle().park(1).inspect_err(|_| self.resume())
+    }
+
+    /// Continue all harts after [`Self::pause`].
+    pub fn resume(&self) {
+        self.handle().resume();
+    }
+
+    /// Execute `count` instructions on `hart` while the others stay paused.
+    /// Returns the number retired.
+    ///
+    /// Outside `run()` only hart 0 exists; while the VM runs, use
+    /// [`VmHandle::step_hart`].
+    pub fn step_hart(&mut self, hart: usize, count: u64) -> Result<u64, String> {
+        let Some(cpu) = self.primary_cpu.as_mut().filter(|_| hart == 0) else {
+            return self.handle().step_hart(hart, count);
+        };
+        let (steps, halt) = step_cpu(cpu, &self.bus, 0, count);
+        self.handle().finish_step(0, steps, halt);
+        Ok(steps)
+    }
+
+    /// Run state and retired instruction count of each hart.
+    pub fn harts(&self) -> &[HartContext] {
+        &self.harts
+    }
+
     /// Get the kernel entry point.
     pub fn entry_pc(&self) -> u64 {
         self.entry_pc
@@ -420,6 +956,20 @@ impl NativeVm {
         false
     }
 
+    /// Send a key press or release (Linux `KEY_*` code) to the keyboard
+    /// attached to the touchscreen controller.
+    ///
+    /// Returns true if the event was sent successfully.
+    pub fn send_key_event(&self, code: u16, pressed: bool) -> bool {
+        if let Ok(mut touch) = self.bus.d1_touch.write()
+            && let Some(dev) = touch.as_mut()
+        {
+            dev.push_key(code, pressed);
+            return true;
+        }
+        false
+    }
+
     /// Start worker threads for secondary harts.
     /// Workers will spin-wait until allow_workers_to_start() is called.
     pub fn start_workers(&mut self) {
@@ -427,16 +977,25 @@ impl NativeVm {
             let bus = Arc::clone(&self.bus);
             let shared = Arc::clone(&self.shared);
             let entry_pc = self.entry_pc;
+            let firmware = self.firmware;
+            let profiler = self.profiler.clone();
+            let tracer = self.trace.as_ref().and_then(|log| log.tracer(hart_id));
+            let core = Arc::clone(&self.core);
+            let mailboxes = Arc::clone(&self.mailboxes);
+            let harts = Arc::clone(&self.harts);
+            let fault = Arc::clone(&self.fault);
 
             let handle = thread::Builder::new()
                 .name(format!("hart-{}", hart_id))
                 .spawn(move || {
-                    hart_thread(hart_id, entry_pc, bus, shared);
+                    hart_thread(
+                        hart_id, entry_pc, firmware, bus, shared, profiler, tracer, core, mailboxes, harts, fault,
+                    );
                 })
                 .expect("Failed to spawn hart thread");
 
             self.handles.push(handle);
-            println!("[VM] Started thread for hart {}", hart_id);
+            log::info!("[VM] Started thread for hart {}", hart_id);
         }
     }
 
@@ -497,13 +1056,21 @@ impl NativeVm {
         let start_time = Instant::now();
 
         let console = Console::new();
+        let mut serial = std::mem::take(&mut self.serial);
+        self.watch_console(&mut serial);
+        let limit_check = LimitCheck::new(&self.limits, self.bus.clint.mtime());
         let mut escaped = false;
+        let mut monitor = std::mem::take(&mut self.monitor);
+        let mut profile_epoch: u64 = 0;
+        let mut tracer = self.trace.as_ref().and_then(|log| log.tracer(0));
+        let mut core_epoch: u64 = 0;
+        let mut faulted = false;
 
         let mut last_report_time = Instant::now();
         let mut last_report_steps: u64 = 0;
         let report_interval = Duration::from_secs(5);
 
-        println!("[VM] Running hart 0 on main thread...");
+        log::info!("[VM] Running hart 0 on main thread...");
 
         const BATCH_SIZE: u64 = 256;
         const VIRTIO_POLL_INTERVAL: u64 = 4096;
@@ -514,8 +1081,24 @@ impl NativeVm {
                 break;
             }
 
-            let (batch_steps, halt_reason) = self.execute_batch(&mut cpu, BATCH_SIZE);
+            self.mailboxes[0].service(&mut cpu);
+            if self.shared.is_paused() {
+                self.harts[0].set_state(hart::HartState::Parked);
+                self.poll_console(&mut cpu, &console, &mut serial, &mut escaped, &mut monitor);
+                self.poll_control(&mut cpu);
+                thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            self.harts[0].set_state(hart::HartState::Running);
+
+            if let Some(profiler) = &self.profiler {
+                profiler.maybe_sample(&mut profile_epoch, &cpu, &*self.bus);
+            }
+            self.core.maybe_publish(&mut core_epoch, &cpu);
+
+            let (batch_steps, halt_reason) = self.execute_batch(&mut cpu, &mut tracer, BATCH_SIZE);
             step_count += batch_steps;
+            self.harts[0].add_instructions(batch_steps);
 
             // After initial boot steps, signal workers to start
             // OpenSBI takes ~50k+ steps before jumping to kernel, so we wait 100k
@@ -527,25 +1110,37 @@ impl NativeVm {
             if let Some(reason) = halt_reason {
                 match reason {
                     HaltReason::Shutdown(code) => {
-                        println!("[VM] Shutdown requested (code: {:#x})", code);
+                        log::info!("[VM] Shutdown requested (code: {:#x})", code);
+                        record_failure_exit(&self.fault, 0, cpu.pc, code);
                         self.shared.signal_halted(code);
                         break;
                     }
                     HaltReason::Fatal(msg, pc) => {
-                        eprintln!("[VM] Fatal error: {} at PC=0x{:x}", msg, pc);
+                        let insn = disasm::disassemble_at(&cpu, &*self.bus, pc)
+                            .unwrap_or_else(|| "unmapped".to_string());
+                        eprintln!("[VM] Fatal error: {} at PC=0x{:x} ({})", msg, pc, insn);
+                        record_fault(&self.fault, 0, pc, &msg);
                         self.shared.signal_halted(0xDEAD);
+                        faulted = true;
                         break;
                     }
                 }
             }
 
+            if let Some(check) = &limit_check
+                && self.check_limits(check)
+            {
+                break;
+            }
+
             if step_count % VIRTIO_POLL_INTERVAL == 0 {
                 self.bus.poll_virtio();
                 self.poll_network();
             }
 
             if step_count % CONSOLE_POLL_INTERVAL == 0 {
-                self.pump_console(&console, &mut escaped);
+                self.poll_console(&mut cpu, &console, &mut serial, &mut escaped, &mut monitor);
+                self.poll_control(&mut cpu);
 
                 if log::log_enabled!(log::Level::Debug) {
                     let now = Instant::now();
@@ -564,7 +1159,27 @@ impl NativeVm {
             }
         }
 
+        self.monitor = monitor;
+        // Deliver what the guest wrote after the last console poll.
+        for (i, port) in serial.iter_mut().enumerate() {
+            if let Some(uart) = self.bus.uart(i) {
+                pump_serial(uart, &mut **port);
+            }
+        }
+        self.serial = serial;
+        self.harts[0].set_state(hart::HartState::Stopped);
         self.shutdown();
+        self.finish_limits();
+        self.report_halt();
+
+        // Workers publish their final state on exit, so after shutdown()
+        // every hart is accounted for.
+        let mut state = HartState::capture(&cpu);
+        state.faulted = faulted;
+        self.core.publish(state);
+        if self.shared.halt_code() == 0xDEAD {
+            self.write_core(&self.core_path(), &self.core.states());
+        }
 
         let elapsed = start_time.elapsed().as_secs_f64();
         let ips = if elapsed > 0.0 {
@@ -572,22 +1187,28 @@ impl NativeVm {
         } else {
             0.0
         };
-        println!(
+        log::info!(
             "[VM] Hart 0 halted after {} steps ({:.2}M IPS)",
             step_count,
             ips / 1_000_000.0
         );
     }
 
-    fn execute_batch(&self, cpu: &mut Cpu, max_steps: u64) -> (u64, Option<HaltReason>) {
+    fn execute_batch(
+        &self,
+        cpu: &mut Cpu,
+        tracer: &mut Option<HartTracer>,
+        max_steps: u64,
+    ) -> (u64, Option<HaltReason>) {
         let mut count = 0u64;
         let hart_id: usize = 0; // Hart 0 runs on main thread
 
         // CRITICAL: Sync CLINT interrupt state to CPU's MIP at batch start.
         // Access MIP directly (bypassing privilege check since this is hardware delivery).
         const CSR_MIP: usize = 0x344;
+        // With M-mode firmware the CLINT raises MSIP/MTIP instead (Cpu::poll_interrupts)
         let (msip, timer) = self.bus.clint.check_interrupts_for_hart(hart_id);
-        if msip || timer {
+        if cpu.builtin_sbi && (msip || timer) {
             let mut mip = cpu.csrs[CSR_MIP];
             if msip {
                 mip |= 1 << 1; // SSIP
@@ -599,7 +1220,11 @@ impl NativeVm {
         }
 
         for _ in 0..max_steps {
-            match cpu.step(&*self.bus) {
+            let result = match tracer {
+                Some(t) => t.step(cpu, &*self.bus),
+                None => cpu.step(&*self.bus),
+            };
+            match result {
                 Ok(()) => {
                     count += 1;
                 }
@@ -613,9 +1238,13 @@ impl NativeVm {
                     // WFI: Advance PC past the instruction
                     cpu.pc = cpu.pc.wrapping_add(4);
 
+                    if !cpu.builtin_sbi && firmware_wfi_wakeup(cpu, &self.bus) {
+                        continue;
+                    }
+
                     // Check if interrupts are already pending from CLINT
                     let (msip, timer) = self.bus.clint.check_interrupts_for_hart(hart_id);
-                    if msip || timer {
+                    if cpu.builtin_sbi && (msip || timer) {
                         // Deliver CLINT interrupts directly to MIP CSR
                         let mut mip = cpu.csrs[CSR_MIP];
                         if msip {
@@ -637,7 +1266,7 @@ impl NativeVm {
 
                     // No pending interrupts - must sleep to save CPU
                     let now = self.bus.clint.mtime();
-                    let trigger = self.bus.clint.get_mtimecmp(hart_id);
+                    let trigger = wfi_deadline(cpu, &self.bus, hart_id);
                     let timeout_ms = if trigger > now {
                         let diff = trigger - now;
                         let ms = diff / TICKS_PER_MS;
@@ -661,41 +1290,100 @@ impl NativeVm {
         (count, None)
     }
 
-    fn pump_console(&self, console: &Console, escaped: &mut bool) {
-        let output = self.bus.uart.drain_output();
+    fn write_core(&self, path: &Path, harts: &[HartState]) {
+        match write_core_file(path, &self.bus.dram, harts) {
+            Ok(()) => eprintln!("[VM] Wrote core file {} ({} harts)", path.display(), harts.len()),
+            Err(e) => eprintln!("[VM] {}", e),
+        }
+    }
+
+    /// Forward console I/O, then run any monitor commands and queued core
+    /// dumps on hart 0's thread.
+    fn poll_console(
+        &self,
+        cpu: &mut Cpu,
+        console: &Console,
+        serial: &mut [Box<dyn Chardev>],
+        escaped: &mut bool,
+        monitor: &mut Monitor,
+    ) {
+        let (console_serial, ports) = serial.split_first_mut().expect("UART 0 has no backend");
+        for line in self.pump_console(console, &mut **console_serial, escaped, monitor) {
+            let output = self.monitor_execute(cpu, monitor, &line);
+            monitor.print(&output);
+        }
+        for (uart, port) in self.bus.extra_uarts.iter().zip(ports) {
+            pump_serial(uart, &mut **port);
+        }
+
+        if let Some(path) = self.core.take_request() {
+            let workers: Vec<usize> = (1..self.num_harts).collect();
+            self.core.publish(HartState::capture(cpu));
+            let harts = self.core.snapshot(&workers);
+            self.write_core(&path, &harts);
+        }
+    }
+
+    /// Pump the console UART, handling the Ctrl-A escapes on the terminal.
+    /// Returns the command lines completed at the monitor prompt.
+    fn pump_console(
+        &self,
+        console: &Console,
+        serial: &mut dyn Chardev,
+        escaped: &mut bool,
+        monitor: &mut Monitor,
+    ) -> Vec<String> {
+        // Guest output is held back while the monitor owns the terminal.
+        let output = if monitor.is_active() && serial.is_stdio() {
+            Vec::new()
+        } else {
+            self.bus.uart.drain_output()
+        };
         if !output.is_empty() {
-            for byte in output {
-                if byte == b'\n' {
-                    print!("\r\n");
-                } else {
-                    print!("{}", byte as char);
-                }
-            }
-            io::stdout().flush().ok();
+            serial.write(&output);
+        }
+        for byte in serial.read_available() {
+            self.bus.uart.push_input(byte);
         }
 
+        // The terminal always carries the Ctrl-A escapes; other keys only
+        // reach the guest when the UART is on the terminal.
+        let mut lines = Vec::new();
         for byte in console.read_available() {
             if *escaped {
                 if byte == b'x' {
                     println!("\r\n[VM] Terminated by user (Ctrl-A x)");
                     self.shared.request_halt();
-                    return;
-                } else if byte == 1 {
-                    self.bus.uart.push_input(1);
-                } else {
+                    return lines;
+                } else if byte == b'd' {
+                    self.core.request(self.core_path());
+                } else if byte == b'c' {
+                    monitor.toggle();
+                } else if monitor.is_active() {
+                    lines.extend(monitor.feed(byte));
+                } else if serial.is_stdio() {
                     self.bus.uart.push_input(byte);
                 }
                 *escaped = false;
             } else if byte == 1 {
                 *escaped = true;
-            } else {
+            } else if monitor.is_active() {
+                lines.extend(monitor.feed(byte));
+            } else if serial.is_stdio() {
                 self.bus.uart.push_input(byte);
             }
         }
+        lines
     }
 
+    #[cfg(not(unix))]
+    fn poll_control(&mut self, _cpu: &mut Cpu) {}
+
+    #[cfg(not(unix))]
+    fn report_halt(&mut self) {}
+
     fn shutdown(&mut self) {
-        println!("[VM] Shutting down...");
+        log::info!("[VM] Shutting down...");
 
         self.shared.request_halt();
 
@@ -705,10 +1393,56 @@ impl NativeVm {
             }
         }
 
-        println!("[VM] All threads stopped");
+        log::info!("[VM] All threads stopped");
+
+        if let Some(log) = &self.trace {
+            log.flush();
+        }
+
+        if let Some(profiler) = self.profiler.take()
+            && let Err(e) = profiler.finish()
+        {
+            eprintln!("[Profile] Failed to write profile: {}", e);
+        }
+    }
+}
+
+/// Move guest output from `uart` to `serial`, and host input back.
+fn pump_serial(uart: &Uart, serial: &mut dyn Chardev) {
+    let output = uart.drain_output();
+    if !output.is_empty() {
+        serial.write(&output);
+    }
+    for byte in serial.read_available() {
+        uart.push_input(byte);
     }
 }
 
+/// Write the DTB for `bus` (one node per UART) to DRAM at `address`.
+fn write_dtb(
+    bus: &SystemBus,
+    num_harts: usize,
+    options: &crate::dtb::DtbOptions,
+    address: u64,
+) -> Result<(), String> {
+    // D1 EMAC is always enabled for kernel probing
+    let d1_config = crate::dtb::D1DeviceConfig {
+        has_display: false, // Will be updated via enable_gpu()
+        has_mmc: false,     // Will be updated via load_disk()
+        has_emac: true,     // Always enabled for kernel probing
+        has_touch: true,    // Touch input always enabled
+        has_audio: false,   // Will be updated via enable_audio()
+    };
+    let dtb = crate::dtb::build_dtb(num_harts, bus.dram_size() as u64, &d1_config, bus.num_uarts(), options)?;
+    crate::dtb::write_dtb_to_dram_at(&bus.dram, &dtb, address);
+
+    log::info!(
+        "[VM] Generated DTB ({} bytes) at 0x{:x}",
+        dtb.len(), address
+    );
+    Ok(())
+}
+
 impl Drop for NativeVm {
     fn drop(&mut self) {
         self.shared.request_halt();
@@ -718,7 +1452,41 @@ impl Drop for NativeVm {
     }
 }
 
-fn hart_thread(hart_id: usize, entry_pc: u64, bus: Arc<SystemBus>, shared: Arc<SharedState>) {
+/// Disassemble `count` instructions at `vaddr`, one line per instruction;
+/// stops early (with an `<unmapped>` line) at the first unreadable address.
+fn disassembly(bus: &dyn Bus, mode: Mode, satp: u64, vaddr: u64, count: usize) -> Vec<String> {
+    let mut lines = Vec::with_capacity(count);
+    let mut pc = vaddr;
+    for _ in 0..count {
+        let Some((raw, len)) = disasm::fetch(bus, mode, satp, pc) else {
+            lines.push(format!("0x{:016x}: <unmapped>", pc));
+            break;
+        };
+        let bits = if len == 2 {
+            format!("{:04x}    ", raw)
+        } else {
+            format!("{:08x}", raw)
+        };
+        lines.push(format!("0x{:016x}: {}  {}", pc, bits, disasm::disassemble(pc, raw)));
+        pc = pc.wrapping_add(len as u64);
+    }
+    lines
+}
+
+#[allow(clippy::too_many_arguments)]
+fn hart_thread(
+    hart_id: usize,
+    entry_pc: u64,
+    firmware: bool,
+    bus: Arc<SystemBus>,
+    shared: Arc<SharedState>,
+    profiler: Option<Arc<Profiler>>,
+    mut tracer: Option<HartTracer>,
+    core: Arc<CoreDumper>,
+    mailboxes: Arc<Vec<HartMailbox>>,
+    harts: Arc<Vec<HartContext>>,
+    fault: FaultRecord,
+) {
     // Wait for hart 0 to signal that workers can start.
     // This ensures hart 0 has executed initial boot code before secondary harts begin.
     while !shared.can_workers_start() {
@@ -728,10 +1496,18 @@ fn hart_thread(hart_id: usize, entry_pc: u64, bus: Arc<SystemBus>, shared: Arc<S
         thread::sleep(Duration::from_micros(100));
     }
 
-    let mut cpu = Cpu::new(entry_pc, hart_id as u64);
-    cpu.setup_smode_boot(); // Enable S-mode operation
+    let mut cpu = if firmware {
+        firmware_cpu(hart_id)
+    } else {
+        let mut cpu = Cpu::new(entry_pc, hart_id as u64);
+        cpu.setup_smode_boot(); // Enable S-mode operation
+        cpu
+    };
     let mut step_count: u64 = 0;
     let start_time = Instant::now();
+    let mut profile_epoch: u64 = 0;
+    let mut core_epoch: u64 = 0;
+    let mut faulted = false;
 
     let mut last_report_time = Instant::now();
     let mut last_report_steps: u64 = 0;
@@ -744,17 +1520,37 @@ fn hart_thread(hart_id: usize, entry_pc: u64, bus: Arc<SystemBus>, shared: Arc<S
             break;
         }
 
-        let (batch_steps, halt_reason) = execute_batch_worker(&mut cpu, &bus, hart_id, BATCH_SIZE);
+        core.maybe_publish(&mut core_epoch, &cpu);
+        mailboxes[hart_id].service(&mut cpu);
+        if shared.is_paused() {
+            harts[hart_id].set_state(hart::HartState::Parked);
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        harts[hart_id].set_state(hart::HartState::Running);
+
+        if let Some(profiler) = &profiler {
+            profiler.maybe_sample(&mut profile_epoch, &cpu, &*bus);
+        }
+
+        let (batch_steps, halt_reason) = execute_batch_worker(&mut cpu, &bus, &mut tracer, hart_id, BATCH_SIZE);
         step_count += batch_steps;
+        harts[hart_id].add_instructions(batch_steps);
 
         if let Some(reason) = halt_reason {
             match reason {
                 HaltReason::Shutdown(code) => {
+                    record_failure_exit(&fault, hart_id, cpu.pc, code);
                     shared.signal_halted(code);
                     break;
                 }
                 HaltReason::Fatal(msg, pc) => {
+                    let insn = disasm::disassemble_at(&cpu, &*bus, pc)
+                        .unwrap_or_else(|| "unmapped".to_string());
+                    eprintln!("[VM] Hart {} fatal error: {} at PC=0x{:x} ({})", hart_id, msg, pc, insn);
+                    record_fault(&fault, hart_id, pc, &msg);
                     shared.signal_halted(0xDEAD);
+                    faulted = true;
                     break;
                 }
             }
@@ -780,6 +1576,11 @@ fn hart_thread(hart_id: usize, entry_pc: u64, bus: Arc<SystemBus>, shared: Arc<S
         }
     }
 
+    harts[hart_id].set_state(hart::HartState::Stopped);
+    let mut state = HartState::capture(&cpu);
+    state.faulted = faulted;
+    core.publish(state);
+
     let elapsed = start_time.elapsed().as_secs_f64();
     let ips = if elapsed > 0.0 {
         step_count as f64 / elapsed
@@ -788,9 +1589,39 @@ fn hart_thread(hart_id: usize, entry_pc: u64, bus: Arc<SystemBus>, shared: Arc<S
     };
 }
 
+/// A hart at the reset vector in M-mode, with ECALLs going to the firmware.
+fn firmware_cpu(hart_id: usize) -> Cpu {
+    let mut cpu = Cpu::new(BOOT_ROM_BASE, hart_id as u64);
+    cpu.set_builtin_sbi(false);
+    cpu
+}
+
+/// WFI under M-mode firmware: refresh MIP and wake if an interrupt enabled
+/// in `mie` is pending, even one masked by `mstatus` as firmware does while
+/// waiting for an IPI.
+fn firmware_wfi_wakeup(cpu: &mut Cpu, bus: &SystemBus) -> bool {
+    cpu.poll_interrupts(bus);
+    // Take the interrupt at the next step rather than up to 256 later
+    cpu.poll_counter = u8::MAX;
+    cpu.csrs[CSR_MIP as usize] & cpu.csrs[CSR_MIE as usize] != 0
+}
+
+/// When a sleeping hart's timer fires: `mtimecmp`, or `stimecmp` if Sstc
+/// is enabled and it is earlier.
+fn wfi_deadline(cpu: &Cpu, bus: &SystemBus, hart_id: usize) -> u64 {
+    let mtimecmp = bus.clint.get_mtimecmp(hart_id);
+    let stimecmp = cpu.csrs[CSR_STIMECMP as usize];
+    if cpu.csrs[CSR_MENVCFG as usize] >> 63 == 1 && stimecmp != 0 {
+        mtimecmp.min(stimecmp)
+    } else {
+        mtimecmp
+    }
+}
+
 fn execute_batch_worker(
     cpu: &mut Cpu,
     bus: &SystemBus,
+    tracer: &mut Option<HartTracer>,
     hart_id: usize,
     max_steps: u64,
 ) -> (u64, Option<HaltReason>) {
@@ -799,8 +1630,9 @@ fn execute_batch_worker(
     // CRITICAL: Sync CLINT interrupt state to CPU's MIP at batch start.
     // Access MIP directly (bypassing privilege check since this is hardware delivery).
     const CSR_MIP: usize = 0x344;
+    // With M-mode firmware the CLINT raises MSIP/MTIP instead (Cpu::poll_interrupts)
     let (msip, timer) = bus.clint.check_interrupts_for_hart(hart_id);
-    if msip || timer {
+    if cpu.builtin_sbi && (msip || timer) {
         let mut mip = cpu.csrs[CSR_MIP];
         if msip {
             mip |= 1 << 1; // SSIP
@@ -812,7 +1644,11 @@ fn execute_batch_worker(
     }
 
     for _ in 0..max_steps {
-        match cpu.step(bus) {
+        let result = match tracer {
+            Some(t) => t.step(cpu, bus),
+            None => cpu.step(bus),
+        };
+        match result {
             Ok(()) => {
                 count += 1;
             }
@@ -826,9 +1662,13 @@ fn execute_batch_worker(
                 // WFI: Advance PC past the instruction
                 cpu.pc = cpu.pc.wrapping_add(4);
 
+                if !cpu.builtin_sbi && firmware_wfi_wakeup(cpu, bus) {
+                    continue;
+                }
+
                 // Check if interrupts are already pending from CLINT
                 let (msip, timer) = bus.clint.check_interrupts_for_hart(hart_id);
-                if msip || timer {
+                if cpu.builtin_sbi && (msip || timer) {
                     // Deliver CLINT interrupts directly to MIP CSR
                     let mut mip = cpu.csrs[CSR_MIP];
                     if msip {
@@ -850,7 +1690,7 @@ fn execute_batch_worker(
 
                 // No pending interrupts - must sleep to save CPU
                 let now = bus.clint.mtime();
-                let trigger = bus.clint.get_mtimecmp(hart_id);
+                let trigger = wfi_deadline(cpu, bus, hart_id);
                 let timeout_ms = if trigger > now {
                     let diff = trigger - now;
                     let ms = diff / TICKS_PER_MS;
@@ -901,6 +1741,190 @@ mod tests {
         println!("Plic size: {} bytes", std::mem::size_of::<Plic>());
     }
 
+    #[test]
+    fn test_disassemble_hart_memory() {
+        // addi t0, zero, 42 ; c.ret ; unimp
+        let kernel = [0x93, 0x02, 0xa0, 0x02, 0x82, 0x80, 0x00, 0x00];
+        let vm = NativeVm::new(&kernel, 2).unwrap();
+        let lines = vm.disassemble(0, DRAM_BASE, 3).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "0x0000000080000000: 02a00293  li t0,42",
+                "0x0000000080000004: 8082      ret",
+                "0x0000000080000006: 0000      unimp",
+            ]
+        );
+        assert!(vm.disassemble(2, DRAM_BASE, 1).is_err());
+        assert_eq!(vm.disassemble(1, 0x10, 1).unwrap(), ["0x0000000000000010: <unmapped>"]);
+    }
+
+    /// Serial backend that records guest output.
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Chardev for Capture {
+        fn write(&mut self, data: &[u8]) {
+            self.0.lock().unwrap().extend_from_slice(data);
+        }
+
+        fn read_available(&mut self) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn describe(&self) -> String {
+            "capture".to_string()
+        }
+    }
+
+    #[test]
+    fn test_extra_uart_output() {
+        let kernel: Vec<u8> = [
+            0x100002b7u32, // lui t0, 0x10000
+            0x04100313,    // li t1, 'A'
+            0x10628023,    // sb t1, 0x100(t0)    UART 1 THR
+            0x001002b7,    // lui t0, 0x100
+            0x00005337,    // lui t1, 0x5
+            0x55530313,    // addi t1, t1, 0x555
+            0x0062a023,    // sw t1, 0(t0)        test finisher pass
+            0x0000006f,    // j .
+        ]
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes())
+        .collect();
+        let mut vm = NativeVm::new(&kernel, 1).unwrap();
+        let console = Arc::new(Mutex::new(Vec::new()));
+        let port = Arc::new(Mutex::new(Vec::new()));
+        vm.set_serial(Box::new(Capture(Arc::clone(&console))));
+        assert_eq!(vm.add_serial(Box::new(Capture(Arc::clone(&port)))).unwrap(), 1);
+        assert_eq!(vm.bus.num_uarts(), 2);
+
+        vm.run();
+        assert_eq!(*port.lock().unwrap(), b"A");
+        assert!(console.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_firmware_boot() {
+        // Each hart records the a2 its reset vector passed; hart 0 waits for
+        // hart 1, installs a trap handler and mrets to the kernel in S-mode.
+        // The handler records mcause, raises MSIP and MTIP through the
+        // CLINT, waits for both in mip, records it and exits.
+        let firmware: Vec<u8> = [
+            0x0000_1297u32, // 00: auipc t0, 1          (t0 = results)
+            0x0035_1313, // 04: slli t1, a0, 3
+            0x0062_8333, // 08: add t1, t0, t1
+            0x00c3_3023, // 0c: sd a2, 0(t1)
+            0x0205_1863, // 10: bnez a0, 0x40
+            0x0082_b303, // 14: ld t1, 8(t0)
+            0xfe03_0ee3, // 18: beqz t1, 0x14
+            0x0000_0317, // 1c: auipc t1, 0
+            0x0283_0313, // 20: addi t1, t1, 40
+            0x3053_1073, // 24: csrw mtvec, t1
+            0x0106_3303, // 28: ld t1, 16(a2)       (fw_dynamic_info.next_addr)
+            0x3413_1073, // 2c: csrw mepc, t1
+            0x0010_0313, // 30: li t1, 1
+            0x00b3_1313, // 34: slli t1, t1, 11
+            0x3003_1073, // 38: csrw mstatus, t1     (MPP = S)
+            0x3020_0073, // 3c: mret
+            0x0000_006f, // 40: j 0x40
+            0x3420_2373, // 44: csrr t1, mcause
+            0x0062_b823, // 48: sd t1, 16(t0)
+            0x0200_03b7, // 4c: lui t2, 0x2000       (CLINT)
+            0x0010_0e13, // 50: li t3, 1
+            0x01c3_a023, // 54: sw t3, 0(t2)         (msip0 = 1)
+            0x0000_4eb7, // 58: lui t4, 4
+            0x01d3_8eb3, // 5c: add t4, t2, t4
+            0x000e_b023, // 60: sd zero, 0(t4)       (mtimecmp0 = 0)
+            0x0880_0f13, // 64: li t5, 0x88
+            0x3440_2373, // 68: csrr t1, mip
+            0x01e3_7fb3, // 6c: and t6, t1, t5
+            0xffef_9ce3, // 70: bne t6, t5, 0x68
+            0x0062_bc23, // 74: sd t1, 24(t0)
+            0x0010_0337, // 78: lui t1, 0x100
+            0x0000_53b7, // 7c: lui t2, 5
+            0x5553_8393, // 80: addi t2, t2, 0x555
+            0x0073_2023, // 84: sw t2, 0(t1)         (test finisher pass)
+            0x0000_006f, // 88: j 0x88
+        ]
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes())
+        .collect();
+        // li a7, 0x10 (SBI base extension) ; ecall ; j .
+        let kernel: Vec<u8> = [0x0100_0893u32, 0x0000_0073, 0x0000_006f]
+            .iter()
+            .flat_map(|insn| insn.to_le_bytes())
+            .collect();
+        let kernel_addr = DRAM_BASE + 0x20_0000;
+        let results = DRAM_BASE + 0x1000;
+
+        let mut vm = NativeVm::new_at(&kernel, 2, kernel_addr).unwrap();
+        assert!(
+            vm.load_firmware(&vec![0; 0x30_0000])
+                .unwrap_err()
+                .contains("overlaps the kernel")
+        );
+        vm.load_firmware(&firmware).unwrap();
+        assert_eq!(vm.primary_cpu.as_ref().unwrap().pc, BOOT_ROM_BASE);
+        vm.set_limits(RunLimits {
+            wall_time: Some(Duration::from_secs(30)),
+            ..RunLimits::default()
+        });
+        vm.run();
+        assert_eq!(vm.outcome(), RunOutcome::Halted(0x5555));
+
+        let bus = &vm.bus;
+        // Both harts came through the reset vector
+        for hart in 0..2 {
+            assert_eq!(
+                bus.read64(results + hart * 8).unwrap(),
+                crate::firmware::FW_DYNAMIC_INFO_ADDRESS
+            );
+        }
+        // The S-mode ECALL trapped to the firmware
+        assert_eq!(bus.read64(results + 16).unwrap(), 9);
+        // MSIP and MTIP, not SSIP and STIP
+        let mip = bus.read64(results + 24).unwrap();
+        assert_eq!(mip & 0xaa, 0x88);
+    }
+
+    #[test]
+    fn test_pause_resume_and_step() {
+        // addi t0, t0, 1 ; j .-4
+        let kernel = [0x93, 0x82, 0x12, 0x00, 0xf5, 0xbf];
+        let mut vm = NativeVm::new(&kernel, 2).unwrap();
+        assert_eq!(vm.step_hart(0, 3).unwrap(), 3);
+        assert_eq!(vm.harts()[0].instructions(), 3);
+        assert!(vm.step_hart(1, 1).unwrap_err().contains("not started"));
+
+        let handle = vm.handle();
+        let harts = Arc::clone(&vm.harts);
+        let runner = thread::spawn(move || vm.run());
+        while harts[1].instructions() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        handle.pause().unwrap();
+        let counts: Vec<u64> = harts.iter().map(|h| h.instructions()).collect();
+        let mtime = handle.bus.clint.mtime();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(harts.iter().map(|h| h.instructions()).collect::<Vec<_>>(), counts);
+        assert_eq!(handle.bus.clint.mtime(), mtime);
+        assert!(harts.iter().all(|h| h.state() == hart::HartState::Parked));
+
+        assert_eq!(handle.step_hart(1, 5).unwrap(), 5);
+        assert_eq!(harts[1].instructions(), counts[1] + 5);
+        assert_eq!(harts[0].instructions(), counts[0]);
+        assert!(handle.step_hart(2, 1).is_err());
+
+        handle.resume();
+        assert!(!handle.bus.clint.is_frozen());
+        while harts[0].instructions() == counts[0] {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.shared.request_halt();
+        runner.join().unwrap();
+    }
+
     #[test]
     fn test_shared_state_alignment() {
         assert_eq!(std::mem::align_of::<SharedState>(), 64);
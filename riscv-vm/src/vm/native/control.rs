@@ -0,0 +1,872 @@
+//! JSON control socket (`--control PATH`).
+//!
+//! External tools drive a running VM over a UNIX stream socket speaking
+//! line-delimited JSON-RPC 2.0: one request object per line, one response
+//! per request. Requests are queued by per-client reader threads and run on
+//! hart 0's thread next to the console, so they see the same VM state as
+//! the monitor. Output goes through a bounded queue to a per-client writer
+//! thread; a client that stops reading is disconnected rather than allowed
+//! to stall the guest.
+//!
+//! Asynchronous events are sent to every client as notifications (objects
+//! without an `id`) whose method names the event:
+//!
+//! - `halt`: `{"code", "reason"}`, reason is `guest`, `fatal` or `host`.
+//!   The socket closes right after.
+//! - `guest-panic`: `{"hart", "pc", "message"}` for the first fatal trap,
+//!   or when the guest exits through the test finisher with a failure code.
+//! - `link-change`: `{"link": bool}` when the network backend connects,
+//!   drops, or is detached while connected.
+//!
+//! ```text
+//! -> {"jsonrpc":"2.0","id":1,"method":"query-harts"}
+//! <- {"jsonrpc":"2.0","id":1,"result":[{"hart":0,"state":"running","instructions":1234}]}
+//! ```
+
+use super::NativeVm;
+use crate::cpu::Cpu;
+use crate::devices::virtio::VirtioP9;
+use crate::hart::HartState;
+use crate::net::NetworkBackend;
+use crate::net::webtransport::WebTransportBackend;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request was valid but could not be carried out.
+const FAILED: i64 = -32000;
+
+const ACCEPT_POLL: Duration = Duration::from_millis(20);
+
+/// Lines queued for a client before it counts as stuck and is dropped.
+const CLIENT_BACKLOG: usize = 256;
+
+const HELP: &[&str] = &[
+    "query-status",
+    "query-harts",
+    "query-sysinfo",
+    "pause",
+    "resume",
+    "snapshot",
+    "restore",
+    "screenshot",
+    "send-keys",
+    "send-touch",
+    "attach-net",
+    "detach-net",
+    "attach-9p",
+    "detach-9p",
+    "shutdown",
+];
+
+struct Request {
+    client: u64,
+    id: Value,
+    method: String,
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<String> for RpcError {
+    fn from(message: String) -> Self {
+        Self {
+            code: FAILED,
+            message,
+        }
+    }
+}
+
+/// A connected client: its output queue and a handle to close the socket.
+struct Client {
+    id: u64,
+    lines: mpsc::SyncSender<String>,
+    stream: UnixStream,
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// Register `stream` as client `id`, with a thread that writes its queued
+/// lines. The writer closes the socket once the queue is dropped and drained.
+fn add_client(clients: &Clients, id: u64, stream: &UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let (lines, queue) = mpsc::sync_channel::<String>(CLIENT_BACKLOG);
+    thread::Builder::new()
+        .name(format!("control-{}-tx", id))
+        .spawn(move || {
+            for line in queue {
+                if writer.write_all(line.as_bytes()).is_err() {
+                    break;
+                }
+            }
+            writer.shutdown(std::net::Shutdown::Both).ok();
+        })?;
+    clients.lock().unwrap().push(Client {
+        id,
+        lines,
+        stream: stream.try_clone()?,
+    });
+    Ok(())
+}
+
+/// Queue `message` for `client`, or for every client if `None`. Never
+/// blocks: clients whose queue is full or whose writer has exited are
+/// disconnected.
+fn send_line(clients: &Clients, client: Option<u64>, message: &Value) {
+    let mut line = message.to_string();
+    line.push('\n');
+    clients.lock().unwrap().retain(|c| {
+        if client.is_some_and(|id| id != c.id) {
+            return true;
+        }
+        let sent = c.lines.try_send(line.clone()).is_ok();
+        if !sent {
+            c.stream.shutdown(std::net::Shutdown::Both).ok();
+        }
+        sent
+    });
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.code, "message": error.message},
+    })
+}
+
+/// Listening socket plus the queue of requests waiting for hart 0.
+pub(super) struct Control {
+    path: PathBuf,
+    clients: Clients,
+    requests: mpsc::Receiver<Request>,
+    stop: Arc<AtomicBool>,
+    /// Last link state reported in a `link-change` event.
+    link: bool,
+}
+
+impl Control {
+    fn bind(path: &Path) -> Result<Self, String> {
+        // Replace a socket left behind by a previous run, but nothing else.
+        if let Ok(meta) = fs::symlink_metadata(path)
+            && meta.file_type().is_socket()
+        {
+            fs::remove_file(path).ok();
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("Failed to bind control socket '{}': {}", path.display(), e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+        let clients = Clients::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, requests) = mpsc::channel();
+        {
+            let clients = Arc::clone(&clients);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("control".to_string())
+                .spawn(move || accept_loop(listener, clients, tx, stop))
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            clients,
+            requests,
+            stop,
+            link: false,
+        })
+    }
+
+    fn respond(&self, request: &Request, result: Result<Value, RpcError>) {
+        if request.id.is_null() {
+            // Notifications from the client get no response.
+            return;
+        }
+        let message = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": request.id, "result": result}),
+            Err(e) => error_response(request.id.clone(), e),
+        };
+        send_line(&self.clients, Some(request.client), &message);
+    }
+
+    fn emit(&self, event: &str, data: Value) {
+        send_line(
+            &self.clients,
+            None,
+            &json!({"jsonrpc": "2.0", "method": event, "params": data}),
+        );
+    }
+
+    /// Report the network link state if it changed since the last event.
+    fn set_link(&mut self, up: bool) {
+        if self.link != up {
+            self.link = up;
+            self.emit("link-change", json!({"link": up}));
+        }
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Stop the readers; each writer flushes what is queued (the `halt`
+        // event) and then closes its socket.
+        for client in self.clients.lock().unwrap().drain(..) {
+            client.stream.shutdown(std::net::Shutdown::Read).ok();
+        }
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+fn accept_loop(
+    listener: UnixListener,
+    clients: Clients,
+    requests: mpsc::Sender<Request>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut next_client = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("[Control] accept failed: {}", e);
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+        };
+        next_client += 1;
+        let client = next_client;
+        stream.set_nonblocking(false).ok();
+        if add_client(&clients, client, &stream).is_err() {
+            continue;
+        }
+
+        let clients = Arc::clone(&clients);
+        let requests = requests.clone();
+        thread::Builder::new()
+            .name(format!("control-{}", client))
+            .spawn(move || read_client(client, stream, clients, requests))
+            .ok();
+    }
+}
+
+fn read_client(client: u64, stream: UnixStream, clients: Clients, requests: mpsc::Sender<Request>) {
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_request(client, &line) {
+            Ok(request) => {
+                if requests.send(request).is_err() {
+                    break;
+                }
+            }
+            Err((id, error)) => send_line(&clients, Some(client), &error_response(id, error)),
+        }
+    }
+    clients.lock().unwrap().retain(|c| c.id != client);
+}
+
+fn parse_request(client: u64, line: &str) -> Result<Request, (Value, RpcError)> {
+    let value: Value = serde_json::from_str(line).map_err(|e| {
+        let error = RpcError {
+            code: PARSE_ERROR,
+            message: e.to_string(),
+        };
+        (Value::Null, error)
+    })?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let invalid = |message: &str| {
+        let error = RpcError {
+            code: INVALID_REQUEST,
+            message: message.to_string(),
+        };
+        (id.clone(), error)
+    };
+    if value.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err(invalid("expected \"jsonrpc\": \"2.0\""));
+    }
+    let Some(method) = value.get("method").and_then(Value::as_str) else {
+        return Err(invalid("missing \"method\""));
+    };
+    let params = value.get("params").cloned().unwrap_or(Value::Null);
+    if !(params.is_null() || params.is_object()) {
+        return Err(invalid("\"params\" must be an object"));
+    }
+    Ok(Request {
+        client,
+        id,
+        method: method.to_string(),
+        params,
+    })
+}
+
+fn str_param<'a>(params: &'a Value, key: &str) -> Result<&'a str, RpcError> {
+    opt_str_param(params, key)?
+        .ok_or_else(|| RpcError::invalid_params(format!("missing \"{}\"", key)))
+}
+
+fn opt_str_param<'a>(params: &'a Value, key: &str) -> Result<Option<&'a str>, RpcError> {
+    match params.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(RpcError::invalid_params(format!(
+            "\"{}\" must be a string",
+            key
+        ))),
+    }
+}
+
+fn u64_param(params: &Value, key: &str) -> Result<u64, RpcError> {
+    params.get(key).and_then(Value::as_u64).ok_or_else(|| {
+        RpcError::invalid_params(format!("\"{}\" must be a non-negative integer", key))
+    })
+}
+
+fn bool_param(params: &Value, key: &str, default: bool) -> Result<bool, RpcError> {
+    match params.get(key) {
+        None | Some(Value::Null) => Ok(default),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => Err(RpcError::invalid_params(format!(
+            "\"{}\" must be a boolean",
+            key
+        ))),
+    }
+}
+
+fn state_name(state: HartState) -> &'static str {
+    match state {
+        HartState::Uninitialized => "not-started",
+        HartState::Parked => "paused",
+        HartState::Running => "running",
+        HartState::WaitingForInterrupt => "wfi",
+        HartState::Stopped => "stopped",
+    }
+}
+
+/// Binary PPM (P6) image of an RGBA framebuffer.
+fn ppm(rgba: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    out.reserve(rgba.len() / 4 * 3);
+    for pixel in rgba.chunks_exact(4) {
+        out.extend_from_slice(&pixel[..3]);
+    }
+    out
+}
+
+impl NativeVm {
+    /// Serve the JSON control protocol on a UNIX socket at `path`.
+    ///
+    /// Requests are answered while [`NativeVm::run`] runs; the socket is
+    /// removed when it returns. Runtime `attach-9p` needs a 9P device to
+    /// exist, see [`NativeVm::enable_9p`].
+    pub fn enable_control(&mut self, path: &Path) -> Result<(), String> {
+        // The link starts down; poll_control raises it once the backend
+        // has connected.
+        self.control = Some(Control::bind(path)?);
+        Ok(())
+    }
+
+    /// Run queued control requests and report link changes on hart 0's
+    /// thread.
+    pub(super) fn poll_control(&mut self, cpu: &mut Cpu) {
+        let Some(mut control) = self.control.take() else {
+            return;
+        };
+        while let Ok(request) = control.requests.try_recv() {
+            let result = self.control_request(cpu, &mut control, &request.method, &request.params);
+            control.respond(&request, result);
+        }
+        if let Some(backend) = &self.wt_backend {
+            control.set_link(backend.is_connected());
+        }
+        self.control = Some(control);
+    }
+
+    /// Send the `guest-panic` and `halt` events and close the socket.
+    pub(super) fn report_halt(&mut self) {
+        let Some(control) = self.control.take() else {
+            return;
+        };
+        let code = self.shared.halt_code();
+        let fault = self.fault.lock().unwrap().clone();
+        if let Some(fault) = &fault {
+            control.emit(
+                "guest-panic",
+                json!({"hart": fault.hart, "pc": fault.pc, "message": fault.message}),
+            );
+        }
+        let reason = if !self.shared.is_halted() {
+            "host"
+        } else if code == 0xDEAD {
+            "fatal"
+        } else {
+            "guest"
+        };
+        control.emit("halt", json!({"code": code, "reason": reason}));
+    }
+
+    fn control_request(
+        &mut self,
+        cpu: &mut Cpu,
+        control: &mut Control,
+        method: &str,
+        params: &Value,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "help" => Ok(json!(HELP)),
+            "query-status" => {
+                let status = if self.shared.should_stop() {
+                    "halted"
+                } else if self.shared.is_paused() {
+                    "paused"
+                } else {
+                    "running"
+                };
+                Ok(json!({
+                    "status": status,
+                    "harts": self.num_harts,
+                    "network": self.wt_backend.is_some(),
+                }))
+            }
+            "query-harts" => Ok(Value::Array(
+                self.harts
+                    .iter()
+                    .map(|h| {
+                        json!({
+                            "hart": h.hart_id,
+                            "state": state_name(h.state()),
+                            "instructions": h.instructions(),
+                        })
+                    })
+                    .collect(),
+            )),
+            "query-sysinfo" => {
+                let (heap_used, heap_total) = self.get_heap_usage();
+                let (disk_used, disk_total) = self.get_disk_usage();
+                Ok(json!({
+                    "heap": {"used": heap_used, "total": heap_total},
+                    "disk": {"used": disk_used, "total": disk_total},
+                    "cpus": self.get_cpu_count(),
+                    "uptime_ms": self.get_uptime_ms(),
+                }))
+            }
+            "pause" => {
+                self.pause()?;
+                Ok(Value::Null)
+            }
+            "resume" => {
+                self.resume();
+                Ok(Value::Null)
+            }
+            "snapshot" => {
+                let path = PathBuf::from(str_param(params, "path")?);
+                self.save_vm(cpu, &path)?;
+                Ok(json!({"path": path}))
+            }
+            "restore" => {
+                let path = PathBuf::from(str_param(params, "path")?);
+                self.load_vm(cpu, &path)?;
+                Ok(json!({"path": path}))
+            }
+            "screenshot" => {
+                let path = str_param(params, "path")?;
+                let (frame, (width, height)) = self
+                    .get_gpu_frame()
+                    .zip(self.get_gpu_size())
+                    .ok_or_else(|| "No display (start with --enable-gpu)".to_string())?;
+                fs::write(path, ppm(&frame, width, height))
+                    .map_err(|e| format!("Failed to write '{}': {}", path, e))?;
+                Ok(json!({"path": path, "width": width, "height": height}))
+            }
+            "send-keys" => {
+                if let Some(text) = opt_str_param(params, "text")? {
+                    for byte in text.bytes() {
+                        self.bus.uart.push_input(byte);
+                    }
+                    return Ok(Value::Null);
+                }
+                let code = u16::try_from(u64_param(params, "code")?)
+                    .map_err(|_| RpcError::invalid_params("\"code\" out of range"))?;
+                let sent = match params.get("pressed") {
+                    Some(_) => self.send_key_event(code, bool_param(params, "pressed", true)?),
+                    None => self.send_key_event(code, true) && self.send_key_event(code, false),
+                };
+                if !sent {
+                    return Err("No keyboard (start with --enable-gpu)".to_string().into());
+                }
+                Ok(Value::Null)
+            }
+            "send-touch" => {
+                let coord = |key| {
+                    u32::try_from(u64_param(params, key)?)
+                        .map_err(|_| RpcError::invalid_params(format!("\"{}\" out of range", key)))
+                };
+                let (x, y) = (coord("x")?, coord("y")?);
+                if !self.send_touch_event(x, y, bool_param(params, "pressed", true)?) {
+                    return Err("No touchscreen (start with --enable-gpu)"
+                        .to_string()
+                        .into());
+                }
+                Ok(Value::Null)
+            }
+            "attach-net" => {
+                let url = str_param(params, "url")?;
+                let cert_hash = opt_str_param(params, "cert_hash")?.map(str::to_string);
+                // Keep the guest's MAC so the driver needs no reconfiguration.
+                let mac = {
+                    let mut emac = self.bus.d1_emac.write().unwrap();
+                    let emac = emac
+                        .as_mut()
+                        .ok_or_else(|| "No network device".to_string())?;
+                    emac.set_link(true);
+                    emac.mac_address()
+                };
+                // `link-change` follows once the backend has connected.
+                self.wt_backend = Some(WebTransportBackend::with_mac(url, cert_hash, mac));
+                Ok(Value::Null)
+            }
+            "detach-net" => {
+                if self.wt_backend.take().is_none() {
+                    return Err("No network backend attached".to_string().into());
+                }
+                if let Some(emac) = self.bus.d1_emac.write().unwrap().as_mut() {
+                    emac.set_link(false);
+                }
+                control.set_link(false);
+                Ok(Value::Null)
+            }
+            "attach-9p" => {
+                let path = Path::new(str_param(params, "path")?);
+                if !path.is_dir() {
+                    return Err(format!("'{}' is not a directory", path.display()).into());
+                }
+                let share = self.share(opt_str_param(params, "tag")?)?;
+                share.attach(path);
+                Ok(json!({"tag": share.mount_tag(), "path": path}))
+            }
+            "detach-9p" => {
+                let share = self.share(opt_str_param(params, "tag")?)?;
+                share.detach();
+                Ok(json!({"tag": share.mount_tag()}))
+            }
+            "shutdown" => {
+                self.shared.request_halt();
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method '{}'", method),
+            }),
+        }
+    }
+
+    /// The 9P device with mount tag `tag` (or the first one).
+    fn share(&self, tag: Option<&str>) -> Result<&VirtioP9, String> {
+        self.bus
+            .virtio_devices
+            .iter()
+            .filter_map(|dev| dev.as_any()?.downcast_ref::<VirtioP9>())
+            .find(|p9| tag.is_none_or(|tag| p9.mount_tag() == tag))
+            .ok_or_else(|| match tag {
+                Some(tag) => format!("No 9P device with tag '{}'", tag),
+                None => "No 9P device (start with --mount or --control)".to_string(),
+            })
+    }
+}
+
+/// Blocking client for the control socket, for Rust orchestrators and tests.
+///
+/// Events received while waiting for a response are queued and returned by
+/// [`ControlClient::next_event`].
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: AtomicU64,
+    events: VecDeque<Value>,
+}
+
+impl ControlClient {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self {
+            reader,
+            writer,
+            next_id: AtomicU64::new(1),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Call `method` and wait for its result; JSON-RPC errors come back as
+    /// `Err(message)`.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut line =
+            json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string();
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .map_err(|e| e.to_string())?;
+        loop {
+            let message = self.read_message(None)?.ok_or("Control socket closed")?;
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                self.events.push_back(message);
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let text = error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("error");
+                return Err(text.to_string());
+            }
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    /// Wait up to `timeout` for the next event notification, returning its
+    /// name and data. `None` on timeout or when the socket closes.
+    pub fn next_event(&mut self, timeout: Duration) -> Option<(String, Value)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let message = match self.events.pop_front() {
+                Some(message) => message,
+                None => {
+                    let left = deadline.checked_duration_since(Instant::now())?;
+                    self.read_message(Some(left)).ok()??
+                }
+            };
+            if message.get("id").is_none()
+                && let Some(method) = message.get("method").and_then(Value::as_str)
+            {
+                let params = message.get("params").cloned().unwrap_or(Value::Null);
+                return Some((method.to_string(), params));
+            }
+        }
+    }
+
+    fn read_message(&mut self, timeout: Option<Duration>) -> Result<Option<Value>, String> {
+        self.reader
+            .get_ref()
+            .set_read_timeout(timeout.map(|t| t.max(Duration::from_millis(1))))
+            .map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => serde_json::from_str(&line)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+
+    const SPIN: [u8; 4] = [0x6f, 0x00, 0x00, 0x00]; // j .
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "riscv-vm-control-{}-{}.sock",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn start(name: &str, harts: usize) -> (PathBuf, thread::JoinHandle<NativeVm>) {
+        let path = socket_path(name);
+        let mut vm = NativeVm::new(&SPIN, harts).unwrap();
+        vm.enable_9p("", Some("share"));
+        vm.enable_control(&path).unwrap();
+        let handle = thread::spawn(move || {
+            vm.run();
+            vm
+        });
+        (path, handle)
+    }
+
+    #[test]
+    fn parse_requests() {
+        let ok = parse_request(3, r#"{"jsonrpc":"2.0","id":7,"method":"pause"}"#)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            (ok.client, ok.id, ok.method.as_str()),
+            (3, json!(7), "pause")
+        );
+        assert!(ok.params.is_null());
+
+        let err = |line| parse_request(1, line).err().unwrap();
+        assert_eq!(err("{").1.code, PARSE_ERROR);
+        assert_eq!(err(r#"{"id":1,"method":"pause"}"#).1.code, INVALID_REQUEST);
+        let (id, e) = err(r#"{"jsonrpc":"2.0","id":"a","method":"x","params":[1]}"#);
+        assert_eq!((id, e.code), (json!("a"), INVALID_REQUEST));
+
+        assert_eq!(
+            ppm(&[1, 2, 3, 255, 4, 5, 6, 255], 2, 1),
+            b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06"
+        );
+    }
+
+    #[test]
+    fn stuck_client_is_dropped() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let clients = Clients::default();
+        add_client(&clients, 1, &ours).unwrap();
+
+        // The peer never reads: the socket buffer and then the queue fill,
+        // and the client is dropped instead of blocking the sender.
+        let message = json!({"data": "x".repeat(4096)});
+        let start = Instant::now();
+        for _ in 0..4096 {
+            send_line(&clients, None, &message);
+        }
+        assert!(clients.lock().unwrap().is_empty());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(theirs);
+    }
+
+    #[test]
+    fn control_session() {
+        let (path, vm) = start("session", 2);
+        let mut client = ControlClient::connect(&path).unwrap();
+
+        let status = client.call("query-status", Value::Null).unwrap();
+        assert_eq!(status["status"], "running");
+        assert_eq!(status["harts"], 2);
+
+        client.call("pause", Value::Null).unwrap();
+        assert_eq!(
+            client.call("query-status", Value::Null).unwrap()["status"],
+            "paused"
+        );
+        let harts = client.call("query-harts", Value::Null).unwrap();
+        assert_eq!(harts[0]["hart"], 0);
+        assert_eq!(harts[0]["state"], "paused");
+        assert!(harts[0]["instructions"].as_u64().unwrap() > 0);
+        client.call("resume", Value::Null).unwrap();
+
+        let sysinfo = client.call("query-sysinfo", Value::Null).unwrap();
+        assert!(sysinfo["heap"]["total"].is_u64());
+
+        client.call("send-keys", json!({"text": "ls\n"})).unwrap();
+        assert_eq!(
+            client.call("send-keys", json!({"code": 28})).unwrap_err(),
+            "No keyboard (start with --enable-gpu)"
+        );
+        assert!(
+            client
+                .call("screenshot", json!({"path": "/nonexistent"}))
+                .is_err()
+        );
+        assert!(
+            client
+                .call("snapshot", Value::Null)
+                .unwrap_err()
+                .contains("missing \"path\"")
+        );
+        assert!(
+            client
+                .call("bogus", Value::Null)
+                .unwrap_err()
+                .contains("Unknown method")
+        );
+
+        let dir = std::env::temp_dir();
+        let share = client.call("attach-9p", json!({"path": dir})).unwrap();
+        assert_eq!(share["tag"], "share");
+        assert!(
+            client
+                .call("attach-9p", json!({"path": "/x", "tag": "nope"}))
+                .is_err()
+        );
+        client.call("detach-9p", json!({"tag": "share"})).unwrap();
+
+        assert!(client.call("detach-net", Value::Null).is_err());
+        // Nothing listens there, so the link never comes up.
+        client
+            .call("attach-net", json!({"url": "https://127.0.0.1:1"}))
+            .unwrap();
+        assert!(client.next_event(Duration::from_millis(500)).is_none());
+        client.call("detach-net", Value::Null).unwrap();
+        assert!(client.next_event(Duration::from_millis(200)).is_none());
+
+        client.call("shutdown", Value::Null).unwrap();
+        let (event, data) = client.next_event(Duration::from_secs(5)).unwrap();
+        assert_eq!(event, "halt");
+        assert_eq!(data["reason"], "host");
+        assert!(client.next_event(Duration::from_secs(5)).is_none());
+
+        vm.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn guest_panic_event() {
+        // lui t0,0x100 ; lui t1,0x33 ; addi t1,t1,0x333 ; sw t1,0(t0) ; j .
+        // i.e. report exit code 3 through the test finisher.
+        let kernel: Vec<u8> = [
+            0x001002b7u32,
+            0x00033337,
+            0x33330313,
+            0x0062a023,
+            0x0000006f,
+        ]
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes())
+        .collect();
+        let path = socket_path("panic");
+        let mut vm = NativeVm::new(&kernel, 1).unwrap();
+        vm.enable_control(&path).unwrap();
+        vm.shared.pause();
+        let vm = thread::spawn(move || vm.run());
+
+        let mut client = ControlClient::connect(&path).unwrap();
+        client.call("resume", Value::Null).unwrap();
+        let (event, data) = client.next_event(Duration::from_secs(5)).unwrap();
+        assert_eq!(event, "guest-panic");
+        assert_eq!(data["hart"], 0);
+        assert!(data["pc"].as_u64().is_some_and(|pc| pc >= DRAM_BASE));
+        assert_eq!(data["message"], "exit code 3");
+        let (event, data) = client.next_event(Duration::from_secs(5)).unwrap();
+        assert_eq!(event, "halt");
+        assert_eq!(data["reason"], "guest");
+        assert_eq!(data["code"], 0x3_3333);
+        vm.join().unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! Console automation for a running [`NativeVm`], see [`crate::vm::expect`].
+
+use super::{NativeVm, SharedState};
+use crate::bus::SystemBus;
+use crate::chardev::Chardev;
+use crate::hart::{HartContext, HartState};
+use crate::vm::expect::{Expect, Transport};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// How long [`NativeConsole::poll`] waits for output.
+const POLL: Duration = Duration::from_millis(10);
+
+/// UART backend feeding an [`Expect`] on another thread.
+struct ExpectPort {
+    output: Sender<Vec<u8>>,
+    input: Receiver<Vec<u8>>,
+}
+
+impl Chardev for ExpectPort {
+    fn write(&mut self, data: &[u8]) {
+        self.output.send(data.to_vec()).ok();
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Ok(chunk) = self.input.try_recv() {
+            bytes.extend(chunk);
+        }
+        bytes
+    }
+
+    fn describe(&self) -> String {
+        "expect".to_string()
+    }
+}
+
+/// The test's end of a UART connected with [`NativeVm::expect_console`].
+pub struct NativeConsole {
+    output: Receiver<Vec<u8>>,
+    input: Sender<Vec<u8>>,
+    bus: Arc<SystemBus>,
+    shared: Arc<SharedState>,
+    harts: Arc<Vec<HartContext>>,
+}
+
+impl Transport for NativeConsole {
+    fn poll(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        // Hart 0 pumps the UARTs one last time before it reports Stopped,
+        // so once it has, everything the guest wrote is in the channel.
+        let stopped = self.harts[0].state() == HartState::Stopped;
+        match self.output.recv_timeout(POLL) {
+            Ok(data) => {
+                out.extend(data);
+                while let Ok(more) = self.output.try_recv() {
+                    out.extend(more);
+                }
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) if !stopped => Ok(()),
+            Err(_) => Err(format!("VM halted (code {:#x})", self.shared.halt_code())),
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        self.input.send(data.to_vec()).ok();
+    }
+
+    fn mtime(&self) -> u64 {
+        self.bus.clint.mtime()
+    }
+}
+
+impl NativeVm {
+    /// Connect UART `index` to an [`Expect`] console instead of its current
+    /// backend. Call before moving the VM to the thread that runs it.
+    pub fn expect_console(&mut self, index: usize) -> Result<Expect<NativeConsole>, String> {
+        if index >= self.serial.len() {
+            return Err(format!("No UART {}", index));
+        }
+        let (output_tx, output) = mpsc::channel();
+        let (input, input_rx) = mpsc::channel();
+        self.serial[index] = Box::new(ExpectPort {
+            output: output_tx,
+            input: input_rx,
+        });
+        Ok(Expect::new(NativeConsole {
+            output,
+            input,
+            bus: Arc::clone(&self.bus),
+            shared: Arc::clone(&self.shared),
+            harts: Arc::clone(&self.harts),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn expect_native_console() {
+        // Echo UART input back until a '!' arrives, then pass the test
+        // finisher.
+        let kernel: Vec<u8> = [
+            0x100002b7u32, // lui t0, 0x10000
+            0x0052c303,    // 1: lbu t1, 5(t0)      LSR
+            0x00137313,    // andi t1, t1, 1        data ready
+            0xfe030ce3,    // beqz t1, 1b
+            0x0002c303,    // lbu t1, 0(t0)
+            0x00628023,    // sb t1, 0(t0)
+            0x02100393,    // li t2, '!'
+            0xfe7314e3,    // bne t1, t2, 1b
+            0x001002b7,    // lui t0, 0x100
+            0x00005337,    // lui t1, 0x5
+            0x55530313,    // addi t1, t1, 0x555
+            0x0062a023,    // sw t1, 0(t0)
+            0x0000006f,    // j .
+        ]
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes())
+        .collect();
+        let mut vm = NativeVm::new(&kernel, 1).unwrap();
+        let mut console = vm.expect_console(0).unwrap();
+        assert!(vm.expect_console(1).is_err());
+        let runner = thread::spawn(move || vm.run());
+
+        console.send("hello");
+        let found = console
+            .wait_for("h(el+)o", Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(found.groups, [Some("ell".to_string())]);
+        console.send_line("!");
+        console.wait_for("!", Duration::from_secs(10)).unwrap();
+        let err = console
+            .wait_for("never", Duration::from_secs(10))
+            .unwrap_err();
+        assert!(err.starts_with("VM halted (code 0x5555)"), "{}", err);
+        assert!(err.contains("hello!"));
+        runner.join().unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+//! Limits and console checks for unattended (CI) runs of a [`NativeVm`].
+//!
+//! A run ends when the guest halts, when a time or instruction limit runs
+//! out, or when the console output decides it: every `expect` pattern seen
+//! (in order) passes, any `fail_on` pattern fails. [`RunOutcome`] says which
+//! and maps it to a process exit status.
+
+use super::{NativeVm, SharedState};
+use crate::bus::{FINISHER_FAIL, FINISHER_PASS};
+use crate::chardev::{Chardev, Null};
+use crate::devices::clint::TICKS_PER_MS;
+use regex::bytes::Regex;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Exit status when a time or instruction limit ran out (as `timeout(1)`).
+pub const EXIT_TIMEOUT: i32 = 124;
+/// Exit status when a `fail_on` pattern appeared or an `expect` never did.
+pub const EXIT_CONSOLE: i32 = 125;
+/// Exit status when a hart hit a fatal trap.
+pub const EXIT_FATAL: i32 = 126;
+
+/// Ways to end a run other than the guest halting. Zero values are unset.
+#[derive(Debug, Clone, Default)]
+pub struct RunLimits {
+    /// Host time the run may take.
+    pub wall_time: Option<Duration>,
+    /// Guest time (CLINT `mtime`) the run may take.
+    pub guest_time: Option<Duration>,
+    /// Instructions the harts may retire in total.
+    pub max_instructions: Option<u64>,
+    /// Console (ttyS0) patterns that pass the run once all have matched, in order.
+    pub expect: Vec<Regex>,
+    /// Console patterns that fail the run as soon as one matches.
+    pub fail_on: Vec<Regex>,
+}
+
+impl RunLimits {
+    fn watches_console(&self) -> bool {
+        !self.expect.is_empty() || !self.fail_on.is_empty()
+    }
+}
+
+/// A limit from [`RunLimits`] that ran out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Limit {
+    WallTime(Duration),
+    GuestTime(Duration),
+    Instructions(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::WallTime(d) => write!(f, "wall-clock timeout of {:?}", d),
+            Limit::GuestTime(d) => write!(f, "guest timeout of {:?}", d),
+            Limit::Instructions(n) => write!(f, "instruction limit of {}", n),
+        }
+    }
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The guest halted with this code (test finisher, HTIF or SBI shutdown),
+    /// or 0 if the host stopped it.
+    Halted(u64),
+    /// A hart hit a fatal trap.
+    Fatal,
+    /// A limit ran out first.
+    Limit(Limit),
+    /// Every `expect` pattern matched.
+    Expected,
+    /// A `fail_on` pattern matched this console line.
+    FailOn(String),
+    /// The guest halted before this `expect` pattern matched.
+    ExpectMissing(String),
+}
+
+impl RunOutcome {
+    fn from_halt_code(code: u64) -> Self {
+        match code {
+            0xDEAD => RunOutcome::Fatal,
+            code => RunOutcome::Halted(code),
+        }
+    }
+
+    /// Process exit status for this outcome: 0 on a pass, the guest's exit
+    /// code on a finisher failure, and `EXIT_*` for everything else.
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            RunOutcome::Halted(0 | FINISHER_PASS) | RunOutcome::Expected => 0,
+            RunOutcome::Halted(code) if code & 0xffff == FINISHER_FAIL => {
+                // Statuses wrap at 256, so keep failures from reading as 0.
+                (code >> 16).clamp(1, 255) as i32
+            }
+            RunOutcome::Halted(_) => 1,
+            RunOutcome::Fatal => EXIT_FATAL,
+            RunOutcome::Limit(_) => EXIT_TIMEOUT,
+            RunOutcome::FailOn(_) | RunOutcome::ExpectMissing(_) => EXIT_CONSOLE,
+        }
+    }
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunOutcome::Halted(FINISHER_PASS) => write!(f, "Clean shutdown (PASS)"),
+            RunOutcome::Halted(0) => write!(f, "Stopped"),
+            RunOutcome::Halted(code) => write!(f, "Shutdown with code: {:#x}", code),
+            RunOutcome::Fatal => write!(f, "Fatal trap"),
+            RunOutcome::Limit(limit) => write!(f, "Stopped: {} reached", limit),
+            RunOutcome::Expected => write!(f, "Expected console output seen (PASS)"),
+            RunOutcome::FailOn(line) => write!(f, "Failure pattern matched: {}", line),
+            RunOutcome::ExpectMissing(pattern) => {
+                write!(f, "Halted before console output matched '{}'", pattern)
+            }
+        }
+    }
+}
+
+/// Console progress shared between the watched UART and the VM.
+#[derive(Default)]
+pub(super) struct Verdict {
+    /// Set by whatever stopped the run early.
+    outcome: Option<RunOutcome>,
+    /// Index of the next `expect` pattern to match.
+    expected: usize,
+}
+
+/// Tracks limits on hart 0's thread while the VM runs.
+pub(super) struct LimitCheck {
+    wall_deadline: Option<Instant>,
+    guest_deadline: Option<u64>,
+}
+
+impl LimitCheck {
+    /// `None` if `limits` sets no time or instruction limit.
+    pub(super) fn new(limits: &RunLimits, mtime: u64) -> Option<Self> {
+        if limits.wall_time.is_none()
+            && limits.guest_time.is_none()
+            && limits.max_instructions.is_none()
+        {
+            return None;
+        }
+        Some(Self {
+            wall_deadline: limits.wall_time.map(|d| Instant::now() + d),
+            guest_deadline: limits
+                .guest_time
+                .map(|d| mtime.saturating_add((d.as_millis() as u64).saturating_mul(TICKS_PER_MS))),
+        })
+    }
+
+    /// The limit that has run out, if any.
+    pub(super) fn exceeded(&self, limits: &RunLimits, mtime: u64, retired: u64) -> Option<Limit> {
+        if let Some(max) = limits.max_instructions
+            && retired >= max
+        {
+            return Some(Limit::Instructions(max));
+        }
+        if let Some(deadline) = self.guest_deadline
+            && mtime >= deadline
+        {
+            return limits.guest_time.map(Limit::GuestTime);
+        }
+        if let Some(deadline) = self.wall_deadline
+            && Instant::now() >= deadline
+        {
+            return limits.wall_time.map(Limit::WallTime);
+        }
+        None
+    }
+}
+
+/// UART backend wrapper matching guest output against the console patterns.
+struct Watch {
+    inner: Box<dyn Chardev>,
+    expect: Vec<Regex>,
+    fail_on: Vec<Regex>,
+    verdict: Arc<Mutex<Verdict>>,
+    shared: Arc<SharedState>,
+    /// The current line; `start` is where the next `expect` search begins.
+    /// Patterns see it without its line ending.
+    line: Vec<u8>,
+    start: usize,
+}
+
+impl Watch {
+    /// Match the current (possibly partial) line. Returns true once the
+    /// run is decided.
+    fn check_line(&mut self) -> bool {
+        let mut verdict = self.verdict.lock().unwrap();
+        if verdict.outcome.is_some() {
+            return true;
+        }
+        let end = self.line.len()
+            - self
+                .line
+                .iter()
+                .rev()
+                .take_while(|&&b| b == b'\n' || b == b'\r')
+                .count();
+        let line = &self.line[..end];
+        if self.fail_on.iter().any(|re| re.is_match(line)) {
+            let line = String::from_utf8_lossy(line).into_owned();
+            verdict.outcome = Some(RunOutcome::FailOn(line));
+        } else {
+            while let Some(re) = self.expect.get(verdict.expected)
+                && self.start <= end
+                && let Some(m) = re.find(&line[self.start..])
+            {
+                self.start += m.end();
+                verdict.expected += 1;
+            }
+            if verdict.expected == self.expect.len() && !self.expect.is_empty() {
+                verdict.outcome = Some(RunOutcome::Expected);
+            }
+        }
+        if verdict.outcome.is_some() {
+            self.shared.request_halt();
+            return true;
+        }
+        false
+    }
+}
+
+impl Chardev for Watch {
+    fn write(&mut self, data: &[u8]) {
+        self.inner.write(data);
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            self.line.extend_from_slice(line);
+            if self.check_line() {
+                return;
+            }
+            if self.line.ends_with(b"\n") {
+                self.line.clear();
+                self.start = 0;
+            }
+        }
+    }
+
+    fn read_available(&mut self) -> Vec<u8> {
+        self.inner.read_available()
+    }
+
+    fn is_stdio(&self) -> bool {
+        self.inner.is_stdio()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+}
+
+impl NativeVm {
+    /// Limit the next [`run`](NativeVm::run); see [`RunLimits`].
+    pub fn set_limits(&mut self, limits: RunLimits) {
+        self.limits = limits;
+    }
+
+    /// How the last run ended.
+    pub fn outcome(&self) -> RunOutcome {
+        self.verdict
+            .lock()
+            .unwrap()
+            .outcome
+            .clone()
+            .unwrap_or_else(|| RunOutcome::from_halt_code(self.shared.halt_code()))
+    }
+
+    /// Put the console watch in front of UART 0 if any patterns are set.
+    pub(super) fn watch_console(&self, serial: &mut [Box<dyn Chardev>]) {
+        if !self.limits.watches_console() || serial.is_empty() {
+            return;
+        }
+        let inner = std::mem::replace(&mut serial[0], Box::new(Null));
+        serial[0] = Box::new(Watch {
+            inner,
+            expect: self.limits.expect.clone(),
+            fail_on: self.limits.fail_on.clone(),
+            verdict: Arc::clone(&self.verdict),
+            shared: Arc::clone(&self.shared),
+            line: Vec::new(),
+            start: 0,
+        });
+    }
+
+    /// Stop the run if a limit has run out.
+    pub(super) fn check_limits(&self, check: &LimitCheck) -> bool {
+        let retired = self.harts.iter().map(|h| h.instructions()).sum();
+        let Some(limit) = check.exceeded(&self.limits, self.bus.clint.mtime(), retired) else {
+            return false;
+        };
+        let mut verdict = self.verdict.lock().unwrap();
+        verdict.outcome.get_or_insert(RunOutcome::Limit(limit));
+        self.shared.request_halt();
+        true
+    }
+
+    /// Record an `expect` pattern that the guest halted without printing.
+    pub(super) fn finish_limits(&self) {
+        let mut verdict = self.verdict.lock().unwrap();
+        if verdict.outcome.is_none()
+            && let Some(re) = self.limits.expect.get(verdict.expected)
+            && self.shared.halt_code() != 0xDEAD
+        {
+            verdict.outcome = Some(RunOutcome::ExpectMissing(re.as_str().to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernel(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    /// Print "ok\nboot done\n" to the UART, then spin.
+    fn printing_kernel(finish: bool) -> Vec<u8> {
+        let mut words = vec![0x100002b7u32]; // lui t0, 0x10000
+        for &b in b"ok\nboot done\n" {
+            words.push(((b as u32) << 20) | 0x00000313); // li t1, b
+            words.push(0x00628023); // sb t1, 0(t0)
+        }
+        if finish {
+            words.extend([
+                0x001002b7, // lui t0, 0x100
+                0x00005337, // lui t1, 0x5
+                0x55530313, // addi t1, t1, 0x555
+                0x0062a023, // sw t1, 0(t0)
+            ]);
+        }
+        words.push(0x0000006f); // j .
+        kernel(&words)
+    }
+
+    #[test]
+    fn exit_status_mapping() {
+        assert_eq!(RunOutcome::Halted(FINISHER_PASS).exit_status(), 0);
+        assert_eq!(
+            RunOutcome::Halted(crate::bus::finisher_code(3)).exit_status(),
+            3
+        );
+        assert_eq!(
+            RunOutcome::Halted(crate::bus::finisher_code(256)).exit_status(),
+            255
+        );
+        assert_eq!(RunOutcome::Halted(0x7777).exit_status(), 1);
+        assert_eq!(RunOutcome::from_halt_code(0xDEAD).exit_status(), EXIT_FATAL);
+        assert_eq!(
+            RunOutcome::Limit(Limit::Instructions(5)).exit_status(),
+            EXIT_TIMEOUT
+        );
+        assert_eq!(
+            RunOutcome::FailOn("panic".into()).exit_status(),
+            EXIT_CONSOLE
+        );
+        assert_eq!(RunOutcome::Expected.exit_status(), 0);
+    }
+
+    #[test]
+    fn instruction_limit_stops_spinning_guest() {
+        let mut vm = NativeVm::new(&kernel(&[0x0000006f]), 1).unwrap();
+        vm.set_serial(Box::new(Null));
+        vm.set_limits(RunLimits {
+            max_instructions: Some(100_000),
+            ..Default::default()
+        });
+        vm.run();
+        assert_eq!(
+            vm.outcome(),
+            RunOutcome::Limit(Limit::Instructions(100_000))
+        );
+    }
+
+    #[test]
+    fn console_patterns_decide_the_run() {
+        let run = |limits: RunLimits, finish: bool| {
+            let mut vm = NativeVm::new(&printing_kernel(finish), 1).unwrap();
+            vm.set_serial(Box::new(Null));
+            vm.set_limits(RunLimits {
+                wall_time: Some(Duration::from_secs(30)),
+                ..limits
+            });
+            vm.run();
+            vm.outcome()
+        };
+        let re = |s: &str| Regex::new(s).unwrap();
+
+        let expect = vec![re("^ok$"), re("boot"), re("done")];
+        let outcome = run(
+            RunLimits {
+                expect,
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(outcome, RunOutcome::Expected);
+
+        let fail_on = vec![re("b..t")];
+        let outcome = run(
+            RunLimits {
+                fail_on,
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(outcome, RunOutcome::FailOn("boot done".into()));
+
+        // Out of order: "ok" never follows "done".
+        let expect = vec![re("done"), re("ok")];
+        let outcome = run(
+            RunLimits {
+                expect,
+                ..Default::default()
+            },
+            true,
+        );
+        assert_eq!(outcome, RunOutcome::ExpectMissing("ok".into()));
+    }
+}
@@ -0,0 +1,1066 @@
+//! Interactive monitor for the native VM.
+//!
+//! `Ctrl-A c` switches the terminal between the guest's UART and a `(vm)`
+//! prompt, in the spirit of QEMU's HMP monitor. Guest output is held back
+//! while the prompt is up and the guest keeps running until `stop`.
+//!
+//! Commands run on hart 0's thread. State of the other harts is reached
+//! through their [`HartMailbox`], which a worker services between batches,
+//! so every command sees a hart between instructions.
+
+use super::{HaltReason, NativeVm, disassembly, step_cpu};
+use crate::Mode;
+use crate::bus::{Bus, VIRTIO_BASE, VIRTIO_STRIDE};
+use crate::coredump::{HartState, write_core_file};
+use crate::cpu::Cpu;
+use crate::csr::{
+    CSR_MCAUSE, CSR_MEDELEG, CSR_MENVCFG, CSR_MEPC, CSR_MIDELEG, CSR_MIE, CSR_MIP, CSR_MISA,
+    CSR_MSTATUS, CSR_MTVAL, CSR_MTVEC, CSR_SATP, CSR_SCAUSE, CSR_SEPC, CSR_SSCRATCH, CSR_STIMECMP,
+    CSR_STVAL, CSR_STVEC, csr_name,
+};
+use crate::devices::plic::{Plic, VIRTIO0_IRQ};
+use crate::devices::virtio::device::{self, QueueState};
+use crate::engine::disasm::ABI_NAMES;
+use crate::mmu::{self, PERM_A, PERM_D, PERM_G, PERM_R, PERM_U, PERM_W, PERM_X, TlbEntry};
+use crate::snapshot::{CpuSnapshot, DeviceSnapshot, MemRegionSnapshot, SNAPSHOT_VERSION, Snapshot};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const PROMPT: &str = "(vm) ";
+
+/// CSRs listed by `info csrs`.
+const MONITOR_CSRS: [u16; 18] = [
+    CSR_MSTATUS,
+    CSR_MISA,
+    CSR_MEDELEG,
+    CSR_MIDELEG,
+    CSR_MIE,
+    CSR_MIP,
+    CSR_MTVEC,
+    CSR_MEPC,
+    CSR_MCAUSE,
+    CSR_MTVAL,
+    CSR_MENVCFG,
+    CSR_STVEC,
+    CSR_SSCRATCH,
+    CSR_SEPC,
+    CSR_SCAUSE,
+    CSR_STVAL,
+    CSR_STIMECMP,
+    CSR_SATP,
+];
+
+const HELP: &str = "\
+info status                    VM state, hart count and mtime
+info cpus                      pc and privilege mode of every hart
+info registers [HART]          integer registers
+info csrs [HART]               machine and supervisor CSRs
+info tlb [HART]                valid TLB entries
+info cache [HART]              block cache statistics
+info mem [HART]                page table mappings
+info pic                       PLIC pending, enable and threshold state
+info virtio                    virtio-mmio devices and virtqueues
+cpu HART                       select the default hart
+x/FMT ADDR                     examine virtual memory (e.g. x/16gx $sp)
+xp/FMT ADDR                    examine physical memory
+gva2gpa ADDR                   translate a virtual address
+stop | cont                    pause or resume all harts
+step [N]                       execute N instructions on a paused hart
+savevm FILE | loadvm FILE      save or restore CPU, CLINT, PLIC, UART and DRAM
+irq N                          raise PLIC source N
+ipi HART                       raise a software interrupt (MSIP) on HART
+nmi [HART]                     non-maskable interrupt: trap to mtvec in M-mode
+dump-guest-memory [FILE]       write an ELF core file
+quit                           stop the VM
+
+FMT is [count][format][size]: format x, d, u, c or i; size b, h, w or g.
+ADDR is a number or $reg[+/-offset], e.g. $pc, $sp+16, $a0.";
+
+/// Line editor and selection state of the monitor prompt.
+#[derive(Debug, Default)]
+pub(super) struct Monitor {
+    active: bool,
+    line: String,
+    /// Hart used by commands that take an optional hart (`cpu N`).
+    hart: usize,
+}
+
+impl Monitor {
+    pub(super) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Switch between the guest console and the monitor prompt.
+    pub(super) fn toggle(&mut self) {
+        self.active = !self.active;
+        self.line.clear();
+        if self.active {
+            print!("\r\n[VM] Monitor ('help' lists commands, Ctrl-A c returns to the guest)\r\n");
+            print!("{}", PROMPT);
+        } else {
+            print!("\r\n");
+        }
+        io::stdout().flush().ok();
+    }
+
+    /// Feed one byte of terminal input. Returns the line when Enter is
+    /// pressed.
+    pub(super) fn feed(&mut self, byte: u8) -> Option<String> {
+        let echo = match byte {
+            b'\r' | b'\n' => {
+                print!("\r\n");
+                io::stdout().flush().ok();
+                return Some(std::mem::take(&mut self.line));
+            }
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    "\x08 \x08".to_string()
+                } else {
+                    String::new()
+                }
+            }
+            // Ctrl-U: kill the line
+            0x15 => {
+                let erase = "\x08 \x08".repeat(self.line.len());
+                self.line.clear();
+                erase
+            }
+            0x20..=0x7e => {
+                self.line.push(byte as char);
+                (byte as char).to_string()
+            }
+            _ => String::new(),
+        };
+        print!("{}", echo);
+        io::stdout().flush().ok();
+        None
+    }
+
+    /// Print command output followed by a fresh prompt.
+    pub(super) fn print(&self, text: &str) {
+        for line in text.lines() {
+            print!("{}\r\n", line);
+        }
+        if self.active {
+            print!("{}", PROMPT);
+        }
+        io::stdout().flush().ok();
+    }
+}
+
+/// A memory operand: a literal or a register plus an offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Addr {
+    Value(u64),
+    /// Register number (32 = pc) and a byte offset.
+    Reg(usize, i64),
+}
+
+/// `/FMT` suffix of `x` and `xp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Format {
+    pub count: usize,
+    pub kind: char,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Info {
+    Status,
+    Cpus,
+    Registers(Option<usize>),
+    Csrs(Option<usize>),
+    Tlb(Option<usize>),
+    Cache(Option<usize>),
+    Mem(Option<usize>),
+    Pic,
+    Virtio,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Command {
+    Help,
+    Info(Info),
+    Cpu(usize),
+    Examine {
+        format: Format,
+        addr: Addr,
+        physical: bool,
+    },
+    Translate(Addr),
+    Stop,
+    Cont,
+    Step(u64),
+    SaveVm(PathBuf),
+    LoadVm(PathBuf),
+    Irq(u32),
+    Ipi(usize),
+    Nmi(Option<usize>),
+    Dump(Option<PathBuf>),
+    Quit,
+}
+
+/// Parse one monitor command line.
+pub(super) fn parse(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let Some(head) = words.next() else {
+        return Err("Empty command".to_string());
+    };
+    let args: Vec<&str> = words.collect();
+    let hart_arg = |args: &[&str]| -> Result<Option<usize>, String> {
+        match args {
+            [] => Ok(None),
+            [hart] => parse_number(hart).map(|h| Some(h as usize)),
+            _ => Err("Too many arguments".to_string()),
+        }
+    };
+    let one = |args: &[&str]| -> Result<String, String> {
+        match args {
+            [arg] => Ok(arg.to_string()),
+            [] => Err(format!("'{}' needs an argument", head)),
+            _ => Err("Too many arguments".to_string()),
+        }
+    };
+
+    let (name, suffix) = match head.split_once('/') {
+        Some((name, suffix)) => (name, Some(suffix)),
+        None => (head, None),
+    };
+
+    let command = match name {
+        "help" | "?" => Command::Help,
+        "info" => {
+            let Some((what, rest)) = args.split_first() else {
+                return Err("'info' needs a subcommand (see 'help')".to_string());
+            };
+            Command::Info(match *what {
+                "status" => Info::Status,
+                "cpus" => Info::Cpus,
+                "registers" | "regs" => Info::Registers(hart_arg(rest)?),
+                "csrs" => Info::Csrs(hart_arg(rest)?),
+                "tlb" => Info::Tlb(hart_arg(rest)?),
+                "cache" => Info::Cache(hart_arg(rest)?),
+                "mem" => Info::Mem(hart_arg(rest)?),
+                "pic" => Info::Pic,
+                "virtio" => Info::Virtio,
+                other => return Err(format!("Unknown info subcommand '{}'", other)),
+            })
+        }
+        "cpu" => Command::Cpu(parse_number(&one(&args)?)? as usize),
+        "x" | "xp" => Command::Examine {
+            format: parse_format(suffix.unwrap_or(""))?,
+            addr: parse_addr(&one(&args)?)?,
+            physical: name == "xp",
+        },
+        "gva2gpa" => Command::Translate(parse_addr(&one(&args)?)?),
+        "stop" => Command::Stop,
+        "cont" | "c" => Command::Cont,
+        "step" | "s" => match args.as_slice() {
+            [] => Command::Step(1),
+            [n] => Command::Step(parse_number(n)?),
+            _ => return Err("Too many arguments".to_string()),
+        },
+        "savevm" => Command::SaveVm(one(&args)?.into()),
+        "loadvm" => Command::LoadVm(one(&args)?.into()),
+        "irq" => Command::Irq(parse_number(&one(&args)?)? as u32),
+        "ipi" => Command::Ipi(parse_number(&one(&args)?)? as usize),
+        "nmi" => Command::Nmi(hart_arg(&args)?),
+        "dump-guest-memory" => match args.as_slice() {
+            [] => Command::Dump(None),
+            [path] => Command::Dump(Some(path.into())),
+            _ => return Err("Too many arguments".to_string()),
+        },
+        "quit" | "q" => Command::Quit,
+        other => return Err(format!("Unknown command '{}' (try 'help')", other)),
+    };
+    if suffix.is_some() && !matches!(command, Command::Examine { .. }) {
+        return Err(format!("'{}' does not take a format", name));
+    }
+    Ok(command)
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid number '{}'", text))
+}
+
+fn parse_format(text: &str) -> Result<Format, String> {
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let count = if digits == 0 {
+        1
+    } else {
+        text[..digits]
+            .parse()
+            .map_err(|_| format!("Invalid count in '/{}'", text))?
+    };
+    let mut format = Format {
+        count,
+        kind: 'x',
+        size: 4,
+    };
+    for c in text[digits..].chars() {
+        match c {
+            'x' | 'd' | 'u' | 'i' => format.kind = c,
+            'c' => {
+                format.kind = c;
+                format.size = 1;
+            }
+            'b' => format.size = 1,
+            'h' => format.size = 2,
+            'w' => format.size = 4,
+            'g' => format.size = 8,
+            _ => return Err(format!("Invalid format character '{}'", c)),
+        }
+    }
+    Ok(format)
+}
+
+fn parse_addr(text: &str) -> Result<Addr, String> {
+    let Some(expr) = text.strip_prefix('$') else {
+        return parse_number(text).map(Addr::Value);
+    };
+    let split = expr.find(['+', '-']).unwrap_or(expr.len());
+    let (name, offset) = expr.split_at(split);
+    let reg = match name {
+        "pc" => 32,
+        "fp" => 8,
+        _ => match name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+            Some(n) if n < 32 => n,
+            _ => ABI_NAMES
+                .iter()
+                .position(|&abi| abi == name)
+                .ok_or_else(|| format!("Unknown register '${}'", name))?,
+        },
+    };
+    let offset = match offset.split_at_checked(1) {
+        Some(("+", n)) => parse_number(n)? as i64,
+        Some(("-", n)) => (parse_number(n)? as i64).wrapping_neg(),
+        _ => 0,
+    };
+    Ok(Addr::Reg(reg, offset))
+}
+
+/// Register and translation state of a hart.
+#[derive(Debug, Clone, Copy)]
+struct HartView {
+    pc: u64,
+    regs: [u64; 32],
+    mode: Mode,
+    satp: u64,
+}
+
+impl HartView {
+    fn capture(cpu: &Cpu) -> Self {
+        Self {
+            pc: cpu.pc,
+            regs: cpu.regs,
+            mode: cpu.mode,
+            satp: cpu.csrs[CSR_SATP as usize],
+        }
+    }
+
+    fn resolve(&self, addr: Addr) -> u64 {
+        match addr {
+            Addr::Value(value) => value,
+            Addr::Reg(32, offset) => self.pc.wrapping_add_signed(offset),
+            Addr::Reg(reg, offset) => self.regs[reg].wrapping_add_signed(offset),
+        }
+    }
+}
+
+fn mode_letter(mode: Mode) -> char {
+    match mode {
+        Mode::User => 'U',
+        Mode::Supervisor => 'S',
+        Mode::Machine => 'M',
+    }
+}
+
+/// `rwxugad` with `-` for clear bits, from TLB permission bits.
+fn perm_string(flags: u8) -> String {
+    [
+        (PERM_R, 'r'),
+        (PERM_W, 'w'),
+        (PERM_X, 'x'),
+        (PERM_U, 'u'),
+        (PERM_G, 'g'),
+        (PERM_A, 'a'),
+        (PERM_D, 'd'),
+    ]
+    .iter()
+    .map(|&(bit, c)| if flags & bit != 0 { c } else { '-' })
+    .collect()
+}
+
+/// Same as [`perm_string`], from the low bits of a PTE (V, R, W, X, U, G, A, D).
+fn pte_string(flags: u8) -> String {
+    "rwxugad"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if flags & (2 << i) != 0 { c } else { '-' })
+        .collect()
+}
+
+fn device_name(id: u32) -> &'static str {
+    match id {
+        device::VIRTIO_NET_DEVICE_ID => "net",
+        device::VIRTIO_BLK_DEVICE_ID => "block",
+        device::VIRTIO_RNG_DEVICE_ID => "rng",
+        device::VIRTIO_9P_DEVICE_ID => "9p",
+        device::VIRTIO_GPU_DEVICE_ID => "gpu",
+        device::VIRTIO_INPUT_DEVICE_ID => "input",
+        _ => "unknown",
+    }
+}
+
+impl NativeVm {
+    /// Run one monitor command (as typed at the `(vm)` prompt) against a VM
+    /// that is not running, i.e. before [`NativeVm::run`].
+    ///
+    /// While the VM runs, the same commands are available from the console
+    /// with `Ctrl-A c`.
+    pub fn monitor_command(&mut self, line: &str) -> Result<String, String> {
+        let Some(mut cpu) = self.primary_cpu.take() else {
+            return Err("VM is running; use the console monitor (Ctrl-A c)".to_string());
+        };
+        let mut monitor = std::mem::take(&mut self.monitor);
+        let result = parse(line).and_then(|cmd| self.run_command(&mut cpu, &mut monitor, cmd));
+        self.monitor = monitor;
+        self.primary_cpu = Some(cpu);
+        result
+    }
+
+    /// Execute a line typed at the monitor prompt on hart 0's thread.
+    pub(super) fn monitor_execute(
+        &self,
+        cpu: &mut Cpu,
+        monitor: &mut Monitor,
+        line: &str,
+    ) -> String {
+        if line.trim().is_empty() {
+            return String::new();
+        }
+        match parse(line).and_then(|cmd| self.run_command(cpu, monitor, cmd)) {
+            Ok(output) => output,
+            Err(e) => e,
+        }
+    }
+
+    /// Run `f` against `hart`'s CPU. Hart 0 is `cpu`; other harts run `f`
+    /// on their own thread at the end of their current batch.
+    fn with_hart<R, F>(&self, cpu: &mut Cpu, hart: usize, f: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Cpu) -> R + Send + 'static,
+    {
+        if hart >= self.num_harts {
+            return Err(format!("No hart {} (have {})", hart, self.num_harts));
+        }
+        if hart == 0 {
+            return Ok(f(cpu));
+        }
+        self.handle().call(hart, f)
+    }
+
+    fn run_command(
+        &self,
+        cpu: &mut Cpu,
+        monitor: &mut Monitor,
+        cmd: Command,
+    ) -> Result<String, String> {
+        match cmd {
+            Command::Help => Ok(HELP.to_string()),
+            Command::Info(info) => self.info(cpu, info, monitor.hart),
+            Command::Cpu(hart) => {
+                if hart >= self.num_harts {
+                    return Err(format!("No hart {} (have {})", hart, self.num_harts));
+                }
+                monitor.hart = hart;
+                Ok(format!("Selected hart {}", hart))
+            }
+            Command::Examine {
+                format,
+                addr,
+                physical,
+            } => {
+                let view = self.with_hart(cpu, monitor.hart, |cpu| HartView::capture(cpu))?;
+                let addr = view.resolve(addr);
+                let (mode, satp) = if physical {
+                    (Mode::Machine, 0)
+                } else {
+                    (view.mode, view.satp)
+                };
+                Ok(self.examine(mode, satp, addr, format))
+            }
+            Command::Translate(addr) => {
+                let view = self.with_hart(cpu, monitor.hart, |cpu| HartView::capture(cpu))?;
+                let vaddr = view.resolve(addr);
+                match mmu::peek_translate(&*self.bus, view.mode, view.satp, vaddr) {
+                    Some(paddr) => Ok(format!("gpa: 0x{:x}", paddr)),
+                    None => Err(format!("0x{:x} is not mapped", vaddr)),
+                }
+            }
+            Command::Stop => {
+                self.pause()?;
+                Ok("VM paused".to_string())
+            }
+            Command::Cont => {
+                self.resume();
+                Ok(String::new())
+            }
+            Command::Step(count) => self.step(cpu, monitor.hart, count),
+            Command::SaveVm(path) => self.save_vm(cpu, &path),
+            Command::LoadVm(path) => self.load_vm(cpu, &path),
+            Command::Irq(irq) => {
+                if irq == 0 || irq >= 32 {
+                    return Err(format!("PLIC source {} out of range (1-31)", irq));
+                }
+                self.bus.plic.update_pending(irq);
+                for hart in 0..self.num_harts {
+                    self.bus.clint.wake_hart(hart);
+                }
+                Ok(String::new())
+            }
+            Command::Ipi(hart) => {
+                if hart >= self.num_harts {
+                    return Err(format!("No hart {} (have {})", hart, self.num_harts));
+                }
+                self.bus.clint.set_msip(hart, 1);
+                Ok(String::new())
+            }
+            Command::Nmi(hart) => {
+                self.with_hart(cpu, hart.unwrap_or(monitor.hart), |cpu| cpu.take_nmi())?;
+                Ok(String::new())
+            }
+            Command::Dump(path) => {
+                let path = path.unwrap_or_else(|| self.core_path());
+                let mut harts = Vec::with_capacity(self.num_harts);
+                for hart in 0..self.num_harts {
+                    harts.push(self.with_hart(cpu, hart, |cpu| HartState::capture(cpu))?);
+                }
+                write_core_file(&path, &self.bus.dram, &harts)?;
+                Ok(format!("Wrote core file {}", path.display()))
+            }
+            Command::Quit => {
+                self.shared.request_halt();
+                monitor.active = false;
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn info(&self, cpu: &mut Cpu, info: Info, default_hart: usize) -> Result<String, String> {
+        let mut out = String::new();
+        match info {
+            Info::Status => {
+                let state = if self.shared.is_paused() {
+                    "paused"
+                } else {
+                    "running"
+                };
+                out += &format!("VM status: {}\n", state);
+                out += &format!("harts: {}\n", self.num_harts);
+                out += &format!("mtime: {}\n", self.bus.clint.mtime());
+            }
+            Info::Cpus => {
+                for hart in 0..self.num_harts {
+                    match self.with_hart(cpu, hart, |cpu| HartView::capture(cpu)) {
+                        Ok(view) => {
+                            let marker = if hart == default_hart { '*' } else { ' ' };
+                            out += &format!(
+                                "{} hart {}: pc=0x{:016x} mode={}\n",
+                                marker,
+                                hart,
+                                view.pc,
+                                mode_letter(view.mode)
+                            );
+                        }
+                        Err(e) => out += &format!("  hart {}: {}\n", hart, e),
+                    }
+                }
+            }
+            Info::Registers(hart) => {
+                let hart = hart.unwrap_or(default_hart);
+                let view = self.with_hart(cpu, hart, |cpu| HartView::capture(cpu))?;
+                out += &format!(
+                    "hart {}  pc {:016x}  mode {}\n",
+                    hart,
+                    view.pc,
+                    mode_letter(view.mode)
+                );
+                for row in (0..32).step_by(4) {
+                    let line: Vec<String> = (row..row + 4)
+                        .map(|r| format!("{:>4} {:016x}", ABI_NAMES[r], view.regs[r]))
+                        .collect();
+                    out += &line.join("  ");
+                    out.push('\n');
+                }
+            }
+            Info::Csrs(hart) => {
+                let hart = hart.unwrap_or(default_hart);
+                let values = self.with_hart(cpu, hart, |cpu| {
+                    MONITOR_CSRS.map(|csr| cpu.csrs[csr as usize])
+                })?;
+                for (csr, value) in MONITOR_CSRS.iter().zip(values) {
+                    let name = csr_name(*csr).unwrap_or("?");
+                    out += &format!("{:<10} 0x{:016x}\n", name, value);
+                }
+            }
+            Info::Tlb(hart) => {
+                let hart = hart.unwrap_or(default_hart);
+                let (capacity, entries) = self.with_hart(cpu, hart, |cpu| {
+                    let entries: Vec<TlbEntry> = cpu.tlb.entries().copied().collect();
+                    (cpu.tlb.capacity(), entries)
+                })?;
+                out += &format!("{}/{} entries valid\n", entries.len(), capacity);
+                for entry in entries {
+                    let size = 4u64 << (10 + 9 * entry.level as u64);
+                    out += &format!(
+                        "va 0x{:016x} -> pa 0x{:016x}  {:>4}K  asid {:<5} {}\n",
+                        entry.vpn << 12,
+                        entry.ppn << 12,
+                        size >> 10,
+                        entry.asid,
+                        perm_string(entry.perm)
+                    );
+                }
+            }
+            Info::Cache(hart) => {
+                let hart = hart.unwrap_or(default_hart);
+                let (stats, invalidations, generation) = self.with_hart(cpu, hart, |cpu| {
+                    let cache = &cpu.block_cache;
+                    (cache.stats(), cache.invalidations, cache.generation)
+                })?;
+                let (hits, misses, blocks, hit_rate) = stats;
+                out += &format!("blocks:        {}\n", blocks);
+                out += &format!("hits:          {}\n", hits);
+                out += &format!("misses:        {}\n", misses);
+                out += &format!("hit rate:      {:.2}%\n", hit_rate * 100.0);
+                out += &format!("invalidations: {}\n", invalidations);
+                out += &format!("generation:    {}\n", generation);
+            }
+            Info::Mem(hart) => {
+                let hart = hart.unwrap_or(default_hart);
+                let view = self.with_hart(cpu, hart, |cpu| HartView::capture(cpu))?;
+                let mappings = mmu::walk_page_table(&*self.bus, view.satp);
+                if view.satp >> 60 == 0 {
+                    out += "Paging disabled (satp mode Bare)\n";
+                }
+                for m in mappings {
+                    out += &format!(
+                        "{:016x}-{:016x} {:016x} {}\n",
+                        m.va,
+                        m.va.wrapping_add(m.len),
+                        m.pa,
+                        pte_string(m.flags)
+                    );
+                }
+            }
+            Info::Pic => out += &self.pic_status(),
+            Info::Virtio => out += &self.virtio_status(),
+        }
+        Ok(out)
+    }
+
+    fn pic_status(&self) -> String {
+        let plic = &self.bus.plic;
+        let pending = plic.get_pending();
+        let enable = plic.get_enable();
+        let threshold = plic.get_threshold();
+        let active = plic.get_active();
+
+        let mut out = format!("pending 0x{:08x}\n", pending);
+        for hart in 0..self.num_harts {
+            for (ctx, mode) in [(Plic::m_context(hart), 'M'), (Plic::s_context(hart), 'S')] {
+                out += &format!(
+                    "context {} (hart {} {}): enable 0x{:08x} threshold {} claimed 0x{:08x}\n",
+                    ctx, hart, mode, enable[ctx], threshold[ctx], active[ctx]
+                );
+            }
+        }
+        let priorities: Vec<String> = plic
+            .get_priority()
+            .iter()
+            .enumerate()
+            .filter(|&(_, &p)| p != 0)
+            .map(|(source, p)| format!("{}={}", source, p))
+            .collect();
+        out += &format!("priority {}\n", priorities.join(" "));
+        out
+    }
+
+    fn virtio_status(&self) -> String {
+        let mut out = String::new();
+        if self.bus.virtio_devices.is_empty() {
+            out += "No virtio devices\n";
+        }
+        for (i, dev) in self.bus.virtio_devices.iter().enumerate() {
+            let status = dev.read(device::STATUS_OFFSET).unwrap_or(0);
+            let isr = dev.read(device::INTERRUPT_STATUS_OFFSET).unwrap_or(0);
+            out += &format!(
+                "virtio{} {} at 0x{:x} irq {}: status 0x{:x} interrupt 0x{:x}\n",
+                i,
+                device_name(dev.device_id()),
+                VIRTIO_BASE + i as u64 * VIRTIO_STRIDE,
+                VIRTIO0_IRQ + i as u32,
+                status,
+                isr
+            );
+            for queue in dev.queues() {
+                out += &self.queue_status(&queue);
+            }
+        }
+        out
+    }
+
+    fn queue_status(&self, q: &QueueState) -> String {
+        if !q.ready {
+            return format!("  queue {}: not ready\n", q.index);
+        }
+        // Ring indices live in guest memory, after the 16-bit flags field.
+        let avail_idx = self.bus.read16(q.avail + 2).ok();
+        let used_idx = self.bus.read16(q.used + 2).ok();
+        let show = |idx: Option<u16>| idx.map_or("?".to_string(), |i| i.to_string());
+        let in_flight = avail_idx.map(|a| a.wrapping_sub(q.last_avail_idx));
+        format!(
+            "  queue {}: size {} avail.idx {} used.idx {} device idx {} pending {} \
+             desc 0x{:x} avail 0x{:x} used 0x{:x}\n",
+            q.index,
+            q.num,
+            show(avail_idx),
+            show(used_idx),
+            q.last_avail_idx,
+            show(in_flight),
+            q.desc,
+            q.avail,
+            q.used
+        )
+    }
+
+    fn examine(&self, mode: Mode, satp: u64, addr: u64, format: Format) -> String {
+        if format.kind == 'i' {
+            let mut out = disassembly(&*self.bus, mode, satp, addr, format.count).join("\n");
+            out.push('\n');
+            return out;
+        }
+
+        let per_line = match format.kind {
+            'c' => 16,
+            _ => (16 / format.size as usize).max(2),
+        };
+        let mut out = String::new();
+        for i in 0..format.count {
+            let at = addr.wrapping_add(i as u64 * format.size);
+            if i % per_line == 0 {
+                if i != 0 {
+                    out.push('\n');
+                }
+                out += &format!("{:016x}:", at);
+            }
+            let value = mmu::peek_translate(&*self.bus, mode, satp, at)
+                .and_then(|pa| self.bus.load(pa, format.size).ok());
+            let Some(value) = value else {
+                out += " <unmapped>";
+                break;
+            };
+            let bits = format.size * 8;
+            out += &match format.kind {
+                'd' => format!(" {}", ((value << (64 - bits)) as i64) >> (64 - bits)),
+                'u' => format!(" {}", value),
+                'c' => match value as u8 {
+                    c @ 0x20..=0x7e => format!(" {}", c as char),
+                    c => format!(" \\x{:02x}", c),
+                },
+                _ => format!(" 0x{:0width$x}", value, width = 2 * format.size as usize),
+            };
+        }
+        out.push('\n');
+        out
+    }
+
+    fn step(&self, cpu: &mut Cpu, hart: usize, count: u64) -> Result<String, String> {
+        if !self.shared.is_paused() {
+            return Err("VM is running; 'stop' it first".to_string());
+        }
+        let bus = Arc::clone(&self.bus);
+        let (steps, halt, view) = self.with_hart(cpu, hart, move |cpu| {
+            let (steps, halt) = step_cpu(cpu, &bus, hart, count);
+            (steps, halt, HartView::capture(cpu))
+        })?;
+
+        let mut out = format!("hart {}: executed {} instruction(s)\n", hart, steps);
+        match &halt {
+            Some(HaltReason::Shutdown(code)) => {
+                out += &format!("Shutdown requested (code: {:#x})\n", code);
+            }
+            Some(HaltReason::Fatal(msg, pc)) => {
+                out += &format!("Fatal error: {} at PC=0x{:x}\n", msg, pc);
+            }
+            None => {
+                let next = disassembly(&*self.bus, view.mode, view.satp, view.pc, 1);
+                out += &next.join("\n");
+                out.push('\n');
+            }
+        }
+        self.handle().finish_step(hart, steps, halt);
+        Ok(out)
+    }
+
+    /// Pause the VM (if needed) for the duration of `f`.
+    fn while_paused<R>(&self, f: impl FnOnce() -> Result<R, String>) -> Result<R, String> {
+        let was_paused = self.shared.is_paused();
+        self.pause()?;
+        let result = f();
+        if !was_paused {
+            self.resume();
+        }
+        result
+    }
+
+    pub(super) fn save_vm(&self, cpu: &mut Cpu, path: &PathBuf) -> Result<String, String> {
+        let snapshot = self.while_paused(|| -> Result<Snapshot, String> {
+            let mut harts = Vec::with_capacity(self.num_harts.saturating_sub(1));
+            for hart in 1..self.num_harts {
+                harts.push(self.with_hart(cpu, hart, |cpu| CpuSnapshot::capture(cpu))?);
+            }
+            Ok(Snapshot {
+                version: SNAPSHOT_VERSION.to_string(),
+                cpu: CpuSnapshot::capture(cpu),
+                harts,
+                devices: DeviceSnapshot::capture(&self.bus),
+                memory: vec![MemRegionSnapshot::capture(&self.bus.dram)],
+            })
+        })?;
+
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create '{}': {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        bincode::serialize_into(&mut out, &snapshot)
+            .map_err(|e| e.to_string())
+            .and_then(|_| out.flush().map_err(|e| e.to_string()))
+            .map_err(|e| format!("Failed to write '{}': {}", path.display(), e))?;
+        Ok(format!("Saved snapshot to {}", path.display()))
+    }
+
+    pub(super) fn load_vm(&self, cpu: &mut Cpu, path: &PathBuf) -> Result<String, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?;
+        let snapshot: Snapshot = bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+        snapshot.check_version()?;
+        if snapshot.harts.len() + 1 != self.num_harts {
+            return Err(format!(
+                "Snapshot has {} harts, VM has {}",
+                snapshot.harts.len() + 1,
+                self.num_harts
+            ));
+        }
+        let region = snapshot
+            .memory
+            .first()
+            .ok_or_else(|| "snapshot missing primary memory region".to_string())?;
+
+        self.while_paused(|| -> Result<(), String> {
+            region.restore(&self.bus.dram)?;
+            snapshot.devices.restore(&self.bus);
+            snapshot.cpu.restore(cpu);
+            for (i, state) in snapshot.harts.iter().enumerate() {
+                let state = state.clone();
+                self.with_hart(cpu, i + 1, move |cpu| state.restore(cpu))?;
+            }
+            Ok(())
+        })?;
+        Ok(format!("Loaded snapshot from {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+
+    // addi t0, zero, 42 ; c.ret ; unimp
+    const KERNEL: [u8; 8] = [0x93, 0x02, 0xa0, 0x02, 0x82, 0x80, 0x00, 0x00];
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse("x/16gx 0x80000000"),
+            Ok(Command::Examine {
+                format: Format {
+                    count: 16,
+                    kind: 'x',
+                    size: 8
+                },
+                addr: Addr::Value(0x8000_0000),
+                physical: false,
+            })
+        );
+        assert_eq!(
+            parse("xp/4i $pc"),
+            Ok(Command::Examine {
+                format: Format {
+                    count: 4,
+                    kind: 'i',
+                    size: 4
+                },
+                addr: Addr::Reg(32, 0),
+                physical: true,
+            })
+        );
+        assert_eq!(
+            parse("gva2gpa $sp-16"),
+            Ok(Command::Translate(Addr::Reg(2, -16)))
+        );
+        assert_eq!(
+            parse("gva2gpa $x10+0x8"),
+            Ok(Command::Translate(Addr::Reg(10, 8)))
+        );
+        assert_eq!(
+            parse("info registers 1"),
+            Ok(Command::Info(Info::Registers(Some(1))))
+        );
+        assert_eq!(parse("info tlb"), Ok(Command::Info(Info::Tlb(None))));
+        assert_eq!(parse("step"), Ok(Command::Step(1)));
+        assert_eq!(parse("s 100"), Ok(Command::Step(100)));
+        assert_eq!(parse("dump-guest-memory"), Ok(Command::Dump(None)));
+        assert_eq!(parse("nmi 1"), Ok(Command::Nmi(Some(1))));
+
+        assert!(parse("").is_err());
+        assert!(parse("info").is_err());
+        assert!(parse("x/4q 0").is_err());
+        assert!(parse("x 0 1").is_err());
+        assert!(parse("stop/4").is_err());
+        assert!(parse("gva2gpa $bogus").is_err());
+        assert!(parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut monitor = Monitor::default();
+        for &b in b"info regz" {
+            assert_eq!(monitor.feed(b), None);
+        }
+        monitor.feed(0x7f);
+        monitor.feed(b's');
+        assert_eq!(monitor.feed(b'\r').as_deref(), Some("info regs"));
+        monitor.feed(b'x');
+        monitor.feed(0x15);
+        assert_eq!(monitor.feed(b'\n').as_deref(), Some(""));
+    }
+
+    #[test]
+    fn inspect_and_step_stopped_vm() {
+        let mut vm = NativeVm::new(&KERNEL, 1).unwrap();
+
+        let words = vm.monitor_command("xp/2wx 0x80000000").unwrap();
+        assert_eq!(words, "0000000080000000: 0x02a00293 0x00008082\n");
+        let insns = vm.monitor_command("x/2i $pc").unwrap();
+        assert!(insns.contains("li t0,42"), "{}", insns);
+        assert!(insns.contains("ret"), "{}", insns);
+        assert_eq!(
+            vm.monitor_command("gva2gpa 0x80000004").unwrap(),
+            "gpa: 0x80000004"
+        );
+
+        let regs = vm.monitor_command("info registers").unwrap();
+        assert!(regs.starts_with("hart 0  pc 0000000080000000"), "{}", regs);
+
+        assert!(vm.monitor_command("step").is_err());
+        vm.monitor_command("stop").unwrap();
+        let stepped = vm.monitor_command("step 1").unwrap();
+        assert!(stepped.contains("executed 1 instruction"), "{}", stepped);
+        assert!(
+            stepped.contains("0x0000000080000004: 8082      ret"),
+            "{}",
+            stepped
+        );
+        let regs = vm.monitor_command("info registers 0").unwrap();
+        assert!(regs.contains("  t0 000000000000002a"), "{}", regs);
+
+        assert!(vm.monitor_command("cpu 1").is_err());
+        assert!(vm.monitor_command("info registers 1").is_err());
+    }
+
+    #[test]
+    fn page_table_commands() {
+        let mut vm = NativeVm::new(&KERNEL, 1).unwrap();
+        // Sv39 root with two 1 GiB leaves onto DRAM: identity at 0x8000_0000
+        // (RWX) and the top gigabyte of the address space (RW).
+        let root = DRAM_BASE + 0x10_0000;
+        let leaf = ((DRAM_BASE >> 12) << 10) | 0xc1;
+        vm.bus.write64(root + 2 * 8, leaf | 0b1110).unwrap();
+        vm.bus.write64(root + 511 * 8, leaf | 0b0110).unwrap();
+        let cpu = vm.primary_cpu.as_mut().unwrap();
+        cpu.mode = Mode::Supervisor;
+        cpu.csrs[CSR_SATP as usize] = (8 << 60) | (root >> 12);
+
+        let mem = vm.monitor_command("info mem").unwrap();
+        assert_eq!(
+            mem,
+            "0000000080000000-00000000c0000000 0000000080000000 rwx--ad\n\
+             ffffffffc0000000-0000000000000000 0000000080000000 rw---ad\n"
+        );
+        assert_eq!(
+            vm.monitor_command("gva2gpa 0xffffffffc0000004").unwrap(),
+            "gpa: 0x80000004"
+        );
+        assert!(vm.monitor_command("gva2gpa 0x1000").is_err());
+        assert_eq!(
+            vm.monitor_command("x/2hx 0xffffffffc0000004").unwrap(),
+            "ffffffffc0000004: 0x8082 0x0000\n"
+        );
+        assert_eq!(
+            vm.monitor_command("x/1gx 0x1000").unwrap(),
+            "0000000000001000: <unmapped>\n"
+        );
+    }
+
+    #[test]
+    fn interrupts_and_device_status() {
+        let mut vm = NativeVm::new(&KERNEL, 2).unwrap();
+
+        vm.monitor_command("irq 5").unwrap();
+        assert_eq!(vm.bus.plic.get_pending() & (1 << 5), 1 << 5);
+        let pic = vm.monitor_command("info pic").unwrap();
+        assert!(pic.starts_with("pending 0x00000020"), "{}", pic);
+        assert!(pic.contains("(hart 1 S)"), "{}", pic);
+        assert!(vm.monitor_command("irq 32").is_err());
+
+        vm.monitor_command("ipi 1").unwrap();
+        assert_eq!(vm.bus.clint.get_msip(1), 1);
+
+        // Hart 1 has no thread yet, so it cannot be inspected.
+        let cpus = vm.monitor_command("info cpus").unwrap();
+        assert!(cpus.contains("* hart 0: pc=0x0000000080000000"), "{}", cpus);
+        assert!(cpus.contains("hart 1: Hart 1 has not started"), "{}", cpus);
+
+        let tlb = vm.monitor_command("info tlb").unwrap();
+        assert!(tlb.starts_with("0/64 entries valid"), "{}", tlb);
+        assert!(
+            vm.monitor_command("info virtio")
+                .unwrap()
+                .contains("No virtio devices")
+        );
+
+        // An NMI enters M-mode at the mtvec base, even when vectored.
+        let cpu = vm.primary_cpu.as_mut().unwrap();
+        cpu.mode = Mode::Supervisor;
+        cpu.csrs[CSR_MTVEC as usize] = DRAM_BASE + 0x101;
+        vm.monitor_command("nmi").unwrap();
+        let cpu = vm.primary_cpu.as_ref().unwrap();
+        assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.mode, Mode::Machine);
+        assert_eq!(cpu.csrs[CSR_MEPC as usize], DRAM_BASE);
+        assert_eq!(cpu.csrs[CSR_MCAUSE as usize], 1 << 63);
+        assert_eq!((cpu.csrs[CSR_MSTATUS as usize] >> 11) & 0b11, 1);
+        assert!(vm.monitor_command("nmi 1").is_err());
+    }
+}
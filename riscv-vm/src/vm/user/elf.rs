@@ -0,0 +1,265 @@
+//! ELF image loading and initial process stack for user-mode emulation.
+
+use super::memory::{
+    Access, AddressSpace, PAGE_SIZE, PROT_EXEC, PROT_READ, PROT_WRITE, page_ceil, page_floor,
+};
+use crate::dram::Dram;
+use goblin::elf::{Elf, header::EM_RISCV, header::ET_DYN, program_header};
+
+/// Where an ELF image ended up in the guest address space.
+#[derive(Debug, Clone)]
+pub struct LoadedImage {
+    /// Entry point (relocated).
+    pub entry: u64,
+    /// Load bias added to the image's virtual addresses.
+    pub bias: u64,
+    /// Address of the program headers in guest memory.
+    pub phdr: u64,
+    pub phnum: u64,
+    /// First byte past the highest segment.
+    pub end: u64,
+    /// Requested program interpreter (`PT_INTERP`), for dynamic executables.
+    pub interp: Option<String>,
+}
+
+/// Map the `PT_LOAD` segments of `data` into `mm`.
+///
+/// Position-independent images (`ET_DYN`) are placed at `dyn_base`.
+pub fn load_image(
+    mm: &mut AddressSpace,
+    dram: &Dram,
+    data: &[u8],
+    dyn_base: u64,
+) -> Result<LoadedImage, String> {
+    let elf = Elf::parse(data).map_err(|e| format!("ELF parse error: {}", e))?;
+    if !elf.is_64 || elf.header.e_machine != EM_RISCV {
+        return Err("not a riscv64 ELF executable".to_string());
+    }
+    let bias = if elf.header.e_type == ET_DYN {
+        dyn_base
+    } else {
+        0
+    };
+
+    let mut end = 0;
+    let mut phdr = None;
+    let mut interp = None;
+    // Last page mapped so far, so segments sharing a page keep both protections.
+    let mut last_page: Option<(u64, u32)> = None;
+
+    for ph in &elf.program_headers {
+        match ph.p_type {
+            program_header::PT_PHDR => phdr = Some(ph.p_vaddr + bias),
+            program_header::PT_INTERP => {
+                let raw = data
+                    .get(ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize)
+                    .ok_or("PT_INTERP exceeds file bounds")?;
+                let path = raw.split(|&b| b == 0).next().unwrap_or(&[]);
+                interp = Some(String::from_utf8_lossy(path).into_owned());
+            }
+            program_header::PT_LOAD if ph.p_memsz > 0 => {
+                let prot = segment_prot(ph.p_flags);
+                let vaddr = ph.p_vaddr + bias;
+                let mut start = page_floor(vaddr);
+                let seg_end = page_ceil(vaddr + ph.p_memsz);
+
+                if let Some((page, prev_prot)) = last_page
+                    && page == start
+                {
+                    mm.protect(dram, page, PAGE_SIZE, prev_prot | prot)
+                        .map_err(|_| "overlapping segments".to_string())?;
+                    start += PAGE_SIZE;
+                }
+                if seg_end > start {
+                    mm.map(dram, start, seg_end - start, prot);
+                }
+
+                let file = data
+                    .get(ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize)
+                    .ok_or("segment exceeds file bounds")?;
+                mm.write_bytes(dram, vaddr, file, Access::Force)
+                    .map_err(|e| format!("failed to load segment at 0x{:x}: {:?}", vaddr, e))?;
+
+                if phdr.is_none()
+                    && ph.p_offset <= elf.header.e_phoff
+                    && elf.header.e_phoff < ph.p_offset + ph.p_filesz
+                {
+                    phdr = Some(vaddr + elf.header.e_phoff - ph.p_offset);
+                }
+                last_page = Some((seg_end - PAGE_SIZE, prot));
+                end = end.max(vaddr + ph.p_memsz);
+            }
+            _ => {}
+        }
+    }
+
+    if end == 0 {
+        return Err("ELF has no loadable segments".to_string());
+    }
+    Ok(LoadedImage {
+        entry: elf.entry + bias,
+        bias,
+        phdr: phdr.unwrap_or(0),
+        phnum: elf.header.e_phnum as u64,
+        end,
+        interp,
+    })
+}
+
+fn segment_prot(flags: u32) -> u32 {
+    let mut prot = 0;
+    if flags & program_header::PF_R != 0 {
+        prot |= PROT_READ;
+    }
+    if flags & program_header::PF_W != 0 {
+        prot |= PROT_WRITE;
+    }
+    if flags & program_header::PF_X != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+// Auxiliary vector keys.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// HWCAP has one bit per single-letter extension: rv64imac.
+const HWCAP_RV64IMAC: u64 = hwcap(b'i') | hwcap(b'm') | hwcap(b'a') | hwcap(b'c');
+
+const fn hwcap(ext: u8) -> u64 {
+    1 << (ext - b'a')
+}
+
+/// Build the initial stack (argc, argv, envp, auxv) below `stack_top` and
+/// return the initial stack pointer.
+pub fn build_stack(
+    mm: &mut AddressSpace,
+    dram: &Dram,
+    stack_top: u64,
+    args: &[String],
+    env: &[String],
+    exe: &LoadedImage,
+    interp_base: Option<u64>,
+) -> Result<u64, String> {
+    let fault = |e| format!("failed to build initial stack: {:?}", e);
+    let mut sp = stack_top;
+    let mut push = |mm: &mut AddressSpace, bytes: &[u8]| {
+        sp -= bytes.len() as u64;
+        mm.write_bytes(dram, sp, bytes, Access::Write).map(|_| sp)
+    };
+
+    let mut random = [0u8; 16];
+    unsafe { libc::getrandom(random.as_mut_ptr().cast(), random.len(), 0) };
+    let at_random = push(mm, &random[..]).map_err(fault)?;
+
+    let mut cstr = |mm: &mut AddressSpace, s: &str| {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        push(mm, &bytes[..])
+    };
+    let execfn = cstr(mm, args.first().map_or("", |s| s.as_str())).map_err(fault)?;
+    let envp = env
+        .iter()
+        .rev()
+        .map(|s| cstr(mm, s.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(fault)?;
+    let argv = args
+        .iter()
+        .rev()
+        .map(|s| cstr(mm, s.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(fault)?;
+
+    let auxv = [
+        (AT_PHDR, exe.phdr),
+        (AT_PHENT, 56),
+        (AT_PHNUM, exe.phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, interp_base.unwrap_or(0)),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, exe.entry),
+        (AT_UID, unsafe { libc::getuid() } as u64),
+        (AT_EUID, unsafe { libc::geteuid() } as u64),
+        (AT_GID, unsafe { libc::getgid() } as u64),
+        (AT_EGID, unsafe { libc::getegid() } as u64),
+        (AT_HWCAP, HWCAP_RV64IMAC),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, at_random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = vec![args.len() as u64];
+    words.extend(argv.iter().rev());
+    words.push(0);
+    words.extend(envp.iter().rev());
+    words.push(0);
+    for (key, value) in auxv {
+        words.extend([key, value]);
+    }
+
+    let sp = (sp - 8 * words.len() as u64) & !15;
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    mm.write_bytes(dram, sp, &bytes, Access::Write)
+        .map_err(fault)?;
+    Ok(sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+
+    #[test]
+    fn test_initial_stack_layout() {
+        let dram = Dram::new(DRAM_BASE, 64 * PAGE_SIZE as usize);
+        let mut mm = AddressSpace::new(&dram).unwrap();
+        let top = 0x40_0000;
+        mm.map(
+            &dram,
+            top - 4 * PAGE_SIZE,
+            4 * PAGE_SIZE,
+            PROT_READ | PROT_WRITE,
+        );
+        let exe = LoadedImage {
+            entry: 0x10078,
+            bias: 0,
+            phdr: 0x10040,
+            phnum: 2,
+            end: 0x11000,
+            interp: None,
+        };
+        let args = ["prog".to_string(), "-v".to_string()];
+        let env = ["A=1".to_string()];
+        let sp = build_stack(&mut mm, &dram, top, &args, &env, &exe, None).unwrap();
+
+        assert_eq!(sp % 16, 0);
+        let word = |mm: &mut AddressSpace, i: u64| mm.read_u64(&dram, sp + 8 * i).unwrap();
+        assert_eq!(word(&mut mm, 0), 2);
+        let argv1 = word(&mut mm, 2);
+        assert_eq!(mm.read_cstr(&dram, argv1, 64).unwrap(), b"-v");
+        assert_eq!(word(&mut mm, 3), 0);
+        let envp0 = word(&mut mm, 4);
+        assert_eq!(mm.read_cstr(&dram, envp0, 64).unwrap(), b"A=1");
+        assert_eq!(word(&mut mm, 5), 0);
+        assert_eq!((word(&mut mm, 6), word(&mut mm, 7)), (AT_PHDR, 0x10040));
+    }
+}
@@ -0,0 +1,444 @@
+//! Guest address space for user-mode emulation.
+//!
+//! The guest runs in U-mode with Sv39 translation enabled, so its virtual
+//! address space is an ordinary set of page tables living in DRAM. Physical
+//! frames are handed out from DRAM on demand: mapping a region only records a
+//! [`Vma`], and pages are populated (zero-filled) by the page fault handler
+//! or when the kernel side copies data into them.
+//!
+//! Pages made inaccessible with `PROT_NONE` keep their frame number in an
+//! invalid PTE (tagged with a software bit) so their contents survive a later
+//! `mprotect` back to an accessible protection.
+
+use crate::dram::Dram;
+use std::collections::BTreeMap;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+pub const PROT_NONE: u32 = 0;
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+/// Software bit: frame is allocated but the page is `PROT_NONE`.
+const PTE_SW_NONE: u64 = 1 << 8;
+
+const SATP_MODE_SV39: u64 = 8 << 60;
+
+pub fn page_floor(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+pub fn page_ceil(addr: u64) -> u64 {
+    addr.wrapping_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Kind of access being checked against a mapping's protection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Exec,
+    /// Kernel-side write that ignores the protection (ELF loading).
+    Force,
+}
+
+/// Why an access could not be satisfied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemFault {
+    /// No mapping covers the address.
+    Unmapped,
+    /// The mapping does not allow this kind of access.
+    Denied,
+    /// Guest DRAM has no free frames left.
+    OutOfMemory,
+}
+
+/// A mapped region; keyed by its start address in [`AddressSpace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub end: u64,
+    pub prot: u32,
+}
+
+pub struct AddressSpace {
+    root: u64,
+    next_frame: u64,
+    frame_end: u64,
+    free_frames: Vec<u64>,
+    vmas: BTreeMap<u64, Vma>,
+}
+
+impl AddressSpace {
+    /// Create an empty address space whose page tables and pages live in `dram`.
+    pub fn new(dram: &Dram) -> Result<Self, String> {
+        let mut mm = Self {
+            root: 0,
+            next_frame: dram.base,
+            frame_end: dram.base + dram.size() as u64,
+            free_frames: Vec::new(),
+            vmas: BTreeMap::new(),
+        };
+        mm.root = mm
+            .alloc_frame(dram)
+            .ok_or("guest memory too small for page tables")?;
+        Ok(mm)
+    }
+
+    /// `satp` value selecting this address space.
+    pub fn satp(&self) -> u64 {
+        SATP_MODE_SV39 | (self.root >> 12)
+    }
+
+    /// Mapping containing `addr`, if any.
+    pub fn find(&self, addr: u64) -> Option<(u64, Vma)> {
+        let (&start, &vma) = self.vmas.range(..=addr).next_back()?;
+        (addr < vma.end).then_some((start, vma))
+    }
+
+    /// Whether no mapping overlaps `[start, start + len)`.
+    pub fn is_free(&self, start: u64, len: u64) -> bool {
+        let end = start.saturating_add(len);
+        if let Some((_, vma)) = self.vmas.range(..start).next_back()
+            && vma.end > start
+        {
+            return false;
+        }
+        self.vmas.range(start..end).next().is_none()
+    }
+
+    /// Highest free, page-aligned range of `len` bytes within `[bottom, top)`.
+    pub fn find_free(&self, len: u64, bottom: u64, top: u64) -> Option<u64> {
+        let mut end = top;
+        for (&start, vma) in self.vmas.range(..top).rev() {
+            if vma.end <= end.saturating_sub(len) {
+                break;
+            }
+            end = end.min(start);
+        }
+        end.checked_sub(len).filter(|&start| start >= bottom)
+    }
+
+    /// Map `[start, start + len)` with `prot`, replacing existing mappings.
+    ///
+    /// Pages are populated lazily.
+    pub fn map(&mut self, dram: &Dram, start: u64, len: u64, prot: u32) {
+        let end = page_ceil(start + len);
+        let start = page_floor(start);
+        self.unmap(dram, start, end - start);
+        self.vmas.insert(start, Vma { end, prot });
+    }
+
+    /// Remove mappings in `[start, start + len)` and free their frames.
+    pub fn unmap(&mut self, dram: &Dram, start: u64, len: u64) {
+        let end = page_ceil(start + len);
+        let start = page_floor(start);
+        for (piece_start, piece) in self.carve(start, end) {
+            for va in (piece_start..piece.end).step_by(PAGE_SIZE as usize) {
+                self.release(dram, va);
+            }
+        }
+    }
+
+    /// Drop the contents of `[start, start + len)` but keep the mappings,
+    /// so the pages read as zero when next touched (`MADV_DONTNEED`).
+    pub fn discard(&mut self, dram: &Dram, start: u64, len: u64) {
+        let end = page_ceil(start + len);
+        for va in (page_floor(start)..end).step_by(PAGE_SIZE as usize) {
+            self.release(dram, va);
+        }
+    }
+
+    /// Change the protection of `[start, start + len)`.
+    ///
+    /// Fails if part of the range is not mapped.
+    pub fn protect(
+        &mut self,
+        dram: &Dram,
+        start: u64,
+        len: u64,
+        prot: u32,
+    ) -> Result<(), MemFault> {
+        let end = page_ceil(start + len);
+        let start = page_floor(start);
+        let mut va = start;
+        while va < end {
+            let (_, vma) = self.find(va).ok_or(MemFault::Unmapped)?;
+            va = vma.end;
+        }
+
+        for (piece_start, piece) in self.carve(start, end) {
+            self.vmas.insert(piece_start, Vma { prot, ..piece });
+            for va in (piece_start..piece.end).step_by(PAGE_SIZE as usize) {
+                if let Some(slot) = self.pte_slot(dram, va, false) {
+                    let pte = load(dram, slot);
+                    if pte & (PTE_V | PTE_SW_NONE) != 0 {
+                        store(dram, slot, leaf_pte((pte >> 10) << 12, prot));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve a guest page fault at `addr` by populating the page.
+    pub fn fault(&mut self, dram: &Dram, addr: u64, access: Access) -> Result<(), MemFault> {
+        self.access(dram, addr, access).map(|_| ())
+    }
+
+    /// Physical address backing `addr`, populating the page if the mapping
+    /// allows `access`.
+    pub fn access(&mut self, dram: &Dram, addr: u64, access: Access) -> Result<u64, MemFault> {
+        let (_, vma) = self.find(addr).ok_or(MemFault::Unmapped)?;
+        let allowed = match access {
+            Access::Read => vma.prot & (PROT_READ | PROT_WRITE | PROT_EXEC) != 0,
+            Access::Write => vma.prot & PROT_WRITE != 0,
+            Access::Exec => vma.prot & PROT_EXEC != 0,
+            Access::Force => true,
+        };
+        if !allowed {
+            return Err(MemFault::Denied);
+        }
+
+        let page = page_floor(addr);
+        let slot = self
+            .pte_slot(dram, page, true)
+            .ok_or(MemFault::OutOfMemory)?;
+        let pte = load(dram, slot);
+        let frame = if pte & (PTE_V | PTE_SW_NONE) != 0 {
+            (pte >> 10) << 12
+        } else {
+            let frame = self.alloc_frame(dram).ok_or(MemFault::OutOfMemory)?;
+            store(dram, slot, leaf_pte(frame, vma.prot));
+            frame
+        };
+        Ok(frame | (addr & (PAGE_SIZE - 1)))
+    }
+
+    /// Copy `len` bytes out of guest memory.
+    pub fn read_bytes(&mut self, dram: &Dram, addr: u64, len: u64) -> Result<Vec<u8>, MemFault> {
+        let mut out = Vec::with_capacity(len as usize);
+        let mut va = addr;
+        let end = addr.checked_add(len).ok_or(MemFault::Unmapped)?;
+        while va < end {
+            let chunk = (page_floor(va) + PAGE_SIZE).min(end) - va;
+            let pa = self.access(dram, va, Access::Read)?;
+            let bytes = dram
+                .read_range((pa - dram.base) as usize, chunk as usize)
+                .map_err(|_| MemFault::Unmapped)?;
+            out.extend_from_slice(&bytes);
+            va += chunk;
+        }
+        Ok(out)
+    }
+
+    /// Copy `data` into guest memory, honouring the protection unless `access`
+    /// is [`Access::Force`].
+    pub fn write_bytes(
+        &mut self,
+        dram: &Dram,
+        addr: u64,
+        data: &[u8],
+        access: Access,
+    ) -> Result<(), MemFault> {
+        let mut va = addr;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let chunk = ((page_floor(va) + PAGE_SIZE - va) as usize).min(rest.len());
+            let pa = self.access(dram, va, access)?;
+            dram.write_bytes(pa - dram.base, &rest[..chunk])
+                .map_err(|_| MemFault::Unmapped)?;
+            va += chunk as u64;
+            rest = &rest[chunk..];
+        }
+        Ok(())
+    }
+
+    /// Read a NUL-terminated string of at most `max` bytes.
+    pub fn read_cstr(&mut self, dram: &Dram, addr: u64, max: usize) -> Result<Vec<u8>, MemFault> {
+        let mut out = Vec::new();
+        let mut va = addr;
+        while out.len() < max {
+            let chunk = page_floor(va) + PAGE_SIZE - va;
+            let bytes = self.read_bytes(dram, va, chunk)?;
+            match bytes.iter().position(|&b| b == 0) {
+                Some(nul) => {
+                    out.extend_from_slice(&bytes[..nul]);
+                    return Ok(out);
+                }
+                None => out.extend_from_slice(&bytes),
+            }
+            va += chunk;
+        }
+        Err(MemFault::Denied)
+    }
+
+    pub fn read_u32(&mut self, dram: &Dram, addr: u64) -> Result<u32, MemFault> {
+        let bytes = self.read_bytes(dram, addr, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self, dram: &Dram, addr: u64) -> Result<u64, MemFault> {
+        let bytes = self.read_bytes(dram, addr, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn write_u32(&mut self, dram: &Dram, addr: u64, value: u32) -> Result<(), MemFault> {
+        self.write_bytes(dram, addr, &value.to_le_bytes(), Access::Write)
+    }
+
+    /// Remove the parts of all mappings inside `[start, end)`, keeping the
+    /// parts outside it, and return the removed pieces.
+    fn carve(&mut self, start: u64, end: u64) -> Vec<(u64, Vma)> {
+        let first = match self.vmas.range(..start).next_back() {
+            Some((&s, vma)) if vma.end > start => s,
+            _ => start,
+        };
+        let overlapping: Vec<(u64, Vma)> =
+            self.vmas.range(first..end).map(|(&s, &v)| (s, v)).collect();
+
+        let mut removed = Vec::new();
+        for (s, vma) in overlapping {
+            self.vmas.remove(&s);
+            if s < start {
+                self.vmas.insert(s, Vma { end: start, ..vma });
+            }
+            if vma.end > end {
+                self.vmas.insert(end, vma);
+            }
+            removed.push((
+                s.max(start),
+                Vma {
+                    end: vma.end.min(end),
+                    ..vma
+                },
+            ));
+        }
+        removed
+    }
+
+    /// Physical address of the leaf PTE for `va`, optionally allocating
+    /// intermediate tables.
+    fn pte_slot(&mut self, dram: &Dram, va: u64, alloc: bool) -> Option<u64> {
+        let mut table = self.root;
+        for level in [2u64, 1] {
+            let slot = table + ((va >> (12 + 9 * level)) & 0x1ff) * 8;
+            let pte = load(dram, slot);
+            table = if pte & PTE_V != 0 {
+                (pte >> 10) << 12
+            } else if alloc {
+                let frame = self.alloc_frame(dram)?;
+                store(dram, slot, ((frame >> 12) << 10) | PTE_V);
+                frame
+            } else {
+                return None;
+            };
+        }
+        Some(table + ((va >> 12) & 0x1ff) * 8)
+    }
+
+    /// Free the frame backing the page at `va`, if it has one.
+    fn release(&mut self, dram: &Dram, va: u64) {
+        if let Some(slot) = self.pte_slot(dram, va, false) {
+            let pte = load(dram, slot);
+            if pte & (PTE_V | PTE_SW_NONE) != 0 {
+                self.free_frames.push((pte >> 10) << 12);
+                store(dram, slot, 0);
+            }
+        }
+    }
+
+    fn alloc_frame(&mut self, dram: &Dram) -> Option<u64> {
+        let frame = match self.free_frames.pop() {
+            Some(frame) => frame,
+            None if self.next_frame < self.frame_end => {
+                self.next_frame += PAGE_SIZE;
+                self.next_frame - PAGE_SIZE
+            }
+            None => return None,
+        };
+        dram.zero_range((frame - dram.base) as usize, PAGE_SIZE as usize)
+            .ok()?;
+        Some(frame)
+    }
+}
+
+fn leaf_pte(frame: u64, prot: u32) -> u64 {
+    let ppn = (frame >> 12) << 10;
+    if prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == PROT_NONE {
+        return ppn | PTE_SW_NONE;
+    }
+    let mut pte = ppn | PTE_V | PTE_U | PTE_A | PTE_D;
+    // W without R is reserved in RISC-V; writable pages are always readable.
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        pte |= PTE_R;
+    }
+    if prot & PROT_WRITE != 0 {
+        pte |= PTE_W;
+    }
+    if prot & PROT_EXEC != 0 {
+        pte |= PTE_X;
+    }
+    pte
+}
+
+fn load(dram: &Dram, pa: u64) -> u64 {
+    dram.load_64(pa - dram.base).unwrap_or(0)
+}
+
+fn store(dram: &Dram, pa: u64, value: u64) {
+    dram.store_64(pa - dram.base, value).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::DRAM_BASE;
+
+    #[test]
+    fn test_map_protect_unmap() {
+        let dram = Dram::new(DRAM_BASE, 64 * PAGE_SIZE as usize);
+        let mut mm = AddressSpace::new(&dram).unwrap();
+        mm.map(&dram, 0x10000, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE);
+        mm.write_bytes(
+            &dram,
+            0x11000,
+            &0xdead_beef_u64.to_le_bytes(),
+            Access::Write,
+        )
+        .unwrap();
+        assert_eq!(mm.read_u64(&dram, 0x11000), Ok(0xdead_beef));
+
+        mm.protect(&dram, 0x11000, PAGE_SIZE, PROT_NONE).unwrap();
+        assert_eq!(mm.read_u64(&dram, 0x11000), Err(MemFault::Denied));
+        assert_eq!(mm.find(0x12000).map(|(s, _)| s), Some(0x12000));
+        mm.protect(&dram, 0x11000, PAGE_SIZE, PROT_READ).unwrap();
+        assert_eq!(mm.read_u64(&dram, 0x11000), Ok(0xdead_beef));
+        assert_eq!(
+            mm.write_bytes(&dram, 0x11000, &[0; 8], Access::Write),
+            Err(MemFault::Denied)
+        );
+
+        mm.unmap(&dram, 0x10000, 2 * PAGE_SIZE);
+        assert_eq!(mm.read_u64(&dram, 0x11000), Err(MemFault::Unmapped));
+        assert!(mm.is_free(0x10000, 2 * PAGE_SIZE));
+        assert!(!mm.is_free(0x10000, 3 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_find_free() {
+        let dram = Dram::new(DRAM_BASE, 16 * PAGE_SIZE as usize);
+        let mut mm = AddressSpace::new(&dram).unwrap();
+        mm.map(&dram, 0x9000, PAGE_SIZE, PROT_READ);
+        assert_eq!(mm.find_free(PAGE_SIZE, 0x1000, 0xa000), Some(0x8000));
+        assert_eq!(mm.find_free(PAGE_SIZE, 0x1000, 0xb000), Some(0xa000));
+        assert_eq!(mm.find_free(0x9000, 0x1000, 0xa000), None);
+    }
+}
@@ -0,0 +1,730 @@
+//! Linux user-mode emulation: run riscv64 Linux ELF binaries directly.
+//!
+//! Instead of booting a kernel, the guest program runs in U-mode on one or
+//! more [`Cpu`]s sharing an Sv39 address space that we build ourselves.
+//! Every `ecall` traps out of the CPU and is serviced here by forwarding the
+//! Linux system call to the host (see `syscall.rs`). Page faults populate
+//! anonymous memory on demand, and guest threads created with `clone` are
+//! scheduled cooperatively on the calling host thread.
+
+mod elf;
+mod memory;
+mod syscall;
+
+use crate::bus::{DRAM_BASE, SystemBus};
+use crate::cpu::csr::{CSR_MCOUNTEREN, CSR_MEPC, CSR_SATP};
+use crate::cpu::{Cpu, Mode, Trap};
+use crate::engine::disasm::disassemble_at;
+use memory::{Access, AddressSpace, MemFault, PAGE_SIZE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Guest memory used when [`UserConfig::memory`] is not overridden.
+pub const DEFAULT_MEMORY: usize = 1024 * 1024 * 1024;
+
+// Guest address space layout (Sv39 user half).
+const PIE_BASE: u64 = 0x2_0000_0000;
+const INTERP_BASE: u64 = 0x20_0000_0000;
+const MMAP_BOTTOM: u64 = 0x10_0000_0000;
+const MMAP_TOP: u64 = 0x3f_0000_0000;
+const STACK_TOP: u64 = 0x3f_ff00_0000;
+const STACK_SIZE: u64 = 8 << 20;
+/// Page holding the `rt_sigreturn` trampoline signal handlers return to.
+const SIGTRAMP: u64 = 0x3f_fff0_0000;
+/// `li a7, 139; ecall`
+const SIGTRAMP_CODE: [u32; 2] = [0x08b0_0893, 0x0000_0073];
+
+/// Instructions a thread may run before the scheduler switches threads.
+const QUANTUM: usize = 10_000;
+
+// Signals with non-default handling.
+const SIGILL: u32 = 4;
+const SIGTRAP: u32 = 5;
+const SIGBUS: u32 = 7;
+const SIGKILL: u32 = 9;
+const SIGSEGV: u32 = 11;
+const SIGCHLD: u32 = 17;
+const SIGCONT: u32 = 18;
+const SIGSTOP: u32 = 19;
+const SIGURG: u32 = 23;
+const SIGWINCH: u32 = 28;
+const NSIG: usize = 64;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+const SA_NODEFER: u64 = 0x4000_0000;
+const SA_RESETHAND: u64 = 0x8000_0000;
+
+// rt_sigframe layout: siginfo, then a ucontext whose mcontext starts with
+// the general registers (pc, x1..x31).
+const SIGINFO_SIZE: u64 = 128;
+const UC_SIGMASK: u64 = 40;
+const UC_MCONTEXT: u64 = 176;
+const UCONTEXT_SIZE: u64 = 960;
+const SIGFRAME_SIZE: u64 = SIGINFO_SIZE + UCONTEXT_SIZE;
+
+/// Options for [`UserProcess::load`].
+#[derive(Debug, Clone)]
+pub struct UserConfig {
+    /// Guest `argv`; `argv[0]` defaults to the executable path.
+    pub args: Vec<String>,
+    /// Guest environment as `KEY=VALUE` strings.
+    pub env: Vec<String>,
+    /// Directory that absolute guest paths (including the dynamic linker)
+    /// are looked up in first.
+    pub sysroot: Option<PathBuf>,
+    /// Guest memory in bytes.
+    pub memory: usize,
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        Self {
+            args: Vec::new(),
+            env: Vec::new(),
+            sysroot: None,
+            memory: DEFAULT_MEMORY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Runnable,
+    /// Blocked in `FUTEX_WAIT` on `addr` until woken or `deadline`.
+    Futex {
+        addr: u64,
+        bitset: u32,
+        deadline: Option<Instant>,
+    },
+    /// In `nanosleep` until the given time.
+    Sleeping(Instant),
+    Exited,
+}
+
+struct Thread {
+    tid: u32,
+    cpu: Cpu,
+    state: ThreadState,
+    /// `CLONE_CHILD_CLEARTID` / `set_tid_address` word, cleared and woken on exit.
+    clear_child_tid: u64,
+    sigmask: u64,
+    /// Pending signals, bit `sig - 1`.
+    pending: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SigAction {
+    handler: u64,
+    flags: u64,
+    mask: u64,
+}
+
+/// A guest Linux process.
+pub struct UserProcess {
+    bus: SystemBus,
+    mm: AddressSpace,
+    threads: Vec<Thread>,
+    current: usize,
+    pid: u32,
+    next_tid: u32,
+    brk_start: u64,
+    brk: u64,
+    sigactions: [SigAction; NSIG + 1],
+    exe: PathBuf,
+    sysroot: Option<PathBuf>,
+    exit_status: Option<i32>,
+}
+
+impl UserProcess {
+    /// Load the executable at `path` and set up its initial thread.
+    pub fn load(path: &Path, config: UserConfig) -> Result<Self, String> {
+        let data = std::fs::read(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+        let bus = SystemBus::new(DRAM_BASE, config.memory);
+        let dram = &bus.dram;
+        let mut mm = AddressSpace::new(dram)?;
+
+        let exe = elf::load_image(&mut mm, dram, &data, PIE_BASE)?;
+        let (entry, interp_base) = match &exe.interp {
+            Some(interp) => {
+                let host = sysroot_path(config.sysroot.as_deref(), interp.as_bytes());
+                let data = std::fs::read(&host).map_err(|e| {
+                    format!(
+                        "Failed to read interpreter '{}': {} (dynamic executables need --sysroot)",
+                        host.display(),
+                        e
+                    )
+                })?;
+                let image = elf::load_image(&mut mm, dram, &data, INTERP_BASE)?;
+                (image.entry, Some(image.bias))
+            }
+            None => (exe.entry, None),
+        };
+
+        mm.map(dram, SIGTRAMP, PAGE_SIZE, PROT_READ | PROT_EXEC);
+        let code: Vec<u8> = SIGTRAMP_CODE.iter().flat_map(|w| w.to_le_bytes()).collect();
+        mm.write_bytes(dram, SIGTRAMP, &code, Access::Force)
+            .map_err(|e| format!("failed to map signal trampoline: {:?}", e))?;
+
+        mm.map(
+            dram,
+            STACK_TOP - STACK_SIZE,
+            STACK_SIZE,
+            PROT_READ | PROT_WRITE,
+        );
+        let args = if config.args.is_empty() {
+            vec![path.display().to_string()]
+        } else {
+            config.args
+        };
+        let sp = elf::build_stack(
+            &mut mm,
+            dram,
+            STACK_TOP,
+            &args,
+            &config.env,
+            &exe,
+            interp_base,
+        )?;
+
+        let pid = std::process::id();
+        let mut cpu = user_cpu(mm.satp(), entry);
+        cpu.regs[2] = sp;
+        let brk = memory::page_ceil(exe.end);
+
+        Ok(Self {
+            bus,
+            mm,
+            threads: vec![Thread {
+                tid: pid,
+                cpu,
+                state: ThreadState::Runnable,
+                clear_child_tid: 0,
+                sigmask: 0,
+                pending: 0,
+            }],
+            current: 0,
+            pid,
+            next_tid: pid + 1,
+            brk_start: brk,
+            brk,
+            sigactions: [SigAction::default(); NSIG + 1],
+            exe: std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            sysroot: config.sysroot,
+            exit_status: None,
+        })
+    }
+
+    /// Run until the process exits and return its exit status.
+    ///
+    /// Death by signal is reported shell-style as `128 + signal`.
+    pub fn run(&mut self) -> Result<i32, String> {
+        loop {
+            if let Some(status) = self.exit_status {
+                return Ok(status);
+            }
+            self.current = self.schedule()?;
+            self.run_slice()?;
+        }
+    }
+
+    /// Pick the next runnable thread, sleeping if all of them are waiting
+    /// with a timeout.
+    fn schedule(&mut self) -> Result<usize, String> {
+        loop {
+            let now = Instant::now();
+            let mut earliest: Option<Instant> = None;
+            for thread in &mut self.threads {
+                let (deadline, ret) = match thread.state {
+                    ThreadState::Runnable | ThreadState::Exited => continue,
+                    // A deliverable signal interrupts the wait.
+                    _ if thread.pending & !thread.sigmask != 0 => (now, -(libc::EINTR as i64)),
+                    ThreadState::Futex {
+                        deadline: Some(deadline),
+                        ..
+                    } => (deadline, -(libc::ETIMEDOUT as i64)),
+                    ThreadState::Sleeping(until) => (until, 0),
+                    ThreadState::Futex { deadline: None, .. } => continue,
+                };
+                if deadline <= now {
+                    thread.state = ThreadState::Runnable;
+                    thread.cpu.regs[10] = ret as u64;
+                } else {
+                    earliest = Some(earliest.map_or(deadline, |e| e.min(deadline)));
+                }
+            }
+
+            let n = self.threads.len();
+            let next = (1..=n)
+                .map(|k| (self.current + k) % n)
+                .find(|&i| self.threads[i].state == ThreadState::Runnable);
+            if let Some(next) = next {
+                return Ok(next);
+            }
+            match earliest {
+                Some(deadline) => std::thread::sleep(deadline - now),
+                None => return Err("deadlock: every guest thread is blocked on a futex".into()),
+            }
+        }
+    }
+
+    /// Run the current thread for up to one quantum.
+    fn run_slice(&mut self) -> Result<(), String> {
+        for _ in 0..QUANTUM {
+            let thread = &mut self.threads[self.current];
+            if thread.pending & !thread.sigmask != 0 {
+                self.deliver_pending();
+            }
+            let thread = &mut self.threads[self.current];
+            if self.exit_status.is_some() || thread.state != ThreadState::Runnable {
+                break;
+            }
+            if let Err(trap) = thread.cpu.step(&self.bus)
+                && !self.handle_trap(trap)?
+            {
+                break;
+            }
+        }
+        self.threads[self.current].cpu.clear_reservation();
+        Ok(())
+    }
+
+    /// Handle a trap taken by the current thread. Returns whether the
+    /// thread should keep running in this quantum.
+    fn handle_trap(&mut self, trap: Trap) -> Result<bool, String> {
+        let cpu = &mut self.threads[self.current].cpu;
+        match trap {
+            Trap::Fatal(msg) => {
+                let insn = disassemble_at(cpu, &self.bus, cpu.pc)
+                    .unwrap_or_else(|| "unmapped".to_string());
+                return Err(format!(
+                    "fatal error at pc=0x{:x} ({}): {}",
+                    cpu.pc, insn, msg
+                ));
+            }
+            Trap::Wfi => {
+                cpu.pc += 4;
+                return Ok(false);
+            }
+            Trap::RequestedTrap(code) => {
+                return Err(format!("unexpected host trap 0x{:x} in user mode", code));
+            }
+            _ => {}
+        }
+
+        // Architectural traps were taken to M-mode; return to the guest.
+        let epc = cpu.csrs[CSR_MEPC as usize];
+        cpu.mode = Mode::User;
+        cpu.pc = epc;
+        match trap {
+            Trap::EnvironmentCallFromU => {
+                cpu.pc = epc + 4;
+                Ok(self.syscall())
+            }
+            Trap::InstructionPageFault(addr) => self.page_fault(addr, Access::Exec),
+            Trap::LoadPageFault(addr) => self.page_fault(addr, Access::Read),
+            Trap::StorePageFault(addr) => self.page_fault(addr, Access::Write),
+            Trap::IllegalInstruction(_) => Ok(self.force_signal(SIGILL, epc)),
+            Trap::Breakpoint => Ok(self.force_signal(SIGTRAP, epc)),
+            Trap::InstructionAddressMisaligned(addr)
+            | Trap::LoadAddressMisaligned(addr)
+            | Trap::StoreAddressMisaligned(addr) => Ok(self.force_signal(SIGBUS, addr)),
+            Trap::InstructionAccessFault(addr)
+            | Trap::LoadAccessFault(addr)
+            | Trap::StoreAccessFault(addr) => Ok(self.force_signal(SIGSEGV, addr)),
+            _ => Ok(true),
+        }
+    }
+
+    fn page_fault(&mut self, addr: u64, access: Access) -> Result<bool, String> {
+        match self.mm.fault(&self.bus.dram, addr, access) {
+            Ok(()) => {
+                self.threads[self.current].cpu.tlb.flush_va(addr);
+                Ok(true)
+            }
+            Err(MemFault::OutOfMemory) => Err(format!(
+                "guest out of memory at 0x{:x} (increase --memory)",
+                addr
+            )),
+            Err(_) => Ok(self.force_signal(SIGSEGV, addr)),
+        }
+    }
+
+    /// Flush every thread's TLB and translated code after the address
+    /// space changed underneath it.
+    fn flush_mappings(&mut self) {
+        for thread in &mut self.threads {
+            thread.cpu.tlb.flush();
+            thread.cpu.invalidate_blocks();
+        }
+    }
+
+    fn new_thread(&mut self, cpu: Cpu) -> u32 {
+        let tid = self.next_tid;
+        self.next_tid += 1;
+        let sigmask = self.threads[self.current].sigmask;
+        self.threads.push(Thread {
+            tid,
+            cpu,
+            state: ThreadState::Runnable,
+            clear_child_tid: 0,
+            sigmask,
+            pending: 0,
+        });
+        tid
+    }
+
+    /// Terminate the current thread, ending the process when it was the last.
+    fn exit_thread(&mut self, status: i32) {
+        let addr = self.threads[self.current].clear_child_tid;
+        if addr != 0 && self.mm.write_u32(&self.bus.dram, addr, 0).is_ok() {
+            self.futex_wake(addr, 1, u32::MAX);
+        }
+        self.threads[self.current].state = ThreadState::Exited;
+        if self.threads.iter().all(|t| t.state == ThreadState::Exited) {
+            self.exit_status = Some(status & 0xff);
+        }
+    }
+
+    /// Wake up to `count` threads waiting on `addr`; returns how many woke.
+    fn futex_wake(&mut self, addr: u64, count: u32, bitset: u32) -> u32 {
+        let mut woken = 0;
+        for thread in &mut self.threads {
+            if woken == count {
+                break;
+            }
+            if let ThreadState::Futex {
+                addr: a, bitset: b, ..
+            } = thread.state
+                && a == addr
+                && b & bitset != 0
+            {
+                thread.state = ThreadState::Runnable;
+                thread.cpu.regs[10] = 0;
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    /// Queue `sig` for the process: on the current thread if it does not
+    /// block it, otherwise on any thread that does not.
+    fn raise(&mut self, sig: u32) {
+        let bit = sig_bit(sig);
+        let current = self.current;
+        let target = std::iter::once(current)
+            .chain(0..self.threads.len())
+            .find(|&i| {
+                let t = &self.threads[i];
+                t.state != ThreadState::Exited && t.sigmask & bit == 0
+            })
+            .unwrap_or(current);
+        self.threads[target].pending |= bit;
+    }
+
+    /// Deliver the lowest pending, unblocked signal to the current thread.
+    fn deliver_pending(&mut self) {
+        let thread = &mut self.threads[self.current];
+        let ready = thread.pending & !thread.sigmask;
+        let sig = ready.trailing_zeros() + 1;
+        thread.pending &= !sig_bit(sig);
+
+        match self.sigactions[sig as usize].handler {
+            SIG_IGN => {}
+            SIG_DFL if default_ignored(sig) => {}
+            SIG_DFL => self.terminate(sig, None),
+            _ => self.setup_frame(sig, 0),
+        }
+    }
+
+    /// Deliver a synchronous fault signal. Returns whether the thread can
+    /// keep running.
+    fn force_signal(&mut self, sig: u32, addr: u64) -> bool {
+        let action = self.sigactions[sig as usize];
+        let blocked = self.threads[self.current].sigmask & sig_bit(sig) != 0;
+        if action.handler > SIG_IGN && !blocked {
+            self.setup_frame(sig, addr);
+        } else {
+            self.terminate(sig, Some(addr));
+        }
+        self.exit_status.is_none()
+    }
+
+    fn terminate(&mut self, sig: u32, addr: Option<u64>) {
+        let pc = self.threads[self.current].cpu.pc;
+        match addr {
+            Some(addr) => eprintln!(
+                "[user] {} (addr=0x{:x}, pc=0x{:x})",
+                signal_name(sig),
+                addr,
+                pc
+            ),
+            None => eprintln!("[user] terminated by {}", signal_name(sig)),
+        }
+        self.exit_status = Some(128 + sig as i32);
+    }
+
+    /// Push an `rt_sigframe` and enter the handler for `sig`.
+    fn setup_frame(&mut self, sig: u32, addr: u64) {
+        let action = self.sigactions[sig as usize];
+        let thread = &self.threads[self.current];
+        let cpu = &thread.cpu;
+        let frame = (cpu.regs[2] - SIGFRAME_SIZE) & !15;
+
+        let mut bytes = vec![0u8; SIGFRAME_SIZE as usize];
+        bytes[0..4].copy_from_slice(&sig.to_le_bytes());
+        if addr != 0 {
+            // si_code = SEGV_MAPERR/ILL_ILLOPC/..., si_addr
+            bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+            bytes[16..24].copy_from_slice(&addr.to_le_bytes());
+        }
+        let uc = SIGINFO_SIZE as usize;
+        let mask = uc + UC_SIGMASK as usize;
+        bytes[mask..mask + 8].copy_from_slice(&thread.sigmask.to_le_bytes());
+        let regs = uc + UC_MCONTEXT as usize;
+        bytes[regs..regs + 8].copy_from_slice(&cpu.pc.to_le_bytes());
+        for i in 1..32 {
+            let off = regs + 8 * i;
+            bytes[off..off + 8].copy_from_slice(&cpu.regs[i].to_le_bytes());
+        }
+        if self
+            .mm
+            .write_bytes(&self.bus.dram, frame, &bytes, Access::Write)
+            .is_err()
+        {
+            self.terminate(SIGSEGV, Some(frame));
+            return;
+        }
+
+        let thread = &mut self.threads[self.current];
+        let cpu = &mut thread.cpu;
+        cpu.regs[1] = SIGTRAMP;
+        cpu.regs[2] = frame;
+        cpu.regs[10] = sig as u64;
+        cpu.regs[11] = frame;
+        cpu.regs[12] = frame + SIGINFO_SIZE;
+        cpu.pc = action.handler;
+        thread.sigmask |= action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            thread.sigmask |= sig_bit(sig);
+        }
+        if action.flags & SA_RESETHAND != 0 {
+            self.sigactions[sig as usize].handler = SIG_DFL;
+        }
+    }
+
+    /// Restore the context saved by [`Self::setup_frame`].
+    fn sigreturn(&mut self) {
+        let uc = self.threads[self.current].cpu.regs[2] + SIGINFO_SIZE;
+        let dram = &self.bus.dram;
+        let saved = self
+            .mm
+            .read_u64(dram, uc + UC_SIGMASK)
+            .and_then(|mask| Ok((mask, self.mm.read_bytes(dram, uc + UC_MCONTEXT, 256)?)));
+        let (mask, regs) = match saved {
+            Ok(saved) => saved,
+            Err(_) => {
+                self.terminate(SIGSEGV, Some(uc));
+                return;
+            }
+        };
+        let thread = &mut self.threads[self.current];
+        let word = |i: usize| u64::from_le_bytes(regs[8 * i..8 * i + 8].try_into().unwrap());
+        thread.cpu.pc = word(0);
+        for i in 1..32 {
+            thread.cpu.regs[i] = word(i);
+        }
+        thread.sigmask = mask & !(sig_bit(SIGKILL) | sig_bit(SIGSTOP));
+    }
+}
+
+/// A CPU running in U-mode on the address space rooted at `satp`.
+fn user_cpu(satp: u64, pc: u64) -> Cpu {
+    let mut cpu = Cpu::new(pc, 0);
+    cpu.csrs[CSR_SATP as usize] = satp;
+    // Let the guest read cycle/time/instret directly.
+    cpu.csrs[CSR_MCOUNTEREN as usize] = 0b111;
+    cpu.mode = Mode::User;
+    cpu
+}
+
+/// Host path for guest `path`: absolute paths prefer the sysroot copy.
+fn sysroot_path(sysroot: Option<&Path>, path: &[u8]) -> PathBuf {
+    if let (Some(root), Some(rel)) = (sysroot, path.strip_prefix(b"/")) {
+        let candidate = root.join(OsStr::from_bytes(rel));
+        if candidate.symlink_metadata().is_ok() {
+            return candidate;
+        }
+    }
+    PathBuf::from(OsStr::from_bytes(path))
+}
+
+fn sig_bit(sig: u32) -> u64 {
+    1 << (sig - 1)
+}
+
+fn default_ignored(sig: u32) -> bool {
+    matches!(sig, SIGCHLD | SIGCONT | SIGURG | SIGWINCH)
+}
+
+fn signal_name(sig: u32) -> String {
+    match sig {
+        SIGILL => "SIGILL".into(),
+        SIGTRAP => "SIGTRAP".into(),
+        SIGBUS => "SIGBUS".into(),
+        SIGKILL => "SIGKILL".into(),
+        SIGSEGV => "SIGSEGV".into(),
+        6 => "SIGABRT".into(),
+        2 => "SIGINT".into(),
+        15 => "SIGTERM".into(),
+        _ => format!("signal {}", sig),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal static riscv64 ELF with one RX segment containing `code`.
+    fn tiny_elf(code: &[u32]) -> Vec<u8> {
+        const BASE: u64 = 0x10000;
+        let text: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let entry = BASE + 64 + 56;
+        let mut elf = Vec::new();
+        elf.extend(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        elf.extend(2u16.to_le_bytes()); // ET_EXEC
+        elf.extend(243u16.to_le_bytes()); // EM_RISCV
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(entry.to_le_bytes());
+        elf.extend(64u64.to_le_bytes()); // e_phoff
+        elf.extend(0u64.to_le_bytes()); // e_shoff
+        elf.extend(0u32.to_le_bytes()); // e_flags
+        elf.extend(
+            [64u16, 56, 1, 64, 0, 0]
+                .iter()
+                .flat_map(|h| h.to_le_bytes()),
+        );
+        let size = (64 + 56 + text.len()) as u64;
+        elf.extend(1u32.to_le_bytes()); // PT_LOAD
+        elf.extend(5u32.to_le_bytes()); // R+X
+        elf.extend(0u64.to_le_bytes());
+        elf.extend(BASE.to_le_bytes());
+        elf.extend(BASE.to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(0x1000u64.to_le_bytes());
+        elf.extend(text);
+        elf
+    }
+
+    fn run(name: &str, code: &[u32]) -> i32 {
+        let path = std::env::temp_dir().join(format!("user-{}-{}", name, std::process::id()));
+        std::fs::write(&path, tiny_elf(code)).unwrap();
+        let config = UserConfig {
+            memory: 16 << 20,
+            ..UserConfig::default()
+        };
+        let status = UserProcess::load(&path, config).and_then(|mut p| p.run());
+        std::fs::remove_file(&path).ok();
+        status.unwrap()
+    }
+
+    #[test]
+    fn test_exit_group_status() {
+        // li a0, 42; li a7, 94; ecall
+        assert_eq!(run("exit", &[0x02a0_0513, 0x05e0_0893, 0x0000_0073]), 42);
+    }
+
+    #[test]
+    fn test_fault_kills_with_sigsegv() {
+        // ld a0, 0(zero)
+        assert_eq!(run("segv", &[0x0000_3503]), 128 + SIGSEGV as i32);
+    }
+
+    #[test]
+    fn test_stack_and_brk() {
+        // sd sp, -8(sp); ld a0, -8(sp); li a7, 214; li a0, 0; ecall;
+        // beqz a0, fail; li a0, 0; li a7, 93; ecall; fail: li a0, 1; ecall
+        let code = [
+            0xfe21_3c23, // sd sp, -8(sp)
+            0xff81_3503, // ld a0, -8(sp)
+            0x0d60_0893, // li a7, 214
+            0x0000_0513, // li a0, 0
+            0x0000_0073, // ecall
+            0x0005_0863, // beqz a0, +16
+            0x0000_0513, // li a0, 0
+            0x05d0_0893, // li a7, 93
+            0x0000_0073, // ecall
+            0x0010_0513, // li a0, 1
+            0x05d0_0893, // li a7, 93
+            0x0000_0073, // ecall
+        ];
+        assert_eq!(run("brk", &code), 0);
+    }
+
+    #[test]
+    fn test_huge_file_mmap_is_enomem() {
+        // mmap(NULL, 1 << 62, PROT_READ, MAP_PRIVATE, 0, 0); exit(-ret)
+        let code = [
+            0x0000_0513, // li a0, 0
+            0x0010_0593, // li a1, 1
+            0x03e5_9593, // slli a1, a1, 62
+            0x0010_0613, // li a2, 1
+            0x0020_0693, // li a3, 2
+            0x0000_0713, // li a4, 0
+            0x0000_0793, // li a5, 0
+            0x0de0_0893, // li a7, 222
+            0x0000_0073, // ecall
+            0x40a0_0533, // neg a0, a0
+            0x05d0_0893, // li a7, 93
+            0x0000_0073, // ecall
+        ];
+        assert_eq!(run("mmap", &code), libc::ENOMEM);
+    }
+
+    #[test]
+    fn test_file_mmap_spans_copy_chunks() {
+        let data_path = std::env::temp_dir().join(format!("user-mmap-data-{}", std::process::id()));
+        let mut data = vec![0u8; 100_000];
+        data[0x1000 + 0x11000] = 99;
+        std::fs::write(&data_path, &data).unwrap();
+
+        // fd = openat(AT_FDCWD, path, O_RDONLY);
+        // p = mmap(NULL, 0x20000, PROT_READ, MAP_PRIVATE, fd, 0x1000);
+        // exit(p[0x11000])
+        let mut code = vec![
+            0x0000_0597, // auipc a1, 0
+            0x04c5_8593, // addi a1, a1, 76     (path)
+            0xf9c0_0513, // li a0, -100
+            0x0000_0613, // li a2, 0
+            0x0380_0893, // li a7, 56
+            0x0000_0073, // ecall
+            0x0005_0713, // mv a4, a0
+            0x0000_0513, // li a0, 0
+            0x0002_05b7, // lui a1, 0x20
+            0x0010_0613, // li a2, 1
+            0x0020_0693, // li a3, 2
+            0x0000_17b7, // lui a5, 1
+            0x0de0_0893, // li a7, 222
+            0x0000_0073, // ecall
+            0x0001_12b7, // lui t0, 0x11
+            0x0055_0533, // add a0, a0, t0
+            0x0005_4503, // lbu a0, 0(a0)
+            0x05d0_0893, // li a7, 93
+            0x0000_0073, // ecall
+        ];
+        let mut path = data_path.to_str().unwrap().as_bytes().to_vec();
+        path.resize(path.len() / 4 * 4 + 4, 0);
+        code.extend(
+            path.chunks(4)
+                .map(|w| u32::from_le_bytes(w.try_into().unwrap())),
+        );
+
+        let status = run("mmap-file", &code);
+        std::fs::remove_file(&data_path).ok();
+        assert_eq!(status, 99);
+    }
+}
@@ -0,0 +1,1126 @@
+//! Linux system call emulation.
+//!
+//! Guest file descriptors are host file descriptors, so most calls are
+//! forwarded to the host after copying their buffers in or out of guest
+//! memory. Memory management, threads, futexes and signals are emulated
+//! on top of [`AddressSpace`](super::memory::AddressSpace).
+
+use super::memory::{Access, PAGE_SIZE, PROT_EXEC, PROT_READ, PROT_WRITE, page_ceil};
+use super::{
+    MMAP_BOTTOM, MMAP_TOP, NSIG, SIGKILL, SIGSTOP, SigAction, ThreadState, UserProcess, sig_bit,
+    sysroot_path, user_cpu,
+};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, Instant};
+
+/// `Ok(value)` or `Err(errno)`.
+type SysResult = Result<i64, i32>;
+
+/// Largest single read/write; longer requests complete partially.
+const MAX_IO: u64 = 16 << 20;
+/// Bytes of a file mapping copied per host read.
+const MMAP_CHUNK: u64 = 64 << 10;
+const PATH_MAX: usize = 4096;
+
+// riscv64 uses the asm-generic system call table.
+const SYS_GETCWD: u64 = 17;
+const SYS_DUP: u64 = 23;
+const SYS_DUP3: u64 = 24;
+const SYS_FCNTL: u64 = 25;
+const SYS_IOCTL: u64 = 29;
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_RENAMEAT: u64 = 38;
+const SYS_FTRUNCATE: u64 = 46;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_CHDIR: u64 = 49;
+const SYS_FCHDIR: u64 = 50;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_PIPE2: u64 = 59;
+const SYS_GETDENTS64: u64 = 61;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_PREAD64: u64 = 67;
+const SYS_PWRITE64: u64 = 68;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_FSYNC: u64 = 82;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_NANOSLEEP: u64 = 101;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_CLOCK_GETRES: u64 = 114;
+const SYS_CLOCK_NANOSLEEP: u64 = 115;
+const SYS_SCHED_SETAFFINITY: u64 = 122;
+const SYS_SCHED_GETAFFINITY: u64 = 123;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_KILL: u64 = 129;
+const SYS_TKILL: u64 = 130;
+const SYS_TGKILL: u64 = 131;
+const SYS_SIGALTSTACK: u64 = 132;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_RT_SIGRETURN: u64 = 139;
+const SYS_UNAME: u64 = 160;
+const SYS_GETRLIMIT: u64 = 163;
+const SYS_UMASK: u64 = 166;
+const SYS_PRCTL: u64 = 167;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MREMAP: u64 = 216;
+const SYS_CLONE: u64 = 220;
+const SYS_EXECVE: u64 = 221;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_RISCV_HWPROBE: u64 = 258;
+const SYS_RISCV_FLUSH_ICACHE: u64 = 259;
+const SYS_WAIT4: u64 = 260;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_RENAMEAT2: u64 = 276;
+const SYS_GETRANDOM: u64 = 278;
+const SYS_MEMBARRIER: u64 = 283;
+const SYS_STATX: u64 = 291;
+const SYS_RSEQ: u64 = 293;
+const SYS_CLONE3: u64 = 435;
+const SYS_FACCESSAT2: u64 = 439;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+const MADV_DONTNEED: u64 = 4;
+
+const CLONE_VM: u64 = 0x100;
+const CLONE_THREAD: u64 = 0x1_0000;
+const CLONE_SETTLS: u64 = 0x8_0000;
+const CLONE_PARENT_SETTID: u64 = 0x10_0000;
+const CLONE_CHILD_CLEARTID: u64 = 0x20_0000;
+const CLONE_CHILD_SETTID: u64 = 0x100_0000;
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+const FUTEX_REQUEUE: u64 = 3;
+const FUTEX_CMP_REQUEUE: u64 = 4;
+const FUTEX_WAIT_BITSET: u64 = 9;
+const FUTEX_WAKE_BITSET: u64 = 10;
+const FUTEX_CMD_MASK: u64 = 0x7f;
+const FUTEX_CLOCK_REALTIME: u64 = 0x100;
+
+const TIMER_ABSTIME: u64 = 1;
+
+const PROT_RW: u32 = PROT_READ | PROT_WRITE;
+const PROT_MASK: u32 = PROT_READ | PROT_WRITE | PROT_EXEC;
+
+/// asm-generic `O_*` bits and their host equivalents.
+const OPEN_FLAGS: &[(u64, libc::c_int)] = &[
+    (0o100, libc::O_CREAT),
+    (0o200, libc::O_EXCL),
+    (0o400, libc::O_NOCTTY),
+    (0o1000, libc::O_TRUNC),
+    (0o2000, libc::O_APPEND),
+    (0o4000, libc::O_NONBLOCK),
+    (0o10000, libc::O_DSYNC),
+    (0o20000, libc::O_ASYNC),
+    (0o40000, libc::O_DIRECT),
+    (0o100000, libc::O_LARGEFILE),
+    (0o200000, libc::O_DIRECTORY),
+    (0o400000, libc::O_NOFOLLOW),
+    (0o1000000, libc::O_NOATIME),
+    (0o2000000, libc::O_CLOEXEC),
+    (0o4000000, libc::O_SYNC & !libc::O_DSYNC),
+    (0o10000000, libc::O_PATH),
+    (0o20000000, libc::O_TMPFILE & !libc::O_DIRECTORY),
+];
+
+/// Terminal ioctls passed through as `(guest request, host request,
+/// argument size, argument is written back)`.
+const IOCTLS: &[(u64, libc::Ioctl, usize, bool)] = &[
+    (0x5401, libc::TCGETS, 36, true),
+    (0x5402, libc::TCSETS, 36, false),
+    (0x5403, libc::TCSETSW, 36, false),
+    (0x5404, libc::TCSETSF, 36, false),
+    (0x540f, libc::TIOCGPGRP, 4, true),
+    (0x5413, libc::TIOCGWINSZ, 8, true),
+    (0x5414, libc::TIOCSWINSZ, 8, false),
+    (0x541b, libc::FIONREAD, 4, true),
+];
+
+impl UserProcess {
+    /// Service the `ecall` the current thread just made. Returns whether
+    /// the thread should keep running in this quantum.
+    pub(super) fn syscall(&mut self) -> bool {
+        let cpu = &self.threads[self.current].cpu;
+        let nr = cpu.regs[17];
+        let a: [u64; 6] = cpu.regs[10..16].try_into().unwrap();
+
+        if let Some(result) = self.dispatch(nr, a) {
+            let ret = result.unwrap_or_else(|errno| -(errno as i64));
+            log::trace!("[user] syscall {}({:#x?}) = {}", nr, a, ret);
+            self.threads[self.current].cpu.regs[10] = ret as u64;
+        }
+        nr != SYS_SCHED_YIELD
+            && self.exit_status.is_none()
+            && self.threads[self.current].state == ThreadState::Runnable
+    }
+
+    /// Run system call `nr`. `None` means a0 must be left alone: the call
+    /// does not return, or returns later (blocking waits).
+    fn dispatch(&mut self, nr: u64, a: [u64; 6]) -> Option<SysResult> {
+        let fd = a[0] as i32;
+        let result = match nr {
+            SYS_READ => self.sys_read(fd, a[1], a[2], None),
+            SYS_PREAD64 => self.sys_read(fd, a[1], a[2], Some(a[3] as i64)),
+            SYS_WRITE => self.sys_write(fd, a[1], a[2], None),
+            SYS_PWRITE64 => self.sys_write(fd, a[1], a[2], Some(a[3] as i64)),
+            SYS_READV => self.sys_readv(fd, a[1], a[2]),
+            SYS_WRITEV => self.sys_writev(fd, a[1], a[2]),
+            SYS_OPENAT => self.path(a[1]).and_then(|path| {
+                let flags = host_open_flags(a[2]);
+                host(unsafe { libc::openat(fd, path.as_ptr(), flags, a[3] as libc::c_uint) })
+            }),
+            SYS_CLOSE => host(unsafe { libc::close(fd) }),
+            SYS_LSEEK => host(unsafe { libc::lseek(fd, a[1] as i64, a[2] as i32) }),
+            SYS_DUP => host(unsafe { libc::dup(fd) }),
+            SYS_DUP3 => host(unsafe { libc::dup3(fd, a[1] as i32, host_open_flags(a[2])) }),
+            SYS_FCNTL => self.sys_fcntl(fd, a[1] as i32, a[2]),
+            SYS_IOCTL => self.sys_ioctl(fd, a[1], a[2]),
+            SYS_FSTAT => self.sys_fstatat(fd, None, a[1], 0),
+            SYS_NEWFSTATAT => self.sys_fstatat(fd, Some(a[1]), a[2], a[3] as i32),
+            SYS_STATX => self.sys_statx(fd, a[1], a[2] as i32, a[3] as u32, a[4]),
+            SYS_GETDENTS64 => self.sys_getdents64(fd, a[1], a[2]),
+            SYS_READLINKAT => self.sys_readlinkat(fd, a[1], a[2], a[3]),
+            SYS_FACCESSAT => self.path(a[1]).and_then(|path| {
+                host(unsafe { libc::faccessat(fd, path.as_ptr(), a[2] as i32, 0) })
+            }),
+            SYS_FACCESSAT2 => self.path(a[1]).and_then(|path| {
+                host(unsafe { libc::faccessat(fd, path.as_ptr(), a[2] as i32, a[3] as i32) })
+            }),
+            SYS_UNLINKAT => self
+                .path(a[1])
+                .and_then(|path| host(unsafe { libc::unlinkat(fd, path.as_ptr(), a[2] as i32) })),
+            SYS_MKDIRAT => self.path(a[1]).and_then(|path| {
+                host(unsafe { libc::mkdirat(fd, path.as_ptr(), a[2] as libc::mode_t) })
+            }),
+            SYS_RENAMEAT => self.sys_renameat(fd, a[1], a[2] as i32, a[3], 0),
+            SYS_RENAMEAT2 => self.sys_renameat(fd, a[1], a[2] as i32, a[3], a[4] as u32),
+            SYS_FTRUNCATE => host(unsafe { libc::ftruncate(fd, a[1] as i64) }),
+            SYS_FSYNC => host(unsafe { libc::fsync(fd) }),
+            SYS_GETCWD => self.sys_getcwd(a[0], a[1]),
+            SYS_CHDIR => self
+                .path(a[0])
+                .and_then(|path| host(unsafe { libc::chdir(path.as_ptr()) })),
+            SYS_FCHDIR => host(unsafe { libc::fchdir(fd) }),
+            SYS_PIPE2 => self.sys_pipe2(a[0], a[1]),
+            SYS_UMASK => Ok(unsafe { libc::umask(a[0] as libc::mode_t) } as i64),
+
+            SYS_BRK => Ok(self.sys_brk(a[0])),
+            SYS_MMAP => self.sys_mmap(a[0], a[1], a[2], a[3], a[4] as i32, a[5]),
+            SYS_MUNMAP => self.sys_munmap(a[0], a[1]),
+            SYS_MPROTECT => self.sys_mprotect(a[0], a[1], a[2]),
+            SYS_MADVISE => self.sys_madvise(a[0], a[1], a[2]),
+            SYS_MREMAP => Err(libc::ENOMEM),
+
+            SYS_CLONE => self.sys_clone(a[0], a[1], a[2], a[3], a[4]),
+            SYS_FUTEX => return self.sys_futex(a[0], a[1], a[2], a[3], a[4], a[5]),
+            SYS_EXIT => {
+                self.exit_thread(a[0] as i32);
+                return None;
+            }
+            SYS_EXIT_GROUP => {
+                self.exit_status = Some(a[0] as i32 & 0xff);
+                return None;
+            }
+            SYS_SET_TID_ADDRESS => {
+                let thread = &mut self.threads[self.current];
+                thread.clear_child_tid = a[0];
+                Ok(thread.tid as i64)
+            }
+            SYS_SET_ROBUST_LIST => Ok(0),
+            SYS_GETPID => Ok(self.pid as i64),
+            SYS_GETTID => Ok(self.threads[self.current].tid as i64),
+            SYS_GETPPID => Ok(unsafe { libc::getppid() } as i64),
+            SYS_GETUID => Ok(unsafe { libc::getuid() } as i64),
+            SYS_GETEUID => Ok(unsafe { libc::geteuid() } as i64),
+            SYS_GETGID => Ok(unsafe { libc::getgid() } as i64),
+            SYS_GETEGID => Ok(unsafe { libc::getegid() } as i64),
+
+            SYS_RT_SIGACTION => self.sys_rt_sigaction(a[0] as u32, a[1], a[2]),
+            SYS_RT_SIGPROCMASK => self.sys_rt_sigprocmask(a[0], a[1], a[2]),
+            SYS_RT_SIGRETURN => {
+                self.sigreturn();
+                return None;
+            }
+            SYS_SIGALTSTACK => self.sys_sigaltstack(a[1]),
+            SYS_KILL => self.sys_kill(a[0] as i32, a[1] as u32),
+            SYS_TKILL => self.sys_tkill(a[0] as u32, a[1] as u32),
+            SYS_TGKILL => self.sys_tkill(a[1] as u32, a[2] as u32),
+
+            SYS_CLOCK_GETTIME => self.sys_clock(a[0] as i32, a[1], false),
+            SYS_CLOCK_GETRES => self.sys_clock(a[0] as i32, a[1], true),
+            SYS_GETTIMEOFDAY => self.sys_gettimeofday(a[0]),
+            SYS_NANOSLEEP => return self.sys_nanosleep(libc::CLOCK_MONOTONIC, 0, a[0]),
+            SYS_CLOCK_NANOSLEEP => return self.sys_nanosleep(a[0] as i32, a[1], a[2]),
+            SYS_SCHED_YIELD => Ok(0),
+            SYS_SCHED_GETAFFINITY => self.sys_sched_getaffinity(a[1], a[2]),
+            SYS_SCHED_SETAFFINITY => Ok(0),
+
+            SYS_UNAME => self.sys_uname(a[0]),
+            SYS_GETRLIMIT => self.sys_prlimit(a[0] as u32, 0, a[1]),
+            SYS_PRLIMIT64 => self.sys_prlimit(a[1] as u32, a[2], a[3]),
+            SYS_GETRANDOM => self.sys_getrandom(a[0], a[1], a[2] as u32),
+            SYS_PRCTL => self.sys_prctl(a[0], a[1]),
+            SYS_RISCV_FLUSH_ICACHE => {
+                for thread in &mut self.threads {
+                    thread.cpu.invalidate_blocks();
+                }
+                Ok(0)
+            }
+            SYS_WAIT4 => Err(libc::ECHILD),
+            // Optional interfaces that libc probes for and falls back from.
+            SYS_RSEQ | SYS_CLONE3 | SYS_MEMBARRIER | SYS_RISCV_HWPROBE => Err(libc::ENOSYS),
+            SYS_EXECVE => {
+                log::warn!("[user] execve is not supported");
+                Err(libc::ENOSYS)
+            }
+            _ => {
+                log::warn!("[user] unimplemented syscall {}", nr);
+                Err(libc::ENOSYS)
+            }
+        };
+        Some(result)
+    }
+
+    fn read_mem(&mut self, addr: u64, len: u64) -> Result<Vec<u8>, i32> {
+        self.mm
+            .read_bytes(&self.bus.dram, addr, len)
+            .map_err(|_| libc::EFAULT)
+    }
+
+    fn write_mem(&mut self, addr: u64, data: &[u8]) -> Result<(), i32> {
+        self.mm
+            .write_bytes(&self.bus.dram, addr, data, Access::Write)
+            .map_err(|_| libc::EFAULT)
+    }
+
+    fn read_u64(&mut self, addr: u64) -> Result<u64, i32> {
+        self.mm
+            .read_u64(&self.bus.dram, addr)
+            .map_err(|_| libc::EFAULT)
+    }
+
+    /// Host path for the guest path string at `addr`.
+    fn path(&mut self, addr: u64) -> Result<CString, i32> {
+        let raw = self
+            .mm
+            .read_cstr(&self.bus.dram, addr, PATH_MAX)
+            .map_err(|_| libc::EFAULT)?;
+        let path = if raw == b"/proc/self/exe" {
+            self.exe.clone()
+        } else {
+            sysroot_path(self.sysroot.as_deref(), &raw)
+        };
+        CString::new(path.as_os_str().as_bytes()).map_err(|_| libc::EINVAL)
+    }
+
+    fn read_timespec(&mut self, addr: u64) -> Result<Duration, i32> {
+        let sec = self.read_u64(addr)?;
+        let nsec = self.read_u64(addr + 8)?;
+        if nsec >= 1_000_000_000 {
+            return Err(libc::EINVAL);
+        }
+        Ok(Duration::new(sec, nsec as u32))
+    }
+
+    fn sys_read(&mut self, fd: i32, buf: u64, count: u64, offset: Option<i64>) -> SysResult {
+        let mut data = vec![0u8; count.min(MAX_IO) as usize];
+        let ptr = data.as_mut_ptr().cast();
+        let n = host(match offset {
+            Some(off) => unsafe { libc::pread(fd, ptr, data.len(), off) },
+            None => unsafe { libc::read(fd, ptr, data.len()) },
+        })?;
+        self.write_mem(buf, &data[..n as usize])?;
+        Ok(n)
+    }
+
+    fn sys_write(&mut self, fd: i32, buf: u64, count: u64, offset: Option<i64>) -> SysResult {
+        let data = self.read_mem(buf, count.min(MAX_IO))?;
+        let ptr = data.as_ptr().cast();
+        host(match offset {
+            Some(off) => unsafe { libc::pwrite(fd, ptr, data.len(), off) },
+            None => unsafe { libc::write(fd, ptr, data.len()) },
+        })
+    }
+
+    fn iovecs(&mut self, iov: u64, count: u64) -> Result<Vec<(u64, u64)>, i32> {
+        if count > 1024 {
+            return Err(libc::EINVAL);
+        }
+        let raw = self.read_mem(iov, 16 * count)?;
+        Ok(raw
+            .chunks_exact(16)
+            .map(|c| {
+                let word = |i: usize| u64::from_le_bytes(c[i..i + 8].try_into().unwrap());
+                (word(0), word(8))
+            })
+            .collect())
+    }
+
+    fn sys_readv(&mut self, fd: i32, iov: u64, count: u64) -> SysResult {
+        let mut total = 0;
+        for (base, len) in self.iovecs(iov, count)? {
+            match self.sys_read(fd, base, len, None) {
+                Ok(n) => {
+                    total += n;
+                    if (n as u64) < len {
+                        break;
+                    }
+                }
+                Err(_) if total > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(total)
+    }
+
+    fn sys_writev(&mut self, fd: i32, iov: u64, count: u64) -> SysResult {
+        let mut data = Vec::new();
+        for (base, len) in self.iovecs(iov, count)? {
+            data.extend(self.read_mem(base, len.min(MAX_IO))?);
+        }
+        host(unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) })
+    }
+
+    fn sys_fcntl(&mut self, fd: i32, cmd: i32, arg: u64) -> SysResult {
+        match cmd {
+            libc::F_DUPFD | libc::F_DUPFD_CLOEXEC | libc::F_GETFD | libc::F_SETFD => {
+                host(unsafe { libc::fcntl(fd, cmd, arg as i32) })
+            }
+            libc::F_GETFL => host(unsafe { libc::fcntl(fd, cmd) }).map(guest_open_flags),
+            libc::F_SETFL => host(unsafe { libc::fcntl(fd, cmd, host_open_flags(arg)) }),
+            libc::F_GETLK | libc::F_SETLK | libc::F_SETLKW => {
+                // struct flock has the same layout on every 64-bit Linux.
+                let mut lock = self.read_mem(arg, 32)?;
+                host(unsafe { libc::fcntl(fd, cmd, lock.as_mut_ptr()) })?;
+                if cmd == libc::F_GETLK {
+                    self.write_mem(arg, &lock)?;
+                }
+                Ok(0)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn sys_ioctl(&mut self, fd: i32, request: u64, arg: u64) -> SysResult {
+        let Some(&(_, host_request, size, out)) = IOCTLS.iter().find(|i| i.0 == request) else {
+            return Err(libc::ENOTTY);
+        };
+        let mut buf = if out {
+            vec![0u8; size]
+        } else {
+            self.read_mem(arg, size as u64)?
+        };
+        let ret = host(unsafe { libc::ioctl(fd, host_request, buf.as_mut_ptr()) })?;
+        if out {
+            self.write_mem(arg, &buf)?;
+        }
+        Ok(ret)
+    }
+
+    fn sys_fstatat(&mut self, dirfd: i32, path: Option<u64>, buf: u64, flags: i32) -> SysResult {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        match path {
+            Some(addr) => {
+                let path = self.path(addr)?;
+                host(unsafe { libc::fstatat(dirfd, path.as_ptr(), &mut st, flags) })?
+            }
+            None => host(unsafe { libc::fstat(dirfd, &mut st) })?,
+        };
+        self.write_mem(buf, &guest_stat(&st))?;
+        Ok(0)
+    }
+
+    fn sys_statx(&mut self, dirfd: i32, path: u64, flags: i32, mask: u32, buf: u64) -> SysResult {
+        let path = self.path(path)?;
+        // struct statx is architecture independent.
+        let mut out = [0u8; 256];
+        host(unsafe {
+            libc::syscall(
+                libc::SYS_statx,
+                dirfd,
+                path.as_ptr(),
+                flags,
+                mask,
+                out.as_mut_ptr(),
+            )
+        })?;
+        self.write_mem(buf, &out)?;
+        Ok(0)
+    }
+
+    fn sys_getdents64(&mut self, fd: i32, buf: u64, count: u64) -> SysResult {
+        let mut data = vec![0u8; count.min(MAX_IO) as usize];
+        let n = host(unsafe {
+            libc::syscall(libc::SYS_getdents64, fd, data.as_mut_ptr(), data.len())
+        })?;
+        self.write_mem(buf, &data[..n as usize])?;
+        Ok(n)
+    }
+
+    fn sys_readlinkat(&mut self, dirfd: i32, path: u64, buf: u64, size: u64) -> SysResult {
+        let raw = self
+            .mm
+            .read_cstr(&self.bus.dram, path, PATH_MAX)
+            .map_err(|_| libc::EFAULT)?;
+        let target = if raw == b"/proc/self/exe" {
+            self.exe.as_os_str().as_bytes().to_vec()
+        } else {
+            let path = self.path(path)?;
+            let mut out = vec![0u8; PATH_MAX];
+            let n = host(unsafe {
+                libc::readlinkat(dirfd, path.as_ptr(), out.as_mut_ptr().cast(), out.len())
+            })?;
+            out.truncate(n as usize);
+            out
+        };
+        let n = target.len().min(size as usize);
+        self.write_mem(buf, &target[..n])?;
+        Ok(n as i64)
+    }
+
+    fn sys_renameat(
+        &mut self,
+        olddir: i32,
+        old: u64,
+        newdir: i32,
+        new: u64,
+        flags: u32,
+    ) -> SysResult {
+        let old = self.path(old)?;
+        let new = self.path(new)?;
+        host(unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                olddir,
+                old.as_ptr(),
+                newdir,
+                new.as_ptr(),
+                flags,
+            )
+        })
+    }
+
+    fn sys_getcwd(&mut self, buf: u64, size: u64) -> SysResult {
+        let cwd = std::env::current_dir().map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        let mut bytes = cwd.as_os_str().as_bytes().to_vec();
+        bytes.push(0);
+        if bytes.len() as u64 > size {
+            return Err(libc::ERANGE);
+        }
+        self.write_mem(buf, &bytes)?;
+        Ok(bytes.len() as i64)
+    }
+
+    fn sys_pipe2(&mut self, fds: u64, flags: u64) -> SysResult {
+        let mut pipe = [0i32; 2];
+        host(unsafe { libc::pipe2(pipe.as_mut_ptr(), host_open_flags(flags)) })?;
+        let bytes: Vec<u8> = pipe.iter().flat_map(|fd| fd.to_le_bytes()).collect();
+        if let Err(e) = self.write_mem(fds, &bytes) {
+            unsafe {
+                libc::close(pipe[0]);
+                libc::close(pipe[1]);
+            }
+            return Err(e);
+        }
+        Ok(0)
+    }
+
+    fn sys_brk(&mut self, addr: u64) -> i64 {
+        if addr >= self.brk_start && addr < MMAP_BOTTOM {
+            let dram = &self.bus.dram;
+            let old_end = page_ceil(self.brk);
+            let new_end = page_ceil(addr);
+            if new_end > old_end {
+                if !self.mm.is_free(old_end, new_end - old_end) {
+                    return self.brk as i64;
+                }
+                self.mm.map(dram, old_end, new_end - old_end, PROT_RW);
+            } else if new_end < old_end {
+                self.mm.unmap(dram, new_end, old_end - new_end);
+                self.flush_mappings();
+            }
+            self.brk = addr;
+        }
+        self.brk as i64
+    }
+
+    fn sys_mmap(
+        &mut self,
+        addr: u64,
+        len: u64,
+        prot: u64,
+        flags: u64,
+        fd: i32,
+        offset: u64,
+    ) -> SysResult {
+        if len == 0 || !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(libc::EINVAL);
+        }
+        if len > MMAP_TOP {
+            return Err(libc::ENOMEM);
+        }
+        let len = page_ceil(len);
+        let fixed = flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0;
+        let start = if fixed {
+            if !addr.is_multiple_of(PAGE_SIZE) {
+                return Err(libc::EINVAL);
+            }
+            if addr.saturating_add(len) > MMAP_TOP {
+                return Err(libc::ENOMEM);
+            }
+            if flags & MAP_FIXED_NOREPLACE != 0 && !self.mm.is_free(addr, len) {
+                return Err(libc::EEXIST);
+            }
+            addr
+        } else if addr != 0
+            && addr.is_multiple_of(PAGE_SIZE)
+            && addr.saturating_add(len) <= MMAP_TOP
+            && self.mm.is_free(addr, len)
+        {
+            addr
+        } else {
+            self.mm
+                .find_free(len, MMAP_BOTTOM, MMAP_TOP)
+                .ok_or(libc::ENOMEM)?
+        };
+
+        // Pages are populated lazily; only the file's bytes are copied in.
+        // File mappings are private copies; writes through MAP_SHARED
+        // mappings do not reach the file.
+        self.mm
+            .map(&self.bus.dram, start, len, prot as u32 & PROT_MASK);
+        if flags & MAP_ANONYMOUS == 0
+            && let Err(e) = self.copy_file_to_mapping(fd, offset, start, len)
+        {
+            self.mm.unmap(&self.bus.dram, start, len);
+            self.flush_mappings();
+            return Err(e);
+        }
+        if fixed {
+            self.flush_mappings();
+        }
+        Ok(start as i64)
+    }
+
+    /// Copy up to `len` bytes of `fd` from `offset` to guest `start`,
+    /// stopping at end of file.
+    fn copy_file_to_mapping(&mut self, fd: i32, offset: u64, start: u64, len: u64) -> SysResult {
+        let mut buf = vec![0u8; MMAP_CHUNK.min(len) as usize];
+        let mut filled = 0;
+        while filled < len {
+            let want = (len - filled).min(buf.len() as u64) as usize;
+            let n = host(unsafe {
+                libc::pread(fd, buf.as_mut_ptr().cast(), want, (offset + filled) as i64)
+            })? as usize;
+            if n == 0 {
+                break;
+            }
+            self.mm
+                .write_bytes(&self.bus.dram, start + filled, &buf[..n], Access::Force)
+                .map_err(|_| libc::ENOMEM)?;
+            filled += n as u64;
+        }
+        Ok(0)
+    }
+
+    fn sys_munmap(&mut self, addr: u64, len: u64) -> SysResult {
+        if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+            return Err(libc::EINVAL);
+        }
+        self.mm.unmap(&self.bus.dram, addr, len);
+        self.flush_mappings();
+        Ok(0)
+    }
+
+    fn sys_mprotect(&mut self, addr: u64, len: u64, prot: u64) -> SysResult {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(libc::EINVAL);
+        }
+        let result = self
+            .mm
+            .protect(&self.bus.dram, addr, len, prot as u32 & PROT_MASK);
+        self.flush_mappings();
+        result.map(|_| 0).map_err(|_| libc::ENOMEM)
+    }
+
+    fn sys_madvise(&mut self, addr: u64, len: u64, advice: u64) -> SysResult {
+        if advice == MADV_DONTNEED {
+            self.mm.discard(&self.bus.dram, addr, len);
+            self.flush_mappings();
+        }
+        Ok(0)
+    }
+
+    /// `clone` for new threads; `fork`-style clones are not supported.
+    fn sys_clone(&mut self, flags: u64, stack: u64, ptid: u64, tls: u64, ctid: u64) -> SysResult {
+        if flags & (CLONE_VM | CLONE_THREAD) != CLONE_VM | CLONE_THREAD {
+            log::warn!("[user] clone without CLONE_THREAD (fork) is not supported");
+            return Err(libc::ENOSYS);
+        }
+        let parent = &self.threads[self.current].cpu;
+        let mut cpu = user_cpu(self.mm.satp(), parent.pc);
+        cpu.regs = parent.regs;
+        cpu.regs[10] = 0;
+        if stack != 0 {
+            cpu.regs[2] = stack;
+        }
+        if flags & CLONE_SETTLS != 0 {
+            cpu.regs[4] = tls;
+        }
+
+        let tid = self.new_thread(cpu);
+        let dram = &self.bus.dram;
+        if flags & CLONE_PARENT_SETTID != 0 {
+            self.mm
+                .write_u32(dram, ptid, tid)
+                .map_err(|_| libc::EFAULT)?;
+        }
+        if flags & CLONE_CHILD_SETTID != 0 {
+            self.mm
+                .write_u32(dram, ctid, tid)
+                .map_err(|_| libc::EFAULT)?;
+        }
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            self.threads.last_mut().unwrap().clear_child_tid = ctid;
+        }
+        Ok(tid as i64)
+    }
+
+    fn sys_futex(
+        &mut self,
+        addr: u64,
+        op: u64,
+        val: u64,
+        timeout: u64,
+        addr2: u64,
+        val3: u64,
+    ) -> Option<SysResult> {
+        let cmd = op & FUTEX_CMD_MASK;
+        let result = match cmd {
+            FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+                let bitset = if cmd == FUTEX_WAIT {
+                    u32::MAX
+                } else {
+                    val3 as u32
+                };
+                match self.futex_wait(addr, val as u32, bitset, timeout, op) {
+                    Ok(()) => return None,
+                    Err(e) => Err(e),
+                }
+            }
+            FUTEX_WAKE | FUTEX_WAKE_BITSET => {
+                let bitset = if cmd == FUTEX_WAKE {
+                    u32::MAX
+                } else {
+                    val3 as u32
+                };
+                Ok(self.futex_wake(addr, val as u32, bitset) as i64)
+            }
+            FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+                let current = self.mm.read_u32(&self.bus.dram, addr);
+                match current {
+                    Err(_) => Err(libc::EFAULT),
+                    Ok(v) if cmd == FUTEX_CMP_REQUEUE && v != val3 as u32 => Err(libc::EAGAIN),
+                    Ok(_) => {
+                        let woken = self.futex_wake(addr, val as u32, u32::MAX);
+                        // For requeue, the timeout argument carries the
+                        // maximum number of waiters to move.
+                        let mut moved = 0;
+                        for thread in &mut self.threads {
+                            if moved == timeout as u32 {
+                                break;
+                            }
+                            if let ThreadState::Futex { addr: a, .. } = &mut thread.state
+                                && *a == addr
+                            {
+                                *a = addr2;
+                                moved += 1;
+                            }
+                        }
+                        Ok((woken + moved) as i64)
+                    }
+                }
+            }
+            _ => Err(libc::ENOSYS),
+        };
+        Some(result)
+    }
+
+    /// Block the current thread on `addr` if it still holds `val`.
+    fn futex_wait(
+        &mut self,
+        addr: u64,
+        val: u32,
+        bitset: u32,
+        timeout: u64,
+        op: u64,
+    ) -> Result<(), i32> {
+        if bitset == 0 {
+            return Err(libc::EINVAL);
+        }
+        let current = self
+            .mm
+            .read_u32(&self.bus.dram, addr)
+            .map_err(|_| libc::EFAULT)?;
+        if current != val {
+            return Err(libc::EAGAIN);
+        }
+        let deadline = if timeout == 0 {
+            None
+        } else {
+            let t = self.read_timespec(timeout)?;
+            // FUTEX_WAIT takes a relative timeout, FUTEX_WAIT_BITSET an
+            // absolute one.
+            let remaining = if op & FUTEX_CMD_MASK == FUTEX_WAIT {
+                t
+            } else if op & FUTEX_CLOCK_REALTIME != 0 {
+                t.saturating_sub(clock_now(libc::CLOCK_REALTIME))
+            } else {
+                t.saturating_sub(clock_now(libc::CLOCK_MONOTONIC))
+            };
+            Some(Instant::now() + remaining)
+        };
+        self.threads[self.current].state = ThreadState::Futex {
+            addr,
+            bitset,
+            deadline,
+        };
+        Ok(())
+    }
+
+    fn sys_rt_sigaction(&mut self, sig: u32, act: u64, oldact: u64) -> SysResult {
+        if sig == 0 || sig as usize > NSIG || (act != 0 && (sig == SIGKILL || sig == SIGSTOP)) {
+            return Err(libc::EINVAL);
+        }
+        // struct sigaction on riscv64: handler, flags, mask (no restorer).
+        if oldact != 0 {
+            let old = self.sigactions[sig as usize];
+            let bytes: Vec<u8> = [old.handler, old.flags, old.mask]
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            self.write_mem(oldact, &bytes)?;
+        }
+        if act != 0 {
+            let handler = self.read_u64(act)?;
+            let flags = self.read_u64(act + 8)?;
+            let mask = self.read_u64(act + 16)?;
+            self.sigactions[sig as usize] = SigAction {
+                handler,
+                flags,
+                mask: mask & !(sig_bit(SIGKILL) | sig_bit(SIGSTOP)),
+            };
+        }
+        Ok(0)
+    }
+
+    fn sys_rt_sigprocmask(&mut self, how: u64, set: u64, oldset: u64) -> SysResult {
+        let old = self.threads[self.current].sigmask;
+        if oldset != 0 {
+            self.write_mem(oldset, &old.to_le_bytes())?;
+        }
+        if set != 0 {
+            let set = self.read_u64(set)?;
+            let mask = match how {
+                0 => old | set,
+                1 => old & !set,
+                2 => set,
+                _ => return Err(libc::EINVAL),
+            };
+            self.threads[self.current].sigmask = mask & !(sig_bit(SIGKILL) | sig_bit(SIGSTOP));
+        }
+        Ok(0)
+    }
+
+    /// Alternate signal stacks are not supported; report one as disabled.
+    fn sys_sigaltstack(&mut self, oldss: u64) -> SysResult {
+        if oldss != 0 {
+            let mut stack = [0u8; 24];
+            stack[8..12].copy_from_slice(&(libc::SS_DISABLE as u32).to_le_bytes());
+            self.write_mem(oldss, &stack)?;
+        }
+        Ok(0)
+    }
+
+    fn sys_kill(&mut self, pid: i32, sig: u32) -> SysResult {
+        if sig as usize > NSIG {
+            return Err(libc::EINVAL);
+        }
+        if pid != 0 && pid != self.pid as i32 {
+            return host(unsafe { libc::kill(pid, sig as i32) });
+        }
+        if sig != 0 {
+            self.raise(sig);
+        }
+        Ok(0)
+    }
+
+    fn sys_tkill(&mut self, tid: u32, sig: u32) -> SysResult {
+        if sig as usize > NSIG {
+            return Err(libc::EINVAL);
+        }
+        let thread = self
+            .threads
+            .iter_mut()
+            .find(|t| t.tid == tid && t.state != ThreadState::Exited)
+            .ok_or(libc::ESRCH)?;
+        if sig != 0 {
+            thread.pending |= sig_bit(sig);
+        }
+        Ok(0)
+    }
+
+    fn sys_clock(&mut self, clock: i32, tp: u64, resolution: bool) -> SysResult {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        host(unsafe {
+            if resolution {
+                libc::clock_getres(clock, &mut ts)
+            } else {
+                libc::clock_gettime(clock, &mut ts)
+            }
+        })?;
+        if tp != 0 {
+            self.write_mem(tp, &timespec_bytes(ts.tv_sec, ts.tv_nsec))?;
+        }
+        Ok(0)
+    }
+
+    fn sys_gettimeofday(&mut self, tv: u64) -> SysResult {
+        if tv != 0 {
+            let now = clock_now(libc::CLOCK_REALTIME);
+            let usec = now.subsec_micros() as i64;
+            self.write_mem(tv, &timespec_bytes(now.as_secs() as i64, usec))?;
+        }
+        Ok(0)
+    }
+
+    /// Put the current thread to sleep; other guest threads keep running.
+    fn sys_nanosleep(&mut self, clock: i32, flags: u64, req: u64) -> Option<SysResult> {
+        let t = match self.read_timespec(req) {
+            Ok(t) => t,
+            Err(e) => return Some(Err(e)),
+        };
+        let duration = if flags & TIMER_ABSTIME != 0 {
+            t.saturating_sub(clock_now(clock))
+        } else {
+            t
+        };
+        self.threads[self.current].state = ThreadState::Sleeping(Instant::now() + duration);
+        None
+    }
+
+    fn sys_sched_getaffinity(&mut self, size: u64, mask: u64) -> SysResult {
+        // Guest threads share one host thread: report a single CPU.
+        if size < 8 {
+            return Err(libc::EINVAL);
+        }
+        self.write_mem(mask, &1u64.to_le_bytes())?;
+        Ok(8)
+    }
+
+    fn sys_uname(&mut self, buf: u64) -> SysResult {
+        let mut host_uts: libc::utsname = unsafe { std::mem::zeroed() };
+        unsafe { libc::uname(&mut host_uts) };
+        let nodename: Vec<u8> = host_uts
+            .nodename
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+
+        let fields: [&[u8]; 6] = [
+            b"Linux",
+            &nodename,
+            b"6.6.0",
+            b"#1 SMP riscv-vm",
+            b"riscv64",
+            b"(none)",
+        ];
+        let mut uts = vec![0u8; 6 * 65];
+        for (i, field) in fields.iter().enumerate() {
+            let n = field.len().min(64);
+            uts[i * 65..i * 65 + n].copy_from_slice(&field[..n]);
+        }
+        self.write_mem(buf, &uts)?;
+        Ok(0)
+    }
+
+    /// Report the host's limits; changes are accepted and ignored.
+    fn sys_prlimit(&mut self, resource: u32, _new: u64, old: u64) -> SysResult {
+        if old != 0 {
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            host(unsafe { libc::getrlimit(resource as _, &mut limit) })?;
+            let bytes: Vec<u8> = [limit.rlim_cur, limit.rlim_max]
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            self.write_mem(old, &bytes)?;
+        }
+        Ok(0)
+    }
+
+    fn sys_getrandom(&mut self, buf: u64, len: u64, flags: u32) -> SysResult {
+        let mut data = vec![0u8; len.min(MAX_IO) as usize];
+        let n = host(unsafe { libc::getrandom(data.as_mut_ptr().cast(), data.len(), flags) })?;
+        self.write_mem(buf, &data[..n as usize])?;
+        Ok(n)
+    }
+
+    fn sys_prctl(&mut self, option: u64, arg: u64) -> SysResult {
+        match option as i32 {
+            libc::PR_SET_NAME => Ok(0),
+            libc::PR_GET_NAME => {
+                let mut name = [0u8; 16];
+                name[..8].copy_from_slice(b"riscv-vm");
+                self.write_mem(arg, &name)?;
+                Ok(0)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+}
+
+/// Convert a host libc return value into a [`SysResult`].
+fn host<T: TryInto<i64>>(ret: T) -> SysResult {
+    let ret = ret.try_into().unwrap_or(-1);
+    if ret < 0 {
+        Err(std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO))
+    } else {
+        Ok(ret)
+    }
+}
+
+fn host_open_flags(flags: u64) -> libc::c_int {
+    let mut host = (flags & 0o3) as libc::c_int;
+    for &(guest, bits) in OPEN_FLAGS {
+        if flags & guest != 0 {
+            host |= bits;
+        }
+    }
+    host
+}
+
+fn guest_open_flags(host: i64) -> i64 {
+    let host = host as libc::c_int;
+    let mut flags = (host & 0o3) as i64;
+    for &(guest, bits) in OPEN_FLAGS {
+        if bits != 0 && host & bits == bits {
+            flags |= guest as i64;
+        }
+    }
+    flags
+}
+
+/// Current time on `clock` as a duration since its epoch.
+fn clock_now(clock: i32) -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+fn timespec_bytes(sec: i64, frac: i64) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out[..8].copy_from_slice(&sec.to_le_bytes());
+    out[8..].copy_from_slice(&frac.to_le_bytes());
+    out
+}
+
+/// Host `struct stat` in the riscv64 (asm-generic) layout.
+// Field types differ between host architectures.
+#[allow(clippy::unnecessary_cast)]
+fn guest_stat(st: &libc::stat) -> Vec<u8> {
+    let mut out = Vec::with_capacity(128);
+    out.extend((st.st_dev as u64).to_le_bytes());
+    out.extend((st.st_ino as u64).to_le_bytes());
+    out.extend((st.st_mode as u32).to_le_bytes());
+    out.extend((st.st_nlink as u32).to_le_bytes());
+    out.extend((st.st_uid as u32).to_le_bytes());
+    out.extend((st.st_gid as u32).to_le_bytes());
+    out.extend((st.st_rdev as u64).to_le_bytes());
+    out.extend(0u64.to_le_bytes());
+    out.extend((st.st_size as i64).to_le_bytes());
+    out.extend((st.st_blksize as i32).to_le_bytes());
+    out.extend(0i32.to_le_bytes());
+    out.extend((st.st_blocks as i64).to_le_bytes());
+    for (sec, nsec) in [
+        (st.st_atime, st.st_atime_nsec),
+        (st.st_mtime, st.st_mtime_nsec),
+        (st.st_ctime, st.st_ctime_nsec),
+    ] {
+        out.extend((sec as i64).to_le_bytes());
+        out.extend((nsec as u64).to_le_bytes());
+    }
+    out.extend(0u64.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_flags_round_trip() {
+        // O_WRONLY | O_CREAT | O_TRUNC | O_CLOEXEC in asm-generic encoding.
+        let guest = 0o1 | 0o100 | 0o1000 | 0o2000000;
+        let host = host_open_flags(guest);
+        assert_eq!(
+            host,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC
+        );
+        assert_eq!(
+            guest_open_flags(libc::O_RDWR as i64 | libc::O_APPEND as i64),
+            0o2 | 0o2000
+        );
+    }
+
+    #[test]
+    fn test_guest_stat_layout() {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        st.st_mode = 0o100644;
+        st.st_size = 1234;
+        let out = guest_stat(&st);
+        assert_eq!(out.len(), 128);
+        assert_eq!(
+            u32::from_le_bytes(out[16..20].try_into().unwrap()),
+            0o100644
+        );
+        assert_eq!(i64::from_le_bytes(out[48..56].try_into().unwrap()), 1234);
+    }
+}
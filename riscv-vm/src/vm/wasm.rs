@@ -1286,8 +1286,11 @@ impl WasmVm {
             }
             Err(Trap::Fatal(msg)) => {
                 web_sys::console::error_1(&wasm_bindgen::JsValue::from_str(&format!(
-                    "[VM] Fatal error: {} at PC=0x{:x}",
-                    msg, self.cpu.pc
+                    "[VM] Fatal error: {} at PC=0x{:x} ({})",
+                    msg,
+                    self.cpu.pc,
+                    crate::engine::disasm::disassemble_at(&self.cpu, &self.bus, self.cpu.pc)
+                        .unwrap_or_else(|| "unmapped".to_string())
                 )));
                 self.halted = true;
                 if let Some(ref control) = self.shared_control {
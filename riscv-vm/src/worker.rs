@@ -285,7 +285,7 @@ impl WorkerState {
                     self.control.signal_halted(0xDEAD);
                     return WorkerStepResult::Error;
                 }
-                Err(Trap::EnvironmentCallFromS) => {
+                Err(Trap::EnvironmentCallFromS) if self.cpu.builtin_sbi => {
                     // CRITICAL FIX: Workers must invoke SBI handler for ecall instructions!
                     if crate::sbi::handle_sbi_call(&mut self.cpu, &self.bus) {
                         self.cpu.pc = self.cpu.pc.wrapping_add(4);
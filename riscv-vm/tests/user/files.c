@@ -0,0 +1,38 @@
+/* File I/O through host file descriptors. */
+#include "sys.h"
+
+struct stat {
+    unsigned long dev, ino;
+    unsigned int mode, nlink, uid, gid;
+    unsigned long rdev, pad;
+    long size;
+    int blksize, pad2;
+    long blocks, times[6];
+    unsigned int unused[2];
+};
+
+int main(int argc, char **argv)
+{
+    CHECK(argc == 2, "usage: files <scratch file>");
+    const char *path = argv[1];
+    static const char text[] = "user-mode file round trip";
+
+    int fd = syscall6(SYS_openat, AT_FDCWD, (long)path, O_RDWR | O_CREAT | O_TRUNC, 0644, 0, 0);
+    CHECK(fd >= 0, "openat");
+    CHECK(syscall3(SYS_write, fd, text, sizeof(text)) == sizeof(text), "write");
+
+    struct stat st;
+    CHECK(syscall3(SYS_fstat, fd, &st, 0) == 0, "fstat");
+    CHECK(st.size == sizeof(text), "fstat size");
+
+    char buf[64];
+    CHECK(syscall3(SYS_lseek, fd, 0, 0) == 0, "lseek");
+    CHECK(syscall3(SYS_read, fd, buf, sizeof(buf)) == sizeof(text), "read");
+    for (size_t i = 0; i < sizeof(text); i++)
+        CHECK(buf[i] == text[i], "read back contents");
+
+    CHECK(syscall3(SYS_close, fd, 0, 0) == 0, "close");
+    CHECK(syscall3(SYS_unlinkat, AT_FDCWD, path, 0) == 0, "unlinkat");
+    CHECK(syscall6(SYS_openat, AT_FDCWD, (long)path, O_RDWR, 0, 0, 0) < 0, "file removed");
+    return 0;
+}
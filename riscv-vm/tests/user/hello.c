@@ -0,0 +1,13 @@
+/* Console output and argv. */
+#include "sys.h"
+
+int main(int argc, char **argv)
+{
+    puts_fd(1, "hello from riscv64\n");
+    CHECK(argc == 3, "argc");
+    puts_fd(1, argv[1]);
+    puts_fd(1, " ");
+    puts_fd(1, argv[2]);
+    puts_fd(1, "\n");
+    return 0;
+}
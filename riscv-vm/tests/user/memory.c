@@ -0,0 +1,26 @@
+/* brk, anonymous mmap, mprotect and munmap. */
+#include "sys.h"
+
+int main(int argc, char **argv)
+{
+    char *start = (char *)syscall3(SYS_brk, 0, 0, 0);
+    char *end = (char *)syscall3(SYS_brk, start + 3 * 4096 + 100, 0, 0);
+    CHECK(end == start + 3 * 4096 + 100, "brk grow");
+    for (char *p = start; p < end; p++)
+        CHECK(*p == 0, "brk memory is zeroed");
+    memset(start, 0xab, end - start);
+
+    size_t len = 1 << 20;
+    char *map = (char *)syscall6(SYS_mmap, 0, len, PROT_READ | PROT_WRITE,
+                                 MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK((long)map > 0, "mmap");
+    for (size_t i = 0; i < len; i += 4096) {
+        CHECK(map[i] == 0, "mmap memory is zeroed");
+        map[i] = (char)i;
+    }
+    CHECK(syscall3(SYS_mprotect, map, 4096, PROT_READ) == 0, "mprotect");
+    CHECK(map[0] == 0, "read after mprotect");
+    CHECK(syscall3(SYS_munmap, map, len, 0) == 0, "munmap");
+    CHECK(syscall3(SYS_mprotect, map, 4096, PROT_READ) != 0, "mprotect unmapped");
+    return 0;
+}
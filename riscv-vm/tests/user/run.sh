@@ -0,0 +1,39 @@
+#!/bin/sh
+# Build the user-mode test programs with a riscv64 cross compiler and run
+# each one under `riscv-vm user`.
+#
+# usage: run.sh <path to riscv-vm> [build dir]
+set -eu
+
+VM=${1:?usage: run.sh <riscv-vm binary> [build dir]}
+OUT=${2:-$(mktemp -d)}
+CC=${CC:-riscv64-linux-gnu-gcc}
+CFLAGS="-march=rv64imac -mabi=lp64 -O2 -static -nostdlib -ffreestanding -fno-builtin -fno-stack-protector"
+DIR=$(cd "$(dirname "$0")" && pwd)
+
+mkdir -p "$OUT"
+for src in "$DIR"/*.c; do
+    name=$(basename "$src" .c)
+    $CC $CFLAGS -o "$OUT/$name" "$src"
+done
+
+failed=0
+check() {
+    name=$1
+    shift
+    if "$@"; then
+        echo "PASS $name"
+    else
+        echo "FAIL $name"
+        failed=1
+    fi
+}
+
+check hello sh -c "'$VM' user '$OUT/hello' one two | diff - '$DIR/hello.expected'"
+check memory "$VM" user "$OUT/memory"
+check files "$VM" user "$OUT/files" "$OUT/scratch.txt"
+check threads "$VM" user "$OUT/threads"
+check signals "$VM" user "$OUT/signals"
+check exit-status sh -c "'$VM' user '$OUT/hello' >/dev/null 2>&1; test \$? -eq 1"
+
+exit $failed
@@ -0,0 +1,124 @@
+/*
+ * Minimal freestanding runtime for the user-mode test programs: raw Linux
+ * riscv64 system calls, an entry point and the few libc routines the
+ * compiler may emit calls to. Build with -nostdlib -static.
+ */
+#ifndef USER_TEST_SYS_H
+#define USER_TEST_SYS_H
+
+typedef unsigned long size_t;
+typedef long ssize_t;
+
+#define SYS_openat 56
+#define SYS_close 57
+#define SYS_lseek 62
+#define SYS_read 63
+#define SYS_write 64
+#define SYS_fstat 80
+#define SYS_unlinkat 35
+#define SYS_exit 93
+#define SYS_exit_group 94
+#define SYS_futex 98
+#define SYS_kill 129
+#define SYS_rt_sigaction 134
+#define SYS_getpid 172
+#define SYS_brk 214
+#define SYS_munmap 215
+#define SYS_clone 220
+#define SYS_mmap 222
+#define SYS_mprotect 226
+
+#define AT_FDCWD -100
+#define O_RDWR 02
+#define O_CREAT 0100
+#define O_TRUNC 01000
+#define PROT_READ 1
+#define PROT_WRITE 2
+#define MAP_PRIVATE 0x02
+#define MAP_ANONYMOUS 0x20
+#define FUTEX_WAIT 0
+#define SIGUSR1 10
+#define SIGSEGV 11
+
+static inline long syscall6(long n, long a, long b, long c, long d, long e, long f)
+{
+    register long a0 __asm__("a0") = a;
+    register long a1 __asm__("a1") = b;
+    register long a2 __asm__("a2") = c;
+    register long a3 __asm__("a3") = d;
+    register long a4 __asm__("a4") = e;
+    register long a5 __asm__("a5") = f;
+    register long a7 __asm__("a7") = n;
+    __asm__ volatile("ecall"
+                     : "+r"(a0)
+                     : "r"(a1), "r"(a2), "r"(a3), "r"(a4), "r"(a5), "r"(a7)
+                     : "memory");
+    return a0;
+}
+
+#define syscall3(n, a, b, c) syscall6(n, (long)(a), (long)(b), (long)(c), 0, 0, 0)
+
+static size_t strlen(const char *s)
+{
+    size_t n = 0;
+    while (s[n])
+        n++;
+    return n;
+}
+
+static void puts_fd(int fd, const char *s)
+{
+    syscall3(SYS_write, fd, s, strlen(s));
+}
+
+static __attribute__((noreturn)) void exit(int status)
+{
+    syscall3(SYS_exit_group, status, 0, 0);
+    __builtin_unreachable();
+}
+
+/* Print `msg` and exit with status 1 unless `cond` holds. */
+#define CHECK(cond, msg)                                                       \
+    do {                                                                       \
+        if (!(cond)) {                                                         \
+            puts_fd(2, "FAIL: " msg "\n");                                     \
+            exit(1);                                                           \
+        }                                                                      \
+    } while (0)
+
+void *memset(void *dst, int c, size_t n)
+{
+    unsigned char *d = dst;
+    while (n--)
+        *d++ = (unsigned char)c;
+    return dst;
+}
+
+void *memcpy(void *dst, const void *src, size_t n)
+{
+    unsigned char *d = dst;
+    const unsigned char *s = src;
+    while (n--)
+        *d++ = *s++;
+    return dst;
+}
+
+int main(int argc, char **argv);
+
+__attribute__((used)) static void start_c(long *sp)
+{
+    exit(main((int)sp[0], (char **)(sp + 1)));
+}
+
+__asm__(".text\n"
+        ".global _start\n"
+        "_start:\n"
+        ".option push\n"
+        ".option norelax\n"
+        "  la gp, __global_pointer$\n"
+        ".option pop\n"
+        "  mv a0, sp\n"
+        "  andi sp, sp, -16\n"
+        "  call start_c\n");
+
+#endif
@@ -0,0 +1,57 @@
+/* Threads created with clone(), joined through CLONE_CHILD_CLEARTID futexes. */
+#include "sys.h"
+
+#define THREADS 4
+#define ITERATIONS 20000
+#define STACK_SIZE 16384
+
+/* spawn_thread(fn, arg, stack_top, ctid): start fn(arg) on a new thread. */
+long spawn_thread(void (*fn)(void *), void *arg, void *stack_top, int *ctid);
+__asm__(".text\n"
+        ".global spawn_thread\n"
+        "spawn_thread:\n"
+        "  addi a2, a2, -16\n"
+        "  sd a0, 0(a2)\n"
+        "  sd a1, 8(a2)\n"
+        "  mv a4, a3\n"
+        "  mv a1, a2\n"
+        /* VM|FS|FILES|SIGHAND|THREAD|SYSVSEM|CHILD_CLEARTID|CHILD_SETTID */
+        "  li a0, 0x1250f00\n"
+        "  li a2, 0\n"
+        "  li a3, 0\n"
+        "  li a7, 220\n"
+        "  ecall\n"
+        "  beqz a0, 1f\n"
+        "  ret\n"
+        "1:\n"
+        "  ld t0, 0(sp)\n"
+        "  ld a0, 8(sp)\n"
+        "  jalr t0\n"
+        "  li a0, 0\n"
+        "  li a7, 93\n"
+        "  ecall\n");
+
+static long counter;
+static char stacks[THREADS][STACK_SIZE] __attribute__((aligned(16)));
+static int tids[THREADS];
+
+static void worker(void *arg)
+{
+    for (int i = 0; i < ITERATIONS; i++)
+        __atomic_fetch_add(&counter, (long)arg, __ATOMIC_SEQ_CST);
+}
+
+int main(int argc, char **argv)
+{
+    for (int i = 0; i < THREADS; i++) {
+        long tid = spawn_thread(worker, (void *)1, stacks[i] + STACK_SIZE, &tids[i]);
+        CHECK(tid > 0, "clone");
+    }
+    for (int i = 0; i < THREADS; i++) {
+        int tid;
+        while ((tid = __atomic_load_n(&tids[i], __ATOMIC_SEQ_CST)) != 0)
+            syscall6(SYS_futex, (long)&tids[i], FUTEX_WAIT, tid, 0, 0, 0);
+    }
+    CHECK(counter == (long)THREADS * ITERATIONS, "atomic counter");
+    return 0;
+}